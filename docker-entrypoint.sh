@@ -1,8 +1,30 @@
 #!/bin/sh
 set -e
 
-# Start backend in background
+# Sign-in codes have nowhere to go by default
+: "${SPLITDUMB_SMS:?set SPLITDUMB_SMS to where sign-in codes are sent}"
+export SPLITDUMB_SMS
+
 splitdumb serve --port 3000 --data-file /data/app_data.json &
+backend_pid=$!
+nginx -g 'daemon off;' &
+nginx_pid=$!
+
+# This shell is PID 1, so signals reach it rather than the servers. Pass
+# them on and wait, letting the backend finish requests and flush its writes.
+stop() {
+    trap - TERM INT
+    kill -TERM "$backend_pid" "$nginx_pid" 2>/dev/null || true
+    status=0
+    wait "$backend_pid" || status=$?
+    wait "$nginx_pid" || true
+    exit "$status"
+}
+trap stop TERM INT
 
-# Start nginx in foreground
-nginx -g 'daemon off;'
+# Whichever server exits first takes the other down with it
+while kill -0 "$backend_pid" 2>/dev/null && kill -0 "$nginx_pid" 2>/dev/null; do
+    sleep 1 &
+    wait $! || true
+done
+stop
@@ -0,0 +1,83 @@
+use axum::http::Method;
+use chrono::{DateTime, Utc};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::{ApiKey, ApiKeyScope, AuthUser};
+use crate::sessions::hash_token;
+
+/// Every API key starts with this, which tells it apart from a session's
+/// access token.
+pub const KEY_PREFIX: &str = "sdk_";
+
+/// Most API keys one user may have.
+const MAX_KEYS_PER_USER: usize = 20;
+
+/// Longest name kept for a key.
+const MAX_NAME_LEN: usize = 100;
+
+/// Creates a key named `name` for `user`, returning it with the key itself.
+/// Only the key's hash is kept, so this is the only time it is seen.
+pub fn add(
+    user: &mut AuthUser,
+    name: &str,
+    scope: ApiKeyScope,
+    now: DateTime<Utc>,
+) -> Result<(ApiKey, String), AppError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("API key name is required".to_string()));
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::BadRequest(format!(
+            "API key name must be at most {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    if user.api_keys.len() >= MAX_KEYS_PER_USER {
+        return Err(AppError::BadRequest(format!(
+            "A user can have at most {} API keys; revoke one first",
+            MAX_KEYS_PER_USER
+        )));
+    }
+
+    let key = format!("{}{}", KEY_PREFIX, Uuid::new_v4().simple());
+    let api_key = ApiKey {
+        id: Uuid::new_v4().to_string(),
+        name: name.to_string(),
+        key_hash: hash_token(&key),
+        scope,
+        created_at: now.to_rfc3339(),
+        last_used_at: None,
+    };
+    user.api_keys.push(api_key.clone());
+    Ok((api_key, key))
+}
+
+/// The user and API key that `key` belongs to. Like `sessions::find`, every
+/// stored hash is compared in full, in constant time.
+pub fn find<'a>(users: &'a [AuthUser], key: &str) -> Option<(&'a AuthUser, &'a ApiKey)> {
+    let hash = hash_token(key);
+    let mut found = None;
+    for user in users {
+        for api_key in &user.api_keys {
+            if bool::from(api_key.key_hash.as_bytes().ct_eq(hash.as_bytes())) {
+                found = Some((user, api_key));
+            }
+        }
+    }
+    found
+}
+
+/// Whether a key with `scope` may make a `method` request to `path`.
+/// Settling up counts as writing expenses, since it records a payment.
+pub fn allows(scope: ApiKeyScope, method: &Method, path: &str) -> bool {
+    let reads = *method == Method::GET || *method == Method::HEAD;
+    let expenses = path == "/api/expenses" || path.starts_with("/api/expenses/");
+    match scope {
+        ApiKeyScope::ReadOnly => reads,
+        ApiKeyScope::WriteExpenses => reads || expenses || path == "/api/settle",
+        ApiKeyScope::Admin => true,
+    }
+}
@@ -1,8 +1,21 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::config::{IpRange, LogFormat};
+use crate::models::ApiKeyScope;
+use crate::money::Money;
+use crate::storage::writer::Durability;
 
 #[derive(Parser)]
 #[clap(author, version, about = "Splitdumb - Expense sharing made simple")]
 pub struct Cli {
+    /// File holding the base64 key the data file is encrypted with; falls
+    /// back to the SPLITDUMB_DATA_KEY environment variable
+    #[clap(long, global = true)]
+    pub key_file: Option<String>,
+
     #[clap(subcommand)]
     pub command: Commands,
 }
@@ -11,13 +24,14 @@ pub struct Cli {
 pub enum Commands {
     /// Starts the web server
     Serve {
-        /// Port to listen on
-        #[clap(short, long, default_value = "3000")]
-        port: u16,
+        #[clap(flatten)]
+        settings: ServeArgs,
+    },
 
-        /// Path to the data file
-        #[clap(short, long, default_value = "app_data.json")]
-        data_file: String,
+    /// Inspects the server configuration
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
     },
 
     /// Adds a new expense
@@ -28,11 +42,11 @@ pub enum Commands {
 
         /// Amount of the expense
         #[clap(short, long)]
-        amount: f64,
+        amount: Money,
 
-        /// Payer of the expense
-        #[clap(short = 'P', long)]
-        payer: String,
+        /// Payer of the expense, or several payers with amounts (e.g. Alice:30,Bob:20)
+        #[clap(short = 'P', long, value_delimiter = ',', required = true)]
+        payer: Vec<PayerArg>,
 
         /// Participants of the expense (comma-separated)
         #[clap(short = 'u', long)]
@@ -48,6 +62,11 @@ pub enum Commands {
         /// Path to the data file
         #[clap(long, default_value = "app_data.json")]
         data_file: String,
+
+        /// Show the balances as they were at this time (RFC 3339); needs an
+        /// `events:` data file
+        #[clap(long)]
+        as_of: Option<chrono::DateTime<chrono::Utc>>,
     },
 
     /// Shows suggested settlements
@@ -55,5 +74,344 @@ pub enum Commands {
         /// Path to the data file
         #[clap(long, default_value = "app_data.json")]
         data_file: String,
+
+        /// Show the settlements as they were at this time (RFC 3339); needs an
+        /// `events:` data file
+        #[clap(long)]
+        as_of: Option<chrono::DateTime<chrono::Utc>>,
+    },
+
+    /// Sets an exchange rate in the first group's rate table
+    SetRate {
+        /// Currency being converted from (e.g. EUR)
+        #[clap(long)]
+        from: String,
+
+        /// Currency being converted to (e.g. USD)
+        #[clap(long)]
+        to: String,
+
+        /// Units of `to` per unit of `from`
+        #[clap(long)]
+        rate: f64,
+
+        /// Path to the data file
+        #[clap(long, default_value = "app_data.json")]
+        data_file: String,
+    },
+
+    /// Upgrades a JSON data file to the current schema version
+    Migrate {
+        /// Path to the data file
+        #[clap(long, default_value = "app_data.json")]
+        data_file: String,
+
+        /// Only report the migrations that would run
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// Rebuilds a damaged JSON data file from the records that can still be read
+    Repair {
+        /// Path to the data file
+        #[clap(long, default_value = "app_data.json")]
+        data_file: String,
+
+        /// Only report what would be kept and dropped
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// Writes a timestamped backup of the data; safe while the server runs
+    Backup {
+        /// Path to the data file
+        #[clap(long, default_value = "app_data.json")]
+        data_file: String,
+
+        /// Directory for timestamped backups
+        #[clap(long, default_value = "backups")]
+        backup_dir: String,
+
+        /// Delete all but this many of the newest backups afterwards
+        #[clap(long)]
+        retain: Option<usize>,
+    },
+
+    /// Replaces the data with a backup after checking it; stop the server first
+    Restore {
+        /// Backup file to restore
+        backup: String,
+
+        /// Path to the data file
+        #[clap(long, default_value = "app_data.json")]
+        data_file: String,
+
+        /// Where the current data is backed up before being replaced
+        #[clap(long, default_value = "backups")]
+        backup_dir: String,
+    },
+
+    /// Writes a new random data key to a file
+    GenerateKey {
+        /// Where to write the key; must not exist yet
+        output: String,
+    },
+
+    /// Encrypts plaintext data with the data key: a JSON data file and its
+    /// backup, or every shard
+    Encrypt {
+        /// Path to the data file, or `shards:<dir>`
+        #[clap(long, default_value = "app_data.json")]
+        data_file: String,
+
+        /// Directory of timestamped backups to encrypt as well
+        #[clap(long)]
+        backup_dir: Option<String>,
+    },
+
+    /// Re-encrypts the data with a new key; stop the server first
+    RotateKey {
+        /// Key file holding the new key
+        #[clap(long)]
+        new_key_file: String,
+
+        /// Path to the data file, or `shards:<dir>`
+        #[clap(long, default_value = "app_data.json")]
+        data_file: String,
+
+        /// Directory of timestamped backups to re-encrypt as well
+        #[clap(long)]
+        backup_dir: Option<String>,
+    },
+
+    /// Shows the first group's exchange rate table
+    ShowRates {
+        /// Path to the data file
+        #[clap(long, default_value = "app_data.json")]
+        data_file: String,
     },
+
+    /// Manages a user's personal API keys
+    ApiKeys {
+        #[clap(subcommand)]
+        command: ApiKeyCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ApiKeyCommand {
+    /// Creates an API key and prints it; it cannot be shown again
+    Create {
+        /// Phone number of the user the key acts as
+        #[clap(long)]
+        phone: String,
+
+        /// Name to recognize the key by
+        #[clap(long)]
+        name: String,
+
+        /// What the key may be used for
+        #[clap(long, value_enum)]
+        scope: ApiKeyScope,
+
+        /// Path to the data file
+        #[clap(long, default_value = "app_data.json")]
+        data_file: String,
+    },
+
+    /// Lists a user's API keys
+    List {
+        /// Phone number of the user
+        #[clap(long)]
+        phone: String,
+
+        /// Path to the data file
+        #[clap(long, default_value = "app_data.json")]
+        data_file: String,
+    },
+
+    /// Revokes an API key
+    Revoke {
+        /// Id of the key, as listed
+        id: String,
+
+        /// Phone number of the user
+        #[clap(long)]
+        phone: String,
+
+        /// Path to the data file
+        #[clap(long, default_value = "app_data.json")]
+        data_file: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Validates the configuration and prints the settings `serve` would
+    /// use with the same file, environment and flags
+    Check {
+        #[clap(flatten)]
+        settings: ServeArgs,
+    },
+}
+
+/// Server settings given on the command line. Each one overrides the
+/// config file and environment; see `Config` for the defaults.
+#[derive(Args, Clone, Debug, Default)]
+pub struct ServeArgs {
+    /// Config file; defaults to SPLITDUMB_CONFIG, then `splitdumb.toml` if
+    /// it exists
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. `127.0.0.1:3000`
+    #[clap(long)]
+    pub bind: Option<SocketAddr>,
+
+    /// Port to listen on, keeping the configured address
+    #[clap(short, long)]
+    pub port: Option<u16>,
+
+    /// Path to the data file
+    #[clap(short, long)]
+    pub data_file: Option<String>,
+
+    /// Storage backend, e.g. `sqlite:splitdumb.db`, `shards:data` or
+    /// `json:app_data.json` (overrides --data-file)
+    #[clap(long)]
+    pub storage: Option<String>,
+
+    /// Start with empty data if the stored data cannot be loaded. The
+    /// unreadable files are copied aside first.
+    #[clap(long)]
+    pub allow_empty: bool,
+
+    /// Origin allowed to call the API from a browser; repeat for several,
+    /// or pass `*` to allow any
+    #[clap(long = "cors-origin")]
+    pub cors_origins: Vec<String>,
+
+    /// How long a login stays valid without being refreshed, e.g. `12h`
+    /// or `30d`
+    #[clap(long, value_parser = parse_interval)]
+    pub token_lifetime: Option<Duration>,
+
+    /// How long an access token works before it has to be refreshed,
+    /// e.g. `15m`
+    #[clap(long, value_parser = parse_interval)]
+    pub access_token_lifetime: Option<Duration>,
+
+    /// Where sign-in codes are sent: `file:<path>` to append them to a
+    /// file, or `stdout` for local development. Required
+    #[clap(long)]
+    pub sms: Option<String>,
+
+    /// Take a timestamped backup this often, e.g. `1h` or `1d`
+    #[clap(long, value_parser = parse_interval)]
+    pub backup_interval: Option<Duration>,
+
+    /// Directory for timestamped backups
+    #[clap(long)]
+    pub backup_dir: Option<String>,
+
+    /// Number of timestamped backups to keep
+    #[clap(long)]
+    pub backup_retain: Option<usize>,
+
+    /// `sync` answers each request once its change is on disk; `batched`
+    /// answers straight away and writes changes together
+    #[clap(long, value_enum)]
+    pub durability: Option<Durability>,
+
+    /// How long batched writes are gathered before being stored
+    #[clap(long, value_parser = parse_interval)]
+    pub batch_window: Option<Duration>,
+
+    /// How long to wait for in-flight requests on SIGINT or SIGTERM
+    /// before dropping them
+    #[clap(long, value_parser = parse_interval)]
+    pub shutdown_timeout: Option<Duration>,
+
+    /// `text`, `compact` or `json`
+    #[clap(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Which logs to show, e.g. `splitdumb=info,tower_http=warn`
+    #[clap(long)]
+    pub log_filter: Option<String>,
+
+    /// Requests each client address may make per minute; 0 turns the
+    /// limit off
+    #[clap(long)]
+    pub rate_limit: Option<u32>,
+
+    /// Requests a client may make at once before the per-minute rate applies
+    #[clap(long)]
+    pub rate_limit_burst: Option<u32>,
+
+    /// Address or CIDR block of a reverse proxy whose forwarding headers
+    /// name the client; repeat for several
+    #[clap(long = "trusted-proxy")]
+    pub trusted_proxies: Vec<IpRange>,
+}
+
+/// A `--payer` entry: a member name, optionally followed by `:amount`.
+#[derive(Clone, Debug)]
+pub struct PayerArg {
+    pub name: String,
+    pub amount: Option<Money>,
+}
+
+impl std::str::FromStr for PayerArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((name, amount)) => Ok(PayerArg {
+                name: name.trim().to_string(),
+                amount: Some(amount.parse().map_err(|e| format!("{}", e))?),
+            }),
+            None => Ok(PayerArg {
+                name: s.trim().to_string(),
+                amount: None,
+            }),
+        }
+    }
+}
+
+/// Parses an interval such as `50ms`, `90s`, `30m`, `1h` or `7d`. Zero is
+/// refused, since no setting taking an interval means anything by it.
+pub fn parse_interval(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (count, millis) = match s.strip_suffix("ms") {
+        Some(count) => (count, 1),
+        None => {
+            // The unit may be any character, so split before the last one
+            let split = s.char_indices().last().map_or(0, |(i, _)| i);
+            let (count, unit) = s.split_at(split);
+            let seconds = match unit {
+                "s" => 1,
+                "m" => 60,
+                "h" => 60 * 60,
+                "d" => 24 * 60 * 60,
+                _ => {
+                    return Err(format!(
+                        "unknown interval unit in '{}', use ms, s, m, h or d",
+                        s
+                    ));
+                }
+            };
+            (count, seconds * 1000)
+        }
+    };
+    let count: u64 = count
+        .parse()
+        .map_err(|_| format!("invalid interval '{}', expected e.g. 50ms, 30m or 1d", s))?;
+    if count == 0 {
+        return Err("interval must be greater than zero".to_string());
+    }
+    count
+        .checked_mul(millis)
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("interval '{}' is too long", s))
 }
@@ -0,0 +1,578 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+use crate::cli::{ServeArgs, parse_interval};
+use crate::storage::backup::BackupPolicy;
+use crate::storage::encryption::DataKey;
+use crate::storage::writer::Durability;
+
+/// Read when neither `--config` nor SPLITDUMB_CONFIG names a file.
+pub const DEFAULT_FILE: &str = "splitdumb.toml";
+
+/// Server settings. Each layer overrides the one before it: built-in
+/// defaults, the TOML config file, `SPLITDUMB_*` environment variables and
+/// finally command line flags.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub auth: AuthConfig,
+    pub storage: StorageConfig,
+    pub backups: BackupConfig,
+    pub log: LogConfig,
+    pub rate_limit: RateLimitConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    /// Origins allowed to call the API from a browser; `*` allows any.
+    pub cors_origins: Vec<String>,
+    pub shutdown_timeout: Interval,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            cors_origins: vec!["*".to_string()],
+            shutdown_timeout: Interval(Duration::from_secs(10)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// How long a session lasts without being refreshed. Each refresh
+    /// starts it over.
+    pub token_lifetime: Interval,
+    /// How long an access token works before it has to be refreshed.
+    pub access_token_lifetime: Interval,
+    /// How long a sign-in code sent by text message stays valid.
+    pub code_lifetime: Interval,
+    /// Wrong guesses allowed before a sign-in code is thrown away.
+    pub code_attempts: u32,
+    /// Where sign-in codes are sent: `file:<path>`, or `stdout` for local
+    /// development. There is no default so a deployment cannot end up
+    /// printing codes to its logs without choosing to.
+    pub sms: Option<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            token_lifetime: Interval(Duration::from_secs(30 * 24 * 60 * 60)),
+            access_token_lifetime: Interval(Duration::from_secs(15 * 60)),
+            code_lifetime: Interval(Duration::from_secs(5 * 60)),
+            code_attempts: 5,
+            sms: None,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn token_lifetime(&self) -> chrono::Duration {
+        chrono::Duration::from_std(self.token_lifetime.0).unwrap_or(chrono::Duration::MAX)
+    }
+
+    pub fn access_token_lifetime(&self) -> chrono::Duration {
+        chrono::Duration::from_std(self.access_token_lifetime.0).unwrap_or(chrono::Duration::MAX)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// A storage spec as accepted by `storage::open`.
+    pub backend: String,
+    pub allow_empty: bool,
+    pub durability: Durability,
+    pub batch_window: Interval,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: "app_data.json".to_string(),
+            allow_empty: false,
+            durability: Durability::Sync,
+            batch_window: Interval(Duration::from_millis(50)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    /// Backups are only taken when this is set.
+    pub interval: Option<Interval>,
+    pub dir: PathBuf,
+    pub retain: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            interval: None,
+            dir: PathBuf::from("backups"),
+            retain: 24,
+        }
+    }
+}
+
+impl BackupConfig {
+    pub fn policy(&self, key: Option<DataKey>) -> Option<BackupPolicy> {
+        self.interval.map(|interval| BackupPolicy {
+            dir: self.dir.clone(),
+            interval: interval.0,
+            retain: self.retain,
+            key,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, one event per line
+    #[default]
+    Text,
+    /// Shorter human readable lines
+    Compact,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// A `tracing` filter such as `splitdumb=info,tower_http=warn`.
+    pub filter: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::Text,
+            filter: "splitdumb=debug,tower_http=debug".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Requests each client address may make per minute; 0 turns the limit
+    /// off.
+    pub requests_per_minute: u32,
+    /// Requests a client may make at once before the rate applies.
+    pub burst: u32,
+    /// Reverse proxies in front of the server, such as the bundled nginx.
+    /// Requests from them are counted against the client named in their
+    /// `X-Forwarded-For` or `X-Real-IP` header instead of the proxy.
+    pub trusted_proxies: Vec<IpRange>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            requests_per_minute: 0,
+            burst: 20,
+            trusted_proxies: vec![],
+        }
+    }
+}
+
+/// An address or a CIDR block of them, e.g. `127.0.0.1` or `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+/// An address as a number, with how many bits its family has.
+fn address_bits(addr: IpAddr) -> (u128, u32) {
+    match addr.to_canonical() {
+        IpAddr::V4(v4) => (u128::from(u32::from(v4)), 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
+
+impl IpRange {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (network, width) = address_bits(self.addr);
+        let (addr, addr_width) = address_bits(addr);
+        width == addr_width
+            && (network ^ addr)
+                .checked_shr(width - self.prefix)
+                .unwrap_or(0)
+                == 0
+    }
+}
+
+impl std::str::FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || {
+            format!(
+                "invalid address '{}', expected e.g. 10.0.0.1 or 10.0.0.0/8",
+                s
+            )
+        };
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let (_, width) = address_bits(addr);
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= width)
+                .ok_or_else(invalid)?,
+            None => width,
+        };
+        Ok(IpRange { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix == address_bits(self.addr).1 {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+impl Serialize for IpRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A duration written the way the command line takes it, e.g. `50ms`,
+/// `10s` or `30d`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interval(pub Duration);
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = self.0.as_millis();
+        if !millis.is_multiple_of(1000) {
+            return write!(f, "{}ms", millis);
+        }
+        let seconds = self.0.as_secs();
+        let (count, unit) = [(24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m")]
+            .into_iter()
+            .find(|(size, _)| seconds > 0 && seconds.is_multiple_of(*size))
+            .map(|(size, unit)| (seconds / size, unit))
+            .unwrap_or((seconds, "s"));
+        write!(f, "{}{}", count, unit)
+    }
+}
+
+impl Serialize for Interval {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Interval {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse_interval(&s)
+            .map(Interval)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Config {
+    /// Resolves the configuration from the process environment and `args`,
+    /// returning it with the config file that was read, if any.
+    pub fn load(args: &ServeArgs) -> Result<(Config, Option<PathBuf>), String> {
+        Config::resolve(args, |name| std::env::var(name).ok())
+    }
+
+    /// Resolves the configuration with `env` standing in for the process
+    /// environment.
+    pub fn resolve(
+        args: &ServeArgs,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<(Config, Option<PathBuf>), String> {
+        let path = args
+            .config
+            .clone()
+            .or_else(|| env("SPLITDUMB_CONFIG").map(PathBuf::from))
+            .or_else(|| Some(PathBuf::from(DEFAULT_FILE)).filter(|path| path.exists()));
+        let mut config = match &path {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply_env(env)?;
+        config.apply_args(args);
+        config.validate()?;
+        Ok((config, path))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        let var = |name: &str| env(name).map(|value| value.trim().to_string());
+        let parsed = |name: &str| -> Result<Option<Parsed>, String> {
+            var(name)
+                .map(|value| Ok(Parsed(name.to_string(), value)))
+                .transpose()
+        };
+
+        if let Some(bind) = parsed("SPLITDUMB_BIND")? {
+            self.server.bind = bind.into_value()?;
+        }
+        if let Some(origins) = var("SPLITDUMB_CORS_ORIGINS") {
+            self.server.cors_origins = origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(timeout) = parsed("SPLITDUMB_SHUTDOWN_TIMEOUT")? {
+            self.server.shutdown_timeout = timeout.into_interval()?;
+        }
+        if let Some(lifetime) = parsed("SPLITDUMB_TOKEN_LIFETIME")? {
+            self.auth.token_lifetime = lifetime.into_interval()?;
+        }
+        if let Some(lifetime) = parsed("SPLITDUMB_ACCESS_TOKEN_LIFETIME")? {
+            self.auth.access_token_lifetime = lifetime.into_interval()?;
+        }
+        if let Some(lifetime) = parsed("SPLITDUMB_CODE_LIFETIME")? {
+            self.auth.code_lifetime = lifetime.into_interval()?;
+        }
+        if let Some(attempts) = parsed("SPLITDUMB_CODE_ATTEMPTS")? {
+            self.auth.code_attempts = attempts.into_value()?;
+        }
+        if let Some(sms) = var("SPLITDUMB_SMS") {
+            self.auth.sms = Some(sms);
+        }
+        if let Some(backend) = var("SPLITDUMB_STORAGE") {
+            self.storage.backend = backend;
+        }
+        if let Some(allow_empty) = parsed("SPLITDUMB_ALLOW_EMPTY")? {
+            self.storage.allow_empty = allow_empty.into_value()?;
+        }
+        if let Some(durability) = parsed("SPLITDUMB_DURABILITY")? {
+            self.storage.durability = durability.into_enum()?;
+        }
+        if let Some(window) = parsed("SPLITDUMB_BATCH_WINDOW")? {
+            self.storage.batch_window = window.into_interval()?;
+        }
+        if let Some(interval) = parsed("SPLITDUMB_BACKUP_INTERVAL")? {
+            self.backups.interval = Some(interval.into_interval()?);
+        }
+        if let Some(dir) = var("SPLITDUMB_BACKUP_DIR") {
+            self.backups.dir = PathBuf::from(dir);
+        }
+        if let Some(retain) = parsed("SPLITDUMB_BACKUP_RETAIN")? {
+            self.backups.retain = retain.into_value()?;
+        }
+        if let Some(format) = parsed("SPLITDUMB_LOG_FORMAT")? {
+            self.log.format = format.into_enum()?;
+        }
+        if let Some(filter) = var("SPLITDUMB_LOG").or_else(|| var("RUST_LOG")) {
+            self.log.filter = filter;
+        }
+        if let Some(rate) = parsed("SPLITDUMB_RATE_LIMIT")? {
+            self.rate_limit.requests_per_minute = rate.into_value()?;
+        }
+        if let Some(burst) = parsed("SPLITDUMB_RATE_LIMIT_BURST")? {
+            self.rate_limit.burst = burst.into_value()?;
+        }
+        if let Some(proxies) = var("SPLITDUMB_TRUSTED_PROXIES") {
+            self.rate_limit.trusted_proxies = proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| {
+                    proxy
+                        .parse()
+                        .map_err(|e| format!("SPLITDUMB_TRUSTED_PROXIES: {}", e))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &ServeArgs) {
+        if let Some(bind) = args.bind {
+            self.server.bind = bind;
+        }
+        if let Some(port) = args.port {
+            self.server.bind.set_port(port);
+        }
+        if !args.cors_origins.is_empty() {
+            self.server.cors_origins = args.cors_origins.clone();
+        }
+        if let Some(timeout) = args.shutdown_timeout {
+            self.server.shutdown_timeout = Interval(timeout);
+        }
+        if let Some(lifetime) = args.token_lifetime {
+            self.auth.token_lifetime = Interval(lifetime);
+        }
+        if let Some(lifetime) = args.access_token_lifetime {
+            self.auth.access_token_lifetime = Interval(lifetime);
+        }
+        if let Some(sms) = &args.sms {
+            self.auth.sms = Some(sms.clone());
+        }
+        if let Some(backend) = args.storage.as_ref().or(args.data_file.as_ref()) {
+            self.storage.backend = backend.clone();
+        }
+        if args.allow_empty {
+            self.storage.allow_empty = true;
+        }
+        if let Some(durability) = args.durability {
+            self.storage.durability = durability;
+        }
+        if let Some(window) = args.batch_window {
+            self.storage.batch_window = Interval(window);
+        }
+        if let Some(interval) = args.backup_interval {
+            self.backups.interval = Some(Interval(interval));
+        }
+        if let Some(dir) = &args.backup_dir {
+            self.backups.dir = PathBuf::from(dir);
+        }
+        if let Some(retain) = args.backup_retain {
+            self.backups.retain = retain;
+        }
+        if let Some(format) = args.log_format {
+            self.log.format = format;
+        }
+        if let Some(filter) = &args.log_filter {
+            self.log.filter = filter.clone();
+        }
+        if let Some(rate) = args.rate_limit {
+            self.rate_limit.requests_per_minute = rate;
+        }
+        if let Some(burst) = args.rate_limit_burst {
+            self.rate_limit.burst = burst;
+        }
+        if !args.trusted_proxies.is_empty() {
+            self.rate_limit.trusted_proxies = args.trusted_proxies.clone();
+        }
+    }
+
+    /// Checks the settings that are only known to be wrong once combined.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.server.cors_origins.is_empty() {
+            return Err("server.cors_origins must list at least one origin, or `*`".to_string());
+        }
+        let _ = self.cors_layer()?;
+        if self.auth.token_lifetime.0.is_zero() {
+            return Err("auth.token_lifetime must be greater than zero".to_string());
+        }
+        if self.auth.access_token_lifetime.0.is_zero() {
+            return Err("auth.access_token_lifetime must be greater than zero".to_string());
+        }
+        if self.auth.access_token_lifetime.0 > self.auth.token_lifetime.0 {
+            return Err(
+                "auth.access_token_lifetime must not be longer than auth.token_lifetime"
+                    .to_string(),
+            );
+        }
+        if self.auth.code_attempts == 0 {
+            return Err("auth.code_attempts must allow at least one attempt".to_string());
+        }
+        let Some(sms) = &self.auth.sms else {
+            return Err(
+                "auth.sms must say where sign-in codes are sent: `file:<path>`, \
+                 or `stdout` for local development"
+                    .to_string(),
+            );
+        };
+        crate::sms::open(sms).map_err(|e| format!("auth.sms: {}", e))?;
+        if self.storage.backend.trim().is_empty() {
+            return Err("storage.backend must not be empty".to_string());
+        }
+        if self.backups.interval.is_some() && self.backups.retain == 0 {
+            return Err("backups.retain must keep at least one backup".to_string());
+        }
+        tracing_subscriber::EnvFilter::try_new(&self.log.filter)
+            .map_err(|e| format!("log.filter: {}", e))?;
+        if self.rate_limit.requests_per_minute > 0 && self.rate_limit.burst == 0 {
+            return Err("rate_limit.burst must be at least 1 when a rate limit is set".to_string());
+        }
+        Ok(())
+    }
+
+    /// The CORS policy for the configured origins.
+    pub fn cors_layer(&self) -> Result<CorsLayer, String> {
+        let origins = &self.server.cors_origins;
+        if origins.iter().any(|origin| origin == "*") {
+            return Ok(CorsLayer::permissive());
+        }
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                origin
+                    .parse()
+                    .map_err(|_| format!("invalid CORS origin '{}'", origin))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_methods(Any)
+            .allow_headers(Any))
+    }
+
+    /// The configuration as TOML, as `config check` prints it.
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("config always serializes")
+    }
+}
+
+/// An environment variable's name and value, parsed on demand so errors can
+/// name the variable.
+struct Parsed(String, String);
+
+impl Parsed {
+    fn into_value<T: std::str::FromStr>(self) -> Result<T, String>
+    where
+        T::Err: fmt::Display,
+    {
+        self.1.parse().map_err(|e| format!("{}: {}", self.0, e))
+    }
+
+    fn into_interval(self) -> Result<Interval, String> {
+        parse_interval(&self.1)
+            .map(Interval)
+            .map_err(|e| format!("{}: {}", self.0, e))
+    }
+
+    fn into_enum<T: clap::ValueEnum>(self) -> Result<T, String> {
+        T::from_str(&self.1, true).map_err(|e| format!("{}: {}", self.0, e))
+    }
+}
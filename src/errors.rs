@@ -1,10 +1,13 @@
 use axum::{
     Json,
-    http::StatusCode,
+    http::{StatusCode, header},
     response::{IntoResponse, Response},
 };
+use std::fmt;
 use thiserror::Error;
 
+use crate::logic::PayerError;
+
 #[derive(Debug, Error)]
 pub enum AppError {
     #[error("Not found: {0}")]
@@ -13,11 +16,80 @@ pub enum AppError {
     #[error("Bad request: {0}")]
     BadRequest(String),
 
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
     #[error("Lock error: failed to acquire lock")]
     LockError,
 
     #[error("Storage error: {0}")]
     StorageError(#[from] std::io::Error),
+
+    #[error("Could not load data: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+    LoadError(Vec<LoadError>),
+
+    #[error("Could not send text message: {0}")]
+    SmsError(String),
+
+    #[error("Too many requests; try again in {0} seconds")]
+    RateLimited(u64),
+}
+
+impl From<PayerError> for AppError {
+    fn from(error: PayerError) -> Self {
+        match error {
+            PayerError::NotFound(_) => AppError::NotFound(error.to_string()),
+            _ => AppError::BadRequest(error.to_string()),
+        }
+    }
+}
+
+/// Why a stored data file could not be read, pointing at the offending
+/// line and column when the file is not valid JSON or does not match the
+/// expected format.
+#[derive(Debug)]
+pub struct LoadError {
+    pub path: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl LoadError {
+    pub fn new(path: &str, message: impl fmt::Display) -> Self {
+        LoadError {
+            path: path.to_string(),
+            line: None,
+            column: None,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn from_json(path: &str, error: &serde_json::Error) -> Self {
+        let located = error.line() > 0;
+        LoadError {
+            path: path.to_string(),
+            line: located.then(|| error.line()),
+            column: located.then(|| error.column()),
+            // serde_json appends the position itself; it is kept separately
+            message: error
+                .to_string()
+                .split(" at line ")
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, " (line {}, column {})", line, column)?;
+        }
+        write!(f, ": {}", self.message)
+    }
 }
 
 impl AppError {
@@ -35,6 +107,7 @@ impl IntoResponse for AppError {
         let (status, message) = match &self {
             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone()),
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
+            AppError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg.clone()),
             AppError::LockError => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Failed to acquire lock".to_string(),
@@ -43,6 +116,16 @@ impl IntoResponse for AppError {
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Storage error: {}", e),
             ),
+            AppError::LoadError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
+            AppError::SmsError(_) => (StatusCode::BAD_GATEWAY, self.to_string()),
+            AppError::RateLimited(retry_after) => {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    Json(serde_json::json!({ "error": self.to_string() })),
+                )
+                    .into_response();
+            }
         };
         (status, Json(serde_json::json!({ "error": message }))).into_response()
     }
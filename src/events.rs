@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+
+use crate::logic::{remove_exchange_rate, remove_expense, replace_expense, set_exchange_rate};
+use crate::models::{
+    AppData, AuthUser, ExchangeRate, Expense, Group, SettledSettlement, SettlementAlgorithm,
+    SettlementConstraints, User,
+};
+
+/// A single change to `AppData`. Handlers describe every mutation they make
+/// as one or more events so that journaling backends can persist the change
+/// itself rather than the whole dataset.
+///
+/// Events carry the resulting records (the stored expense, the updated user)
+/// rather than the request that produced them, so replaying one never needs
+/// to repeat validation or generate ids and timestamps.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    UserRegistered {
+        user: AuthUser,
+    },
+    /// Token rotation or a change of current group
+    UserUpdated {
+        user: AuthUser,
+    },
+    GroupCreated {
+        group: Group,
+    },
+    GroupRenamed {
+        group_id: usize,
+        name: String,
+        currency: String,
+    },
+    GroupDeleted {
+        group_id: usize,
+    },
+    SettlementOptionsChanged {
+        group_id: usize,
+        simplify_debts: bool,
+        settlement_algorithm: SettlementAlgorithm,
+        optimal_member_limit: usize,
+        settlement_constraints: SettlementConstraints,
+    },
+    MemberAdded {
+        group_id: usize,
+        member: User,
+    },
+    MemberRemoved {
+        group_id: usize,
+        member_id: usize,
+    },
+    ExpenseCreated {
+        group_id: usize,
+        expense: Expense,
+    },
+    ExpenseUpdated {
+        group_id: usize,
+        expense: Expense,
+    },
+    ExpenseDeleted {
+        group_id: usize,
+        expense_id: usize,
+    },
+    SettlementRecorded {
+        group_id: usize,
+        expense: Expense,
+        settlement: SettledSettlement,
+    },
+    /// `group_id` is only missing from events journaled while every group
+    /// shared one rate table, when the change applied to all of them.
+    ExchangeRateSet {
+        #[serde(default)]
+        group_id: Option<usize>,
+        rate: ExchangeRate,
+    },
+    ExchangeRateDeleted {
+        #[serde(default)]
+        group_id: Option<usize>,
+        from: String,
+        to: String,
+    },
+}
+
+impl Event {
+    pub fn options_changed(group: &Group) -> Self {
+        Event::SettlementOptionsChanged {
+            group_id: group.id,
+            simplify_debts: group.simplify_debts,
+            settlement_algorithm: group.settlement_algorithm,
+            optimal_member_limit: group.optimal_member_limit,
+            settlement_constraints: group.settlement_constraints.clone(),
+        }
+    }
+
+    /// Applies the event to `data`. Events that refer to a group or user that
+    /// no longer exists are ignored, matching what the handler would have seen.
+    pub fn apply(&self, data: &mut AppData) {
+        match self {
+            Event::UserRegistered { user } => data.users.push(user.clone()),
+            Event::UserUpdated { user } => {
+                if let Some(existing) = data.users.iter_mut().find(|u| u.id == user.id) {
+                    *existing = user.clone();
+                }
+            }
+            Event::GroupCreated { group } => data.groups.push(group.clone()),
+            Event::GroupRenamed {
+                group_id,
+                name,
+                currency,
+            } => {
+                if let Some(group) = group_mut(data, *group_id) {
+                    group.name = name.clone();
+                    group.currency = currency.clone();
+                }
+            }
+            Event::GroupDeleted { group_id } => data.groups.retain(|g| g.id != *group_id),
+            Event::SettlementOptionsChanged {
+                group_id,
+                simplify_debts,
+                settlement_algorithm,
+                optimal_member_limit,
+                settlement_constraints,
+            } => {
+                if let Some(group) = group_mut(data, *group_id) {
+                    group.simplify_debts = *simplify_debts;
+                    group.settlement_algorithm = *settlement_algorithm;
+                    group.optimal_member_limit = *optimal_member_limit;
+                    group.settlement_constraints = settlement_constraints.clone();
+                }
+            }
+            Event::MemberAdded { group_id, member } => {
+                if let Some(group) = group_mut(data, *group_id) {
+                    group.members.push(member.clone());
+                }
+            }
+            Event::MemberRemoved {
+                group_id,
+                member_id,
+            } => {
+                if let Some(group) = group_mut(data, *group_id)
+                    && let Some(index) = group.members.iter().position(|m| m.id == *member_id)
+                {
+                    let member = group.members.remove(index);
+                    group.settlement_constraints.remove_member(&member.name);
+                }
+            }
+            Event::ExpenseCreated { group_id, expense } => {
+                if let Some(group) = group_mut(data, *group_id) {
+                    group.expenses.push(expense.clone());
+                }
+            }
+            Event::ExpenseUpdated { group_id, expense } => {
+                if let Some(group) = group_mut(data, *group_id) {
+                    replace_expense(group, expense.clone());
+                }
+            }
+            Event::ExpenseDeleted {
+                group_id,
+                expense_id,
+            } => {
+                if let Some(group) = group_mut(data, *group_id) {
+                    remove_expense(group, *expense_id);
+                }
+            }
+            Event::SettlementRecorded {
+                group_id,
+                expense,
+                settlement,
+            } => {
+                if let Some(group) = group_mut(data, *group_id) {
+                    group.expenses.push(expense.clone());
+                    group.settled_settlements.push(settlement.clone());
+                }
+            }
+            Event::ExchangeRateSet { group_id, rate } => {
+                for group in rate_groups(data, *group_id) {
+                    set_exchange_rate(&mut group.exchange_rates, rate.clone());
+                }
+            }
+            Event::ExchangeRateDeleted { group_id, from, to } => {
+                for group in rate_groups(data, *group_id) {
+                    remove_exchange_rate(&mut group.exchange_rates, from, to);
+                }
+            }
+        }
+    }
+}
+
+fn group_mut(data: &mut AppData, id: usize) -> Option<&mut Group> {
+    data.groups.iter_mut().find(|g| g.id == id)
+}
+
+/// The groups a rate change applies to: the one named, or all of them for a
+/// change from before rates belonged to groups.
+fn rate_groups(data: &mut AppData, id: Option<usize>) -> impl Iterator<Item = &mut Group> {
+    data.groups
+        .iter_mut()
+        .filter(move |g| id.is_none_or(|id| g.id == id))
+}
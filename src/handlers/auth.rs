@@ -1,35 +1,185 @@
-use axum::{Json, extract::State};
-use chrono::{Duration, Utc};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::{HeaderMap, header},
+};
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use tracing::{info, warn};
-use uuid::Uuid;
+use std::time::Instant;
+use tracing::{debug, error, info, warn};
 
+use crate::api_keys;
+use crate::config::AuthConfig;
 use crate::errors::{AppError, AppResult};
-use crate::models::AuthUser;
-use crate::storage;
+use crate::events::Event;
+use crate::models::{ApiKey, ApiKeyScope, AuthUser, PublicUser, Session};
+use crate::sessions::{self, Refresh, Tokens};
+use crate::storage::Change;
+use crate::storage::writer::Pending;
 
-const TOKEN_EXPIRY_DAYS: i64 = 30;
+use super::{AppState, Authenticated, SharedState, masked_phone, validate_phone};
 
-use super::{SharedState, validate_phone};
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    pub phone: String,
+}
+
+#[derive(Serialize)]
+pub struct CodeResponse {
+    /// Seconds until the code expires.
+    pub expires_in: u64,
+}
 
 #[derive(Deserialize)]
 pub struct RegisterRequest {
     pub phone: String,
     pub name: String,
+    pub code: String,
+    /// Label for the new session; defaults to the User-Agent.
+    #[serde(default)]
+    pub device: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct LoginRequest {
     pub phone: String,
+    pub code: String,
+    /// Label for the new session; defaults to the User-Agent.
+    #[serde(default)]
+    pub device: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
 }
 
 #[derive(Serialize)]
 pub struct AuthResponse {
-    pub user: AuthUser,
+    pub user: PublicUser,
+    /// Bearer token for the session. Tokens are only given out once; the
+    /// server keeps just their hashes.
+    pub token: String,
+    /// Seconds until `token` stops working and has to be refreshed.
+    pub expires_in: u64,
+    /// Swapped for new tokens at `POST /api/auth/refresh`; good for one use.
+    pub refresh_token: String,
+}
+
+impl AuthResponse {
+    fn new(user: &AuthUser, tokens: Tokens, auth: &AuthConfig) -> Self {
+        AuthResponse {
+            user: PublicUser::from(user),
+            token: tokens.access,
+            expires_in: auth.access_token_lifetime.0.as_secs(),
+            refresh_token: tokens.refresh,
+        }
+    }
+}
+
+/// A session as listed to its owner.
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub device: String,
+    pub created_at: String,
+    pub last_used_at: String,
+    pub expires_at: String,
+    /// Whether this is the session making the request.
+    pub current: bool,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scope: ApiKeyScope,
+}
+
+/// An API key as listed to its owner.
+#[derive(Serialize)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    pub scope: ApiKeyScope,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+impl From<ApiKey> for ApiKeyInfo {
+    fn from(key: ApiKey) -> Self {
+        ApiKeyInfo {
+            id: key.id,
+            name: key.name,
+            scope: key.scope,
+            created_at: key.created_at,
+            last_used_at: key.last_used_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CreateApiKeyResponse {
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+    /// The key itself. This is the only time it is given out; the server
+    /// keeps just its hash.
+    pub key: String,
+}
+
+/// Names the device a session is started on.
+fn device_label(requested: Option<&str>, headers: &HeaderMap) -> String {
+    requested
+        .filter(|label| !label.trim().is_empty())
+        .or_else(|| headers.get(header::USER_AGENT)?.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Changes the sessions of user `user_id` and queues the result to be
+/// stored.
+fn update_sessions<T>(
+    state: &AppState,
+    user_id: usize,
+    update: impl FnOnce(&mut Vec<Session>) -> AppResult<T>,
+) -> AppResult<(Pending, T)> {
+    let mut users = state.users.write().map_err(|_| AppError::LockError)?;
+    let user = users
+        .iter_mut()
+        .find(|u| u.id == user_id)
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+    let out = update(&mut user.sessions)?;
+    let user = user.clone();
+    let pending = state
+        .storage
+        .record(&[Event::UserUpdated { user }], &[Change::Users(&users)]);
+    Ok((pending, out))
+}
+
+/// Texts a sign-in code to a phone number. Registering and logging in both
+/// need one, so the phone's owner is the only one who can do either.
+pub async fn request_code(
+    State(state): State<SharedState>,
+    Json(payload): Json<CodeRequest>,
+) -> AppResult<Json<CodeResponse>> {
+    let phone = validate_phone(&payload.phone)?;
+    let code = state.codes.issue(&phone, Instant::now())?;
+
+    let message = format!("Your Splitdumb code is {}", code);
+    if let Err(e) = state.sms.send(&phone, &message) {
+        error!(phone = %masked_phone(&phone), error = %e, "could not send sign-in code");
+        state.codes.revoke(&phone)?;
+        return Err(AppError::SmsError(e));
+    }
+
+    info!(phone = %masked_phone(&phone), "sign-in code sent");
+    Ok(Json(CodeResponse {
+        expires_in: state.codes.lifetime().as_secs(),
+    }))
 }
 
 pub async fn register(
     State(state): State<SharedState>,
+    headers: HeaderMap,
     Json(payload): Json<RegisterRequest>,
 ) -> AppResult<Json<AuthResponse>> {
     let phone = validate_phone(&payload.phone)?;
@@ -38,57 +188,289 @@ pub async fn register(
     if name.is_empty() {
         return Err(AppError::BadRequest("Name is required".to_string()));
     }
+    state.codes.verify(&phone, &payload.code, Instant::now())?;
 
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
+    let device = device_label(payload.device.as_deref(), &headers);
 
-    if app_data.users.iter().any(|u| u.phone == phone) {
-        warn!(phone = %phone, "registration failed: phone already registered");
-        return Err(AppError::BadRequest(
-            "Phone number already registered".to_string(),
-        ));
-    }
+    let (pending, (user, tokens)) = {
+        let mut users = state.users.write().map_err(|_| AppError::LockError)?;
+
+        if users.iter().any(|u| u.phone == phone) {
+            warn!(
+                phone = %masked_phone(&phone),
+                "registration failed: phone already registered"
+            );
+            return Err(AppError::BadRequest(
+                "Phone number already registered".to_string(),
+            ));
+        }
 
-    let max_id = app_data.users.iter().map(|u| u.id).max().unwrap_or(0);
-    let expires_at = Utc::now() + Duration::days(TOKEN_EXPIRY_DAYS);
-    let user = AuthUser {
-        id: max_id + 1,
-        phone: phone.to_string(),
-        name: name.to_string(),
-        token: Uuid::new_v4().to_string(),
-        current_group_id: 0,
-        token_expires_at: Some(expires_at.to_rfc3339()),
+        let max_id = users.iter().map(|u| u.id).max().unwrap_or(0);
+        let (session, tokens) = sessions::start(&device, &state.auth, Utc::now());
+        let user = AuthUser {
+            id: max_id + 1,
+            phone: phone.to_string(),
+            name: name.to_string(),
+            current_group_id: 0,
+            sessions: vec![session],
+            api_keys: Vec::new(),
+        };
+        users.push(user.clone());
+        let pending = state.storage.record(
+            &[Event::UserRegistered { user: user.clone() }],
+            &[Change::Users(&users)],
+        );
+        (pending, (user, tokens))
     };
-    app_data.users.push(user.clone());
-    storage::save(&app_data)?;
+    pending.wait().await?;
 
     info!(user_id = user.id, name = %user.name, "user registered");
-    Ok(Json(AuthResponse { user }))
+    Ok(Json(AuthResponse::new(&user, tokens, &state.auth)))
 }
 
+/// Starts a new session; the user's sessions on other devices stay signed in.
 pub async fn login(
     State(state): State<SharedState>,
+    headers: HeaderMap,
     Json(payload): Json<LoginRequest>,
 ) -> AppResult<Json<AuthResponse>> {
     let phone = validate_phone(&payload.phone)?;
+    state.codes.verify(&phone, &payload.code, Instant::now())?;
+    let device = device_label(payload.device.as_deref(), &headers);
 
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
+    let (pending, (user, tokens)) = {
+        let mut users = state.users.write().map_err(|_| AppError::LockError)?;
 
-    let user = app_data
-        .users
-        .iter_mut()
-        .find(|u| u.phone == phone)
-        .ok_or_else(AppError::phone_not_registered)?;
+        let user = users
+            .iter_mut()
+            .find(|u| u.phone == phone)
+            .ok_or_else(AppError::phone_not_registered)?;
 
-    // Rotate token on login
-    user.token = Uuid::new_v4().to_string();
-    user.token_expires_at = Some((Utc::now() + Duration::days(TOKEN_EXPIRY_DAYS)).to_rfc3339());
-    let user = user.clone();
-    storage::save(&app_data)?;
+        let now = Utc::now();
+        let (session, tokens) = sessions::start(&device, &state.auth, now);
+        user.sessions.retain(|s| !sessions::is_expired(s, now));
+        user.sessions.push(session);
+        let user = user.clone();
+        let pending = state.storage.record(
+            &[Event::UserUpdated { user: user.clone() }],
+            &[Change::Users(&users)],
+        );
+        (pending, (user, tokens))
+    };
+    pending.wait().await?;
 
-    info!(user_id = user.id, name = %user.name, "user logged in");
-    Ok(Json(AuthResponse { user }))
+    info!(user_id = user.id, name = %user.name, device = %device, "user logged in");
+    Ok(Json(AuthResponse::new(&user, tokens, &state.auth)))
 }
 
-pub async fn get_me(user: AuthUser) -> AppResult<Json<AuthUser>> {
-    Ok(Json(user))
+/// Swaps a refresh token for new tokens, keeping the session alive. A
+/// refresh token that was already used means it has been copied, so the
+/// session is ended for whoever holds it.
+pub async fn refresh(
+    State(state): State<SharedState>,
+    Json(payload): Json<RefreshRequest>,
+) -> AppResult<Json<AuthResponse>> {
+    let now = Utc::now();
+    let (pending, (user, session_id, tokens)) = {
+        let mut users = state.users.write().map_err(|_| AppError::LockError)?;
+        let (user_id, session_id, refresh) = sessions::find_refresh(&users, &payload.refresh_token)
+            .map(|(user, session, refresh)| (user.id, session.id.clone(), refresh))
+            .ok_or_else(|| AppError::Unauthorized("Invalid refresh token".to_string()))?;
+        let user = users
+            .iter_mut()
+            .find(|u| u.id == user_id)
+            .expect("found above");
+        let index = user
+            .sessions
+            .iter()
+            .position(|s| s.id == session_id)
+            .expect("found above");
+
+        let tokens = match refresh {
+            Refresh::Current if sessions::is_expired(&user.sessions[index], now) => {
+                return Err(AppError::Unauthorized(
+                    "Session expired; sign in again".to_string(),
+                ));
+            }
+            Refresh::Current => Some(sessions::rotate(
+                &mut user.sessions[index],
+                &state.auth,
+                now,
+            )),
+            Refresh::Replayed => {
+                user.sessions.remove(index);
+                None
+            }
+        };
+        let user = user.clone();
+        let pending = state.storage.record(
+            &[Event::UserUpdated { user: user.clone() }],
+            &[Change::Users(&users)],
+        );
+        (pending, (user, session_id, tokens))
+    };
+    pending.wait().await?;
+
+    let Some(tokens) = tokens else {
+        warn!(
+            user_id = user.id,
+            session_id = %session_id,
+            "refresh token reused; session ended"
+        );
+        return Err(AppError::Unauthorized(
+            "Refresh token was already used; sign in again".to_string(),
+        ));
+    };
+    debug!(user_id = user.id, session_id = %session_id, "session refreshed");
+    Ok(Json(AuthResponse::new(&user, tokens, &state.auth)))
+}
+
+pub async fn get_me(user: AuthUser) -> AppResult<Json<PublicUser>> {
+    Ok(Json(PublicUser::from(&user)))
+}
+
+/// Lists the devices the user is signed in on.
+pub async fn list_sessions(auth: Authenticated) -> AppResult<Json<Vec<SessionInfo>>> {
+    let now = Utc::now();
+    let sessions = auth
+        .user
+        .sessions
+        .into_iter()
+        .filter(|s| !sessions::is_expired(s, now))
+        .map(|s| SessionInfo {
+            current: s.id == auth.session_id,
+            id: s.id,
+            device: s.device,
+            created_at: s.created_at,
+            last_used_at: s.last_used_at,
+            expires_at: s.expires_at,
+        })
+        .collect();
+    Ok(Json(sessions))
+}
+
+/// Signs one of the user's devices out.
+pub async fn revoke_session(
+    State(state): State<SharedState>,
+    auth: Authenticated,
+    Path(id): Path<String>,
+) -> AppResult<Json<serde_json::Value>> {
+    let (pending, ()) = update_sessions(&state, auth.user.id, |sessions| {
+        let index = sessions
+            .iter()
+            .position(|s| s.id == id)
+            .ok_or_else(|| AppError::NotFound("Session not found".to_string()))?;
+        sessions.remove(index);
+        Ok(())
+    })?;
+    pending.wait().await?;
+
+    info!(user_id = auth.user.id, session_id = %id, "session revoked");
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+/// Ends the session making the request.
+pub async fn logout(
+    State(state): State<SharedState>,
+    auth: Authenticated,
+) -> AppResult<Json<serde_json::Value>> {
+    let (pending, ()) = update_sessions(&state, auth.user.id, |sessions| {
+        sessions.retain(|s| s.id != auth.session_id);
+        Ok(())
+    })?;
+    pending.wait().await?;
+
+    info!(user_id = auth.user.id, session_id = %auth.session_id, "user logged out");
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+/// Ends every one of the user's sessions, including this one.
+pub async fn logout_everywhere(
+    State(state): State<SharedState>,
+    auth: Authenticated,
+) -> AppResult<Json<serde_json::Value>> {
+    let (pending, ended) = update_sessions(&state, auth.user.id, |sessions| {
+        Ok(std::mem::take(sessions).len())
+    })?;
+    pending.wait().await?;
+
+    info!(
+        user_id = auth.user.id,
+        sessions = ended,
+        "user logged out everywhere"
+    );
+    Ok(Json(
+        serde_json::json!({ "success": true, "sessions": ended }),
+    ))
+}
+
+/// Lists the user's API keys.
+pub async fn list_api_keys(user: AuthUser) -> AppResult<Json<Vec<ApiKeyInfo>>> {
+    Ok(Json(
+        user.api_keys.into_iter().map(ApiKeyInfo::from).collect(),
+    ))
+}
+
+/// Creates an API key for scripts acting as the user. Only a session may,
+/// so a leaked key cannot be used to mint more.
+pub async fn create_api_key(
+    State(state): State<SharedState>,
+    auth: Authenticated,
+    Json(payload): Json<CreateApiKeyRequest>,
+) -> AppResult<Json<CreateApiKeyResponse>> {
+    let (pending, (api_key, key)) = {
+        let mut users = state.users.write().map_err(|_| AppError::LockError)?;
+        let user = users
+            .iter_mut()
+            .find(|u| u.id == auth.user.id)
+            .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+        let created = api_keys::add(user, &payload.name, payload.scope, Utc::now())?;
+        let user = user.clone();
+        let pending = state
+            .storage
+            .record(&[Event::UserUpdated { user }], &[Change::Users(&users)]);
+        (pending, created)
+    };
+    pending.wait().await?;
+
+    info!(
+        user_id = auth.user.id,
+        key_id = %api_key.id,
+        scope = %api_key.scope,
+        "API key created"
+    );
+    Ok(Json(CreateApiKeyResponse {
+        info: ApiKeyInfo::from(api_key),
+        key,
+    }))
+}
+
+/// Revokes one of the user's API keys; scripts using it stop working. Like
+/// creating one, this needs a session rather than a key.
+pub async fn revoke_api_key(
+    State(state): State<SharedState>,
+    auth: Authenticated,
+    Path(id): Path<String>,
+) -> AppResult<Json<serde_json::Value>> {
+    let pending = {
+        let mut users = state.users.write().map_err(|_| AppError::LockError)?;
+        let user = users
+            .iter_mut()
+            .find(|u| u.id == auth.user.id)
+            .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+        let index = user
+            .api_keys
+            .iter()
+            .position(|k| k.id == id)
+            .ok_or_else(|| AppError::NotFound("API key not found".to_string()))?;
+        user.api_keys.remove(index);
+        let user = user.clone();
+        state
+            .storage
+            .record(&[Event::UserUpdated { user }], &[Change::Users(&users)])
+    };
+    pending.wait().await?;
+
+    info!(user_id = auth.user.id, key_id = %id, "API key revoked");
+    Ok(Json(serde_json::json!({ "success": true })))
 }
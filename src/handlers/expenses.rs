@@ -6,37 +6,279 @@ use serde::Deserialize;
 use tracing::info;
 
 use crate::errors::{AppError, AppResult};
-use crate::logic::add_expense;
-use crate::models::{AuthUser, Expense, SettledSettlement};
-use crate::storage;
+use crate::events::Event;
+use crate::logic::{
+    add_expense, base_amount, find_exchange_rate, group_total, percentage_basis_points,
+    remove_expense, replace_expense, resolve_payers, validate_payers,
+};
+use crate::models::{
+    AuthUser, Contribution, ExchangeRate, Expense, Itemization, SettledSettlement, Split, User,
+};
+use crate::money::Money;
+use crate::storage::Change;
 
-use super::SharedState;
+use super::{SharedState, validate_currency, validate_rate};
 
 #[derive(Deserialize)]
 pub struct CreateExpenseRequest {
     pub description: String,
-    pub amount: f64,
-    pub payer: String,
+    pub amount: Money,
+    /// Defaults to the group's currency
+    pub currency: Option<String>,
+    /// Overrides the rate table for a foreign-currency expense
+    pub exchange_rate: Option<f64>,
+    pub payer: Option<String>,
+    /// Several payers and their amounts, used instead of `payer`
+    #[serde(default)]
+    pub payers: Vec<Contribution>,
+    /// May be left empty for itemized expenses, in which case everyone named
+    /// on a line item participates
+    #[serde(default)]
     pub participants: Vec<String>,
     pub category: Option<String>,
     pub notes: Option<String>,
+    #[serde(default)]
+    pub split: Split,
+    pub itemization: Option<Itemization>,
 }
 
 #[derive(Deserialize)]
 pub struct UpdateExpenseRequest {
     pub description: Option<String>,
-    pub amount: Option<f64>,
+    pub amount: Option<Money>,
+    pub currency: Option<String>,
+    pub exchange_rate: Option<f64>,
     pub payer: Option<String>,
+    pub payers: Option<Vec<Contribution>>,
     pub participants: Option<Vec<String>>,
     pub category: Option<String>,
     pub notes: Option<String>,
+    /// Replaces the split; also clears any itemization unless one is given
+    pub split: Option<Split>,
+    pub itemization: Option<Itemization>,
 }
 
 #[derive(Deserialize)]
 pub struct SettleRequest {
     pub from: String,
     pub to: String,
-    pub amount: f64,
+    /// Amount handed over, in `currency`
+    pub amount: Money,
+    /// Defaults to the group's currency
+    pub currency: Option<String>,
+    /// Overrides the rate table when paying in another currency
+    pub exchange_rate: Option<f64>,
+}
+
+/// Adds up amounts from a request, rejecting totals beyond `Money::MAX`.
+fn checked_total(amounts: impl IntoIterator<Item = Money>) -> AppResult<Money> {
+    Money::checked_sum(amounts)
+        .and_then(Money::in_range)
+        .ok_or_else(|| AppError::BadRequest("Amounts add up to more than is supported".to_string()))
+}
+
+/// Rejects a change that would take a group's expenses past `Money::MAX` in
+/// total, which keeps its balances from overflowing.
+fn validate_group_total<'a>(expenses: impl IntoIterator<Item = &'a Expense>) -> AppResult<()> {
+    group_total(expenses).map(|_| ()).ok_or_else(|| {
+        AppError::BadRequest(
+            "The group's expenses would add up to more than is supported".to_string(),
+        )
+    })
+}
+
+/// Checks that a split covers exactly the given participants and that its
+/// parts add up to the expense amount.
+fn validate_split(split: &Split, amount: Money, participants: &[User]) -> AppResult<()> {
+    let keys: Vec<&String> = match split {
+        Split::Equal => return Ok(()),
+        Split::Exact { amounts } => amounts.keys().collect(),
+        Split::Percentage { percentages } => percentages.keys().collect(),
+        Split::Shares { shares } => shares.keys().collect(),
+    };
+
+    for participant in participants {
+        if !keys.contains(&&participant.name) {
+            return Err(AppError::BadRequest(format!(
+                "Split is missing participant '{}'",
+                participant.name
+            )));
+        }
+    }
+    if let Some(extra) = keys
+        .iter()
+        .find(|name| !participants.iter().any(|p| p.name == ***name))
+    {
+        return Err(AppError::BadRequest(format!(
+            "Split includes '{}' who is not a participant",
+            extra
+        )));
+    }
+
+    match split {
+        Split::Equal => {}
+        Split::Exact { amounts } => {
+            if amounts.values().any(|a| a.is_negative()) {
+                return Err(AppError::BadRequest(
+                    "Split amounts cannot be negative".to_string(),
+                ));
+            }
+            let total = checked_total(amounts.values().copied())?;
+            if total != amount {
+                return Err(AppError::BadRequest(format!(
+                    "Split amounts add up to {} but the expense is {}",
+                    total, amount
+                )));
+            }
+        }
+        Split::Percentage { percentages } => {
+            let mut total = 0;
+            for value in percentages.values() {
+                total += percentage_basis_points(*value).ok_or_else(|| {
+                    AppError::BadRequest(format!(
+                        "Invalid percentage {}: must be between 0 and 100 with at most two decimals",
+                        value
+                    ))
+                })?;
+            }
+            if total != 10_000 {
+                return Err(AppError::BadRequest(format!(
+                    "Percentages add up to {} but must add up to 100",
+                    total as f64 / 100.0
+                )));
+            }
+        }
+        Split::Shares { shares } => {
+            if shares.values().sum::<u64>() == 0 {
+                return Err(AppError::BadRequest(
+                    "At least one participant must have a share".to_string(),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Everyone named on a line item, in order of first appearance.
+fn itemization_participants(itemization: &Itemization) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for item in &itemization.items {
+        for name in &item.participants {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+    names
+}
+
+/// Checks that line items only name expense participants, cover all of them,
+/// and together with tax and tip add up to the expense amount.
+fn validate_itemization(
+    itemization: &Itemization,
+    split: &Split,
+    amount: Money,
+    participants: &[User],
+) -> AppResult<()> {
+    if *split != Split::Equal {
+        return Err(AppError::BadRequest(
+            "An itemized expense cannot also have a split".to_string(),
+        ));
+    }
+    if itemization.items.is_empty() {
+        return Err(AppError::BadRequest(
+            "Itemization must have at least one item".to_string(),
+        ));
+    }
+    if itemization.tax.is_negative() || itemization.tip.is_negative() {
+        return Err(AppError::BadRequest(
+            "Tax and tip cannot be negative".to_string(),
+        ));
+    }
+
+    for item in &itemization.items {
+        if !item.amount.is_positive() {
+            return Err(AppError::BadRequest(format!(
+                "Item '{}' must have a positive amount",
+                item.description
+            )));
+        }
+        if item.participants.is_empty() {
+            return Err(AppError::BadRequest(format!(
+                "Item '{}' must have at least one participant",
+                item.description
+            )));
+        }
+        if let Some(name) = item
+            .participants
+            .iter()
+            .find(|name| !participants.iter().any(|p| p.name == **name))
+        {
+            return Err(AppError::BadRequest(format!(
+                "Item '{}' includes '{}' who is not a participant",
+                item.description, name
+            )));
+        }
+    }
+
+    let named = itemization_participants(itemization);
+    if let Some(participant) = participants.iter().find(|p| !named.contains(&p.name)) {
+        return Err(AppError::BadRequest(format!(
+            "Participant '{}' is not on any item",
+            participant.name
+        )));
+    }
+
+    let total = checked_total(
+        itemization
+            .items
+            .iter()
+            .map(|i| i.amount)
+            .chain([itemization.tax, itemization.tip]),
+    )?;
+    if total != amount {
+        return Err(AppError::BadRequest(format!(
+            "Items, tax and tip add up to {} but the expense is {}",
+            total, amount
+        )));
+    }
+
+    Ok(())
+}
+
+/// Resolves an expense currency against the group's currency, taking the rate
+/// from the rate table unless one is given. Expenses in the group's currency
+/// come back as `(None, None)`.
+fn resolve_currency(
+    currency: Option<&str>,
+    rate: Option<f64>,
+    group_currency: &str,
+    rates: &[ExchangeRate],
+) -> AppResult<(Option<String>, Option<f64>)> {
+    let Some(currency) = currency else {
+        if rate.is_some() {
+            return Err(AppError::BadRequest(
+                "An exchange rate requires a currency".to_string(),
+            ));
+        }
+        return Ok((None, None));
+    };
+
+    let code = validate_currency(currency)?;
+    if code == group_currency {
+        return Ok((None, None));
+    }
+    let rate = match rate {
+        Some(rate) => validate_rate(rate)?,
+        None => find_exchange_rate(rates, &code, group_currency).ok_or_else(|| {
+            AppError::BadRequest(format!(
+                "No exchange rate from {} to {}; add one or pass exchange_rate",
+                code, group_currency
+            ))
+        })?,
+    };
+    Ok((Some(code), Some(rate)))
 }
 
 pub async fn create_expense(
@@ -50,59 +292,112 @@ pub async fn create_expense(
             "Description cannot be empty".to_string(),
         ));
     }
-    if payload.amount <= 0.0 {
+    if !payload.amount.is_positive() {
         return Err(AppError::BadRequest("Amount must be positive".to_string()));
     }
-    if payload.participants.is_empty() {
+    let participant_names = match &payload.itemization {
+        Some(itemization) if payload.participants.is_empty() => {
+            itemization_participants(itemization)
+        }
+        _ => payload.participants,
+    };
+    if participant_names.is_empty() {
         return Err(AppError::BadRequest(
             "Must have at least one participant".to_string(),
         ));
     }
 
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
-
-    let group = app_data
-        .groups
-        .iter_mut()
-        .find(|g| g.id == auth_user.current_group_id)
-        .ok_or_else(AppError::group_not_found)?;
-
-    let payer_user = group
-        .members
-        .iter()
-        .find(|u| u.name == payload.payer)
-        .ok_or_else(|| AppError::NotFound(format!("Payer '{}' not found", payload.payer)))?
-        .clone();
-
-    let mut participant_users = Vec::new();
-    for name in &payload.participants {
-        let user = group
-            .members
-            .iter()
-            .find(|u| u.name == *name)
-            .ok_or_else(|| AppError::NotFound(format!("Participant '{}' not found", name)))?
-            .clone();
-        participant_users.push(user);
-    }
+    let (pending, expense) = {
+        let handle = state
+            .group(auth_user.current_group_id)?
+            .ok_or_else(AppError::group_not_found)?;
+        let mut guard = handle.write().map_err(|_| AppError::LockError)?;
+        let group = &mut *guard;
+
+        let (currency, exchange_rate) = resolve_currency(
+            payload.currency.as_deref(),
+            payload.exchange_rate,
+            &group.currency,
+            &group.exchange_rates,
+        )?;
+
+        let (payer_user, payers) = match (payload.payer, payload.payers.is_empty()) {
+            (Some(_), false) => {
+                return Err(AppError::BadRequest(
+                    "Specify either payer or payers, not both".to_string(),
+                ));
+            }
+            (None, true) => return Err(AppError::BadRequest("Payer is required".to_string())),
+            (Some(payer), true) => {
+                let user = group
+                    .members
+                    .iter()
+                    .find(|u| u.name == payer)
+                    .ok_or_else(|| AppError::NotFound(format!("Payer '{}' not found", payer)))?
+                    .clone();
+                (user, vec![])
+            }
+            (None, false) => (
+                resolve_payers(&group.members, &payload.payers)?,
+                payload.payers,
+            ),
+        };
+
+        let mut participant_users = Vec::new();
+        for name in &participant_names {
+            let user = group
+                .members
+                .iter()
+                .find(|u| u.name == *name)
+                .ok_or_else(|| AppError::NotFound(format!("Participant '{}' not found", name)))?
+                .clone();
+            participant_users.push(user);
+        }
 
-    let max_id = group.expenses.iter().map(|e| e.id).max().unwrap_or(0);
-    let expense = Expense {
-        id: max_id + 1,
-        description: description.to_string(),
-        amount: payload.amount,
-        payer: payer_user,
-        participants: participant_users,
-        created_at: chrono::Utc::now().to_rfc3339(),
-        category: payload.category,
-        notes: payload.notes,
+        validate_split(&payload.split, payload.amount, &participant_users)?;
+        if let Some(itemization) = &payload.itemization {
+            validate_itemization(
+                itemization,
+                &payload.split,
+                payload.amount,
+                &participant_users,
+            )?;
+        }
+        validate_payers(&payers, payload.amount)?;
+
+        let max_id = group.expenses.iter().map(|e| e.id).max().unwrap_or(0);
+        let expense = Expense {
+            id: max_id + 1,
+            description: description.to_string(),
+            amount: payload.amount,
+            currency,
+            exchange_rate,
+            payer: payer_user,
+            payers,
+            participants: participant_users,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            category: payload.category,
+            notes: payload.notes,
+            split: payload.split,
+            itemization: payload.itemization,
+            shares: vec![],
+        };
+        validate_group_total(group.expenses.iter().chain([&expense]))?;
+
+        let expense = add_expense(expense, group).clone();
+        let event = Event::ExpenseCreated {
+            group_id: group.id,
+            expense: expense.clone(),
+        };
+        let pending = state.storage.record(&[event], &[Change::Group(group)]);
+        (pending, expense)
     };
-
-    add_expense(expense.clone(), group);
-    storage::save(&app_data)?;
+    pending.wait().await?;
 
     info!(
         expense_id = expense.id,
-        amount = expense.amount,
+        amount = %expense.amount,
+        currency = ?expense.currency,
         description = %expense.description,
         "expense created"
     );
@@ -115,77 +410,130 @@ pub async fn update_expense(
     Path(id): Path<usize>,
     Json(payload): Json<UpdateExpenseRequest>,
 ) -> AppResult<Json<Expense>> {
-    if let Some(amount) = payload.amount {
-        if amount <= 0.0 {
-            return Err(AppError::BadRequest("Amount must be positive".to_string()));
-        }
+    if let Some(amount) = payload.amount
+        && !amount.is_positive()
+    {
+        return Err(AppError::BadRequest("Amount must be positive".to_string()));
     }
-    if let Some(ref desc) = payload.description {
-        if desc.trim().is_empty() {
-            return Err(AppError::BadRequest(
-                "Description cannot be empty".to_string(),
-            ));
-        }
+    if let Some(ref desc) = payload.description
+        && desc.trim().is_empty()
+    {
+        return Err(AppError::BadRequest(
+            "Description cannot be empty".to_string(),
+        ));
     }
-    if let Some(ref participants) = payload.participants {
-        if participants.is_empty() {
-            return Err(AppError::BadRequest(
-                "Must have at least one participant".to_string(),
-            ));
-        }
+    if let Some(ref participants) = payload.participants
+        && participants.is_empty()
+    {
+        return Err(AppError::BadRequest(
+            "Must have at least one participant".to_string(),
+        ));
     }
 
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
-
-    let group = app_data
-        .groups
-        .iter_mut()
-        .find(|g| g.id == auth_user.current_group_id)
-        .ok_or_else(AppError::group_not_found)?;
+    let (pending, expense) = {
+        let handle = state
+            .group(auth_user.current_group_id)?
+            .ok_or_else(AppError::group_not_found)?;
+        let mut guard = handle.write().map_err(|_| AppError::LockError)?;
+        let group = &mut *guard;
 
-    let expense = group
-        .expenses
-        .iter_mut()
-        .find(|e| e.id == id)
-        .ok_or_else(|| AppError::NotFound(format!("Expense with id {} not found", id)))?;
-
-    if let Some(description) = payload.description {
-        expense.description = description.trim().to_string();
-    }
-    if let Some(amount) = payload.amount {
-        expense.amount = amount;
-    }
-    if let Some(payer_name) = payload.payer {
-        let payer = group
-            .members
+        let index = group
+            .expenses
             .iter()
-            .find(|u| u.name == payer_name)
-            .ok_or_else(|| AppError::NotFound(format!("Payer '{}' not found", payer_name)))?
-            .clone();
-        expense.payer = payer;
-    }
-    if let Some(participant_names) = payload.participants {
-        let mut participants = Vec::new();
-        for name in &participant_names {
-            let user = group
+            .position(|e| e.id == id)
+            .ok_or_else(|| AppError::NotFound(format!("Expense with id {} not found", id)))?;
+
+        // Apply changes to a copy so a validation failure leaves the expense intact
+        let mut expense = group.expenses[index].clone();
+        if let Some(description) = payload.description {
+            expense.description = description.trim().to_string();
+        }
+        if let Some(amount) = payload.amount {
+            expense.amount = amount;
+        }
+        if payload.currency.is_some() || payload.exchange_rate.is_some() {
+            let currency = payload.currency.as_deref().or(expense.currency.as_deref());
+            (expense.currency, expense.exchange_rate) = resolve_currency(
+                currency,
+                payload.exchange_rate,
+                &group.currency,
+                &group.exchange_rates,
+            )?;
+        }
+        if let Some(payer_name) = payload.payer {
+            let payer = group
                 .members
                 .iter()
-                .find(|u| u.name == *name)
-                .ok_or_else(|| AppError::NotFound(format!("Participant '{}' not found", name)))?
+                .find(|u| u.name == payer_name)
+                .ok_or_else(|| AppError::NotFound(format!("Payer '{}' not found", payer_name)))?
                 .clone();
-            participants.push(user);
+            expense.payer = payer;
+            expense.payers = vec![];
+        }
+        if let Some(payers) = payload.payers {
+            expense.payer = resolve_payers(&group.members, &payers)?;
+            expense.payers = payers;
+        }
+        let participant_names = match (&payload.itemization, payload.participants) {
+            (_, Some(names)) => Some(names),
+            (Some(itemization), None) => Some(itemization_participants(itemization)),
+            (None, None) => None,
+        };
+        if let Some(participant_names) = participant_names {
+            let mut participants = Vec::new();
+            for name in &participant_names {
+                let user = group
+                    .members
+                    .iter()
+                    .find(|u| u.name == *name)
+                    .ok_or_else(|| AppError::NotFound(format!("Participant '{}' not found", name)))?
+                    .clone();
+                participants.push(user);
+            }
+            expense.participants = participants;
+        }
+        if payload.category.is_some() {
+            expense.category = payload.category;
+        }
+        if payload.notes.is_some() {
+            expense.notes = payload.notes;
+        }
+        if let Some(split) = payload.split {
+            expense.split = split;
+            expense.itemization = None;
+        }
+        if payload.itemization.is_some() {
+            expense.itemization = payload.itemization;
         }
-        expense.participants = participants;
-    }
-    if payload.category.is_some() {
-        expense.category = payload.category;
-    }
-    if payload.notes.is_some() {
-        expense.notes = payload.notes;
-    }
 
-    let expense = expense.clone();
-    storage::save(&app_data)?;
+        validate_split(&expense.split, expense.amount, &expense.participants)?;
+        if let Some(itemization) = &expense.itemization {
+            validate_itemization(
+                itemization,
+                &expense.split,
+                expense.amount,
+                &expense.participants,
+            )?;
+        }
+        validate_payers(&expense.payers, expense.amount)?;
+        validate_group_total(
+            group
+                .expenses
+                .iter()
+                .map(|e| if e.id == id { &expense } else { e }),
+        )?;
+        let expense = replace_expense(group, expense)
+            .expect("found above while the group is locked")
+            .clone();
+
+        let event = Event::ExpenseUpdated {
+            group_id: group.id,
+            expense: expense.clone(),
+        };
+        let pending = state.storage.record(&[event], &[Change::Group(group)]);
+        (pending, expense)
+    };
+    pending.wait().await?;
 
     info!(expense_id = expense.id, "expense updated");
     Ok(Json(expense))
@@ -196,33 +544,22 @@ pub async fn delete_expense(
     auth_user: AuthUser,
     Path(id): Path<usize>,
 ) -> AppResult<Json<serde_json::Value>> {
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
-
-    let group = app_data
-        .groups
-        .iter_mut()
-        .find(|g| g.id == auth_user.current_group_id)
-        .ok_or_else(AppError::group_not_found)?;
-
-    let index = group
-        .expenses
-        .iter()
-        .position(|e| e.id == id)
-        .ok_or_else(|| AppError::NotFound(format!("Expense with id {} not found", id)))?;
-
-    let expense = &group.expenses[index];
-    if expense.category.as_deref() == Some("Settlement") {
-        let payer_name = &expense.payer.name;
-        let participant_name = expense.participants.first().map(|p| &p.name);
-        if let Some(to_name) = participant_name {
-            group
-                .settled_settlements
-                .retain(|s| !(s.from == *payer_name && s.to == *to_name));
-        }
-    }
-
-    group.expenses.remove(index);
-    storage::save(&app_data)?;
+    let pending = {
+        let handle = state
+            .group(auth_user.current_group_id)?
+            .ok_or_else(AppError::group_not_found)?;
+        let mut guard = handle.write().map_err(|_| AppError::LockError)?;
+        let group = &mut *guard;
+
+        remove_expense(group, id)
+            .ok_or_else(|| AppError::NotFound(format!("Expense with id {} not found", id)))?;
+        let event = Event::ExpenseDeleted {
+            group_id: group.id,
+            expense_id: id,
+        };
+        state.storage.record(&[event], &[Change::Group(group)])
+    };
+    pending.wait().await?;
 
     info!(expense_id = id, "expense deleted");
     Ok(Json(serde_json::json!({ "success": true })))
@@ -233,7 +570,7 @@ pub async fn settle(
     auth_user: AuthUser,
     Json(payload): Json<SettleRequest>,
 ) -> AppResult<Json<Expense>> {
-    if payload.amount <= 0.0 {
+    if !payload.amount.is_positive() {
         return Err(AppError::BadRequest("Amount must be positive".to_string()));
     }
     if payload.from == payload.to {
@@ -242,54 +579,91 @@ pub async fn settle(
         ));
     }
 
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
+    let (pending, (expense, converted)) = {
+        let handle = state
+            .group(auth_user.current_group_id)?
+            .ok_or_else(AppError::group_not_found)?;
+        let mut guard = handle.write().map_err(|_| AppError::LockError)?;
+        let group = &mut *guard;
+
+        let (currency, exchange_rate) = resolve_currency(
+            payload.currency.as_deref(),
+            payload.exchange_rate,
+            &group.currency,
+            &group.exchange_rates,
+        )?;
+
+        let from_user = group
+            .members
+            .iter()
+            .find(|u| u.name == payload.from)
+            .ok_or_else(|| AppError::BadRequest(format!("User '{}' not found", payload.from)))?
+            .clone();
 
-    let group = app_data
-        .groups
-        .iter_mut()
-        .find(|g| g.id == auth_user.current_group_id)
-        .ok_or_else(AppError::group_not_found)?;
+        let to_user = group
+            .members
+            .iter()
+            .find(|u| u.name == payload.to)
+            .ok_or_else(|| AppError::BadRequest(format!("User '{}' not found", payload.to)))?
+            .clone();
 
-    let from_user = group
-        .members
-        .iter()
-        .find(|u| u.name == payload.from)
-        .ok_or_else(|| AppError::BadRequest(format!("User '{}' not found", payload.from)))?
-        .clone();
+        let max_id = group.expenses.iter().map(|e| e.id).max().unwrap_or(0);
+        let mut expense = Expense {
+            id: max_id + 1,
+            description: format!("{} paid {}", payload.from, payload.to),
+            amount: payload.amount,
+            currency,
+            exchange_rate,
+            payer: from_user,
+            payers: vec![],
+            participants: vec![to_user],
+            created_at: chrono::Utc::now().to_rfc3339(),
+            category: Some("Settlement".to_string()),
+            notes: None,
+            split: Split::Equal,
+            itemization: None,
+            shares: vec![],
+        };
+        validate_group_total(group.expenses.iter().chain([&expense]))?;
+
+        // Balances go down by the converted amount
+        let converted = base_amount(&expense);
+        if let (Some(currency), Some(rate)) = (&expense.currency, expense.exchange_rate) {
+            expense.notes = Some(format!(
+                "Paid {} {} at {} = {} {}",
+                expense.amount, currency, rate, converted, group.currency
+            ));
+        }
 
-    let to_user = group
-        .members
-        .iter()
-        .find(|u| u.name == payload.to)
-        .ok_or_else(|| AppError::BadRequest(format!("User '{}' not found", payload.to)))?
-        .clone();
-
-    let max_id = group.expenses.iter().map(|e| e.id).max().unwrap_or(0);
-    let expense = Expense {
-        id: max_id + 1,
-        description: format!("{} paid {}", payload.from, payload.to),
-        amount: payload.amount,
-        payer: from_user,
-        participants: vec![to_user],
-        created_at: chrono::Utc::now().to_rfc3339(),
-        category: Some("Settlement".to_string()),
-        notes: None,
+        let expense = add_expense(expense, group).clone();
+
+        let settlement = SettledSettlement {
+            from: payload.from.clone(),
+            to: payload.to.clone(),
+            amount: converted,
+            settled_at: chrono::Utc::now().to_rfc3339(),
+            expense_id: Some(expense.id),
+            original_amount: expense.currency.as_ref().map(|_| expense.amount),
+            original_currency: expense.currency.clone(),
+            exchange_rate: expense.exchange_rate,
+        };
+        group.settled_settlements.push(settlement.clone());
+        let event = Event::SettlementRecorded {
+            group_id: group.id,
+            expense: expense.clone(),
+            settlement,
+        };
+        let pending = state.storage.record(&[event], &[Change::Group(group)]);
+        (pending, (expense, converted))
     };
-
-    add_expense(expense.clone(), group);
-
-    group.settled_settlements.push(SettledSettlement {
-        from: payload.from.clone(),
-        to: payload.to.clone(),
-        amount: payload.amount,
-        settled_at: chrono::Utc::now().to_rfc3339(),
-    });
-    storage::save(&app_data)?;
+    pending.wait().await?;
 
     info!(
         from = %payload.from,
         to = %payload.to,
-        amount = payload.amount,
+        amount = %converted,
+        original_amount = %expense.amount,
+        currency = ?expense.currency,
         "settlement recorded"
     );
     Ok(Json(expense))
@@ -3,20 +3,26 @@ use axum::{
     extract::{Path, State},
 };
 use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
 use tracing::info;
 
 use crate::errors::{AppError, AppResult};
-use crate::logic::{
-    Settlement, calculate_balances, calculate_settlements, calculate_simplified_settlements,
+use crate::events::Event;
+use crate::logic::{Settlement, calculate_balances, settlement_plan};
+use crate::models::{
+    AuthUser, Group, SettlementAlgorithm, SettlementConstraints, default_currency,
+    default_optimal_member_limit,
 };
-use crate::models::{AuthUser, Group};
-use crate::storage;
+use crate::money::Money;
+use crate::storage::Change;
 
-use super::SharedState;
+use super::{AppState, GroupHandle, SharedState, validate_currency};
 
 #[derive(Deserialize)]
 pub struct CreateGroupRequest {
     pub name: String,
+    /// Defaults to USD
+    pub currency: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -27,56 +33,93 @@ pub struct SwitchGroupRequest {
 #[derive(Deserialize)]
 pub struct UpdateGroupRequest {
     pub name: String,
+    pub currency: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct SettlementOptionsRequest {
+    pub simplify_debts: Option<bool>,
+    pub settlement_algorithm: Option<SettlementAlgorithm>,
+    pub optimal_member_limit: Option<usize>,
+    /// Replaces the group's constraints when present
+    pub constraints: Option<SettlementConstraints>,
+}
+
+/// Largest accepted `optimal_member_limit`; the optimal search is exponential.
+const MAX_OPTIMAL_MEMBER_LIMIT: usize = 20;
+
 #[derive(Serialize)]
 pub struct BalanceResponse {
-    pub balances: std::collections::HashMap<String, f64>,
+    pub balances: std::collections::HashMap<String, Money>,
+    pub currency: String,
 }
 
 #[derive(Serialize)]
 pub struct SettlementsResponse {
     pub settlements: Vec<Settlement>,
+    pub currency: String,
+    /// Why the plan differs from the unconstrained one, if it does
+    pub explanations: Vec<String>,
 }
 
-pub async fn get_current_group(
-    State(state): State<SharedState>,
-    user: AuthUser,
-) -> AppResult<Json<Group>> {
-    let app_data = state.read().map_err(|_| AppError::LockError)?;
-
-    // Find user's groups (where they are a member)
-    let user_groups: Vec<&Group> = app_data
-        .groups
-        .iter()
-        .filter(|g| g.members.iter().any(|m| m.id == user.id))
-        .collect();
-
-    if user_groups.is_empty() {
-        return Err(AppError::NotFound("No groups found".to_string()));
+/// The user's current group, or their first group if they are not a member
+/// of the current one.
+pub(super) fn member_group(state: &AppState, user: &AuthUser) -> AppResult<GroupHandle> {
+    let mut first = None;
+    for handle in state.all_groups()? {
+        let (id, is_member) = {
+            let group = handle.read().map_err(|_| AppError::LockError)?;
+            (group.id, group.members.iter().any(|m| m.id == user.id))
+        };
+        if !is_member {
+            continue;
+        }
+        if id == user.current_group_id {
+            return Ok(handle);
+        }
+        first.get_or_insert(handle);
     }
+    first.ok_or_else(|| AppError::NotFound("No groups found".to_string()))
+}
 
-    let group = user_groups
-        .iter()
-        .find(|g| g.id == user.current_group_id)
-        .or(user_groups.first())
+/// The user's current group for changing, provided they are a member of it.
+pub(super) fn current_group(state: &AppState, user: &AuthUser) -> AppResult<GroupHandle> {
+    let handle = state
+        .group(user.current_group_id)?
         .ok_or_else(AppError::group_not_found)?;
+    let is_member = handle
+        .read()
+        .map_err(|_| AppError::LockError)?
+        .members
+        .iter()
+        .any(|m| m.id == user.id);
+    if !is_member {
+        return Err(AppError::group_not_found());
+    }
+    Ok(handle)
+}
 
-    Ok(Json((*group).clone()))
+pub async fn get_current_group(
+    State(state): State<SharedState>,
+    user: AuthUser,
+) -> AppResult<Json<Group>> {
+    let handle = member_group(&state, &user)?;
+    let group = handle.read().map_err(|_| AppError::LockError)?;
+    Ok(Json(group.clone()))
 }
 
 pub async fn list_groups(
     State(state): State<SharedState>,
     user: AuthUser,
 ) -> AppResult<Json<Vec<Group>>> {
-    let app_data = state.read().map_err(|_| AppError::LockError)?;
     // Only return groups where the user is a member
-    let user_groups: Vec<Group> = app_data
-        .groups
-        .iter()
-        .filter(|g| g.members.iter().any(|m| m.id == user.id))
-        .cloned()
-        .collect();
+    let mut user_groups = Vec::new();
+    for handle in state.all_groups()? {
+        let group = handle.read().map_err(|_| AppError::LockError)?;
+        if group.members.iter().any(|m| m.id == user.id) {
+            user_groups.push(group.clone());
+        }
+    }
     Ok(Json(user_groups))
 }
 
@@ -91,33 +134,53 @@ pub async fn create_group(
             "Group name cannot be empty".to_string(),
         ));
     }
-
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
-
-    let max_id = app_data.groups.iter().map(|g| g.id).max().unwrap_or(0);
-    // Automatically add the creator as a member
-    let creator_member = crate::models::User {
-        id: user.id,
-        name: user.name.clone(),
+    let currency = match payload.currency {
+        Some(code) => validate_currency(&code)?,
+        None => default_currency(),
     };
-    let group = Group {
-        id: max_id + 1,
-        name: name.to_string(),
-        members: vec![creator_member],
-        expenses: vec![],
-        simplify_debts: false,
-        settled_settlements: vec![],
-    };
-
-    let is_first_group = app_data.groups.is_empty();
-    app_data.groups.push(group.clone());
 
-    if is_first_group {
-        if let Some(u) = app_data.users.iter_mut().find(|u| u.id == user.id) {
+    let (pending, group) = {
+        let mut users = state.users.write().map_err(|_| AppError::LockError)?;
+        let mut groups = state.groups.write().map_err(|_| AppError::LockError)?;
+
+        let max_id = groups.keys().next_back().copied().unwrap_or(0);
+        // Automatically add the creator as a member
+        let creator_member = crate::models::User {
+            id: user.id,
+            name: user.name.clone(),
+        };
+        let group = Group {
+            id: max_id + 1,
+            name: name.to_string(),
+            members: vec![creator_member],
+            expenses: vec![],
+            simplify_debts: false,
+            settlement_algorithm: SettlementAlgorithm::default(),
+            optimal_member_limit: default_optimal_member_limit(),
+            settlement_constraints: SettlementConstraints::default(),
+            settled_settlements: vec![],
+            currency,
+            exchange_rates: vec![],
+        };
+
+        let is_first_group = groups.is_empty();
+        groups.insert(group.id, Arc::new(RwLock::new(group.clone())));
+        let mut events = vec![Event::GroupCreated {
+            group: group.clone(),
+        }];
+
+        if is_first_group && let Some(u) = users.iter_mut().find(|u| u.id == user.id) {
             u.current_group_id = group.id;
+            events.push(Event::UserUpdated { user: u.clone() });
         }
-    }
-    storage::save(&app_data)?;
+        let mut changes = vec![Change::Group(&group)];
+        if events.len() > 1 {
+            changes.push(Change::Users(&users));
+        }
+        let pending = state.storage.record(&events, &changes);
+        (pending, group)
+    };
+    pending.wait().await?;
 
     info!(group_id = group.id, name = %group.name, user_id = user.id, "group created");
     Ok(Json(group))
@@ -128,19 +191,24 @@ pub async fn switch_group(
     user: AuthUser,
     Json(payload): Json<SwitchGroupRequest>,
 ) -> AppResult<Json<serde_json::Value>> {
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
-
-    if !app_data.groups.iter().any(|g| g.id == payload.group_id) {
-        return Err(AppError::NotFound(format!(
-            "Group with id {} not found",
-            payload.group_id
-        )));
-    }
+    let pending = {
+        let mut users = state.users.write().map_err(|_| AppError::LockError)?;
+
+        if state.group(payload.group_id)?.is_none() {
+            return Err(AppError::NotFound(format!(
+                "Group with id {} not found",
+                payload.group_id
+            )));
+        }
 
-    if let Some(u) = app_data.users.iter_mut().find(|u| u.id == user.id) {
-        u.current_group_id = payload.group_id;
-    }
-    storage::save(&app_data)?;
+        let mut events = vec![];
+        if let Some(u) = users.iter_mut().find(|u| u.id == user.id) {
+            u.current_group_id = payload.group_id;
+            events.push(Event::UserUpdated { user: u.clone() });
+        }
+        state.storage.record(&events, &[Change::Users(&users)])
+    };
+    pending.wait().await?;
 
     Ok(Json(
         serde_json::json!({ "success": true, "current_group_id": payload.group_id }),
@@ -159,18 +227,43 @@ pub async fn update_group(
             "Group name cannot be empty".to_string(),
         ));
     }
-
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
-
-    let group = app_data
-        .groups
-        .iter_mut()
-        .find(|g| g.id == id)
-        .ok_or_else(|| AppError::NotFound(format!("Group with id {} not found", id)))?;
-
-    group.name = name.to_string();
-    let updated_group = group.clone();
-    storage::save(&app_data)?;
+    let currency = payload
+        .currency
+        .as_deref()
+        .map(validate_currency)
+        .transpose()?;
+
+    let (pending, updated_group) = {
+        let handle = state
+            .group(id)?
+            .ok_or_else(|| AppError::NotFound(format!("Group with id {} not found", id)))?;
+        let mut guard = handle.write().map_err(|_| AppError::LockError)?;
+        let group = &mut *guard;
+
+        if let Some(currency) = currency
+            && currency != group.currency
+        {
+            // Stored exchange rates are relative to the old currency
+            if !group.expenses.is_empty() {
+                return Err(AppError::BadRequest(
+                    "Cannot change the currency of a group that has expenses".to_string(),
+                ));
+            }
+            group.currency = currency;
+        }
+        group.name = name.to_string();
+        let updated_group = group.clone();
+        let pending = state.storage.record(
+            &[Event::GroupRenamed {
+                group_id: id,
+                name: updated_group.name.clone(),
+                currency: updated_group.currency.clone(),
+            }],
+            &[Change::Group(group)],
+        );
+        (pending, updated_group)
+    };
+    pending.wait().await?;
 
     Ok(Json(updated_group))
 }
@@ -180,26 +273,36 @@ pub async fn delete_group(
     user: AuthUser,
     Path(id): Path<usize>,
 ) -> AppResult<Json<serde_json::Value>> {
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
-
-    let index = app_data
-        .groups
-        .iter()
-        .position(|g| g.id == id)
-        .ok_or_else(|| AppError::NotFound(format!("Group with id {} not found", id)))?;
-
-    app_data.groups.remove(index);
-
-    let switched_group = if user.current_group_id == id {
-        let new_id = app_data.groups.first().map(|g| g.id).unwrap_or(0);
-        if let Some(u) = app_data.users.iter_mut().find(|u| u.id == user.id) {
-            u.current_group_id = new_id;
+    let (pending, switched_group) = {
+        let mut users = state.users.write().map_err(|_| AppError::LockError)?;
+        let mut groups = state.groups.write().map_err(|_| AppError::LockError)?;
+
+        if groups.remove(&id).is_none() {
+            return Err(AppError::NotFound(format!(
+                "Group with id {} not found",
+                id
+            )));
+        }
+        let mut events = vec![Event::GroupDeleted { group_id: id }];
+
+        let switched_group = if user.current_group_id == id {
+            let new_id = groups.keys().next().copied().unwrap_or(0);
+            if let Some(u) = users.iter_mut().find(|u| u.id == user.id) {
+                u.current_group_id = new_id;
+                events.push(Event::UserUpdated { user: u.clone() });
+            }
+            Some(new_id)
+        } else {
+            None
+        };
+        let mut changes = vec![Change::GroupDeleted(id)];
+        if events.len() > 1 {
+            changes.push(Change::Users(&users));
         }
-        Some(new_id)
-    } else {
-        None
+        let pending = state.storage.record(&events, &changes);
+        (pending, switched_group)
     };
-    storage::save(&app_data)?;
+    pending.wait().await?;
 
     info!(group_id = id, user_id = user.id, "group deleted");
     Ok(Json(serde_json::json!({
@@ -212,85 +315,129 @@ pub async fn get_balances(
     State(state): State<SharedState>,
     user: AuthUser,
 ) -> AppResult<Json<BalanceResponse>> {
-    let app_data = state.read().map_err(|_| AppError::LockError)?;
-
-    // Find user's groups (where they are a member)
-    let user_groups: Vec<&Group> = app_data
-        .groups
-        .iter()
-        .filter(|g| g.members.iter().any(|m| m.id == user.id))
-        .collect();
-
-    if user_groups.is_empty() {
-        return Err(AppError::NotFound("No groups found".to_string()));
-    }
-
-    let group = user_groups
-        .iter()
-        .find(|g| g.id == user.current_group_id)
-        .or(user_groups.first())
-        .ok_or_else(AppError::group_not_found)?;
-
-    let balances = calculate_balances(group);
-    Ok(Json(BalanceResponse { balances }))
+    let handle = member_group(&state, &user)?;
+    let group = handle.read().map_err(|_| AppError::LockError)?;
+
+    let balances = calculate_balances(&group);
+    Ok(Json(BalanceResponse {
+        balances,
+        currency: group.currency.clone(),
+    }))
 }
 
 pub async fn get_settlements(
     State(state): State<SharedState>,
     user: AuthUser,
 ) -> AppResult<Json<SettlementsResponse>> {
-    let app_data = state.read().map_err(|_| AppError::LockError)?;
+    let handle = member_group(&state, &user)?;
+    let group = handle.read().map_err(|_| AppError::LockError)?;
 
-    // Find user's groups (where they are a member)
-    let user_groups: Vec<&Group> = app_data
-        .groups
-        .iter()
-        .filter(|g| g.members.iter().any(|m| m.id == user.id))
-        .collect();
+    let plan = settlement_plan(&group);
 
-    if user_groups.is_empty() {
-        return Err(AppError::NotFound("No groups found".to_string()));
-    }
-
-    let group = user_groups
-        .iter()
-        .find(|g| g.id == user.current_group_id)
-        .or(user_groups.first())
-        .ok_or_else(AppError::group_not_found)?;
+    Ok(Json(SettlementsResponse {
+        settlements: plan.settlements,
+        currency: group.currency.clone(),
+        explanations: plan.explanations,
+    }))
+}
 
-    let mut settlements = if group.simplify_debts {
-        calculate_simplified_settlements(group)
-    } else {
-        calculate_settlements(group)
+pub async fn toggle_simplify(
+    State(state): State<SharedState>,
+    user: AuthUser,
+) -> AppResult<Json<serde_json::Value>> {
+    let (pending, new_value) = {
+        let handle = current_group(&state, &user)?;
+        let mut group = handle.write().map_err(|_| AppError::LockError)?;
+
+        group.simplify_debts = !group.simplify_debts;
+        let new_value = group.simplify_debts;
+        let event = Event::options_changed(&group);
+        let pending = state.storage.record(&[event], &[Change::Group(&group)]);
+        (pending, new_value)
     };
+    pending.wait().await?;
 
-    for settlement in &mut settlements {
-        settlement.settled = group
-            .settled_settlements
-            .iter()
-            .any(|s| s.from == settlement.from && s.to == settlement.to);
-    }
-
-    Ok(Json(SettlementsResponse { settlements }))
+    Ok(Json(serde_json::json!({ "simplify_debts": new_value })))
 }
 
-pub async fn toggle_simplify(
+pub async fn update_settlement_options(
     State(state): State<SharedState>,
     user: AuthUser,
+    Json(payload): Json<SettlementOptionsRequest>,
 ) -> AppResult<Json<serde_json::Value>> {
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
-
-    // Find user's current group (where they are a member)
-    let group = app_data
-        .groups
-        .iter_mut()
-        .filter(|g| g.members.iter().any(|m| m.id == user.id))
-        .find(|g| g.id == user.current_group_id)
-        .ok_or_else(AppError::group_not_found)?;
+    if let Some(limit) = payload.optimal_member_limit
+        && limit > MAX_OPTIMAL_MEMBER_LIMIT
+    {
+        return Err(AppError::BadRequest(format!(
+            "optimal_member_limit cannot be more than {}",
+            MAX_OPTIMAL_MEMBER_LIMIT
+        )));
+    }
 
-    group.simplify_debts = !group.simplify_debts;
-    let new_value = group.simplify_debts;
-    storage::save(&app_data)?;
+    let (pending, response) = {
+        let handle = current_group(&state, &user)?;
+        let mut guard = handle.write().map_err(|_| AppError::LockError)?;
+        let group = &mut *guard;
 
-    Ok(Json(serde_json::json!({ "simplify_debts": new_value })))
+        if let Some(simplify_debts) = payload.simplify_debts {
+            group.simplify_debts = simplify_debts;
+        }
+        if let Some(algorithm) = payload.settlement_algorithm {
+            group.settlement_algorithm = algorithm;
+        }
+        if let Some(limit) = payload.optimal_member_limit {
+            group.optimal_member_limit = limit;
+        }
+        if let Some(constraints) = payload.constraints {
+            validate_constraints(group, &constraints)?;
+            group.settlement_constraints = constraints;
+        }
+        let response = serde_json::json!({
+            "simplify_debts": group.simplify_debts,
+            "settlement_algorithm": group.settlement_algorithm,
+            "optimal_member_limit": group.optimal_member_limit,
+            "constraints": group.settlement_constraints,
+        });
+        let event = Event::options_changed(group);
+        let pending = state.storage.record(&[event], &[Change::Group(group)]);
+        (pending, response)
+    };
+    pending.wait().await?;
+
+    Ok(Json(response))
+}
+
+fn validate_constraints(group: &Group, constraints: &SettlementConstraints) -> AppResult<()> {
+    let is_member = |name: &str| group.members.iter().any(|m| m.name == name);
+    let names = constraints
+        .forbidden_pairs
+        .iter()
+        .flat_map(|(a, b)| [a, b])
+        .chain(&constraints.preferred_receivers)
+        .chain(&constraints.hub);
+    for name in names {
+        if !is_member(name) {
+            return Err(AppError::BadRequest(format!(
+                "{} is not a member of this group",
+                name
+            )));
+        }
+    }
+    if constraints.forbidden_pairs.iter().any(|(a, b)| a == b) {
+        return Err(AppError::BadRequest(
+            "A forbidden pair needs two different members".to_string(),
+        ));
+    }
+    if let Some(hub) = &constraints.hub
+        && constraints
+            .forbidden_pairs
+            .iter()
+            .any(|(a, b)| a == hub || b == hub)
+    {
+        return Err(AppError::BadRequest(format!(
+            "{} is the hub and cannot be part of a forbidden pair",
+            hub
+        )));
+    }
+    Ok(())
 }
@@ -1,18 +1,98 @@
 pub mod auth;
 pub mod expenses;
 pub mod groups;
+pub mod rates;
 pub mod users;
 
+use crate::api_keys;
+use crate::config::AuthConfig;
 use crate::errors::AppError;
-use crate::models::{AppData, AuthUser};
+use crate::events::Event;
+use crate::models::{AppData, AuthUser, Group};
+use crate::otp::Codes;
+use crate::sessions;
+use crate::sms::{SmsSender, StdoutSender};
+use crate::storage::Change;
+use crate::storage::writer::StorageWriter;
 use axum::{
     extract::FromRequestParts,
     http::{StatusCode, request::Parts},
 };
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
 use std::sync::{Arc, RwLock};
-use tracing::warn;
+use tracing::{debug, warn};
 
-pub type SharedState = Arc<RwLock<AppData>>;
+/// How stale a session's last-used time may get before it is updated.
+const LAST_USED_RESOLUTION: chrono::Duration = chrono::Duration::minutes(5);
+
+/// A group behind its own lock.
+pub type GroupHandle = Arc<RwLock<Group>>;
+
+/// In-memory data shared by all handlers, plus the writer that persists it.
+///
+/// Each group has its own lock so requests for different groups do not wait
+/// on each other. Locks are taken in field order: `users`, then `groups`,
+/// then a single group. Changes are queued with the
+/// writer before the lock is released and waited on after.
+pub struct AppState {
+    pub users: RwLock<Vec<AuthUser>>,
+    pub groups: RwLock<BTreeMap<usize, GroupHandle>>,
+    pub storage: StorageWriter,
+    pub auth: AuthConfig,
+    pub codes: Codes,
+    pub sms: Box<dyn SmsSender>,
+}
+
+impl AppState {
+    pub fn new(data: AppData, storage: StorageWriter) -> Self {
+        let groups = data
+            .groups
+            .into_iter()
+            .map(|group| (group.id, Arc::new(RwLock::new(group))))
+            .collect();
+        AppState {
+            users: RwLock::new(data.users),
+            groups: RwLock::new(groups),
+            storage,
+            auth: AuthConfig::default(),
+            codes: Codes::new(&AuthConfig::default()),
+            sms: Box::new(StdoutSender),
+        }
+    }
+
+    pub fn with_auth(self, auth: AuthConfig) -> Self {
+        AppState {
+            codes: Codes::new(&auth),
+            auth,
+            ..self
+        }
+    }
+
+    pub fn with_sms(self, sms: Box<dyn SmsSender>) -> Self {
+        AppState { sms, ..self }
+    }
+
+    /// The group with `id`, if it exists.
+    pub fn group(&self, id: usize) -> Result<Option<GroupHandle>, AppError> {
+        let groups = self.groups.read().map_err(|_| AppError::LockError)?;
+        Ok(groups.get(&id).cloned())
+    }
+
+    /// Every group, in id order. The map is only locked while listing them.
+    pub fn all_groups(&self) -> Result<Vec<GroupHandle>, AppError> {
+        let groups = self.groups.read().map_err(|_| AppError::LockError)?;
+        Ok(groups.values().cloned().collect())
+    }
+}
+
+pub type SharedState = Arc<AppState>;
+
+/// A phone number fit for logs: all but the last four digits hidden.
+pub fn masked_phone(phone: &str) -> String {
+    let visible = phone.len().saturating_sub(4);
+    format!("***{}", &phone[visible..])
+}
 
 pub fn validate_phone(phone: &str) -> Result<String, AppError> {
     let digits: String = phone.chars().filter(|c| c.is_ascii_digit()).collect();
@@ -24,39 +104,163 @@ pub fn validate_phone(phone: &str) -> Result<String, AppError> {
     Ok(digits)
 }
 
+/// Normalizes an ISO 4217 currency code such as `eur` to `EUR`.
+pub fn validate_currency(code: &str) -> Result<String, AppError> {
+    let code = code.trim().to_ascii_uppercase();
+    if code.len() != 3 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(AppError::BadRequest(format!(
+            "Invalid currency code '{}'",
+            code
+        )));
+    }
+    Ok(code)
+}
+
+pub fn validate_rate(rate: f64) -> Result<f64, AppError> {
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(AppError::BadRequest(
+            "Exchange rate must be a positive number".to_string(),
+        ));
+    }
+    Ok(rate)
+}
+
+type Rejection = (StatusCode, &'static str);
+
+/// The bearer token the request carries.
+fn bearer_token(parts: &Parts) -> Result<&str, Rejection> {
+    parts
+        .headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or((StatusCode::UNAUTHORIZED, "Missing authorization token"))
+}
+
+/// Whether a last-used time is stale enough to update. Last use is only
+/// recorded now and then, so reads stay reads.
+fn use_is_stale(last_used: Option<&str>, now: DateTime<Utc>) -> bool {
+    last_used
+        .and_then(|used| DateTime::parse_from_rfc3339(used).ok())
+        .is_none_or(|used| now - used.with_timezone(&Utc) >= LAST_USED_RESOLUTION)
+}
+
+/// Applies `mark` to user `user_id` and queues the result to be stored.
+/// Nothing is lost if this write fails, so it is not waited on.
+fn record_use(
+    state: &AppState,
+    user_id: usize,
+    mark: impl FnOnce(&mut AuthUser) -> bool,
+) -> Result<(), Rejection> {
+    let mut users = state
+        .users
+        .write()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read state"))?;
+    if let Some(user) = users.iter_mut().find(|u| u.id == user_id)
+        && mark(user)
+    {
+        let user = user.clone();
+        let _ = state
+            .storage
+            .record(&[Event::UserUpdated { user }], &[Change::Users(&users)]);
+    }
+    Ok(())
+}
+
+/// The signed-in user together with the session their token belongs to.
+/// Only session tokens are accepted, not API keys.
+pub struct Authenticated {
+    pub user: AuthUser,
+    pub session_id: String,
+}
+
+impl FromRequestParts<SharedState> for Authenticated {
+    type Rejection = Rejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &SharedState,
+    ) -> Result<Self, Self::Rejection> {
+        let token = bearer_token(parts)?;
+        let now = Utc::now();
+        let failed = (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read state");
+
+        let (user, session) = {
+            let users = state.users.read().map_err(|_| failed)?;
+            sessions::find(&users, token)
+                .map(|(user, session)| (user.clone(), session.clone()))
+                .ok_or((StatusCode::UNAUTHORIZED, "Invalid token"))?
+        };
+
+        if sessions::access_expired(&session, now) {
+            debug!(user_id = user.id, session_id = %session.id, "access token expired");
+            return Err((StatusCode::UNAUTHORIZED, "Token expired"));
+        }
+
+        if use_is_stale(Some(&session.last_used_at), now) {
+            record_use(state, user.id, |user| {
+                let Some(session) = user.sessions.iter_mut().find(|s| s.id == session.id) else {
+                    return false;
+                };
+                session.last_used_at = now.to_rfc3339();
+                true
+            })?;
+        }
+
+        Ok(Authenticated {
+            user,
+            session_id: session.id,
+        })
+    }
+}
+
+/// The user behind a session token or an API key. An API key is only
+/// accepted for requests its scope allows.
 impl FromRequestParts<SharedState> for AuthUser {
-    type Rejection = (StatusCode, &'static str);
+    type Rejection = Rejection;
 
     async fn from_request_parts(
         parts: &mut Parts,
         state: &SharedState,
     ) -> Result<Self, Self::Rejection> {
-        let token = parts
-            .headers
-            .get("Authorization")
-            .and_then(|v| v.to_str().ok())
-            .and_then(|v| v.strip_prefix("Bearer "))
-            .ok_or((StatusCode::UNAUTHORIZED, "Missing authorization token"))?;
-
-        let app_data = state
-            .read()
-            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read state"))?;
-
-        let user = app_data
-            .users
-            .iter()
-            .find(|u| u.token == token)
-            .cloned()
-            .ok_or((StatusCode::UNAUTHORIZED, "Invalid token"))?;
-
-        // Check token expiry
-        if let Some(expires_at) = &user.token_expires_at {
-            if let Ok(expires) = chrono::DateTime::parse_from_rfc3339(expires_at) {
-                if chrono::Utc::now() > expires {
-                    warn!(user_id = user.id, "token expired");
-                    return Err((StatusCode::UNAUTHORIZED, "Token expired"));
-                }
-            }
+        let token = bearer_token(parts)?;
+        if !token.starts_with(api_keys::KEY_PREFIX) {
+            return Authenticated::from_request_parts(parts, state)
+                .await
+                .map(|auth| auth.user);
+        }
+        let now = Utc::now();
+        let failed = (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read state");
+
+        let (user, key) = {
+            let users = state.users.read().map_err(|_| failed)?;
+            api_keys::find(&users, token)
+                .map(|(user, key)| (user.clone(), key.clone()))
+                .ok_or((StatusCode::UNAUTHORIZED, "Invalid API key"))?
+        };
+
+        if !api_keys::allows(key.scope, &parts.method, parts.uri.path()) {
+            warn!(
+                user_id = user.id,
+                key_id = %key.id,
+                method = %parts.method,
+                path = parts.uri.path(),
+                "API key scope does not allow request"
+            );
+            return Err((
+                StatusCode::FORBIDDEN,
+                "This API key's scope does not allow this request",
+            ));
+        }
+
+        if use_is_stale(key.last_used_at.as_deref(), now) {
+            record_use(state, user.id, |user| {
+                let Some(key) = user.api_keys.iter_mut().find(|k| k.id == key.id) else {
+                    return false;
+                };
+                key.last_used_at = Some(now.to_rfc3339());
+                true
+            })?;
         }
 
         Ok(user)
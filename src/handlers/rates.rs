@@ -0,0 +1,113 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::errors::{AppError, AppResult};
+use crate::events::Event;
+use crate::logic::{remove_exchange_rate, set_exchange_rate};
+use crate::models::{AuthUser, ExchangeRate};
+use crate::storage::Change;
+
+use super::groups::{current_group, member_group};
+use super::{SharedState, validate_currency, validate_rate};
+
+#[derive(Deserialize)]
+pub struct SetRateRequest {
+    pub from: String,
+    pub to: String,
+    pub rate: f64,
+}
+
+#[derive(Serialize)]
+pub struct RatesResponse {
+    pub rates: Vec<ExchangeRate>,
+}
+
+pub async fn list_rates(
+    State(state): State<SharedState>,
+    user: AuthUser,
+) -> AppResult<Json<RatesResponse>> {
+    let handle = member_group(&state, &user)?;
+    let group = handle.read().map_err(|_| AppError::LockError)?;
+    Ok(Json(RatesResponse {
+        rates: group.exchange_rates.clone(),
+    }))
+}
+
+pub async fn set_rate(
+    State(state): State<SharedState>,
+    user: AuthUser,
+    Json(payload): Json<SetRateRequest>,
+) -> AppResult<Json<ExchangeRate>> {
+    let from = validate_currency(&payload.from)?;
+    let to = validate_currency(&payload.to)?;
+    let rate = validate_rate(payload.rate)?;
+    if from == to {
+        return Err(AppError::BadRequest(
+            "Cannot set a rate between a currency and itself".to_string(),
+        ));
+    }
+
+    let (pending, group_id, entry) = {
+        let handle = current_group(&state, &user)?;
+        let mut group = handle.write().map_err(|_| AppError::LockError)?;
+
+        let entry = ExchangeRate {
+            from,
+            to,
+            rate,
+            updated_at: chrono::Utc::now().to_rfc3339(),
+        };
+        set_exchange_rate(&mut group.exchange_rates, entry.clone());
+        let pending = state.storage.record(
+            &[Event::ExchangeRateSet {
+                group_id: Some(group.id),
+                rate: entry.clone(),
+            }],
+            &[Change::Group(&group)],
+        );
+        (pending, group.id, entry)
+    };
+    pending.wait().await?;
+
+    info!(from = %entry.from, to = %entry.to, rate = entry.rate, group_id, user_id = user.id, "exchange rate set");
+    Ok(Json(entry))
+}
+
+/// Removes the rate between two currencies, whichever way round it was set.
+pub async fn delete_rate(
+    State(state): State<SharedState>,
+    user: AuthUser,
+    Path((from, to)): Path<(String, String)>,
+) -> AppResult<Json<serde_json::Value>> {
+    let from = validate_currency(&from)?;
+    let to = validate_currency(&to)?;
+
+    let (pending, group_id) = {
+        let handle = current_group(&state, &user)?;
+        let mut group = handle.write().map_err(|_| AppError::LockError)?;
+
+        if !remove_exchange_rate(&mut group.exchange_rates, &from, &to) {
+            return Err(AppError::NotFound(format!(
+                "No exchange rate between {} and {}",
+                from, to
+            )));
+        }
+        let pending = state.storage.record(
+            &[Event::ExchangeRateDeleted {
+                group_id: Some(group.id),
+                from: from.clone(),
+                to: to.clone(),
+            }],
+            &[Change::Group(&group)],
+        );
+        (pending, group.id)
+    };
+    pending.wait().await?;
+
+    info!(from = %from, to = %to, group_id, user_id = user.id, "exchange rate deleted");
+    Ok(Json(serde_json::json!({ "success": true })))
+}
@@ -6,8 +6,9 @@ use serde::Deserialize;
 use tracing::info;
 
 use crate::errors::{AppError, AppResult};
+use crate::events::Event;
 use crate::models::{AuthUser, User};
-use crate::storage;
+use crate::storage::Change;
 
 use super::{SharedState, validate_phone};
 
@@ -23,37 +24,44 @@ pub async fn create_user(
 ) -> AppResult<Json<User>> {
     let phone = validate_phone(&payload.phone)?;
 
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
-
-    let registered_user = app_data
-        .users
-        .iter()
-        .find(|u| u.phone == phone)
-        .ok_or_else(AppError::phone_not_registered)?;
-
-    let name = registered_user.name.clone();
-
-    let group = app_data
-        .groups
-        .iter_mut()
-        .find(|g| g.id == auth_user.current_group_id)
-        .ok_or_else(AppError::group_not_found)?;
-
-    if group.members.iter().any(|u| u.name == name) {
-        return Err(AppError::BadRequest(format!(
-            "User '{}' is already in this group",
-            name
-        )));
-    }
-
-    let max_id = group.members.iter().map(|u| u.id).max().unwrap_or(0);
-    let user = User {
-        id: max_id + 1,
-        name,
+    let (pending, user) = {
+        let name = {
+            let users = state.users.read().map_err(|_| AppError::LockError)?;
+            users
+                .iter()
+                .find(|u| u.phone == phone)
+                .ok_or_else(AppError::phone_not_registered)?
+                .name
+                .clone()
+        };
+
+        let handle = state
+            .group(auth_user.current_group_id)?
+            .ok_or_else(AppError::group_not_found)?;
+        let mut group = handle.write().map_err(|_| AppError::LockError)?;
+
+        if group.members.iter().any(|u| u.name == name) {
+            return Err(AppError::BadRequest(format!(
+                "User '{}' is already in this group",
+                name
+            )));
+        }
+
+        let max_id = group.members.iter().map(|u| u.id).max().unwrap_or(0);
+        let user = User {
+            id: max_id + 1,
+            name,
+        };
+
+        group.members.push(user.clone());
+        let event = Event::MemberAdded {
+            group_id: group.id,
+            member: user.clone(),
+        };
+        let pending = state.storage.record(&[event], &[Change::Group(&group)]);
+        (pending, user)
     };
-
-    group.members.push(user.clone());
-    storage::save(&app_data)?;
+    pending.wait().await?;
 
     info!(
         user_id = user.id,
@@ -69,35 +77,41 @@ pub async fn delete_user(
     auth_user: AuthUser,
     Path(id): Path<usize>,
 ) -> AppResult<Json<serde_json::Value>> {
-    let mut app_data = state.write().map_err(|_| AppError::LockError)?;
-
-    let group = app_data
-        .groups
-        .iter_mut()
-        .find(|g| g.id == auth_user.current_group_id)
-        .ok_or_else(AppError::group_not_found)?;
-
-    let index = group
-        .members
-        .iter()
-        .position(|u| u.id == id)
-        .ok_or_else(|| AppError::NotFound(format!("User with id {} not found", id)))?;
-
-    let user_name = &group.members[index].name;
-    let has_expenses = group
-        .expenses
-        .iter()
-        .any(|e| e.payer.name == *user_name || e.participants.iter().any(|p| p.name == *user_name));
-
-    if has_expenses {
-        return Err(AppError::BadRequest(
-            "Cannot delete user with existing expenses".to_string(),
-        ));
-    }
-
-    let removed_name = group.members[index].name.clone();
-    group.members.remove(index);
-    storage::save(&app_data)?;
+    let (pending, removed_name) = {
+        let handle = state
+            .group(auth_user.current_group_id)?
+            .ok_or_else(AppError::group_not_found)?;
+        let mut group = handle.write().map_err(|_| AppError::LockError)?;
+
+        let index = group
+            .members
+            .iter()
+            .position(|u| u.id == id)
+            .ok_or_else(|| AppError::NotFound(format!("User with id {} not found", id)))?;
+
+        let user_name = &group.members[index].name;
+        let has_expenses = group.expenses.iter().any(|e| {
+            e.payer.name == *user_name
+                || e.payers.iter().any(|p| p.name == *user_name)
+                || e.participants.iter().any(|p| p.name == *user_name)
+        });
+
+        if has_expenses {
+            return Err(AppError::BadRequest(
+                "Cannot delete user with existing expenses".to_string(),
+            ));
+        }
+
+        let removed_name = group.members.remove(index).name;
+        group.settlement_constraints.remove_member(&removed_name);
+        let event = Event::MemberRemoved {
+            group_id: group.id,
+            member_id: id,
+        };
+        let pending = state.storage.record(&[event], &[Change::Group(&group)]);
+        (pending, removed_name)
+    };
+    pending.wait().await?;
 
     info!(
         user_id = id,
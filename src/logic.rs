@@ -1,57 +1,461 @@
 use serde::Serialize;
 use std::collections::HashMap;
 
-use crate::models::{Expense, Group};
+use crate::models::{
+    AppData, Contribution, ExchangeRate, Expense, Group, ParticipantShare, SettledSettlement,
+    SettlementAlgorithm, Split, User,
+};
+use crate::money::Money;
 
-pub fn add_expense(expense: Expense, group: &mut Group) {
+pub fn add_expense(mut expense: Expense, group: &mut Group) -> &Expense {
+    expense.shares = expense_shares(&expense);
     group.expenses.push(expense);
+    group.expenses.last().unwrap()
 }
 
-pub fn calculate_balances(group: &Group) -> HashMap<String, f64> {
+/// Removes an expense, along with the settle-up record if it was a payment.
+pub fn remove_expense(group: &mut Group, id: usize) -> Option<Expense> {
+    let index = group.expenses.iter().position(|e| e.id == id)?;
+
+    let expense = &group.expenses[index];
+    if expense.category.as_deref() == Some("Settlement") {
+        let payer_name = expense.payer.name.clone();
+        let to_name = expense.participants.first().map(|p| p.name.clone());
+        group.settled_settlements.retain(|s| match s.expense_id {
+            Some(expense_id) => expense_id != id,
+            // Older records are only linked by who paid whom
+            None => !(s.from == payer_name && Some(&s.to) == to_name.as_ref()),
+        });
+    }
+
+    Some(group.expenses.remove(index))
+}
+
+/// Replaces an expense with its edited version, keeping the settle-up record
+/// of a payment in step with it (see `sync_settlement`).
+pub fn replace_expense(group: &mut Group, mut expense: Expense) -> Option<&Expense> {
+    let index = group.expenses.iter().position(|e| e.id == expense.id)?;
+    expense.shares = expense_shares(&expense);
+
+    let previous = &group.expenses[index];
+    if previous.category.as_deref() == Some("Settlement") {
+        let to_name = previous.participants.first().map(|p| &p.name);
+        let record = group
+            .settled_settlements
+            .iter()
+            .position(|s| match s.expense_id {
+                Some(expense_id) => expense_id == expense.id,
+                // Older records are only linked by who paid whom
+                None => s.from == previous.payer.name && Some(&s.to) == to_name,
+            });
+        if let Some(record) = record
+            && !sync_settlement(&mut group.settled_settlements[record], &expense)
+        {
+            group.settled_settlements.remove(record);
+        }
+    }
+
+    group.expenses[index] = expense;
+    Some(&group.expenses[index])
+}
+
+/// Makes a settle-up record follow an edit to the payment expense it was
+/// recorded as: its amount, currency and the people involved. Returns false
+/// if the expense no longer records a payment from one member to another,
+/// in which case the record should be dropped.
+pub fn sync_settlement(record: &mut SettledSettlement, expense: &Expense) -> bool {
+    let [to] = expense.participants.as_slice() else {
+        return false;
+    };
+    if expense.category.as_deref() != Some("Settlement")
+        || !expense.payers.is_empty()
+        || to.name == expense.payer.name
+    {
+        return false;
+    }
+    record.from = expense.payer.name.clone();
+    record.to = to.name.clone();
+    record.amount = base_amount(expense);
+    record.expense_id = Some(expense.id);
+    record.original_amount = expense.currency.as_ref().map(|_| expense.amount);
+    record.original_currency = expense.currency.clone();
+    record.exchange_rate = expense.exchange_rate;
+    true
+}
+
+/// Converts a percentage with at most two decimals into hundredths of a
+/// percent, so 12.5% becomes 1250. Returns `None` for values outside 0-100.
+pub fn percentage_basis_points(percentage: f64) -> Option<u64> {
+    let scaled = percentage * 100.0;
+    if !(0.0..=10_000.0).contains(&scaled) || (scaled - scaled.round()).abs() > 1e-6 {
+        return None;
+    }
+    Some(scaled.round() as u64)
+}
+
+/// Divides an expense between its participants according to its split or
+/// itemization, returning shares in participant order. Unless the split gives
+/// exact amounts, the shares always add up to exactly `expense.amount`.
+///
+/// Equal, percentage and weighted splits are computed proportionally and
+/// rounded down to the cent. Leftover cents then go one at a time to the
+/// participants whose shares were rounded down the most; ties go to the payer
+/// first (if they participate), then to the other participants in the order
+/// they are listed on the expense. For an equal split this simply means the
+/// payer absorbs the first extra cent. The result only depends on the expense
+/// itself, so it never changes between calls.
+///
+/// Itemized expenses split each line item equally between the people who had
+/// it, then share tax and tip in proportion to those subtotals, using the same
+/// rounding rule at every step.
+pub fn expense_shares(expense: &Expense) -> Vec<ParticipantShare> {
+    let participants = &expense.participants;
+
+    let amounts: Vec<Money> = match (&expense.itemization, &expense.split) {
+        (Some(itemization), _) => {
+            let mut subtotals = vec![Money::ZERO; participants.len()];
+            for item in &itemization.items {
+                let weights: Vec<u64> = participants
+                    .iter()
+                    .map(|p| u64::from(item.participants.contains(&p.name)))
+                    .collect();
+                for (subtotal, part) in
+                    subtotals
+                        .iter_mut()
+                        .zip(allocate_payer_first(expense, item.amount, &weights))
+                {
+                    *subtotal += part;
+                }
+            }
+
+            let weights: Vec<u64> = subtotals.iter().map(|s| s.cents().max(0) as u64).collect();
+            let extras = allocate_payer_first(expense, itemization.tax + itemization.tip, &weights);
+            subtotals.iter().zip(extras).map(|(s, e)| *s + e).collect()
+        }
+        (None, Split::Exact { amounts }) => participants
+            .iter()
+            .map(|p| amounts.get(&p.name).copied().unwrap_or(Money::ZERO))
+            .collect(),
+        (None, split) => {
+            let weights: Vec<u64> = participants
+                .iter()
+                .map(|p| split_weight(split, &p.name))
+                .collect();
+            allocate_payer_first(expense, expense.amount, &weights)
+        }
+    };
+
+    participants
+        .iter()
+        .zip(amounts)
+        .map(|(participant, amount)| ParticipantShare {
+            name: participant.name.clone(),
+            amount,
+        })
+        .collect()
+}
+
+/// Allocates `amount` over the expense's participants by `weights` (given in
+/// participant order), breaking rounding ties payer first, then by listed order.
+fn allocate_payer_first(expense: &Expense, amount: Money, weights: &[u64]) -> Vec<Money> {
+    let participants = &expense.participants;
+    let mut order: Vec<usize> = (0..participants.len()).collect();
+    order.sort_by_key(|&i| participants[i].name != expense.payer.name);
+
+    let ordered_weights: Vec<u64> = order.iter().map(|&i| weights[i]).collect();
+
+    let mut amounts = vec![Money::ZERO; participants.len()];
+    for (&i, part) in order.iter().zip(amount.allocate(&ordered_weights)) {
+        amounts[i] = part;
+    }
+    amounts
+}
+
+fn split_weight(split: &Split, name: &str) -> u64 {
+    match split {
+        Split::Equal => 1,
+        Split::Exact { .. } => 0,
+        Split::Percentage { percentages } => percentages
+            .get(name)
+            .and_then(|p| percentage_basis_points(*p))
+            .unwrap_or(0),
+        Split::Shares { shares } => shares.get(name).copied().unwrap_or(0),
+    }
+}
+
+/// Returns who paid how much towards an expense. Expenses with a single payer
+/// record no `payers`, in which case the primary payer covered everything.
+pub fn expense_payments(expense: &Expense) -> Vec<Contribution> {
+    if expense.payers.is_empty() {
+        vec![Contribution {
+            name: expense.payer.name.clone(),
+            amount: expense.amount,
+        }]
+    } else {
+        expense.payers.clone()
+    }
+}
+
+/// Why the payers given for an expense cannot be stored.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum PayerError {
+    #[error("Payer '{0}' not found")]
+    NotFound(String),
+    #[error("Payer '{0}' is listed more than once")]
+    Duplicate(String),
+    #[error("Amount paid by '{0}' must be positive")]
+    NotPositive(String),
+    #[error("Payer is required")]
+    Missing,
+    #[error("Amounts add up to more than is supported")]
+    TooLarge,
+    #[error("Payers paid {paid} in total but the expense is {amount}")]
+    Mismatch { paid: Money, amount: Money },
+}
+
+/// Checks that each of several payers is a member of the group, listed once,
+/// paying a positive amount. Returns the first, who is recorded as the
+/// expense's payer.
+pub fn resolve_payers(members: &[User], payers: &[Contribution]) -> Result<User, PayerError> {
+    let mut primary = None;
+    for (i, payer) in payers.iter().enumerate() {
+        let user = members
+            .iter()
+            .find(|u| u.name == payer.name)
+            .ok_or_else(|| PayerError::NotFound(payer.name.clone()))?;
+        if payers[..i].iter().any(|p| p.name == payer.name) {
+            return Err(PayerError::Duplicate(payer.name.clone()));
+        }
+        if !payer.amount.is_positive() {
+            return Err(PayerError::NotPositive(payer.name.clone()));
+        }
+        primary.get_or_insert_with(|| user.clone());
+    }
+    primary.ok_or(PayerError::Missing)
+}
+
+/// Checks that the amounts paid by several payers add up to the expense.
+pub fn validate_payers(payers: &[Contribution], amount: Money) -> Result<(), PayerError> {
+    if payers.is_empty() {
+        return Ok(());
+    }
+    let paid = Money::checked_sum(payers.iter().map(|p| p.amount))
+        .and_then(Money::in_range)
+        .ok_or(PayerError::TooLarge)?;
+    if paid != amount {
+        return Err(PayerError::Mismatch { paid, amount });
+    }
+    Ok(())
+}
+
+/// Looks up the rate table for how many units of `to` one unit of `from` is
+/// worth, using the inverse of a stored `to`→`from` rate if needed.
+pub fn find_exchange_rate(rates: &[ExchangeRate], from: &str, to: &str) -> Option<f64> {
+    if from == to {
+        return Some(1.0);
+    }
+    rates
+        .iter()
+        .find(|r| r.from == from && r.to == to)
+        .map(|r| r.rate)
+        .or_else(|| {
+            rates
+                .iter()
+                .find(|r| r.from == to && r.to == from)
+                .map(|r| 1.0 / r.rate)
+        })
+}
+
+/// Adds or replaces a rate-table entry. A currency pair is stored once, so
+/// an existing entry for the inverse pair is replaced too.
+pub fn set_exchange_rate(rates: &mut Vec<ExchangeRate>, entry: ExchangeRate) {
+    rates.retain(|r| {
+        !((r.from == entry.from && r.to == entry.to) || (r.from == entry.to && r.to == entry.from))
+    });
+    rates.push(entry);
+}
+
+/// Removes the rate-table entry for a currency pair, whichever way round it
+/// was stored. Returns whether there was one.
+pub fn remove_exchange_rate(rates: &mut Vec<ExchangeRate>, from: &str, to: &str) -> bool {
+    let before = rates.len();
+    rates.retain(|r| !((r.from == from && r.to == to) || (r.from == to && r.to == from)));
+    rates.len() != before
+}
+
+/// The expense amount converted into the group's currency.
+pub fn base_amount(expense: &Expense) -> Money {
+    match expense.exchange_rate {
+        Some(rate) => expense.amount.convert(rate),
+        None => expense.amount,
+    }
+}
+
+/// What `expenses` add up to in the group's currency, or `None` if that is
+/// more than `Money::MAX`. Every balance, debt and suggested payment in a
+/// group is bounded by this total, so none of them can overflow while it fits.
+pub fn group_total<'a>(expenses: impl IntoIterator<Item = &'a Expense>) -> Option<Money> {
+    expenses
+        .into_iter()
+        .try_fold(Money::ZERO, |total, expense| {
+            total.checked_add(base_amount(expense).in_range()?.abs())
+        })?
+        .in_range()
+}
+
+/// Re-expresses parts of an expense in the group's currency. The converted
+/// total is allocated in proportion to the original parts rather than
+/// converting each part, so the results still add up to exactly `base_amount`.
+fn to_base_currency(expense: &Expense, parts: &[Money]) -> Vec<Money> {
+    if expense.exchange_rate.is_none() {
+        return parts.to_vec();
+    }
+    let weights: Vec<u64> = parts.iter().map(|p| p.cents().max(0) as u64).collect();
+    base_amount(expense).allocate(&weights)
+}
+
+/// `expense_shares` in the group's currency.
+fn base_shares(expense: &Expense) -> Vec<ParticipantShare> {
+    let mut shares = expense_shares(expense);
+    let amounts: Vec<Money> = shares.iter().map(|s| s.amount).collect();
+    for (share, amount) in shares.iter_mut().zip(to_base_currency(expense, &amounts)) {
+        share.amount = amount;
+    }
+    shares
+}
+
+/// `expense_payments` in the group's currency.
+fn base_payments(expense: &Expense) -> Vec<Contribution> {
+    let mut payments = expense_payments(expense);
+    let amounts: Vec<Money> = payments.iter().map(|p| p.amount).collect();
+    for (payment, amount) in payments.iter_mut().zip(to_base_currency(expense, &amounts)) {
+        payment.amount = amount;
+    }
+    payments
+}
+
+/// Works out how much each participant owes each payer of an expense, as
+/// `(participant, payer, amount)` triples in the group's currency.
+///
+/// Every participant's share is spread over the payers in proportion to what
+/// each payer has not yet been paid back, walking participants in listed
+/// order. This keeps both sides exact: each participant's parts add up to
+/// their share and each payer's parts add up to their contribution.
+fn expense_debts(expense: &Expense) -> Vec<(String, String, Money)> {
+    let payments = base_payments(expense);
+    let mut remaining: Vec<Money> = payments.iter().map(|p| p.amount).collect();
+    let mut debts = Vec::new();
+
+    for share in base_shares(expense) {
+        let weights: Vec<u64> = remaining.iter().map(|r| r.cents().max(0) as u64).collect();
+        for (i, part) in share.amount.allocate(&weights).into_iter().enumerate() {
+            remaining[i] -= part;
+            if !part.is_zero() {
+                debts.push((share.name.clone(), payments[i].name.clone(), part));
+            }
+        }
+    }
+
+    debts
+}
+
+/// Recomputes the stored shares of every expense, e.g. after loading data
+/// written before shares were recorded.
+pub fn refresh_shares(app_data: &mut AppData) {
+    for group in &mut app_data.groups {
+        for expense in &mut group.expenses {
+            expense.shares = expense_shares(expense);
+        }
+    }
+}
+
+/// Net position of each member in the group's currency: positive means they
+/// are owed money, negative means they owe.
+pub fn calculate_balances(group: &Group) -> HashMap<String, Money> {
     let mut balances = HashMap::new();
 
     for member in &group.members {
-        balances.insert(member.name.clone(), 0.0);
+        balances.insert(member.name.clone(), Money::ZERO);
     }
 
     for expense in &group.expenses {
-        let share = expense.amount / expense.participants.len() as f64;
-        *balances.get_mut(&expense.payer.name).unwrap() += expense.amount;
-        for participant in &expense.participants {
-            *balances.get_mut(&participant.name).unwrap() -= share;
+        for payment in base_payments(expense) {
+            *balances.get_mut(&payment.name).unwrap() += payment.amount;
+        }
+        for share in base_shares(expense) {
+            *balances.get_mut(&share.name).unwrap() -= share.amount;
         }
     }
 
     balances
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettlementStatus {
+    Outstanding,
+    PartiallyPaid,
+    Paid,
+}
+
+/// A suggested payment. `amount` is what is still owed; `paid` is what has
+/// already been paid towards it in the current balance period.
 #[derive(Debug, Clone, Serialize)]
 pub struct Settlement {
     pub from: String,
     pub to: String,
-    pub amount: f64,
+    pub amount: Money,
+    pub paid: Money,
+    /// `amount + paid`: the debt as it stood before any payments.
+    pub total: Money,
+    pub status: SettlementStatus,
+    /// Kept for older clients; true once the debt is fully paid.
     #[serde(default)]
     pub settled: bool,
 }
 
+impl Settlement {
+    fn outstanding(from: String, to: String, amount: Money) -> Self {
+        Settlement {
+            from,
+            to,
+            amount,
+            paid: Money::ZERO,
+            total: amount,
+            status: SettlementStatus::Outstanding,
+            settled: false,
+        }
+    }
+
+    fn record_payment(&mut self, paid: Money) {
+        self.paid += paid;
+        self.total = self.amount + self.paid;
+        self.status = if !self.amount.is_positive() {
+            SettlementStatus::Paid
+        } else if self.paid.is_positive() {
+            SettlementStatus::PartiallyPaid
+        } else {
+            SettlementStatus::Outstanding
+        };
+        self.settled = self.status == SettlementStatus::Paid;
+    }
+}
+
 pub fn calculate_settlements(group: &Group) -> Vec<Settlement> {
-    let mut debts: HashMap<String, HashMap<String, f64>> = HashMap::new();
+    let mut debts: HashMap<String, HashMap<String, Money>> = HashMap::new();
 
     for member in &group.members {
         debts.insert(member.name.clone(), HashMap::new());
     }
 
     for expense in &group.expenses {
-        let payer = &expense.payer.name;
-        let share = expense.amount / expense.participants.len() as f64;
-
-        for participant in &expense.participants {
-            if participant.name != *payer {
+        for (participant, payer, amount) in expense_debts(expense) {
+            if participant != payer {
                 *debts
-                    .get_mut(&participant.name)
+                    .get_mut(&participant)
                     .unwrap()
-                    .entry(payer.clone())
-                    .or_insert(0.0) += share;
+                    .entry(payer)
+                    .or_insert(Money::ZERO) += amount;
             }
         }
     }
@@ -76,23 +480,17 @@ pub fn calculate_settlements(group: &Group) -> Vec<Settlement> {
                 .get(person_b)
                 .and_then(|d| d.get(person_a))
                 .copied()
-                .unwrap_or(0.0);
+                .unwrap_or(Money::ZERO);
 
-            let net = amount_a_owes_b - amount_b_owes_a;
-            let rounded = (net.abs() * 100.0).round() / 100.0;
+            let net = *amount_a_owes_b - amount_b_owes_a;
 
-            if rounded > 0.01 {
-                let (from, to) = if net > 0.0 {
+            if !net.is_zero() {
+                let (from, to) = if net.is_positive() {
                     (person_a.clone(), person_b.clone())
                 } else {
                     (person_b.clone(), person_a.clone())
                 };
-                settlements.push(Settlement {
-                    from,
-                    to,
-                    amount: rounded,
-                    settled: false,
-                });
+                settlements.push(Settlement::outstanding(from, to, net.abs()));
             }
         }
     }
@@ -101,24 +499,30 @@ pub fn calculate_settlements(group: &Group) -> Vec<Settlement> {
     settlements
 }
 
+/// Greedy debt simplification: repeatedly matches the largest debtor with the
+/// largest creditor. Fast, but not guaranteed to use the fewest payments.
 pub fn calculate_simplified_settlements(group: &Group) -> Vec<Settlement> {
-    let balances = calculate_balances(group);
+    let balances: Vec<(String, Money)> = calculate_balances(group).into_iter().collect();
+    greedy_settlements(&balances)
+}
+
+fn greedy_settlements(balances: &[(String, Money)]) -> Vec<Settlement> {
     let mut settlements = Vec::new();
 
-    let mut debtors: Vec<(String, f64)> = balances
+    let mut debtors: Vec<(String, Money)> = balances
         .iter()
-        .filter(|(_, balance)| **balance < -0.01)
-        .map(|(name, balance)| (name.clone(), -balance))
+        .filter(|(_, balance)| balance.is_negative())
+        .map(|(name, balance)| (name.clone(), -*balance))
         .collect();
 
-    let mut creditors: Vec<(String, f64)> = balances
+    let mut creditors: Vec<(String, Money)> = balances
         .iter()
-        .filter(|(_, balance)| **balance > 0.01)
+        .filter(|(_, balance)| balance.is_positive())
         .map(|(name, balance)| (name.clone(), *balance))
         .collect();
 
-    debtors.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then_with(|| a.0.cmp(&b.0)));
-    creditors.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then_with(|| a.0.cmp(&b.0)));
+    debtors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    creditors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
 
     let mut i = 0;
     let mut j = 0;
@@ -126,25 +530,359 @@ pub fn calculate_simplified_settlements(group: &Group) -> Vec<Settlement> {
     while i < debtors.len() && j < creditors.len() {
         let amount = debtors[i].1.min(creditors[j].1);
 
-        if amount > 0.01 {
-            settlements.push(Settlement {
-                from: debtors[i].0.clone(),
-                to: creditors[j].0.clone(),
-                amount: (amount * 100.0).round() / 100.0,
-                settled: false,
-            });
-        }
+        settlements.push(Settlement::outstanding(
+            debtors[i].0.clone(),
+            creditors[j].0.clone(),
+            amount,
+        ));
 
         debtors[i].1 -= amount;
         creditors[j].1 -= amount;
 
-        if debtors[i].1 < 0.01 {
+        if debtors[i].1.is_zero() {
             i += 1;
         }
-        if creditors[j].1 < 0.01 {
+        if creditors[j].1.is_zero() {
             j += 1;
         }
     }
 
     settlements
 }
+
+/// Settles the group with the fewest possible payments.
+///
+/// A group whose members owe or are owed money can always be settled with one
+/// payment fewer than the number of people in each independent zero-sum
+/// subgroup, so the minimum is found by splitting the members into as many
+/// zero-sum subgroups as possible. This is an exact search over subsets of
+/// members with a non-zero balance and takes O(2^n · n) time, so callers
+/// should fall back to `calculate_simplified_settlements` for large groups.
+pub fn calculate_optimal_settlements(group: &Group) -> Vec<Settlement> {
+    let mut balances: Vec<(String, Money)> = calculate_balances(group)
+        .into_iter()
+        .filter(|(_, balance)| !balance.is_zero())
+        .collect();
+    balances.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let n = balances.len();
+    if n == 0 {
+        return vec![];
+    }
+
+    let full = (1usize << n) - 1;
+    let mut sums = vec![Money::ZERO; full + 1];
+    for mask in 1..=full {
+        let lowest = mask.trailing_zeros() as usize;
+        sums[mask] = sums[mask & (mask - 1)] + balances[lowest].1;
+    }
+
+    // groups[mask]: the most zero-sum subgroups `mask` can be split into,
+    // where `mask` is built up by adding members one at a time
+    let mut groups = vec![0u32; full + 1];
+    let mut removed = vec![0usize; full + 1];
+    for mask in 1..=full {
+        let mut best = 0;
+        for i in 0..n {
+            let bit = 1 << i;
+            if mask & bit != 0 && (removed[mask] == 0 || groups[mask ^ bit] > best) {
+                best = groups[mask ^ bit];
+                removed[mask] = bit;
+            }
+        }
+        groups[mask] = best + u32::from(sums[mask].is_zero());
+    }
+
+    // Walk back from the full set; each stretch between two zero-sum masks is
+    // one independent subgroup that settles with greedy matching
+    let mut settlements = Vec::new();
+    let mut mask = full;
+    let mut subgroup = Vec::new();
+    while mask != 0 {
+        let bit = removed[mask];
+        subgroup.push(balances[bit.trailing_zeros() as usize].clone());
+        mask ^= bit;
+        if sums[mask].is_zero() {
+            settlements.extend(greedy_settlements(&subgroup));
+            subgroup.clear();
+        }
+    }
+
+    settlements
+}
+
+/// Debt simplification for a group, honouring its chosen algorithm.
+/// Optimal search falls back to greedy matching when more members than the
+/// group's limit have a non-zero balance.
+pub fn calculate_group_simplified_settlements(group: &Group) -> Vec<Settlement> {
+    match group.settlement_algorithm {
+        SettlementAlgorithm::Greedy => calculate_simplified_settlements(group),
+        SettlementAlgorithm::Optimal => {
+            let unsettled = calculate_balances(group)
+                .values()
+                .filter(|b| !b.is_zero())
+                .count();
+            if unsettled <= group.optimal_member_limit {
+                calculate_optimal_settlements(group)
+            } else {
+                calculate_simplified_settlements(group)
+            }
+        }
+    }
+}
+
+/// Index of the first expense in the current balance period. Everything
+/// before it is settled, so a new period starts each time the group is
+/// completely settled up.
+///
+/// Groups that settle pairwise are only settled up once every pair's debt is
+/// zero; net balances can all be zero while a cycle of debts (A owes B, B
+/// owes C, C owes A) is still outstanding.
+pub fn balance_period_start(group: &Group) -> usize {
+    let pairwise = !group.simplify_debts && group.settlement_constraints.is_empty();
+    let mut balances: HashMap<String, Money> = HashMap::new();
+    // What the first of each pair, by name, owes the second
+    let mut debts: HashMap<(String, String), Money> = HashMap::new();
+    let mut start = 0;
+
+    for (i, expense) in group.expenses.iter().enumerate() {
+        let settled = if pairwise {
+            for (participant, payer, amount) in expense_debts(expense) {
+                if participant < payer {
+                    *debts.entry((participant, payer)).or_default() += amount;
+                } else if participant > payer {
+                    *debts.entry((payer, participant)).or_default() -= amount;
+                }
+            }
+            debts.values().all(|d| d.is_zero())
+        } else {
+            for payment in base_payments(expense) {
+                *balances.entry(payment.name).or_default() += payment.amount;
+            }
+            for share in base_shares(expense) {
+                *balances.entry(share.name).or_default() -= share.amount;
+            }
+            balances.values().all(|b| b.is_zero())
+        };
+        if settled {
+            start = i + 1;
+        }
+    }
+
+    start
+}
+
+/// Settles a group's balances while respecting its settlement constraints,
+/// returning the payments and a note for every detour a constraint forced.
+///
+/// With a hub, every debtor pays the hub and the hub pays every creditor.
+/// Otherwise debtors are matched greedily, largest first, against creditors in
+/// order of preference and then size, skipping forbidden pairs. A debt that
+/// can only go to a forbidden creditor is relayed through another member
+/// (the hub or a preferred receiver if possible) who may pay both sides.
+fn constrained_settlements(group: &Group) -> (Vec<Settlement>, Vec<String>) {
+    let constraints = &group.settlement_constraints;
+    let balances = calculate_balances(group);
+
+    let mut debtors: Vec<(String, Money)> = balances
+        .iter()
+        .filter(|(_, balance)| balance.is_negative())
+        .map(|(name, balance)| (name.clone(), -*balance))
+        .collect();
+    let mut creditors: Vec<(String, Money)> = balances
+        .iter()
+        .filter(|(_, balance)| balance.is_positive())
+        .map(|(name, balance)| (name.clone(), *balance))
+        .collect();
+    debtors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let preference = |name: &str| {
+        constraints
+            .preferred_receivers
+            .iter()
+            .position(|p| p == name)
+            .unwrap_or(usize::MAX)
+    };
+    creditors.sort_by(|a, b| {
+        preference(&a.0)
+            .cmp(&preference(&b.0))
+            .then_with(|| b.1.cmp(&a.1))
+            .then_with(|| a.0.cmp(&b.0))
+    });
+
+    let mut relays: Vec<String> = constraints.hub.iter().cloned().collect();
+    relays.extend(constraints.preferred_receivers.iter().cloned());
+    let mut others: Vec<String> = group.members.iter().map(|m| m.name.clone()).collect();
+    others.sort();
+    relays.extend(others);
+
+    let mut transfers: Vec<(String, String, Money)> = Vec::new();
+    let mut notes = Vec::new();
+    let mut pay = |from: &str, to: &str, amount: Money| {
+        if constraints.allows(from, to) {
+            transfers.push((from.to_string(), to.to_string(), amount));
+            return;
+        }
+        let relay = relays.iter().find(|r| {
+            r.as_str() != from
+                && r.as_str() != to
+                && constraints.allows(from, r)
+                && constraints.allows(r, to)
+        });
+        match relay {
+            Some(relay) => {
+                transfers.push((from.to_string(), relay.clone(), amount));
+                transfers.push((relay.clone(), to.to_string(), amount));
+                notes.push(format!(
+                    "{} cannot pay {} directly, so {} passes on {}",
+                    from, to, relay, amount
+                ));
+            }
+            None => {
+                transfers.push((from.to_string(), to.to_string(), amount));
+                notes.push(format!(
+                    "{} has no allowed way to pay {}; this payment ignores the constraint",
+                    from, to
+                ));
+            }
+        }
+    };
+
+    if let Some(hub) = &constraints.hub {
+        for (debtor, amount) in &debtors {
+            if debtor != hub {
+                pay(debtor, hub, *amount);
+            }
+        }
+        for (creditor, amount) in &creditors {
+            if creditor != hub {
+                pay(hub, creditor, *amount);
+            }
+        }
+    } else {
+        for (debtor, owed) in &mut debtors {
+            while owed.is_positive() {
+                let open = |(_, c): &(String, Money)| c.is_positive();
+                let Some(index) = creditors
+                    .iter()
+                    .position(|c| open(c) && constraints.allows(debtor, &c.0))
+                    .or_else(|| creditors.iter().position(open))
+                else {
+                    break;
+                };
+                let amount = (*owed).min(creditors[index].1);
+                pay(debtor, &creditors[index].0, amount);
+                *owed -= amount;
+                creditors[index].1 -= amount;
+            }
+        }
+    }
+
+    // Relays can produce several payments between the same two people
+    let mut settlements: Vec<Settlement> = Vec::new();
+    for (from, to, amount) in transfers {
+        match settlements
+            .iter_mut()
+            .find(|s| s.from == from && s.to == to)
+        {
+            Some(existing) => {
+                existing.amount += amount;
+                existing.total = existing.amount;
+            }
+            None => settlements.push(Settlement::outstanding(from, to, amount)),
+        }
+    }
+    if let Some(hub) = &constraints.hub {
+        notes.insert(0, format!("All payments are routed through {}", hub));
+    }
+
+    (settlements, notes)
+}
+
+/// What the settle-up screen shows: the suggested payments and, when the
+/// group's constraints changed the plan, why.
+#[derive(Debug, Clone, Serialize)]
+pub struct SettlementPlan {
+    pub settlements: Vec<Settlement>,
+    /// How many payments settling up would take without any constraints.
+    pub unconstrained_payments: usize,
+    pub explanations: Vec<String>,
+}
+
+/// Suggested payments for the current balance period, with the payments
+/// already recorded in that period applied to them.
+///
+/// Suggestions come from the group's current balances, so they always show
+/// what is still owed. Payments recorded through settle-up in the same period
+/// are added back on top to report how much of each debt has been paid; pairs
+/// that have been fully paid remain listed as paid until the period ends.
+///
+/// Groups with settlement constraints always plan from net balances, since
+/// pairwise debts cannot be rerouted.
+pub fn settlement_plan(group: &Group) -> SettlementPlan {
+    let start = balance_period_start(group);
+    let period = Group {
+        expenses: group.expenses[start..].to_vec(),
+        ..group.clone()
+    };
+
+    let unconstrained = if group.simplify_debts {
+        calculate_group_simplified_settlements(&period)
+    } else {
+        calculate_settlements(&period)
+    };
+    let unconstrained_payments = unconstrained.len();
+
+    let (mut settlements, mut explanations) = if group.settlement_constraints.is_empty() {
+        (unconstrained, vec![])
+    } else {
+        constrained_settlements(&period)
+    };
+    if settlements.len() > unconstrained_payments {
+        explanations.insert(
+            0,
+            format!(
+                "Settling up takes {} payments instead of {} because of the group's payment constraints",
+                settlements.len(),
+                unconstrained_payments
+            ),
+        );
+    }
+    let period_start = period
+        .expenses
+        .first()
+        .and_then(|e| chrono::DateTime::parse_from_rfc3339(&e.created_at).ok());
+
+    for record in &group.settled_settlements {
+        let in_period = match record.expense_id {
+            Some(id) => period.expenses.iter().any(|e| e.id == id),
+            // Records from before settlements were linked to their expense
+            None => period_start.is_some_and(|start| {
+                chrono::DateTime::parse_from_rfc3339(&record.settled_at)
+                    .is_ok_and(|settled_at| settled_at >= start)
+            }),
+        };
+        if !in_period {
+            continue;
+        }
+
+        match settlements
+            .iter_mut()
+            .find(|s| s.from == record.from && s.to == record.to)
+        {
+            Some(settlement) => settlement.record_payment(record.amount),
+            None => {
+                let mut settlement =
+                    Settlement::outstanding(record.from.clone(), record.to.clone(), Money::ZERO);
+                settlement.record_payment(record.amount);
+                settlements.push(settlement);
+            }
+        }
+    }
+
+    SettlementPlan {
+        settlements,
+        unconstrained_payments,
+        explanations,
+    }
+}
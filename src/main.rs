@@ -1,33 +1,71 @@
 use axum::{
-    Json, Router,
+    Json, Router, middleware,
     routing::{delete, get, post, put},
 };
 use clap::Parser;
-use std::sync::{Arc, RwLock};
-use tower_http::cors::CorsLayer;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod api_keys;
 mod cli;
+mod config;
 mod errors;
+mod events;
 mod handlers;
 mod logic;
 mod models;
+mod money;
+mod otp;
+mod rate_limit;
+mod sessions;
+mod shutdown;
+mod sms;
 mod storage;
+#[cfg(test)]
 mod tests;
 
-use cli::{Cli, Commands};
-use handlers::{auth, expenses, groups, users};
-use logic::{add_expense, calculate_balances, calculate_settlements};
-use models::{Expense, User};
+use cli::{ApiKeyCommand, Cli, Commands, ConfigCommand, PayerArg, ServeArgs};
+use config::{Config, LogFormat};
+use errors::AppError;
+use events::Event;
+use handlers::{AppState, auth, expenses, groups, rates, users};
+use logic::{
+    SettlementStatus, add_expense, calculate_balances, group_total, resolve_payers,
+    set_exchange_rate, settlement_plan, validate_payers,
+};
+use models::{AppData, Contribution, ExchangeRate, Expense, Group, Split, User};
+use money::Money;
+use rate_limit::RateLimiter;
+use storage::backup::{BackupDir, RotatingBackups};
+use storage::encryption::DataKey;
+use storage::writer::StorageWriter;
+use storage::{Change, Storage};
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    let key = DataKey::load(cli.key_file.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Error loading data key: {}", e);
+        std::process::exit(1);
+    });
 
     match cli.command {
-        Commands::Serve { port, data_file } => {
-            run_server(port, &data_file).await;
+        Commands::Serve { settings } => {
+            let (config, path) = load_config(&settings);
+            run_server(config, path, key).await;
+        }
+        Commands::Config {
+            command: ConfigCommand::Check { settings },
+        } => {
+            let (config, path) = load_config(&settings);
+            match path {
+                Some(path) => println!("# Settings from {}", path.display()),
+                None => println!("# No config file; using defaults"),
+            }
+            println!("# with SPLITDUMB_* environment variables and flags applied\n");
+            print!("{}", config.to_toml());
         }
         Commands::AddExpense {
             description,
@@ -36,55 +74,49 @@ async fn main() {
             participants,
             data_file,
         } => {
-            storage::init(&data_file);
-            let mut app_data = storage::load();
-
-            let group = app_data
-                .groups
-                .first_mut()
-                .expect("No groups found. Create a group first.");
+            let storage = open_storage(&data_file, &key);
+            let mut app_data = load_data(storage.as_ref());
 
-            let payer_user = group
-                .members
-                .iter()
-                .find(|u| u.name == payer)
-                .expect("Payer not found")
-                .clone();
-
-            let participant_users: Vec<User> = participants
-                .split(',')
-                .map(|name| {
-                    group
-                        .members
-                        .iter()
-                        .find(|u| u.name.trim() == name.trim())
-                        .expect("Participant not found")
-                        .clone()
-                })
-                .collect();
-
-            let expense = Expense {
-                id: group.expenses.len() + 1,
-                description,
-                amount,
-                payer: payer_user,
-                participants: participant_users,
-                created_at: chrono::Utc::now().to_rfc3339(),
-                category: None,
-                notes: None,
+            let Some(group) = app_data.groups.first_mut() else {
+                eprintln!("No groups found. Create a group first.");
+                std::process::exit(1);
             };
+            let expense = match cli_expense(group, description, amount, &payer, &participants) {
+                Ok(expense) => expense,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            if group_total(group.expenses.iter().chain([&expense])).is_none() {
+                eprintln!("The group's expenses would add up to more than is supported");
+                std::process::exit(1);
+            }
 
-            add_expense(expense, group);
+            let expense = add_expense(expense, group).clone();
+            let event = Event::ExpenseCreated {
+                group_id: group.id,
+                expense: expense.clone(),
+            };
 
-            if let Err(e) = storage::save(&app_data) {
+            if let Err(e) = storage.record(&[event], &[Change::Group(group)]) {
                 eprintln!("Error saving data: {}", e);
                 std::process::exit(1);
             }
             println!("Expense added successfully.");
+            for share in expense.shares {
+                println!("  {} owes ${}", share.name, share.amount);
+            }
         }
-        Commands::ShowBalances { data_file } => {
-            storage::init(&data_file);
-            let app_data = storage::load();
+        Commands::ShowBalances { data_file, as_of } => {
+            let storage = open_storage(&data_file, &key);
+            let app_data = match as_of {
+                Some(at) => storage.load_as_of(at).unwrap_or_else(|e| {
+                    eprintln!("Error loading data as of {}: {}", at, e);
+                    std::process::exit(1);
+                }),
+                None => load_data(storage.as_ref()),
+            };
 
             let group = app_data
                 .groups
@@ -92,69 +124,626 @@ async fn main() {
                 .expect("No groups found. Create a group first.");
 
             let balances = calculate_balances(group);
-            println!("Balances for group '{}':", group.name);
+            println!("Balances for group '{}' ({}):", group.name, group.currency);
             for (user, balance) in balances {
-                let sign = if balance >= 0.0 { "+" } else { "" };
-                println!("  {}: {}${:.2}", user, sign, balance);
+                let sign = if balance >= Money::ZERO { "+" } else { "" };
+                println!("  {}: {}{}", user, sign, balance);
             }
         }
-        Commands::ShowSettlements { data_file } => {
-            storage::init(&data_file);
-            let app_data = storage::load();
+        Commands::ShowSettlements { data_file, as_of } => {
+            let storage = open_storage(&data_file, &key);
+            let app_data = match as_of {
+                Some(at) => storage.load_as_of(at).unwrap_or_else(|e| {
+                    eprintln!("Error loading data as of {}: {}", at, e);
+                    std::process::exit(1);
+                }),
+                None => load_data(storage.as_ref()),
+            };
 
             let group = app_data
                 .groups
                 .first()
                 .expect("No groups found. Create a group first.");
 
-            let settlements = calculate_settlements(group);
-            println!("Settlements for group '{}':", group.name);
-            if settlements.is_empty() {
+            let plan = settlement_plan(group);
+            println!(
+                "Settlements for group '{}' ({}):",
+                group.name, group.currency
+            );
+            if plan.settlements.is_empty() {
                 println!("  All settled up!");
             } else {
-                for settlement in settlements {
+                for settlement in plan.settlements {
+                    match settlement.status {
+                        SettlementStatus::Outstanding => println!(
+                            "  {} pays {} {}",
+                            settlement.from, settlement.to, settlement.amount
+                        ),
+                        SettlementStatus::PartiallyPaid => println!(
+                            "  {} pays {} {} ({} of {} already paid)",
+                            settlement.from,
+                            settlement.to,
+                            settlement.amount,
+                            settlement.paid,
+                            settlement.total
+                        ),
+                        SettlementStatus::Paid => println!(
+                            "  {} paid {} {} (settled)",
+                            settlement.from, settlement.to, settlement.paid
+                        ),
+                    }
+                }
+            }
+            for explanation in plan.explanations {
+                println!("  Note: {}", explanation);
+            }
+        }
+        Commands::SetRate {
+            from,
+            to,
+            rate,
+            data_file,
+        } => {
+            let storage = open_storage(&data_file, &key);
+            let mut app_data = load_data(storage.as_ref());
+
+            let (from, to) = match (
+                handlers::validate_currency(&from),
+                handlers::validate_currency(&to),
+            ) {
+                (Ok(from), Ok(to)) if from != to => (from, to),
+                _ => {
+                    eprintln!("Invalid currency pair {} -> {}", from, to);
+                    std::process::exit(1);
+                }
+            };
+            if handlers::validate_rate(rate).is_err() {
+                eprintln!("Exchange rate must be a positive number");
+                std::process::exit(1);
+            }
+
+            let entry = ExchangeRate {
+                from: from.clone(),
+                to: to.clone(),
+                rate,
+                updated_at: chrono::Utc::now().to_rfc3339(),
+            };
+            let Some(group) = app_data.groups.first_mut() else {
+                eprintln!("No groups found. Create a group first.");
+                std::process::exit(1);
+            };
+            set_exchange_rate(&mut group.exchange_rates, entry.clone());
+            let event = Event::ExchangeRateSet {
+                group_id: Some(group.id),
+                rate: entry,
+            };
+
+            let change = Change::Group(group);
+            if let Err(e) = storage.record(&[event], &[change]) {
+                eprintln!("Error saving data: {}", e);
+                std::process::exit(1);
+            }
+            println!("1 {} = {} {}", from, rate, to);
+        }
+        Commands::Migrate { data_file, dry_run } => {
+            let Some(path) = storage::json_file_path(&data_file) else {
+                eprintln!("Only JSON data files have a schema version to migrate");
+                std::process::exit(1);
+            };
+
+            match storage::JsonFileStorage::new(path)
+                .with_key(key)
+                .migrate(dry_run)
+            {
+                Ok(applied) if applied.is_empty() => println!(
+                    "{} is already at schema version {}",
+                    path,
+                    storage::migrations::CURRENT_VERSION
+                ),
+                Ok(applied) => {
+                    for step in &applied {
+                        println!("  {}", step);
+                    }
+                    if dry_run {
+                        println!("Dry run: {} left unchanged.", path);
+                    } else {
+                        println!(
+                            "Migrated {} to schema version {}.",
+                            path,
+                            storage::migrations::CURRENT_VERSION
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Migration failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Repair { data_file, dry_run } => {
+            let Some(path) = storage::json_file_path(&data_file) else {
+                eprintln!("Only JSON data files can be repaired");
+                std::process::exit(1);
+            };
+
+            match storage::JsonFileStorage::new(path)
+                .with_key(key)
+                .repair(dry_run)
+            {
+                Ok((source, data, dropped)) => {
                     println!(
-                        "  {} pays {} ${:.2}",
-                        settlement.from, settlement.to, settlement.amount
+                        "Recovered {} groups and {} users from {}.",
+                        data.groups.len(),
+                        data.users.len(),
+                        source
                     );
+                    for line in &dropped {
+                        println!("  dropped {}", line);
+                    }
+                    if dry_run {
+                        println!("Dry run: {} left unchanged.", path);
+                    } else {
+                        println!("Rewrote {}; the originals were copied aside.", path);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Repair failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Backup {
+            data_file,
+            backup_dir,
+            retain,
+        } => {
+            let storage = open_storage(&data_file, &key);
+            let app_data = load_data(storage.as_ref());
+            let backups = BackupDir::new(&backup_dir).with_key(key);
+
+            let result = backups.write(&app_data).and_then(|path| {
+                let removed = match retain {
+                    Some(retain) => backups.prune(retain)?,
+                    None => vec![],
+                };
+                Ok((path, removed))
+            });
+            match result {
+                Ok((path, removed)) => {
+                    println!("Backup written to {}", path.display());
+                    for path in removed {
+                        println!("  removed old backup {}", path.display());
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Backup failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Restore {
+            backup,
+            data_file,
+            backup_dir,
+        } => {
+            let restored = storage::backup::validate(std::path::Path::new(&backup), key.as_ref())
+                .unwrap_or_else(|e| {
+                    eprintln!("Not restoring {}: {}", backup, e);
+                    std::process::exit(1);
+                });
+            let storage = open_storage(&data_file, &key);
+
+            // Keep what is being replaced, unless there is nothing readable
+            match storage.load() {
+                Ok(current) => match BackupDir::new(&backup_dir)
+                    .with_key(key.clone())
+                    .write(&current)
+                {
+                    Ok(path) => println!("Current data backed up to {}", path.display()),
+                    Err(e) => {
+                        eprintln!("Could not back up the current data: {}", e);
+                        std::process::exit(1);
+                    }
+                },
+                Err(e) => println!("Current data is unreadable and will be replaced: {}", e),
+            }
+
+            if let Err(e) = storage.save(&restored) {
+                eprintln!("Restore failed: {}", e);
+                std::process::exit(1);
+            }
+            println!(
+                "Restored {} groups and {} users from {}.",
+                restored.groups.len(),
+                restored.users.len(),
+                backup
+            );
+        }
+        Commands::GenerateKey { output } => {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+            let result = options.open(&output).and_then(|mut file| {
+                use std::io::Write;
+                writeln!(file, "{}", DataKey::generate().encode())
+            });
+            if let Err(e) = result {
+                eprintln!("Could not write key file {}: {}", output, e);
+                std::process::exit(1);
+            }
+            println!(
+                "New data key written to {}. Keep a copy somewhere safe.",
+                output
+            );
+        }
+        Commands::Encrypt {
+            data_file,
+            backup_dir,
+        } => {
+            let Some(key) = key else {
+                eprintln!(
+                    "No data key: pass --key-file or set {}",
+                    storage::encryption::KEY_ENV_VAR
+                );
+                std::process::exit(1);
+            };
+
+            // Files already encrypted with this key are rewritten unchanged
+            match rekey(&data_file, backup_dir.as_deref(), &key, &key, true) {
+                Ok(files) if files.is_empty() => {
+                    eprintln!("{} not found", data_file);
+                    std::process::exit(1);
                 }
+                Ok(files) => {
+                    for file in files {
+                        println!("Encrypted {}", file);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Encryption failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::RotateKey {
+            new_key_file,
+            data_file,
+            backup_dir,
+        } => {
+            let Some(old_key) = key else {
+                eprintln!(
+                    "Pass the current key with --key-file or {}",
+                    storage::encryption::KEY_ENV_VAR
+                );
+                std::process::exit(1);
+            };
+            let new_key = DataKey::from_file(&new_key_file).unwrap_or_else(|e| {
+                eprintln!("Error loading new data key: {}", e);
+                std::process::exit(1);
+            });
+
+            match rekey(&data_file, backup_dir.as_deref(), &old_key, &new_key, false) {
+                Ok(files) => {
+                    for file in files {
+                        println!("Re-encrypted {}", file);
+                    }
+                    if backup_dir.is_none() {
+                        println!(
+                            "Existing timestamped backups still need the old key; \
+                             pass --backup-dir to re-encrypt them."
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Key rotation failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::ShowRates { data_file } => {
+            let storage = open_storage(&data_file, &key);
+            let app_data = load_data(storage.as_ref());
+
+            let Some(group) = app_data.groups.first() else {
+                println!("No groups found.");
+                return;
+            };
+            if group.exchange_rates.is_empty() {
+                println!("No exchange rates set.");
+            }
+            for r in &group.exchange_rates {
+                println!(
+                    "  1 {} = {} {} (updated {})",
+                    r.from, r.rate, r.to, r.updated_at
+                );
+            }
+        }
+        Commands::ApiKeys { command } => api_keys_command(command, &key),
+    }
+}
+
+fn api_keys_command(command: ApiKeyCommand, key: &Option<DataKey>) {
+    let (phone, data_file) = match &command {
+        ApiKeyCommand::Create {
+            phone, data_file, ..
+        }
+        | ApiKeyCommand::List { phone, data_file }
+        | ApiKeyCommand::Revoke {
+            phone, data_file, ..
+        } => (phone, data_file),
+    };
+    let storage = open_storage(data_file, key);
+    let mut app_data = load_data(storage.as_ref());
+    let phone = handlers::validate_phone(phone).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let Some(user) = app_data.users.iter_mut().find(|u| u.phone == phone) else {
+        eprintln!(
+            "No user with phone number {}",
+            handlers::masked_phone(&phone)
+        );
+        std::process::exit(1);
+    };
+
+    match command {
+        ApiKeyCommand::List { .. } => {
+            if user.api_keys.is_empty() {
+                println!("No API keys.");
+            }
+            for k in &user.api_keys {
+                println!(
+                    "  {}  {} ({}, created {}, last used {})",
+                    k.id,
+                    k.name,
+                    k.scope,
+                    k.created_at,
+                    k.last_used_at.as_deref().unwrap_or("never")
+                );
+            }
+            return;
+        }
+        ApiKeyCommand::Create { name, scope, .. } => {
+            match api_keys::add(user, &name, scope, chrono::Utc::now()) {
+                Ok((api_key, secret)) => {
+                    println!("Created API key {} ({})", api_key.name, api_key.id);
+                    println!("{}", secret);
+                    println!("Store it now; it cannot be shown again.");
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        ApiKeyCommand::Revoke { id, .. } => {
+            let Some(index) = user.api_keys.iter().position(|k| k.id == id) else {
+                eprintln!("API key {} not found", id);
+                std::process::exit(1);
+            };
+            let api_key = user.api_keys.remove(index);
+            println!("Revoked API key {} ({})", api_key.name, api_key.id);
+        }
+    }
+
+    let event = Event::UserUpdated { user: user.clone() };
+    let change = Change::Users(&app_data.users);
+    if let Err(e) = storage.record(&[event], &[change]) {
+        eprintln!("Error saving data: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Builds the expense described by `add-expense` arguments, checking them as
+/// the API would.
+fn cli_expense(
+    group: &Group,
+    description: String,
+    amount: Money,
+    payer: &[PayerArg],
+    participants: &str,
+) -> Result<Expense, String> {
+    if !amount.is_positive() {
+        return Err("Amount must be positive".to_string());
+    }
+
+    let find_member = |name: &str, role: &str| {
+        group
+            .members
+            .iter()
+            .find(|u| u.name.trim() == name.trim())
+            .cloned()
+            .ok_or_else(|| format!("{} '{}' not found", role, name.trim()))
+    };
+    let (payer_user, payers) = match payer {
+        [single] if single.amount.is_none() => (find_member(&single.name, "Payer")?, vec![]),
+        _ => {
+            let mut payers = Vec::new();
+            for p in payer {
+                let amount = p
+                    .amount
+                    .ok_or_else(|| format!("Each payer needs an amount, e.g. {}:30", p.name))?;
+                payers.push(Contribution {
+                    name: p.name.clone(),
+                    amount,
+                });
             }
+            let payer_user = resolve_payers(&group.members, &payers).map_err(|e| e.to_string())?;
+            validate_payers(&payers, amount).map_err(|e| e.to_string())?;
+            (payer_user, payers)
         }
+    };
+    let participant_users = participants
+        .split(',')
+        .map(|name| find_member(name, "Participant"))
+        .collect::<Result<Vec<User>, String>>()?;
+
+    Ok(Expense {
+        id: group.expenses.len() + 1,
+        description,
+        amount,
+        currency: None,
+        exchange_rate: None,
+        payer: payer_user,
+        payers,
+        participants: participant_users,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        category: None,
+        notes: None,
+        split: Split::Equal,
+        itemization: None,
+        shares: vec![],
+    })
+}
+
+/// Rewrites the data stored at `spec`, and the backups in `backup_dir`,
+/// under `new_key` after reading them all with `key`.
+fn rekey(
+    spec: &str,
+    backup_dir: Option<&str>,
+    key: &DataKey,
+    new_key: &DataKey,
+    allow_plaintext: bool,
+) -> Result<Vec<String>, AppError> {
+    let mut paths = storage::data_files(spec)?;
+    if let Some(dir) = backup_dir {
+        paths.extend(BackupDir::new(dir).files()?);
     }
+    storage::rekey_files(&paths, Some(key), Some(new_key), allow_plaintext)
+}
+
+fn open_storage(spec: &str, key: &Option<DataKey>) -> Box<dyn Storage> {
+    storage::open(spec, key.clone()).unwrap_or_else(|e| {
+        eprintln!("Error opening storage '{}': {}", spec, e);
+        std::process::exit(1);
+    })
+}
+
+fn load_data(storage: &dyn Storage) -> AppData {
+    storage.load().unwrap_or_else(|e| {
+        eprintln!("Error loading data: {}", e);
+        std::process::exit(1);
+    })
 }
 
 async fn health_check() -> Json<serde_json::Value> {
     Json(serde_json::json!({ "status": "ok" }))
 }
 
-async fn run_server(port: u16, data_file: &str) {
+fn load_config(settings: &ServeArgs) -> (Config, Option<PathBuf>) {
+    Config::load(settings).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    })
+}
+
+async fn run_server(config: Config, config_path: Option<PathBuf>, key: Option<DataKey>) {
     // Initialize logging
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "splitdumb=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let filter = tracing_subscriber::EnvFilter::new(&config.log.filter);
+    let logs = tracing_subscriber::registry().with(filter);
+    match config.log.format {
+        LogFormat::Text => logs.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Compact => logs.with(tracing_subscriber::fmt::layer().compact()).init(),
+        LogFormat::Json => logs.with(tracing_subscriber::fmt::layer().json()).init(),
+    }
+    if let Some(path) = &config_path {
+        tracing::info!(path = %path.display(), "loaded config file");
+    }
+
+    let storage_spec = config.storage.backend.as_str();
+    let durability = config.storage.durability;
+    let batch_window = config.storage.batch_window.0;
+    let backups = config.backups.policy(key.clone());
 
     // Initialize storage
-    storage::init(data_file);
-    tracing::info!(data_file, "initializing storage");
-    let app_data = storage::load();
+    tracing::info!(storage = storage_spec, "initializing storage");
+    if key.is_some() {
+        tracing::info!("data is encrypted at rest");
+    }
+    let mut storage = open_storage(storage_spec, &key);
+    if let Some(policy) = backups {
+        tracing::info!(dir = %policy.dir.display(), interval = ?policy.interval, retain = policy.retain, "rotating backups enabled");
+        storage = match RotatingBackups::new(storage, policy) {
+            Ok(storage) => Box::new(storage),
+            Err(e) => {
+                eprintln!("Error opening backup directory: {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+    let mut app_data = match storage.load() {
+        Ok(app_data) => app_data,
+        Err(e) if config.storage.allow_empty => {
+            tracing::error!(error = %e, "could not load data; starting empty (--allow-empty)");
+            if let Some(path) = storage::json_file_path(storage_spec) {
+                match storage::JsonFileStorage::new(path).preserve_files() {
+                    Ok(copies) => tracing::warn!(?copies, "unreadable data files preserved"),
+                    Err(e) => {
+                        eprintln!("Could not preserve unreadable data files: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            AppData {
+                groups: vec![],
+                users: vec![],
+            }
+        }
+        Err(e) => {
+            eprintln!("Error loading data: {}", e);
+            eprintln!(
+                "Refusing to start. Run `splitdumb repair` to salvage the data file, \
+                 or pass --allow-empty to start with no data."
+            );
+            std::process::exit(1);
+        }
+    };
+    logic::refresh_shares(&mut app_data);
     tracing::info!(
         groups = app_data.groups.len(),
         users = app_data.users.len(),
         "loaded data"
     );
-    let shared_state = Arc::new(RwLock::new(app_data));
+    tracing::info!(?durability, ?batch_window, "starting storage writer");
+    let writer = StorageWriter::spawn(storage, durability, batch_window);
+    let sms_spec = config
+        .auth
+        .sms
+        .as_deref()
+        .expect("validated with the config");
+    let sms = sms::open(sms_spec).expect("validated with the config");
+    if sms_spec == "stdout" {
+        tracing::warn!("sign-in codes are printed to stdout; use this for local development only");
+    } else {
+        tracing::info!(sender = sms_spec, "sending sign-in codes");
+    }
+    let shared_state = Arc::new(
+        AppState::new(app_data, writer)
+            .with_auth(config.auth.clone())
+            .with_sms(sms),
+    );
+    let cors = config.cors_layer().expect("validated with the config");
 
     let app = Router::new()
         // Health check
         .route("/api/health", get(health_check))
         // Auth routes
+        .route("/api/auth/code", post(auth::request_code))
         .route("/api/auth/register", post(auth::register))
         .route("/api/auth/login", post(auth::login))
+        .route("/api/auth/refresh", post(auth::refresh))
         .route("/api/auth/me", get(auth::get_me))
+        .route("/api/auth/logout", post(auth::logout))
+        .route("/api/auth/logout-everywhere", post(auth::logout_everywhere))
+        .route("/api/auth/sessions", get(auth::list_sessions))
+        .route("/api/auth/sessions/{id}", delete(auth::revoke_session))
+        .route(
+            "/api/auth/api-keys",
+            get(auth::list_api_keys).post(auth::create_api_key),
+        )
+        .route("/api/auth/api-keys/{id}", delete(auth::revoke_api_key))
         // Group routes
         .route(
             "/api/groups",
@@ -173,6 +762,9 @@ async fn run_server(port: u16, data_file: &str) {
             put(expenses::update_expense).delete(expenses::delete_expense),
         )
         .route("/api/settle", post(expenses::settle))
+        // Exchange rate routes
+        .route("/api/rates", get(rates::list_rates).put(rates::set_rate))
+        .route("/api/rates/{from}/{to}", delete(rates::delete_rate))
         // User routes
         .route("/api/users", post(users::create_user))
         .route("/api/users/{id}", delete(users::delete_user))
@@ -180,13 +772,57 @@ async fn run_server(port: u16, data_file: &str) {
         .route("/api/balances", get(groups::get_balances))
         .route("/api/settlements", get(groups::get_settlements))
         .route("/api/simplify", post(groups::toggle_simplify))
+        .route(
+            "/api/settlement-options",
+            put(groups::update_settlement_options),
+        )
         // Middleware
         .layer(TraceLayer::new_for_http())
-        .layer(CorsLayer::permissive())
-        .with_state(shared_state);
+        .layer(cors)
+        .with_state(shared_state.clone());
+    let app = match RateLimiter::new(&config.rate_limit) {
+        Some(limiter) => {
+            tracing::info!(
+                requests_per_minute = config.rate_limit.requests_per_minute,
+                burst = config.rate_limit.burst,
+                "rate limiting enabled"
+            );
+            app.layer(middleware::from_fn_with_state(limiter, rate_limit::limit))
+        }
+        None => app,
+    };
 
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    let addr = config.server.bind;
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
     println!("Server listening on http://{}", addr);
-    axum::serve(listener, app).await.unwrap();
+    let shutdown_timeout = config.server.shutdown_timeout.0;
+    let summary = shutdown::serve(listener, app, shutdown::signal(), shutdown_timeout)
+        .await
+        .unwrap();
+
+    // Everything queued must be written before the process exits
+    let stored = match shared_state.storage.flush().await {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::error!(error = %e, "final write failed");
+            false
+        }
+    };
+    tracing::info!(
+        reason = summary.reason,
+        served = summary.served,
+        abandoned = summary.abandoned,
+        drain_time = ?summary.drain_time,
+        stored,
+        "server stopped"
+    );
+    if !stored {
+        std::process::exit(1);
+    }
 }
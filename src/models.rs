@@ -1,15 +1,133 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+use crate::money::Money;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AuthUser {
     pub id: usize,
     pub phone: String,
     pub name: String,
-    pub token: String,
     #[serde(default)]
     pub current_group_id: usize,
+    /// Devices the user is signed in on.
+    #[serde(default)]
+    pub sessions: Vec<Session>,
+    /// Keys the user's scripts authenticate with.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+}
+
+/// Leaves out the phone number, sessions and keys, so a user can be logged.
+impl fmt::Debug for AuthUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthUser")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("current_group_id", &self.current_group_id)
+            .field("sessions", &self.sessions.len())
+            .field("api_keys", &self.api_keys.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// What a user is shown about their own account. Unlike `AuthUser` it has
+/// no phone number, sessions or keys.
+#[derive(Clone, Debug, Serialize)]
+pub struct PublicUser {
+    pub id: usize,
+    pub name: String,
+    pub current_group_id: usize,
+}
+
+impl From<&AuthUser> for PublicUser {
+    fn from(user: &AuthUser) -> Self {
+        PublicUser {
+            id: user.id,
+            name: user.name.clone(),
+            current_group_id: user.current_group_id,
+        }
+    }
+}
+
+/// A signed-in device. Only hashes of its tokens are kept.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    /// Hash of the current access token.
+    pub token_hash: String,
+    pub device: String,
+    pub created_at: String,
+    pub last_used_at: String,
+    /// When the session ends unless it is refreshed first.
+    pub expires_at: String,
+    /// When the access token stops working. Sessions started before
+    /// refresh tokens have none; their token lasts as long as the session.
+    #[serde(default)]
+    pub access_expires_at: Option<String>,
+    /// Hash of the refresh token that may be swapped for new tokens.
+    #[serde(default)]
+    pub refresh_hash: Option<String>,
+    /// Hashes of refresh tokens already swapped, oldest first. One of them
+    /// turning up again means it was copied.
     #[serde(default)]
-    pub token_expires_at: Option<String>,
+    pub used_refresh_hashes: Vec<String>,
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("id", &self.id)
+            .field("device", &self.device)
+            .field("expires_at", &self.expires_at)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A personal API key, for scripts and integrations. Only a hash of the key
+/// is kept.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub key_hash: String,
+    pub scope: ApiKeyScope,
+    pub created_at: String,
+    #[serde(default)]
+    pub last_used_at: Option<String>,
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("scope", &self.scope)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What an API key may be used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiKeyScope {
+    /// Read anything the user can see.
+    ReadOnly,
+    /// Read, and add, change or settle expenses.
+    WriteExpenses,
+    /// Anything the user can do.
+    Admin,
+}
+
+impl fmt::Display for ApiKeyScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ApiKeyScope::ReadOnly => "read-only",
+            ApiKeyScope::WriteExpenses => "write-expenses",
+            ApiKeyScope::Admin => "admin",
+        })
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -30,24 +148,109 @@ pub struct Group {
     pub expenses: Vec<Expense>,
     #[serde(default)]
     pub simplify_debts: bool,
+    /// How debts are simplified when `simplify_debts` is on.
+    #[serde(default)]
+    pub settlement_algorithm: SettlementAlgorithm,
+    /// Largest number of members with a non-zero balance the optimal
+    /// algorithm is used for before falling back to greedy matching.
+    #[serde(default = "default_optimal_member_limit")]
+    pub optimal_member_limit: usize,
+    #[serde(default)]
+    pub settlement_constraints: SettlementConstraints,
     #[serde(default)]
     pub settled_settlements: Vec<SettledSettlement>,
+    /// ISO 4217 code that balances and settlements are expressed in.
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    /// Rates for converting other currencies, set by the group's members.
+    #[serde(default)]
+    pub exchange_rates: Vec<ExchangeRate>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettlementAlgorithm {
+    /// Match the largest debtor with the largest creditor.
+    #[default]
+    Greedy,
+    /// Search for the fewest possible payments.
+    Optimal,
+}
+
+/// Limits on who can pay whom when suggesting settlements. Names refer to
+/// group members.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SettlementConstraints {
+    /// Pairs who cannot pay each other directly, in either direction.
+    #[serde(default)]
+    pub forbidden_pairs: Vec<(String, String)>,
+    /// Members who should be paid before anyone else, in order of preference.
+    #[serde(default)]
+    pub preferred_receivers: Vec<String>,
+    /// A treasurer that every payment is routed through.
+    #[serde(default)]
+    pub hub: Option<String>,
+}
+
+impl SettlementConstraints {
+    pub fn is_empty(&self) -> bool {
+        self.forbidden_pairs.is_empty() && self.preferred_receivers.is_empty() && self.hub.is_none()
+    }
+
+    /// Forgets a member who left the group, so no payment is routed to or
+    /// through them.
+    pub fn remove_member(&mut self, name: &str) {
+        self.forbidden_pairs.retain(|(a, b)| a != name && b != name);
+        self.preferred_receivers.retain(|p| p != name);
+        if self.hub.as_deref() == Some(name) {
+            self.hub = None;
+        }
+    }
+
+    pub fn allows(&self, from: &str, to: &str) -> bool {
+        !self
+            .forbidden_pairs
+            .iter()
+            .any(|(a, b)| (a == from && b == to) || (a == to && b == from))
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SettledSettlement {
     pub from: String,
     pub to: String,
-    pub amount: f64,
+    /// Amount in the group's currency.
+    pub amount: Money,
     pub settled_at: String,
+    /// The settlement expense this payment was recorded as.
+    #[serde(default)]
+    pub expense_id: Option<usize>,
+    /// What was actually handed over when paying in another currency.
+    #[serde(default)]
+    pub original_amount: Option<Money>,
+    #[serde(default)]
+    pub original_currency: Option<String>,
+    #[serde(default)]
+    pub exchange_rate: Option<f64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Expense {
     pub id: usize,
     pub description: String,
-    pub amount: f64,
+    pub amount: Money,
+    /// ISO 4217 code of `amount`; `None` means the group's currency.
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// Units of group currency per unit of `currency`, fixed when the expense
+    /// was recorded. Only set for foreign-currency expenses.
+    #[serde(default)]
+    pub exchange_rate: Option<f64>,
+    /// The primary payer. When `payers` is empty they paid the whole amount.
     pub payer: User,
+    /// Who paid how much when several people paid. Adds up to `amount`.
+    #[serde(default)]
+    pub payers: Vec<Contribution>,
     pub participants: Vec<User>,
     #[serde(default = "default_timestamp")]
     pub created_at: String,
@@ -55,8 +258,74 @@ pub struct Expense {
     pub category: Option<String>,
     #[serde(default)]
     pub notes: Option<String>,
+    #[serde(default)]
+    pub split: Split,
+    /// Line items that replace `split` when present.
+    #[serde(default)]
+    pub itemization: Option<Itemization>,
+    /// What each participant owes, filled in by `logic::expense_shares`.
+    #[serde(default)]
+    pub shares: Vec<ParticipantShare>,
+}
+
+/// How an expense is divided between its participants. Maps are keyed by
+/// participant name and must cover exactly the expense's participants.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Split {
+    #[default]
+    Equal,
+    /// Fixed amounts that add up to the expense amount.
+    Exact { amounts: BTreeMap<String, Money> },
+    /// Percentages (up to two decimals) that add up to 100.
+    Percentage { percentages: BTreeMap<String, f64> },
+    /// Whole-number weights, e.g. adult = 2, child = 1.
+    Shares { shares: BTreeMap<String, u64> },
 }
 
+/// A receipt broken down into line items. Tax and tip are shared in
+/// proportion to what each participant ordered.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Itemization {
+    pub items: Vec<LineItem>,
+    #[serde(default)]
+    pub tax: Money,
+    #[serde(default)]
+    pub tip: Money,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LineItem {
+    pub description: String,
+    pub amount: Money,
+    /// Names of the participants sharing this item equally.
+    pub participants: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Contribution {
+    pub name: String,
+    pub amount: Money,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ParticipantShare {
+    pub name: String,
+    pub amount: Money,
+}
+
+/// An entry in the locally maintained rate table: one unit of `from` is
+/// worth `rate` units of `to`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExchangeRate {
+    pub from: String,
+    pub to: String,
+    pub rate: f64,
+    pub updated_at: String,
+}
+
+/// Everything the app stores. Changes to the stored format need a new step in
+/// `storage::migrations` so existing data files keep loading.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AppData {
     pub groups: Vec<Group>,
@@ -67,3 +336,11 @@ pub struct AppData {
 fn default_timestamp() -> String {
     chrono::Utc::now().to_rfc3339()
 }
+
+pub fn default_optimal_member_limit() -> usize {
+    16
+}
+
+pub fn default_currency() -> String {
+    "USD".to_string()
+}
@@ -0,0 +1,266 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+/// An exact monetary amount stored as an integer number of cents.
+///
+/// Serializes as a JSON number with two decimals (e.g. `12.5` for 1250 cents)
+/// so existing clients keep working, and deserializes from numbers or decimal
+/// strings. Legacy float values are rounded to the nearest cent, and amounts
+/// beyond `Money::MAX` are rejected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    /// Largest amount accepted from clients and data files: a trillion whole
+    /// units. Groups are also kept within it in total, which leaves every
+    /// balance and payment worked out from them far inside `i64`.
+    pub const MAX: Money = Money(100_000_000_000_000);
+
+    pub const fn from_cents(cents: i64) -> Self {
+        Money(cents)
+    }
+
+    pub const fn cents(self) -> i64 {
+        self.0
+    }
+
+    /// Converts a float amount, rounding half away from zero to the nearest
+    /// cent. Returns `None` for amounts that are not finite or are larger
+    /// than `MAX` either way.
+    pub fn from_f64(amount: f64) -> Option<Self> {
+        let cents = (amount * 100.0).round();
+        if !cents.is_finite() || cents.abs() > Money::MAX.0 as f64 {
+            return None;
+        }
+        Some(Money(cents as i64))
+    }
+
+    /// The amount itself if it is within `MAX` either way.
+    pub fn in_range(self) -> Option<Self> {
+        (self.0.unsigned_abs() <= Money::MAX.0 as u64).then_some(self)
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / 100.0
+    }
+
+    /// Converts into another currency at `rate`, rounding to the nearest cent.
+    pub fn convert(self, rate: f64) -> Money {
+        Money((self.0 as f64 * rate).round() as i64)
+    }
+
+    pub fn checked_add(self, rhs: Money) -> Option<Money> {
+        self.0.checked_add(rhs.0).map(Money)
+    }
+
+    /// Adds up `amounts`, returning `None` instead of overflowing.
+    pub fn checked_sum(amounts: impl IntoIterator<Item = Money>) -> Option<Money> {
+        amounts
+            .into_iter()
+            .try_fold(Money::ZERO, |total, amount| total.checked_add(amount))
+    }
+
+    pub fn abs(self) -> Self {
+        Money(self.0.abs())
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Splits the amount in proportion to `weights`, returning one part per
+    /// weight that together add back up to exactly `self`.
+    ///
+    /// Each part is first rounded down; the leftover cents then go one each to
+    /// the parts with the largest discarded fraction, ties going to the earlier
+    /// weight. Callers control who receives leftovers by ordering `weights`.
+    /// Returns all zeros if the weights sum to zero.
+    pub fn allocate(self, weights: &[u64]) -> Vec<Money> {
+        let total_weight: i128 = weights.iter().map(|&w| i128::from(w)).sum();
+        if total_weight == 0 {
+            return vec![Money::ZERO; weights.len()];
+        }
+
+        let amount = i128::from(self.0);
+        let mut parts = Vec::with_capacity(weights.len());
+        let mut fractions = Vec::with_capacity(weights.len());
+        for &weight in weights {
+            let scaled = amount * i128::from(weight);
+            parts.push(scaled.div_euclid(total_weight) as i64);
+            fractions.push(scaled.rem_euclid(total_weight));
+        }
+
+        let leftover = self.0 - parts.iter().sum::<i64>();
+        let mut order: Vec<usize> = (0..weights.len()).collect();
+        order.sort_by(|&a, &b| fractions[b].cmp(&fractions[a]).then(a.cmp(&b)));
+        for &i in order.iter().take(leftover as usize) {
+            parts[i] += 1;
+        }
+
+        parts.into_iter().map(Money).collect()
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, rhs: Money) -> Money {
+        Money(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, rhs: Money) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, rhs: Money) -> Money {
+        Money(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, rhs: Money) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+
+impl<'a> Sum<&'a Money> for Money {
+    fn sum<I: Iterator<Item = &'a Money>>(iter: I) -> Money {
+        iter.copied().sum()
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        write!(f, "{}{}.{:02}", sign, abs / 100, abs % 100)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseMoneyError(String);
+
+impl fmt::Display for ParseMoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid amount '{}'", self.0)
+    }
+}
+
+impl std::error::Error for ParseMoneyError {}
+
+impl FromStr for Money {
+    type Err = ParseMoneyError;
+
+    /// Parses a plain decimal such as `12`, `12.5`, `-0.05`. More than two
+    /// decimal places is rejected rather than silently rounded, as are
+    /// amounts beyond `Money::MAX`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseMoneyError(s.to_string());
+        let trimmed = s.trim();
+        let (negative, digits) = match trimmed.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, trimmed),
+        };
+        let (whole, frac) = digits.split_once('.').unwrap_or((digits, ""));
+        if whole.is_empty() && frac.is_empty() {
+            return Err(err());
+        }
+        if frac.len() > 2
+            || !whole.chars().all(|c| c.is_ascii_digit())
+            || !frac.chars().all(|c| c.is_ascii_digit())
+        {
+            return Err(err());
+        }
+        let whole: i64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| err())?
+        };
+        let frac: i64 = format!("{:0<2}", frac).parse().map_err(|_| err())?;
+        let cents = whole
+            .checked_mul(100)
+            .and_then(|c| c.checked_add(frac))
+            .ok_or_else(err)?;
+        Money(if negative { -cents } else { cents })
+            .in_range()
+            .ok_or_else(err)
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.to_f64())
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MoneyVisitor;
+
+        impl de::Visitor<'_> for MoneyVisitor {
+            type Value = Money;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number or decimal string")
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Money, E> {
+                if !v.is_finite() {
+                    return Err(E::custom("amount must be finite"));
+                }
+                Money::from_f64(v).ok_or_else(|| E::custom("amount out of range"))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Money, E> {
+                v.checked_mul(100)
+                    .and_then(|cents| Money(cents).in_range())
+                    .ok_or_else(|| E::custom("amount out of range"))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Money, E> {
+                i64::try_from(v)
+                    .map_err(|_| E::custom("amount out of range"))
+                    .and_then(|v| self.visit_i64(v))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Money, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(MoneyVisitor)
+    }
+}
@@ -0,0 +1,173 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::config::AuthConfig;
+use crate::errors::AppError;
+
+/// Digits in a sign-in code.
+pub const CODE_DIGITS: usize = 6;
+
+/// How long a phone has to wait before another code is sent to it.
+const RESEND_AFTER: Duration = Duration::from_secs(30);
+
+/// How long a phone gets no new codes after one is used up by wrong
+/// guesses. Each further lockout doubles it, up to `MAX_LOCKOUT`.
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+const MAX_LOCKOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// One-time codes sent to phones to prove the person signing in owns the
+/// number. Only a salted hash of each code is kept, in memory; a code is
+/// good for one use, expires after the configured lifetime and is dropped
+/// after too many wrong guesses, which also locks the phone out of new codes
+/// for a while.
+pub struct Codes {
+    lifetime: Duration,
+    max_attempts: u32,
+    pending: Mutex<HashMap<String, Challenge>>,
+    lockouts: Mutex<HashMap<String, Lockout>>,
+}
+
+struct Challenge {
+    salt: [u8; 16],
+    hash: [u8; 32],
+    sent_at: Instant,
+    attempts: u32,
+}
+
+/// Kept apart from the challenge so that dropping a used-up code does not
+/// end the lockout.
+struct Lockout {
+    locked_at: Instant,
+    /// Lockouts in a row, each twice as long as the one before.
+    count: u32,
+}
+
+impl Lockout {
+    fn duration(&self) -> Duration {
+        LOCKOUT
+            .saturating_mul(2u32.saturating_pow(self.count - 1))
+            .min(MAX_LOCKOUT)
+    }
+
+    /// Time left before `now`, if the lockout has not ended.
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.duration()
+            .checked_sub(now.duration_since(self.locked_at))
+            .filter(|left| !left.is_zero())
+    }
+}
+
+fn hash(salt: &[u8; 16], code: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(code.as_bytes());
+    hasher.finalize().into()
+}
+
+impl Codes {
+    pub fn new(config: &AuthConfig) -> Self {
+        Codes {
+            lifetime: config.code_lifetime.0,
+            max_attempts: config.code_attempts,
+            pending: Mutex::new(HashMap::new()),
+            lockouts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long a new code stays valid.
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Creates a code for `phone`, replacing any earlier one, and returns it
+    /// so it can be sent.
+    pub fn issue(&self, phone: &str, now: Instant) -> Result<String, AppError> {
+        let mut pending = self.pending.lock().map_err(|_| AppError::LockError)?;
+        let mut lockouts = self.lockouts.lock().map_err(|_| AppError::LockError)?;
+        pending.retain(|_, challenge| now.duration_since(challenge.sent_at) < self.lifetime);
+        // Ended lockouts are remembered for a while so repeats escalate
+        lockouts.retain(|_, lockout| now.duration_since(lockout.locked_at) < MAX_LOCKOUT * 2);
+        if let Some(left) = lockouts.get(phone).and_then(|l| l.remaining(now)) {
+            return Err(AppError::RateLimited(left.as_secs().max(1)));
+        }
+        if let Some(challenge) = pending.get(phone) {
+            let since = now.duration_since(challenge.sent_at);
+            if since < RESEND_AFTER {
+                return Err(AppError::RateLimited(
+                    (RESEND_AFTER - since).as_secs().max(1),
+                ));
+            }
+        }
+
+        let random = Uuid::new_v4().as_u128();
+        let code = format!(
+            "{:0width$}",
+            random % 10u128.pow(CODE_DIGITS as u32),
+            width = CODE_DIGITS
+        );
+        let salt = Uuid::new_v4().into_bytes();
+        pending.insert(
+            phone.to_string(),
+            Challenge {
+                salt,
+                hash: hash(&salt, &code),
+                sent_at: now,
+                attempts: 0,
+            },
+        );
+        Ok(code)
+    }
+
+    /// Forgets the code for `phone`, e.g. because it could not be sent.
+    pub fn revoke(&self, phone: &str) -> Result<(), AppError> {
+        let mut pending = self.pending.lock().map_err(|_| AppError::LockError)?;
+        pending.remove(phone);
+        Ok(())
+    }
+
+    /// Checks `code` against the one sent to `phone`, using it up if it
+    /// matches.
+    pub fn verify(&self, phone: &str, code: &str, now: Instant) -> Result<(), AppError> {
+        let mut pending = self.pending.lock().map_err(|_| AppError::LockError)?;
+        let Some(challenge) = pending.get_mut(phone) else {
+            return Err(AppError::Unauthorized(
+                "No code was requested for this phone number, or it has expired".to_string(),
+            ));
+        };
+        if now.duration_since(challenge.sent_at) >= self.lifetime {
+            pending.remove(phone);
+            return Err(AppError::Unauthorized(
+                "Code has expired; request a new one".to_string(),
+            ));
+        }
+        if bool::from(hash(&challenge.salt, code.trim()).ct_eq(&challenge.hash)) {
+            pending.remove(phone);
+            self.lockouts
+                .lock()
+                .map_err(|_| AppError::LockError)?
+                .remove(phone);
+            return Ok(());
+        }
+        challenge.attempts += 1;
+        if challenge.attempts >= self.max_attempts {
+            pending.remove(phone);
+            let mut lockouts = self.lockouts.lock().map_err(|_| AppError::LockError)?;
+            let count = lockouts.get(phone).map_or(0, |l| l.count) + 1;
+            let lockout = Lockout {
+                locked_at: now,
+                count,
+            };
+            let minutes = lockout.duration().as_secs() / 60;
+            lockouts.insert(phone.to_string(), lockout);
+            return Err(AppError::Unauthorized(format!(
+                "Too many wrong codes; request a new one in {} minutes",
+                minutes
+            )));
+        }
+        Err(AppError::Unauthorized("Wrong code".to_string()))
+    }
+}
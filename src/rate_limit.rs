@@ -0,0 +1,135 @@
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{IpRange, RateLimitConfig};
+use crate::errors::AppError;
+
+/// Clients tracked before idle ones are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Limits how often each client address may call the API. Every client has
+/// a bucket holding up to `burst` requests that refills at the configured
+/// rate; a request is turned away when the bucket is empty.
+///
+/// Requests arriving through a trusted proxy are counted against the client
+/// the proxy forwarded them for.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    trusted_proxies: Vec<IpRange>,
+    clients: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// A limiter for `config`, or `None` when rate limiting is off.
+    pub fn new(config: &RateLimitConfig) -> Option<Arc<Self>> {
+        (config.requests_per_minute > 0).then(|| {
+            Arc::new(RateLimiter {
+                per_second: f64::from(config.requests_per_minute) / 60.0,
+                burst: f64::from(config.burst.max(1)),
+                trusted_proxies: config.trusted_proxies.clone(),
+                clients: Mutex::new(HashMap::new()),
+            })
+        })
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|proxy| proxy.contains(addr))
+    }
+
+    /// The client a request from `peer` was made for. Each proxy appends the
+    /// address it received the request from to `X-Forwarded-For`, so the
+    /// hops are walked back from the end while they are trusted proxies;
+    /// anything further left may have been made up by the client. A proxy
+    /// that only sets `X-Real-IP` is taken at its word.
+    pub fn client(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let hops: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        if hops.is_empty() {
+            return headers
+                .get("x-real-ip")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(peer);
+        }
+
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            let Ok(addr) = hop.trim().parse() else {
+                break;
+            };
+            client = addr;
+            if !self.is_trusted(addr) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// Takes a request from `client`'s bucket, or says how long until one
+    /// is available.
+    pub fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        if clients.len() >= MAX_TRACKED_CLIENTS {
+            // A full bucket is the same as no bucket
+            clients.retain(|_, bucket| self.refilled(bucket, now) < self.burst);
+        }
+        let bucket = clients.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_second).min(self.burst)
+    }
+}
+
+/// Middleware answering 429 Too Many Requests once a client is over its
+/// limit.
+pub async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = limiter.client(addr.ip(), request.headers());
+    match limiter.check(client, Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            tracing::warn!(%client, path = %request.uri().path(), "rate limited");
+            AppError::RateLimited(wait.as_secs_f64().ceil().max(1.0) as u64).into_response()
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::config::AuthConfig;
+use crate::models::{AuthUser, Session};
+
+/// Longest device label kept for a session.
+const MAX_DEVICE_LEN: usize = 100;
+
+/// Used refresh tokens remembered per session to catch one being replayed.
+const MAX_USED_REFRESH: usize = 32;
+
+/// Tokens handed to a client when a session starts or is refreshed. The
+/// server keeps only their hashes.
+pub struct Tokens {
+    pub access: String,
+    pub refresh: String,
+}
+
+impl Tokens {
+    fn new() -> Self {
+        Tokens {
+            access: Uuid::new_v4().to_string(),
+            refresh: Uuid::new_v4().to_string(),
+        }
+    }
+}
+
+/// How a refresh token matched a session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refresh {
+    /// It is the session's current refresh token.
+    Current,
+    /// It was already swapped for new tokens, so someone kept a copy.
+    Replayed,
+}
+
+/// Hex-encoded SHA-256 of a bearer token, as stored in a session.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The user and session that `token` belongs to. Every stored hash is
+/// compared in full, in constant time, so how long a lookup takes says
+/// nothing about how close a guessed token was.
+pub fn find<'a>(users: &'a [AuthUser], token: &str) -> Option<(&'a AuthUser, &'a Session)> {
+    let hash = hash_token(token);
+    let mut found = None;
+    for user in users {
+        for session in &user.sessions {
+            if bool::from(session.token_hash.as_bytes().ct_eq(hash.as_bytes())) {
+                found = Some((user, session));
+            }
+        }
+    }
+    found
+}
+
+/// Starts a session on `device`, returning it with its tokens.
+pub fn start(device: &str, auth: &AuthConfig, now: DateTime<Utc>) -> (Session, Tokens) {
+    let tokens = Tokens::new();
+    let device = device.trim();
+    let device = match device.char_indices().nth(MAX_DEVICE_LEN) {
+        Some((end, _)) => &device[..end],
+        None => device,
+    };
+    let session = Session {
+        id: Uuid::new_v4().to_string(),
+        token_hash: hash_token(&tokens.access),
+        device: if device.is_empty() {
+            "Unknown device".to_string()
+        } else {
+            device.to_string()
+        },
+        created_at: now.to_rfc3339(),
+        last_used_at: now.to_rfc3339(),
+        expires_at: (now + auth.token_lifetime()).to_rfc3339(),
+        access_expires_at: Some((now + auth.access_token_lifetime()).to_rfc3339()),
+        refresh_hash: Some(hash_token(&tokens.refresh)),
+        used_refresh_hashes: Vec::new(),
+    };
+    (session, tokens)
+}
+
+/// The user and session that refresh token `token` was issued to, and
+/// whether it is still the current one. Like `find`, every stored hash is
+/// compared.
+pub fn find_refresh<'a>(
+    users: &'a [AuthUser],
+    token: &str,
+) -> Option<(&'a AuthUser, &'a Session, Refresh)> {
+    let hash = hash_token(token);
+    let matches = |stored: &String| bool::from(stored.as_bytes().ct_eq(hash.as_bytes()));
+    let mut found = None;
+    for user in users {
+        for session in &user.sessions {
+            if session.refresh_hash.iter().any(matches) {
+                found = Some((user, session, Refresh::Current));
+            }
+            if session.used_refresh_hashes.iter().any(matches) {
+                found = Some((user, session, Refresh::Replayed));
+            }
+        }
+    }
+    found
+}
+
+/// Swaps the session's tokens for new ones and starts its lifetime over.
+/// The old refresh token is remembered so a replay of it can be caught.
+pub fn rotate(session: &mut Session, auth: &AuthConfig, now: DateTime<Utc>) -> Tokens {
+    let tokens = Tokens::new();
+    if let Some(used) = session.refresh_hash.take() {
+        session.used_refresh_hashes.push(used);
+    }
+    let excess = session
+        .used_refresh_hashes
+        .len()
+        .saturating_sub(MAX_USED_REFRESH);
+    session.used_refresh_hashes.drain(..excess);
+
+    session.token_hash = hash_token(&tokens.access);
+    session.refresh_hash = Some(hash_token(&tokens.refresh));
+    session.last_used_at = now.to_rfc3339();
+    session.expires_at = (now + auth.token_lifetime()).to_rfc3339();
+    session.access_expires_at = Some((now + auth.access_token_lifetime()).to_rfc3339());
+    tokens
+}
+
+/// The session standing in for a token issued before users could have
+/// several. One that never expired gets the default 30 days from `now`.
+pub fn carried_over(token: &str, expires_at: Option<&str>, now: DateTime<Utc>) -> Session {
+    Session {
+        id: Uuid::new_v4().to_string(),
+        token_hash: hash_token(token),
+        device: "Signed in before sessions".to_string(),
+        created_at: now.to_rfc3339(),
+        last_used_at: now.to_rfc3339(),
+        expires_at: expires_at
+            .map(str::to_string)
+            .unwrap_or_else(|| (now + Duration::days(30)).to_rfc3339()),
+        access_expires_at: None,
+        refresh_hash: None,
+        used_refresh_hashes: Vec::new(),
+    }
+}
+
+/// Whether `session` has expired. Unreadable expiry times count as expired.
+pub fn is_expired(session: &Session, now: DateTime<Utc>) -> bool {
+    DateTime::parse_from_rfc3339(&session.expires_at).map_or(true, |expires| now >= expires)
+}
+
+/// Whether the session's access token no longer works, either because the
+/// session has expired or the token needs refreshing.
+pub fn access_expired(session: &Session, now: DateTime<Utc>) -> bool {
+    is_expired(session, now)
+        || session.access_expires_at.as_deref().is_some_and(|expires| {
+            DateTime::parse_from_rfc3339(expires).map_or(true, |expires| now >= expires)
+        })
+}
@@ -0,0 +1,155 @@
+use axum::Router;
+use axum::extract::{Request, State};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+/// Counts requests as they pass through the router, so shutdown can report
+/// how many were served and how many were still running when it gave up.
+#[derive(Clone, Default)]
+struct RequestCounter {
+    in_flight: Arc<AtomicUsize>,
+    served: Arc<AtomicUsize>,
+}
+
+/// Decrements the in-flight count even if the request is cancelled.
+struct InFlight(RequestCounter);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn count_request(
+    State(counter): State<RequestCounter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    counter.in_flight.fetch_add(1, Ordering::SeqCst);
+    let _in_flight = InFlight(counter.clone());
+    let response = next.run(request).await;
+    counter.served.fetch_add(1, Ordering::SeqCst);
+    response
+}
+
+/// What happened while the server shut down.
+#[derive(Debug)]
+pub struct Summary {
+    /// Why the server stopped, e.g. `SIGTERM`.
+    pub reason: &'static str,
+    /// Requests answered over the server's lifetime.
+    pub served: usize,
+    /// Requests still running when the drain timeout ran out.
+    pub abandoned: usize,
+    /// How long draining took.
+    pub drain_time: Duration,
+}
+
+/// Serves `app` until `shutdown` resolves, then stops accepting connections
+/// and waits up to `timeout` for in-flight requests to finish. Requests still
+/// running after that are dropped.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    shutdown: impl Future<Output = &'static str>,
+    timeout: Duration,
+) -> std::io::Result<Summary> {
+    let counter = RequestCounter::default();
+    let app = app.layer(middleware::from_fn_with_state(
+        counter.clone(),
+        count_request,
+    ));
+
+    let (stop, stopped) = oneshot::channel();
+    let mut server = tokio::spawn(async move {
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+
+    let reason = tokio::select! {
+        reason = shutdown => reason,
+        result = &mut server => {
+            // The server stopped by itself; there is nothing to drain
+            result.map_err(std::io::Error::other)??;
+            return Ok(Summary {
+                reason: "server stopped",
+                served: counter.served.load(Ordering::SeqCst),
+                abandoned: 0,
+                drain_time: Duration::ZERO,
+            });
+        }
+    };
+    tracing::info!(
+        reason,
+        in_flight = counter.in_flight.load(Ordering::SeqCst),
+        ?timeout,
+        "shutting down; no longer accepting connections"
+    );
+
+    let started = Instant::now();
+    let _ = stop.send(());
+    let abandoned = match tokio::time::timeout(timeout, &mut server).await {
+        Ok(Ok(Ok(()))) => 0,
+        Ok(Ok(Err(e))) => {
+            tracing::error!(error = %e, "server failed while draining");
+            0
+        }
+        Ok(Err(e)) => {
+            tracing::error!(error = %e, "server task failed while draining");
+            0
+        }
+        Err(_) => {
+            let abandoned = counter.in_flight.load(Ordering::SeqCst);
+            tracing::warn!(
+                abandoned,
+                "drain timeout reached; dropping remaining requests"
+            );
+            server.abort();
+            abandoned
+        }
+    };
+
+    Ok(Summary {
+        reason,
+        served: counter.served.load(Ordering::SeqCst),
+        abandoned,
+        drain_time: started.elapsed(),
+    })
+}
+
+/// Resolves with the signal's name once SIGINT or SIGTERM arrives.
+pub async fn signal() -> &'static str {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for SIGINT");
+        "SIGINT"
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+        "SIGTERM"
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<&'static str>();
+
+    tokio::select! {
+        name = interrupt => name,
+        name = terminate => name,
+    }
+}
@@ -0,0 +1,55 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Delivers text messages, such as sign-in codes, to a phone number.
+pub trait SmsSender: Send + Sync {
+    fn send(&self, phone: &str, message: &str) -> Result<(), String>;
+}
+
+/// Prints messages instead of sending them, for local development. It has
+/// to be chosen explicitly and the server warns when it is used.
+pub struct StdoutSender;
+
+impl SmsSender for StdoutSender {
+    fn send(&self, phone: &str, message: &str) -> Result<(), String> {
+        println!("SMS to {}: {}", phone, message);
+        Ok(())
+    }
+}
+
+/// Appends messages to a file, one `phone<TAB>message` line each, so tests
+/// and scripts can read them back.
+pub struct FileSender {
+    path: PathBuf,
+}
+
+impl FileSender {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileSender { path: path.into() }
+    }
+}
+
+impl SmsSender for FileSender {
+    fn send(&self, phone: &str, message: &str) -> Result<(), String> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("could not open {}: {}", self.path.display(), e))?;
+        writeln!(file, "{}\t{}", phone, message.replace('\n', " "))
+            .map_err(|e| format!("could not write {}: {}", self.path.display(), e))
+    }
+}
+
+/// Opens the sender named by `spec`: `stdout`, or `file:<path>`.
+pub fn open(spec: &str) -> Result<Box<dyn SmsSender>, String> {
+    match spec.split_once(':') {
+        None if spec == "stdout" => Ok(Box::new(StdoutSender)),
+        Some(("file", path)) if !path.is_empty() => Ok(Box::new(FileSender::new(path))),
+        _ => Err(format!(
+            "unknown SMS sender '{}', use `stdout` or `file:<path>`",
+            spec
+        )),
+    }
+}
@@ -1,70 +1,403 @@
+pub mod backup;
+pub mod encryption;
+mod event_log;
+pub mod migrations;
+mod repair;
+mod shards;
+mod sqlite;
+pub mod writer;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
 use std::fs;
-use std::io::Read;
-use std::sync::OnceLock;
-use tracing::{debug, warn};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::errors::{AppError, LoadError};
+use crate::events::Event;
+use crate::models::{AppData, AuthUser, Group};
+use encryption::DataKey;
+
+pub use event_log::EventLogStorage;
+pub use shards::ShardedStorage;
+pub use sqlite::SqliteStorage;
+
+/// Where application data is persisted. Handlers hold the data in memory and
+/// write the records they changed through here after every change.
+pub trait Storage: Send + Sync {
+    /// Loads the stored data, or empty data if nothing has been saved yet.
+    fn load(&self) -> Result<AppData, AppError>;
 
-use crate::errors::AppError;
-use crate::models::AppData;
+    /// Loads the data as it was at `at`, for backends that keep history.
+    fn load_as_of(&self, at: DateTime<Utc>) -> Result<AppData, AppError> {
+        let _ = at;
+        Err(AppError::BadRequest(
+            "This storage backend does not keep history".to_string(),
+        ))
+    }
 
-static DATA_FILE: OnceLock<String> = OnceLock::new();
+    /// Persists the whole dataset.
+    fn save(&self, app_data: &AppData) -> Result<(), AppError>;
 
-pub fn init(path: &str) {
-    DATA_FILE.get_or_init(|| path.to_string());
+    /// Persists a change described by `events`, after they have been applied.
+    /// `changes` holds the records the events touched in their new state, so
+    /// backends that store records separately rewrite only those; backends
+    /// that can update single rows work from the events instead.
+    fn record(&self, events: &[Event], changes: &[Change]) -> Result<(), AppError>;
 }
 
-pub fn get_data_file() -> &'static str {
-    DATA_FILE.get_or_init(|| "app_data.json".to_string())
+/// A record left in a new state by a change.
+#[derive(Clone, Copy, Debug)]
+pub enum Change<'a> {
+    Group(&'a Group),
+    GroupDeleted(usize),
+    Users(&'a [AuthUser]),
 }
 
-pub fn load() -> AppData {
-    let path = get_data_file();
-    let backup_path = format!("{}.bak", path);
+impl Change<'_> {
+    /// Applies the change to a full copy of the data, for backends that
+    /// always write everything.
+    pub fn apply(&self, data: &mut AppData) {
+        match *self {
+            Change::Group(group) => match data.groups.iter_mut().find(|g| g.id == group.id) {
+                Some(existing) => *existing = group.clone(),
+                None => data.groups.push(group.clone()),
+            },
+            Change::GroupDeleted(id) => data.groups.retain(|g| g.id != id),
+            Change::Users(users) => data.users = users.to_vec(),
+        }
+    }
+}
 
-    // Try main file first
-    if let Some(data) = try_load_file(path) {
-        return data;
+/// Opens the backend named by a `--storage` value: `sqlite:<path>`,
+/// `events:<directory>`, `shards:<directory>`, `json:<path>`, or a bare path
+/// to a JSON file. With a key the data is encrypted at rest, which only the
+/// JSON file and sharded backends support.
+pub fn open(spec: &str, key: Option<DataKey>) -> Result<Box<dyn Storage>, AppError> {
+    if let Some(path) = json_file_path(spec) {
+        return Ok(Box::new(JsonFileStorage::new(path).with_key(key)));
+    }
+    if let Some(("shards", dir)) = spec.split_once(':') {
+        return Ok(Box::new(ShardedStorage::open(dir)?.with_key(key)));
     }
+    if key.is_some() {
+        return Err(AppError::BadRequest(
+            "Encryption at rest is only supported for JSON and sharded storage".to_string(),
+        ));
+    }
+    match spec.split_once(':') {
+        Some(("sqlite", path)) => Ok(Box::new(SqliteStorage::open(path)?)),
+        Some(("events", dir)) => Ok(Box::new(EventLogStorage::open(dir)?)),
+        _ => unreachable!("every other spec names a JSON file"),
+    }
+}
 
-    // Fall back to backup if main file is corrupted
-    if let Some(data) = try_load_file(&backup_path) {
-        warn!("loaded from backup file (main file was corrupted)");
-        return data;
+/// The file path of a `--storage` value that names a JSON data file.
+pub fn json_file_path(spec: &str) -> Option<&str> {
+    match spec.split_once(':') {
+        Some(("sqlite" | "events" | "shards", _)) => None,
+        Some(("json", path)) => Some(path),
+        _ => Some(spec),
     }
+}
 
+/// The files a `--storage` value keeps its data in, for the backends that
+/// can be encrypted.
+pub fn data_files(spec: &str) -> Result<Vec<String>, AppError> {
+    if let Some(path) = json_file_path(spec) {
+        return Ok(JsonFileStorage::new(path).files());
+    }
+    match spec.split_once(':') {
+        Some(("shards", dir)) => ShardedStorage::open(dir)?.files(),
+        _ => Err(AppError::BadRequest(
+            "Encryption at rest is only supported for JSON and sharded storage".to_string(),
+        )),
+    }
+}
+
+/// Rewrites `paths` under `new_key`, or as plaintext without one, after
+/// reading them with `key`. Used both to encrypt existing plaintext files,
+/// with `allow_plaintext`, and to rotate the key. Files that do not exist are
+/// skipped. Returns the files rewritten.
+pub fn rekey_files(
+    paths: &[String],
+    key: Option<&DataKey>,
+    new_key: Option<&DataKey>,
+    allow_plaintext: bool,
+) -> Result<Vec<String>, AppError> {
+    // Read everything first so a wrong key leaves every file untouched
+    let mut contents = Vec::new();
+    for path in paths {
+        match JsonFileStorage::read_text(path, key, allow_plaintext) {
+            Ok(Some(text)) => contents.push((path, text)),
+            Ok(None) => {}
+            Err(e) => return Err(AppError::LoadError(vec![e])),
+        }
+    }
+
+    let mut rewritten = Vec::new();
+    for (path, text) in contents {
+        let tmp_path = format!("{}.tmp", path);
+        write_synced(&tmp_path, &encryption::encode(text, new_key))?;
+        fs::rename(&tmp_path, path)?;
+        rewritten.push(path.clone());
+    }
+    Ok(rewritten)
+}
+
+/// Writes `contents` to `path` and waits until it is on disk, so a rename
+/// over the real file afterwards cannot expose a half-written one.
+fn write_synced(path: impl AsRef<Path>, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+fn empty_data() -> AppData {
     AppData {
         groups: vec![],
         users: vec![],
     }
 }
 
-fn try_load_file(path: &str) -> Option<AppData> {
-    let mut file = fs::File::open(path).ok()?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).ok()?;
-    serde_json::from_str(&contents).ok()
+/// Stores everything in one pretty-printed JSON file, keeping the previous
+/// version next to it as `<path>.bak`. The file records the schema version it
+/// was written with and older files are migrated as they are loaded. With a
+/// key the file, its backup and the temporary file are all encrypted.
+///
+/// Every change rewrites the whole file, from a copy of the data kept since
+/// the last load or save.
+pub struct JsonFileStorage {
+    path: String,
+    key: Option<DataKey>,
+    current: Mutex<Option<AppData>>,
 }
 
-pub fn save(app_data: &AppData) -> Result<(), AppError> {
-    use std::path::Path;
+/// The on-disk layout: the data plus the schema version it was written with.
+#[derive(Serialize)]
+struct VersionedData<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    data: &'a AppData,
+}
 
-    let path = get_data_file();
-    let tmp_path = format!("{}.tmp", path);
-    let backup_path = format!("{}.bak", path);
+impl JsonFileStorage {
+    pub fn new(path: &str) -> Self {
+        JsonFileStorage {
+            path: path.to_string(),
+            key: None,
+            current: Mutex::new(None),
+        }
+    }
 
-    let json = serde_json::to_string_pretty(app_data)
-        .map_err(|e| AppError::StorageError(std::io::Error::other(e)))?;
+    pub fn with_key(self, key: Option<DataKey>) -> Self {
+        JsonFileStorage { key, ..self }
+    }
 
-    // Write to temp file first
-    fs::write(&tmp_path, &json)?;
+    fn backup_path(&self) -> String {
+        format!("{}.bak", self.path)
+    }
+
+    /// Parses and migrates the contents of a data file, returning the data,
+    /// the version it was stored with and the migrations applied.
+    fn parse(path: &str, contents: &str) -> Result<(AppData, u32, Vec<String>), LoadError> {
+        let mut document: serde_json::Value =
+            serde_json::from_str(contents).map_err(|e| LoadError::from_json(path, &e))?;
+        let version = migrations::version_of(&document).map_err(|e| LoadError::new(path, e))?;
+        let applied = migrations::migrate(&mut document).map_err(|e| LoadError::new(path, e))?;
 
-    // Backup existing file
-    if Path::new(path).exists() {
-        fs::copy(path, &backup_path)?;
+        let data = serde_json::from_value(document).map_err(|e| {
+            // Reparse the text, which fails at the same record in most cases,
+            // so the error can point at a line
+            let located = serde_json::from_str::<AppData>(contents).err();
+            LoadError::from_json(path, located.as_ref().unwrap_or(&e))
+        })?;
+        Ok((data, version, applied))
     }
 
-    // Atomic rename
-    fs::rename(&tmp_path, path)?;
+    /// Reads a data file as JSON text, decrypting it with `key`. Plaintext is
+    /// only accepted with a key when `allow_plaintext` is set. Returns
+    /// `Ok(None)` if it does not exist.
+    fn read_text(
+        path: &str,
+        key: Option<&DataKey>,
+        allow_plaintext: bool,
+    ) -> Result<Option<String>, LoadError> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(LoadError::new(path, e)),
+        };
+        encryption::decode(contents, key, allow_plaintext)
+            .map(Some)
+            .map_err(|e| LoadError::new(path, e))
+    }
 
-    debug!(path, "data saved");
-    Ok(())
+    /// Reads and migrates one data file. Returns `Ok(None)` if it does not
+    /// exist.
+    fn read_file(path: &str, key: Option<&DataKey>) -> Result<Option<AppData>, LoadError> {
+        let Some(contents) = Self::read_text(path, key, false)? else {
+            return Ok(None);
+        };
+        let (data, _, applied) = Self::parse(path, &contents)?;
+        for step in applied {
+            info!(path, step = %step, "migrated data file");
+        }
+        Ok(Some(data))
+    }
+
+    /// Upgrades the data file to the current schema version, returning the
+    /// migrations applied. The original is kept as `<path>.v<version>`.
+    /// With `dry_run` the migrated data is checked but nothing is written.
+    pub fn migrate(&self, dry_run: bool) -> Result<Vec<String>, AppError> {
+        let contents = Self::read_text(&self.path, self.key.as_ref(), false)
+            .and_then(|contents| {
+                contents.ok_or_else(|| LoadError::new(&self.path, "data file not found"))
+            })
+            .map_err(|e| AppError::LoadError(vec![e]))?;
+        let (data, version, applied) =
+            Self::parse(&self.path, &contents).map_err(|e| AppError::LoadError(vec![e]))?;
+
+        if !dry_run && !applied.is_empty() {
+            fs::copy(&self.path, format!("{}.v{}", self.path, version))?;
+            self.save(&data)?;
+        }
+        Ok(applied)
+    }
+
+    /// Rebuilds the data file from the records that can still be read,
+    /// starting from the backup if the file itself is not valid JSON. Returns
+    /// the file salvaged from, the recovered data and what was dropped. The
+    /// originals are preserved as with `preserve_files` unless `dry_run`.
+    pub fn repair(&self, dry_run: bool) -> Result<(String, AppData, Vec<String>), AppError> {
+        let mut errors = Vec::new();
+        for path in [self.path.clone(), self.backup_path()] {
+            let document = match Self::read_text(&path, self.key.as_ref(), false) {
+                Ok(Some(contents)) => {
+                    serde_json::from_str(&contents).map_err(|e| LoadError::from_json(&path, &e))
+                }
+                Ok(None) => Err(LoadError::new(&path, "not found")),
+                Err(e) => Err(e),
+            };
+            let salvaged = document.and_then(|document| {
+                repair::salvage(document).map_err(|e| LoadError::new(&path, e))
+            });
+            match salvaged {
+                Ok((data, dropped)) => {
+                    if !dry_run {
+                        self.preserve_files()?;
+                        self.save(&data)?;
+                    }
+                    return Ok((path, data, dropped));
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        Err(AppError::LoadError(errors))
+    }
+
+    /// Copies the data file and its backup aside as `<file>.unreadable-<time>`
+    /// so starting over with empty data cannot overwrite them. Returns the
+    /// copies made.
+    pub fn preserve_files(&self) -> Result<Vec<String>, AppError> {
+        let suffix = Utc::now().format("%Y%m%dT%H%M%SZ");
+        let mut copies = Vec::new();
+        for path in [self.path.clone(), self.backup_path()] {
+            if Path::new(&path).exists() {
+                let copy = format!("{}.unreadable-{}", path, suffix);
+                fs::copy(&path, &copy)?;
+                copies.push(copy);
+            }
+        }
+        Ok(copies)
+    }
+
+    /// The data file and its backup.
+    pub fn files(&self) -> Vec<String> {
+        vec![self.path.clone(), self.backup_path()]
+    }
+
+    fn load_from_disk(&self) -> Result<AppData, AppError> {
+        let backup_path = self.backup_path();
+
+        // Try main file first
+        let main_error = match Self::read_file(&self.path, self.key.as_ref()) {
+            Ok(Some(data)) => return Ok(data),
+            Ok(None) => None,
+            Err(e) => {
+                warn!(error = %e, "could not read data file");
+                Some(e)
+            }
+        };
+
+        // Fall back to backup if main file is missing or corrupted
+        match (Self::read_file(&backup_path, self.key.as_ref()), main_error) {
+            (Ok(Some(data)), _) => {
+                warn!(path = %backup_path, "loaded from backup file");
+                Ok(data)
+            }
+            (Ok(None), None) => Ok(empty_data()),
+            // Starting empty here would overwrite the real data on next save
+            (Ok(None), Some(e)) => Err(AppError::LoadError(vec![e])),
+            (Err(backup_error), main_error) => Err(AppError::LoadError(
+                main_error.into_iter().chain([backup_error]).collect(),
+            )),
+        }
+    }
+
+    fn write(&self, app_data: &AppData) -> Result<(), AppError> {
+        let path = self.path.as_str();
+        let tmp_path = format!("{}.tmp", path);
+
+        let versioned = VersionedData {
+            schema_version: migrations::CURRENT_VERSION,
+            data: app_data,
+        };
+        let json = serde_json::to_string_pretty(&versioned)
+            .map_err(|e| AppError::StorageError(std::io::Error::other(e)))?;
+
+        // Write to temp file first
+        write_synced(&tmp_path, &encryption::encode(json, self.key.as_ref()))?;
+
+        // Backup existing file
+        if Path::new(path).exists() {
+            fs::copy(path, self.backup_path())?;
+        }
+
+        // Atomic rename
+        fs::rename(&tmp_path, path)?;
+
+        debug!(path, "data saved");
+        Ok(())
+    }
+}
+
+impl Storage for JsonFileStorage {
+    fn load(&self) -> Result<AppData, AppError> {
+        let data = self.load_from_disk()?;
+        *self.current.lock().map_err(|_| AppError::LockError)? = Some(data.clone());
+        Ok(data)
+    }
+
+    fn save(&self, app_data: &AppData) -> Result<(), AppError> {
+        let mut current = self.current.lock().map_err(|_| AppError::LockError)?;
+        self.write(app_data)?;
+        *current = Some(app_data.clone());
+        Ok(())
+    }
+
+    fn record(&self, events: &[Event], changes: &[Change]) -> Result<(), AppError> {
+        let _ = events;
+        let mut current = self.current.lock().map_err(|_| AppError::LockError)?;
+        let data = match current.as_mut() {
+            Some(data) => data,
+            None => current.insert(self.load_from_disk()?),
+        };
+        for change in changes {
+            change.apply(data);
+        }
+        self.write(data)
+    }
 }
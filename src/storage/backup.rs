@@ -0,0 +1,247 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use super::encryption::{self, DataKey};
+use super::{Change, JsonFileStorage, Storage, VersionedData, migrations};
+use crate::errors::{AppError, LoadError};
+use crate::events::Event;
+use crate::models::AppData;
+
+const PREFIX: &str = "backup-";
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// How often to take a timestamped backup and how many to keep.
+#[derive(Clone, Debug)]
+pub struct BackupPolicy {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    pub retain: usize,
+    /// Encrypts backups like the data file they are taken from.
+    pub key: Option<DataKey>,
+}
+
+/// A directory of timestamped backups named `backup-<time>.json`. Each is a
+/// complete data file that `JsonFileStorage` can read, encrypted when the
+/// directory has a key.
+pub struct BackupDir {
+    dir: PathBuf,
+    key: Option<DataKey>,
+}
+
+impl BackupDir {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        BackupDir {
+            dir: dir.into(),
+            key: None,
+        }
+    }
+
+    pub fn with_key(self, key: Option<DataKey>) -> Self {
+        BackupDir { key, ..self }
+    }
+
+    /// Backups in the directory with the time they were taken, oldest first.
+    pub fn list(&self) -> Result<Vec<(DateTime<Utc>, PathBuf)>, AppError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut backups: Vec<(DateTime<Utc>, PathBuf)> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let name = path.file_name()?.to_str()?;
+                let stamp = name.strip_prefix(PREFIX)?.strip_suffix(".json")?;
+                let taken = NaiveDateTime::parse_from_str(stamp, TIMESTAMP_FORMAT).ok()?;
+                Some((taken.and_utc(), path))
+            })
+            .collect();
+        backups.sort();
+        Ok(backups)
+    }
+
+    /// Paths of the backups in the directory, oldest first.
+    pub fn files(&self) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .map(|(_, path)| path.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    /// Writes `app_data` as a new backup, returning its path.
+    pub fn write(&self, app_data: &AppData) -> Result<PathBuf, AppError> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!(
+            "{}{}.json",
+            PREFIX,
+            Utc::now().format(TIMESTAMP_FORMAT)
+        ));
+        let tmp_path = path.with_extension("json.tmp");
+
+        let versioned = VersionedData {
+            schema_version: migrations::CURRENT_VERSION,
+            data: app_data,
+        };
+        let json = serde_json::to_string_pretty(&versioned)
+            .map_err(|e| AppError::StorageError(std::io::Error::other(e)))?;
+        fs::write(&tmp_path, encryption::encode(json, self.key.as_ref()))?;
+        fs::rename(&tmp_path, &path)?;
+
+        info!(path = %path.display(), "backup written");
+        Ok(path)
+    }
+
+    /// Deletes all but the newest `retain` backups, returning those removed.
+    pub fn prune(&self, retain: usize) -> Result<Vec<PathBuf>, AppError> {
+        let backups = self.list()?;
+        let excess = backups.len().saturating_sub(retain);
+        let mut removed = Vec::new();
+        for (_, path) in backups.into_iter().take(excess) {
+            fs::remove_file(&path)?;
+            removed.push(path);
+        }
+        Ok(removed)
+    }
+}
+
+/// Reads a backup and checks that it is safe to restore: it must parse,
+/// migrate to the current schema and be internally consistent. Encrypted
+/// backups need the key they were written with.
+pub fn validate(path: &Path, key: Option<&DataKey>) -> Result<AppData, AppError> {
+    let path_str = path.to_string_lossy();
+    let data = JsonFileStorage::read_file(&path_str, key)
+        .map_err(|e| AppError::LoadError(vec![e]))?
+        .ok_or_else(|| AppError::LoadError(vec![LoadError::new(&path_str, "not found")]))?;
+
+    let problems = consistency_problems(&data);
+    if !problems.is_empty() {
+        return Err(AppError::LoadError(
+            problems
+                .into_iter()
+                .map(|problem| LoadError::new(&path_str, problem))
+                .collect(),
+        ));
+    }
+    Ok(data)
+}
+
+/// Things that deserialize fine but would break handlers: duplicate ids and
+/// expenses that refer to people who are not in their group.
+fn consistency_problems(data: &AppData) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let mut user_ids = HashSet::new();
+    for user in &data.users {
+        if !user_ids.insert(user.id) {
+            problems.push(format!("duplicate user id {}", user.id));
+        }
+    }
+
+    let mut group_ids = HashSet::new();
+    for group in &data.groups {
+        if !group_ids.insert(group.id) {
+            problems.push(format!("duplicate group id {}", group.id));
+        }
+        let members: HashSet<&str> = group.members.iter().map(|m| m.name.as_str()).collect();
+        let mut expense_ids = HashSet::new();
+        for expense in &group.expenses {
+            if !expense_ids.insert(expense.id) {
+                problems.push(format!(
+                    "group {}: duplicate expense id {}",
+                    group.id, expense.id
+                ));
+            }
+            let people = std::iter::once(&expense.payer.name)
+                .chain(expense.payers.iter().map(|p| &p.name))
+                .chain(expense.participants.iter().map(|p| &p.name));
+            for name in people {
+                if !members.contains(name.as_str()) {
+                    problems.push(format!(
+                        "group {}: expense {} refers to {}, who is not a member",
+                        group.id, expense.id, name
+                    ));
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// Wraps a backend to take a timestamped backup after a save whenever the
+/// newest backup is older than the policy's interval.
+pub struct RotatingBackups {
+    inner: Box<dyn Storage>,
+    backups: BackupDir,
+    policy: BackupPolicy,
+    last_backup: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl RotatingBackups {
+    pub fn new(inner: Box<dyn Storage>, policy: BackupPolicy) -> Result<Self, AppError> {
+        let backups = BackupDir::new(&policy.dir).with_key(policy.key.clone());
+        let last_backup = backups.list()?.last().map(|(taken, _)| *taken);
+        Ok(RotatingBackups {
+            inner,
+            backups,
+            policy,
+            last_backup: Mutex::new(last_backup),
+        })
+    }
+
+    /// Takes a backup if one is due, of `app_data` or, after a partial
+    /// change, of everything the backend now holds.
+    fn maybe_backup(&self, app_data: Option<&AppData>) -> Result<(), AppError> {
+        let mut last_backup = self.last_backup.lock().map_err(|_| AppError::LockError)?;
+        let now = Utc::now();
+        let due = last_backup
+            .is_none_or(|last| (now - last).to_std().unwrap_or_default() >= self.policy.interval);
+        if !due {
+            return Ok(());
+        }
+
+        match app_data {
+            Some(app_data) => self.backups.write(app_data)?,
+            None => self.backups.write(&self.inner.load()?)?,
+        };
+        *last_backup = Some(now);
+        for removed in self.backups.prune(self.policy.retain)? {
+            info!(path = %removed.display(), "old backup removed");
+        }
+        Ok(())
+    }
+
+    /// The change itself is already stored, so a failed backup is only logged.
+    fn after_write(&self, app_data: Option<&AppData>) {
+        if let Err(e) = self.maybe_backup(app_data) {
+            warn!(error = %e, "could not write backup");
+        }
+    }
+}
+
+impl Storage for RotatingBackups {
+    fn load(&self) -> Result<AppData, AppError> {
+        self.inner.load()
+    }
+
+    fn load_as_of(&self, at: DateTime<Utc>) -> Result<AppData, AppError> {
+        self.inner.load_as_of(at)
+    }
+
+    fn save(&self, app_data: &AppData) -> Result<(), AppError> {
+        self.inner.save(app_data)?;
+        self.after_write(Some(app_data));
+        Ok(())
+    }
+
+    fn record(&self, events: &[Event], changes: &[Change]) -> Result<(), AppError> {
+        self.inner.record(events, changes)?;
+        self.after_write(None);
+        Ok(())
+    }
+}
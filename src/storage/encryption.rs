@@ -0,0 +1,144 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::fmt;
+use std::fs;
+
+/// Environment variable holding the base64 data key when no key file is given.
+pub const KEY_ENV_VAR: &str = "SPLITDUMB_DATA_KEY";
+
+/// Marks an encrypted file and is authenticated along with the contents, so
+/// the format version cannot be swapped without the key.
+const MAGIC: &[u8] = b"splitdumb-encrypted-v1\n";
+const NONCE_LEN: usize = 12;
+
+/// A 256-bit key for ChaCha20-Poly1305, stored as base64 in a key file or in
+/// `SPLITDUMB_DATA_KEY`.
+#[derive(Clone)]
+pub struct DataKey(Key);
+
+impl DataKey {
+    pub fn generate() -> Self {
+        DataKey(ChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let bytes = BASE64
+            .decode(text.trim())
+            .map_err(|e| format!("data key is not valid base64: {}", e))?;
+        if bytes.len() != 32 {
+            return Err(format!("data key must be 32 bytes, found {}", bytes.len()));
+        }
+        Ok(DataKey(*Key::from_slice(&bytes)))
+    }
+
+    pub fn encode(&self) -> String {
+        BASE64.encode(self.0)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("could not read key file {}: {}", path, e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// The key from `key_file` if given, otherwise from `SPLITDUMB_DATA_KEY`.
+    /// `Ok(None)` means data is stored in plaintext.
+    pub fn load(key_file: Option<&str>) -> Result<Option<Self>, String> {
+        if let Some(path) = key_file {
+            return Self::from_file(path).map(Some);
+        }
+        match std::env::var(KEY_ENV_VAR) {
+            Ok(text) => Self::parse(&text)
+                .map(Some)
+                .map_err(|e| format!("{}: {}", KEY_ENV_VAR, e)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Encrypts `plaintext` under a fresh random nonce.
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: MAGIC,
+                },
+            )
+            .expect("encryption only fails for oversized messages");
+
+        let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypts what `seal` produced, failing if the key is wrong or the
+    /// contents were modified.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        let body = sealed.strip_prefix(MAGIC).ok_or("file is not encrypted")?;
+        if body.len() < NONCE_LEN {
+            return Err("encrypted file is truncated".to_string());
+        }
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        ChaCha20Poly1305::new(&self.0)
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: MAGIC,
+                },
+            )
+            .map_err(|_| "could not decrypt: wrong data key or the file was modified".to_string())
+    }
+}
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+/// Turns stored bytes into the JSON text they hold, decrypting if needed.
+/// With a key a plaintext file is an error, since it could have been put in
+/// place of the encrypted one, unless `allow_plaintext` is set to encrypt
+/// existing data.
+pub fn decode(
+    contents: Vec<u8>,
+    key: Option<&DataKey>,
+    allow_plaintext: bool,
+) -> Result<String, String> {
+    let plaintext = match (is_encrypted(&contents), key) {
+        (true, Some(key)) => key.open(&contents)?,
+        (true, None) => {
+            return Err(format!(
+                "file is encrypted; pass --key-file or set {}",
+                KEY_ENV_VAR
+            ));
+        }
+        (false, Some(_)) if !allow_plaintext => {
+            return Err(
+                "file is not encrypted but a data key is set; run `splitdumb encrypt` first"
+                    .to_string(),
+            );
+        }
+        (false, _) => contents,
+    };
+    String::from_utf8(plaintext).map_err(|e| format!("file is not valid UTF-8: {}", e))
+}
+
+/// The bytes to store for `json`, encrypted when there is a key.
+pub fn encode(json: String, key: Option<&DataKey>) -> Vec<u8> {
+    match key {
+        Some(key) => key.seal(json.as_bytes()),
+        None => json.into_bytes(),
+    }
+}
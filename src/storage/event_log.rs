@@ -0,0 +1,439 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use super::{Change, Storage, empty_data, migrations, write_synced};
+use crate::errors::AppError;
+use crate::events::Event;
+use crate::models::AppData;
+
+/// How many events are journaled between automatic snapshots.
+const SNAPSHOT_INTERVAL: u64 = 1000;
+
+/// How many snapshots are kept along with the journal segments after them.
+/// Older ones are deleted, which bounds both disk use and how far back
+/// `load_as_of` can go.
+const SNAPSHOTS_KEPT: usize = 10;
+
+/// The single journal written before it was split into segments. It holds
+/// every event from the first, so it becomes the first segment.
+const LEGACY_JOURNAL_FILE: &str = "journal.jsonl";
+
+/// Stores every change as a line in an append-only journal, with periodic
+/// full snapshots so loading only replays the events since the latest one.
+///
+/// The directory holds `snapshot-<seq>.json` files and the journal, split
+/// into `journal-<seq>.jsonl` segments named after their first event. Each
+/// snapshot starts a new segment, so loading reads the latest snapshot and
+/// the segment after it. State can be rebuilt as of any moment since the
+/// oldest kept snapshot by replaying from the last snapshot taken before it.
+/// Snapshots and journal entries record their schema version. Older ones are
+/// migrated as they are read, and opening the directory rewrites them.
+pub struct EventLogStorage {
+    dir: PathBuf,
+    journal: Mutex<Journal>,
+}
+
+struct Journal {
+    /// The segment being appended to
+    file: File,
+    last_seq: u64,
+    since_snapshot: u64,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    seq: u64,
+    at: DateTime<Utc>,
+    /// Schema version of the records in `event`
+    schema_version: u32,
+    event: Event,
+}
+
+/// An entry as read back, before its event is migrated.
+#[derive(Deserialize)]
+struct StoredEntry {
+    seq: u64,
+    at: DateTime<Utc>,
+    /// Missing from entries journaled before they were versioned
+    #[serde(default)]
+    schema_version: Option<u32>,
+    event: serde_json::Value,
+}
+
+impl StoredEntry {
+    /// Upgrades the event to the current schema, like snapshots are.
+    fn migrate(mut self) -> Result<Entry, String> {
+        // Unversioned entries get every migration, each of which only fills
+        // in what is missing
+        let version = self.schema_version.unwrap_or(migrations::UNVERSIONED);
+        if version != migrations::CURRENT_VERSION {
+            migrations::migrate_event(&mut self.event, version)?;
+        }
+        Ok(Entry {
+            seq: self.seq,
+            at: self.at,
+            schema_version: migrations::CURRENT_VERSION,
+            event: serde_json::from_value(self.event).map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// Last journal entry included in `data`
+    seq: u64,
+    at: DateTime<Utc>,
+    /// Schema version of `data`
+    #[serde(default)]
+    schema_version: Option<u32>,
+    data: AppData,
+}
+
+fn corrupt(path: &Path, message: impl std::fmt::Display) -> AppError {
+    AppError::StorageError(std::io::Error::other(format!(
+        "{}: {}",
+        path.display(),
+        message
+    )))
+}
+
+impl EventLogStorage {
+    pub fn open(dir: &str) -> Result<Self, AppError> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+
+        let legacy = dir.join(LEGACY_JOURNAL_FILE);
+        if legacy.exists() {
+            fs::rename(&legacy, segment_path(&dir, 1))?;
+            info!("journal split into segments");
+        }
+
+        let snapshot_seq = Self::snapshot_seqs(&dir)?.last().copied().unwrap_or(0);
+        let (segment, last_seq, outdated) = match Self::segment_starts(&dir)?.last() {
+            Some(&start) => {
+                let (entries, outdated) = Self::read_journal(&segment_path(&dir, start), true)?;
+                let last_seq = entries.last().map_or(start - 1, |e| e.seq);
+                (start, last_seq.max(snapshot_seq), outdated)
+            }
+            None => (snapshot_seq + 1, snapshot_seq, false),
+        };
+
+        let file = Self::open_segment(&dir, segment)?;
+        let storage = EventLogStorage {
+            dir,
+            journal: Mutex::new(Journal {
+                file,
+                last_seq,
+                since_snapshot: last_seq - snapshot_seq,
+            }),
+        };
+        // Files are upgraded oldest first, so an older schema anywhere shows
+        // in the newest snapshot or segment
+        let snapshot_outdated = snapshot_seq > 0
+            && storage.read_snapshot(snapshot_seq)?.schema_version
+                != Some(migrations::CURRENT_VERSION);
+        if outdated || snapshot_outdated {
+            storage.upgrade()?;
+        }
+        Ok(storage)
+    }
+
+    /// Rewrites the snapshots and journal segments stored with an older
+    /// schema in the current one. Besides sparing every later load the
+    /// migrations, this is what removes the bearer tokens that older
+    /// versions journaled in cleartext.
+    fn upgrade(&self) -> Result<(), AppError> {
+        let mut journal = self.journal.lock().map_err(|_| AppError::LockError)?;
+        for seq in Self::snapshot_seqs(&self.dir)? {
+            let snapshot = self.read_snapshot(seq)?;
+            if snapshot.schema_version != Some(migrations::CURRENT_VERSION) {
+                self.write_snapshot(seq, snapshot.at, &snapshot.data)?;
+            }
+        }
+
+        let segments = Self::segment_starts(&self.dir)?;
+        for start in &segments {
+            let path = segment_path(&self.dir, *start);
+            let (entries, outdated) = Self::read_journal(&path, true)?;
+            if !outdated {
+                continue;
+            }
+            let mut lines = String::new();
+            for entry in &entries {
+                lines.push_str(&to_line(entry)?);
+            }
+            let tmp_path = path.with_extension("jsonl.tmp");
+            write_synced(&tmp_path, lines.as_bytes())?;
+            fs::rename(&tmp_path, &path)?;
+        }
+
+        // The segment being appended to may have been replaced
+        if let Some(&last) = segments.last() {
+            journal.file = Self::open_segment(&self.dir, last)?;
+        }
+        info!(
+            schema_version = migrations::CURRENT_VERSION,
+            "event log upgraded"
+        );
+        Ok(())
+    }
+
+    fn open_segment(dir: &Path, start: u64) -> Result<File, AppError> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(dir, start))?)
+    }
+
+    /// First sequence numbers of the journal segments, oldest first.
+    fn segment_starts(dir: &Path) -> Result<Vec<u64>, AppError> {
+        numbered_files(dir, "journal-", ".jsonl")
+    }
+
+    /// Reads all journal entries in order, migrating events written with an
+    /// older schema, and says whether any were. A final line without a
+    /// newline is what a crash mid-append leaves behind, even if it happens
+    /// to parse or was cut inside a character; it is cut off when `repair` is
+    /// set (and otherwise skipped) so later appends start on a clean line.
+    /// Any other bad line is an error.
+    fn read_journal(path: &Path, repair: bool) -> Result<(Vec<Entry>, bool), AppError> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((vec![], false)),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries = Vec::new();
+        let mut outdated = false;
+        let mut valid_len = 0;
+        // Only the final piece can lack a newline
+        for (number, line) in contents.split_inclusive(|&b| b == b'\n').enumerate() {
+            if !line.ends_with(b"\n") {
+                warn!(path = %path.display(), line = number + 1, "discarding incomplete journal entry");
+                if repair {
+                    OpenOptions::new()
+                        .write(true)
+                        .open(path)?
+                        .set_len(valid_len as u64)?;
+                }
+                break;
+            }
+            let entry = serde_json::from_slice::<StoredEntry>(line)
+                .map_err(|e| corrupt(path, format!("line {}: {}", number + 1, e)))?;
+            valid_len += line.len();
+            outdated |= entry.schema_version != Some(migrations::CURRENT_VERSION);
+            let entry = entry
+                .migrate()
+                .map_err(|e| corrupt(path, format!("line {}: {}", number + 1, e)))?;
+            entries.push(entry);
+        }
+        Ok((entries, outdated))
+    }
+
+    fn snapshot_path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("snapshot-{:012}.json", seq))
+    }
+
+    /// Sequence numbers of the stored snapshots, oldest first.
+    fn snapshot_seqs(dir: &Path) -> Result<Vec<u64>, AppError> {
+        numbered_files(dir, "snapshot-", ".json")
+    }
+
+    fn read_snapshot(&self, seq: u64) -> Result<Snapshot, AppError> {
+        let path = self.snapshot_path(seq);
+        let contents = fs::read_to_string(&path)?;
+        let mut document: serde_json::Value =
+            serde_json::from_str(&contents).map_err(|e| corrupt(&path, e))?;
+
+        // The data is versioned like the JSON data file
+        if let Some(snapshot) = document.as_object_mut() {
+            let version = snapshot.get("schema_version").cloned();
+            if let Some(data) = snapshot.get_mut("data") {
+                if let (Some(version), Some(object)) = (version, data.as_object_mut()) {
+                    object.insert("schema_version".to_string(), version);
+                }
+                migrations::migrate(data).map_err(|e| corrupt(&path, e))?;
+            }
+        }
+        serde_json::from_value(document).map_err(|e| corrupt(&path, e))
+    }
+
+    fn write_snapshot(&self, seq: u64, at: DateTime<Utc>, data: &AppData) -> Result<(), AppError> {
+        let path = self.snapshot_path(seq);
+        let tmp_path = path.with_extension("json.tmp");
+        let snapshot = Snapshot {
+            seq,
+            at,
+            schema_version: Some(migrations::CURRENT_VERSION),
+            data: data.clone(),
+        };
+        let json = serde_json::to_string(&snapshot)
+            .map_err(|e| AppError::StorageError(std::io::Error::other(e)))?;
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+
+        info!(seq, "snapshot written");
+        Ok(())
+    }
+
+    /// Takes a snapshot of `data` as the state after the last journaled event
+    /// and starts a new journal segment after it. Snapshots beyond
+    /// `SNAPSHOTS_KEPT` are then deleted, with the segments only they needed.
+    fn snapshot(&self, journal: &mut Journal, data: &AppData) -> Result<(), AppError> {
+        self.write_snapshot(journal.last_seq, Utc::now(), data)?;
+        journal.file = Self::open_segment(&self.dir, journal.last_seq + 1)?;
+        journal.since_snapshot = 0;
+
+        let snapshots = Self::snapshot_seqs(&self.dir)?;
+        let Some(&oldest_kept) = snapshots.iter().rev().nth(SNAPSHOTS_KEPT - 1) else {
+            return Ok(());
+        };
+        for seq in snapshots.iter().filter(|&&seq| seq < oldest_kept) {
+            fs::remove_file(self.snapshot_path(*seq))?;
+        }
+        // A segment ends where the next one starts
+        let segments = Self::segment_starts(&self.dir)?;
+        for pair in segments.windows(2) {
+            if pair[1] <= oldest_kept + 1 {
+                fs::remove_file(segment_path(&self.dir, pair[0]))?;
+            }
+        }
+        debug!(oldest_kept, "old snapshots and journal segments removed");
+        Ok(())
+    }
+
+    /// Rebuilds the state from the newest snapshot taken no later than
+    /// `until`, replaying journal entries up to and including `until`. Only
+    /// the segments after that snapshot are read.
+    fn replay(&self, until: Option<DateTime<Utc>>) -> Result<AppData, AppError> {
+        let mut start = None;
+        for seq in Self::snapshot_seqs(&self.dir)?.into_iter().rev() {
+            let snapshot = self.read_snapshot(seq)?;
+            if until.is_none_or(|until| snapshot.at <= until) {
+                start = Some(snapshot);
+                break;
+            }
+        }
+        let segments = Self::segment_starts(&self.dir)?;
+        let (from_seq, mut data) = match start {
+            Some(snapshot) => (snapshot.seq, snapshot.data),
+            // Without a snapshot the journal has to reach back to the start
+            None if segments.first().is_none_or(|&first| first == 1) => (0, empty_data()),
+            None if until.is_some() => {
+                return Err(AppError::BadRequest(
+                    "History from that time is no longer kept".to_string(),
+                ));
+            }
+            None => {
+                return Err(corrupt(
+                    &self.dir,
+                    "the journal has no snapshot covering its first events",
+                ));
+            }
+        };
+
+        let mut replayed = 0;
+        'segments: for (i, start) in segments.iter().enumerate() {
+            if segments.get(i + 1).is_some_and(|next| next - 1 <= from_seq) {
+                continue;
+            }
+            let (entries, _) = Self::read_journal(&segment_path(&self.dir, *start), false)?;
+            for entry in entries {
+                if entry.seq <= from_seq {
+                    continue;
+                }
+                if until.is_some_and(|until| entry.at > until) {
+                    break 'segments;
+                }
+                entry.event.apply(&mut data);
+                replayed += 1;
+            }
+        }
+
+        debug!(snapshot_seq = from_seq, replayed, "event log replayed");
+        Ok(data)
+    }
+}
+
+/// `entry` as a journal line, newline included.
+fn to_line(entry: &Entry) -> Result<String, AppError> {
+    let json = serde_json::to_string(entry)
+        .map_err(|e| AppError::StorageError(std::io::Error::other(e)))?;
+    Ok(json + "\n")
+}
+
+fn segment_path(dir: &Path, start: u64) -> PathBuf {
+    dir.join(format!("journal-{:012}.jsonl", start))
+}
+
+/// The numbers in the names of files in `dir` made up of `prefix`, a number
+/// and `suffix`, in ascending order.
+fn numbered_files(dir: &Path, prefix: &str, suffix: &str) -> Result<Vec<u64>, AppError> {
+    let mut numbers: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_prefix(prefix)?
+                .strip_suffix(suffix)?
+                .parse()
+                .ok()
+        })
+        .collect();
+    numbers.sort();
+    Ok(numbers)
+}
+
+impl Storage for EventLogStorage {
+    fn load(&self) -> Result<AppData, AppError> {
+        self.replay(None)
+    }
+
+    fn load_as_of(&self, at: DateTime<Utc>) -> Result<AppData, AppError> {
+        self.replay(Some(at))
+    }
+
+    /// Takes a snapshot of `app_data` as the state after the last journaled
+    /// event. Used when data is replaced wholesale rather than changed.
+    fn save(&self, app_data: &AppData) -> Result<(), AppError> {
+        let mut journal = self.journal.lock().map_err(|_| AppError::LockError)?;
+        self.snapshot(&mut journal, app_data)
+    }
+
+    /// Appends `events` to the journal; the changed records are not needed
+    /// since replaying the events reproduces them.
+    fn record(&self, events: &[Event], _changes: &[Change]) -> Result<(), AppError> {
+        let mut journal = self.journal.lock().map_err(|_| AppError::LockError)?;
+
+        let mut lines = String::new();
+        for (offset, event) in events.iter().enumerate() {
+            let entry = Entry {
+                seq: journal.last_seq + 1 + offset as u64,
+                at: Utc::now(),
+                schema_version: migrations::CURRENT_VERSION,
+                event: event.clone(),
+            };
+            lines.push_str(&to_line(&entry)?);
+        }
+
+        // One write per request so a crash leaves at most one partial line
+        journal.file.write_all(lines.as_bytes())?;
+        journal.file.sync_data()?;
+        journal.last_seq += events.len() as u64;
+        journal.since_snapshot += events.len() as u64;
+
+        if journal.since_snapshot >= SNAPSHOT_INTERVAL {
+            // The journal is complete up to `last_seq` and cannot grow while
+            // it is locked here
+            let data = self.replay(None)?;
+            self.snapshot(&mut journal, &data)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use serde_json::{Map, Value, json};
+
+use crate::sessions;
+
+/// Schema version written by this build. Bump it together with a new entry
+/// in `MIGRATIONS` whenever the stored format changes.
+pub const CURRENT_VERSION: u32 = 6;
+
+/// Files written before versioning was introduced have no `schema_version`.
+pub const UNVERSIONED: u32 = 1;
+
+struct Migration {
+    /// Version the migration upgrades from, to `from + 1`
+    from: u32,
+    description: &'static str,
+    apply: fn(&mut Map<String, Value>),
+}
+
+/// Every migration in order, one per version step.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "fill in settlement options, currencies, splits and payers",
+        apply: fill_pre_versioning_defaults,
+    },
+    Migration {
+        from: 2,
+        description: "turn each user's token into a session, keeping only its hash",
+        apply: tokens_to_sessions,
+    },
+    Migration {
+        from: 3,
+        description: "give sessions an access token expiry and refresh token fields",
+        apply: add_refresh_tokens,
+    },
+    Migration {
+        from: 4,
+        description: "give users an empty list of API keys",
+        apply: add_api_keys,
+    },
+    Migration {
+        from: 5,
+        description: "give every group its own copy of the exchange rate table",
+        apply: rates_per_group,
+    },
+];
+
+/// Reads the schema version of a stored document.
+pub fn version_of(document: &Value) -> Result<u32, String> {
+    let object = document
+        .as_object()
+        .ok_or("expected a JSON object at the top level")?;
+    match object.get("schema_version") {
+        None => Ok(UNVERSIONED),
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| format!("invalid schema_version {}", version)),
+    }
+}
+
+/// Upgrades a stored document to `CURRENT_VERSION` in place, returning a
+/// description of each migration applied. Documents written by a newer
+/// build are rejected rather than guessed at.
+pub fn migrate(document: &mut Value) -> Result<Vec<String>, String> {
+    let mut version = version_of(document)?;
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "schema version {} is newer than this build supports ({})",
+            version, CURRENT_VERSION
+        ));
+    }
+
+    let object = document.as_object_mut().expect("checked by version_of");
+    let mut applied = Vec::new();
+    while version < CURRENT_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.from == version)
+            .ok_or_else(|| format!("no migration from schema version {}", version))?;
+        (migration.apply)(object);
+        applied.push(format!(
+            "{} -> {}: {}",
+            version,
+            version + 1,
+            migration.description
+        ));
+        version += 1;
+    }
+    object.insert("schema_version".to_string(), json!(version));
+    Ok(applied)
+}
+
+/// Upgrades one journaled event written at schema `version`, by migrating a
+/// document made of the records it carries. Events only hold whole users,
+/// groups and expenses, which are what migrations change.
+pub fn migrate_event(event: &mut Value, version: u32) -> Result<(), String> {
+    let event = event
+        .as_object_mut()
+        .ok_or("expected a JSON object for the event")?;
+    let user = event.remove("user");
+    let group = event.remove("group");
+    let expense = event.remove("expense");
+    let (has_user, has_group, has_expense) = (user.is_some(), group.is_some(), expense.is_some());
+
+    // An expense travels in a group of its own
+    let groups: Vec<Value> = group
+        .into_iter()
+        .chain(expense.map(|expense| json!({ "expenses": [expense] })))
+        .collect();
+    let mut document = json!({
+        "schema_version": version,
+        "users": Vec::from_iter(user),
+        "groups": groups,
+    });
+    migrate(&mut document)?;
+
+    if has_user {
+        event.insert("user".to_string(), document["users"][0].take());
+    }
+    let groups = &mut document["groups"];
+    if has_group {
+        event.insert("group".to_string(), groups[0].take());
+    }
+    if has_expense {
+        let wrapper = &mut groups[usize::from(has_group)];
+        event.insert("expense".to_string(), wrapper["expenses"][0].take());
+    }
+    Ok(())
+}
+
+fn set_missing(object: &mut Map<String, Value>, key: &str, value: Value) {
+    object.entry(key).or_insert(value);
+}
+
+fn objects<'a>(
+    object: &'a mut Map<String, Value>,
+    key: &str,
+) -> impl Iterator<Item = &'a mut Map<String, Value>> {
+    object
+        .get_mut(key)
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut)
+}
+
+/// Version 1 is the original format plus everything that was later added
+/// behind `#[serde(default)]`. Writes those defaults out explicitly.
+fn fill_pre_versioning_defaults(data: &mut Map<String, Value>) {
+    set_missing(data, "users", json!([]));
+    set_missing(data, "exchange_rates", json!([]));
+
+    for user in objects(data, "users") {
+        set_missing(user, "current_group_id", json!(0));
+        set_missing(user, "token_expires_at", Value::Null);
+    }
+
+    for group in objects(data, "groups") {
+        set_missing(group, "members", json!([]));
+        set_missing(group, "expenses", json!([]));
+        set_missing(group, "simplify_debts", json!(false));
+        set_missing(group, "settlement_algorithm", json!("greedy"));
+        set_missing(group, "optimal_member_limit", json!(16));
+        set_missing(group, "settlement_constraints", json!({}));
+        set_missing(group, "settled_settlements", json!([]));
+        set_missing(group, "currency", json!("USD"));
+
+        for expense in objects(group, "expenses") {
+            set_missing(expense, "payers", json!([]));
+            set_missing(expense, "split", json!({ "mode": "equal" }));
+            set_missing(expense, "shares", json!([]));
+            set_missing(
+                expense,
+                "created_at",
+                json!(chrono::Utc::now().to_rfc3339()),
+            );
+        }
+    }
+}
+
+/// Version 3 lets a user be signed in on several devices. The single token
+/// becomes the user's first session, so existing logins keep working.
+fn tokens_to_sessions(data: &mut Map<String, Value>) {
+    let now = chrono::Utc::now();
+    for user in objects(data, "users") {
+        let token = user.remove("token");
+        let expires_at = user.remove("token_expires_at");
+        let sessions: Vec<_> = token
+            .as_ref()
+            .and_then(Value::as_str)
+            .filter(|token| !token.is_empty())
+            .map(|token| {
+                let expires_at = expires_at.as_ref().and_then(Value::as_str);
+                sessions::carried_over(token, expires_at, now)
+            })
+            .into_iter()
+            .collect();
+        set_missing(user, "sessions", json!(sessions));
+    }
+}
+
+/// Version 4 splits sessions into short-lived access tokens and rotating
+/// refresh tokens. Existing sessions have neither an access expiry nor a
+/// refresh token, so their token keeps working until the session ends.
+fn add_refresh_tokens(data: &mut Map<String, Value>) {
+    for user in objects(data, "users") {
+        for session in objects(user, "sessions") {
+            set_missing(session, "access_expires_at", Value::Null);
+            set_missing(session, "refresh_hash", Value::Null);
+            set_missing(session, "used_refresh_hashes", json!([]));
+        }
+    }
+}
+
+/// Version 5 lets users create API keys for scripts. Nobody has any yet.
+fn add_api_keys(data: &mut Map<String, Value>) {
+    for user in objects(data, "users") {
+        set_missing(user, "api_keys", json!([]));
+    }
+}
+
+/// Version 6 keeps exchange rates per group, so one group's members cannot
+/// change another's conversions. Each group starts from the shared table.
+fn rates_per_group(data: &mut Map<String, Value>) {
+    let rates = data.remove("exchange_rates").unwrap_or_else(|| json!([]));
+    for group in objects(data, "groups") {
+        set_missing(group, "exchange_rates", rates.clone());
+    }
+}
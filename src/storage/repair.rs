@@ -0,0 +1,92 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::migrations;
+use crate::models::{AppData, ExchangeRate, Expense, Group, SettledSettlement};
+
+/// Recovers whatever can be understood from a data file that no longer
+/// deserializes as a whole. Records are kept or dropped one at a time, so one
+/// bad expense costs only that expense rather than its group or the file.
+/// Returns the recovered data and a line for every record that was dropped.
+pub fn salvage(mut document: Value) -> Result<(AppData, Vec<String>), String> {
+    migrations::migrate(&mut document)?;
+    let mut dropped = Vec::new();
+
+    let users = salvage_list(&mut document, "users", "user", &mut dropped);
+
+    let mut groups = Vec::new();
+    for (index, mut value) in take_array(&mut document, "groups").into_iter().enumerate() {
+        let id = value
+            .get("id")
+            .map(ToString::to_string)
+            .unwrap_or_else(|| format!("#{}", index + 1));
+        let label = match value.get("name").and_then(Value::as_str) {
+            Some(name) => format!("group {} '{}'", id, name),
+            None => format!("group {}", id),
+        };
+
+        let expenses: Vec<Expense> = salvage_list(
+            &mut value,
+            "expenses",
+            &format!("{} expense", label),
+            &mut dropped,
+        );
+        let settled: Vec<SettledSettlement> = salvage_list(
+            &mut value,
+            "settled_settlements",
+            &format!("{} settlement record", label),
+            &mut dropped,
+        );
+        let exchange_rates: Vec<ExchangeRate> = salvage_list(
+            &mut value,
+            "exchange_rates",
+            &format!("{} exchange rate", label),
+            &mut dropped,
+        );
+
+        match serde_json::from_value::<Group>(value) {
+            Ok(group) => groups.push(Group {
+                expenses,
+                settled_settlements: settled,
+                exchange_rates,
+                ..group
+            }),
+            Err(e) => dropped.push(format!("{} with {} expenses: {}", label, expenses.len(), e)),
+        }
+    }
+
+    let data = AppData { groups, users };
+    Ok((data, dropped))
+}
+
+/// Removes an array from `value`, leaving an empty one in its place.
+fn take_array(value: &mut Value, key: &str) -> Vec<Value> {
+    let Some(object) = value.as_object_mut() else {
+        return vec![];
+    };
+    match object.insert(key.to_string(), Value::Array(vec![])) {
+        Some(Value::Array(items)) => items,
+        _ => vec![],
+    }
+}
+
+fn salvage_list<T: DeserializeOwned>(
+    value: &mut Value,
+    key: &str,
+    label: &str,
+    dropped: &mut Vec<String>,
+) -> Vec<T> {
+    take_array(value, key)
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, item)| {
+            let id = item
+                .get("id")
+                .map(|id| format!("id {}", id))
+                .unwrap_or_else(|| format!("#{}", index + 1));
+            serde_json::from_value(item)
+                .map_err(|e| dropped.push(format!("{} {}: {}", label, id, e)))
+                .ok()
+        })
+        .collect()
+}
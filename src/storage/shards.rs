@@ -0,0 +1,219 @@
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+use super::encryption::{self, DataKey};
+use super::{Change, Storage, empty_data, migrations, write_synced};
+use crate::errors::{AppError, LoadError};
+use crate::events::Event;
+use crate::models::{AppData, AuthUser, Group};
+
+const USERS_FILE: &str = "users.json";
+/// The rate table shared by all groups before schema version 6. It is copied
+/// into every group file written at an older version as it is loaded, and
+/// removed by the next full save.
+const LEGACY_RATES_FILE: &str = "exchange_rates.json";
+const GROUPS_DIR: &str = "groups";
+
+/// Stores each group in its own file next to an index of users, so a change
+/// to one group rewrites only that group.
+///
+/// The directory holds `users.json` and `groups/<id>.json`. Every file
+/// records the schema version it was written with and is migrated on its own
+/// as it is loaded.
+pub struct ShardedStorage {
+    dir: PathBuf,
+    key: Option<DataKey>,
+}
+
+#[derive(Serialize)]
+struct Versioned<T: Serialize> {
+    schema_version: u32,
+    #[serde(flatten)]
+    data: T,
+}
+
+impl ShardedStorage {
+    pub fn open(dir: &str) -> Result<Self, AppError> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(dir.join(GROUPS_DIR))?;
+        Ok(ShardedStorage { dir, key: None })
+    }
+
+    pub fn with_key(self, key: Option<DataKey>) -> Self {
+        ShardedStorage { key, ..self }
+    }
+
+    fn group_path(&self, id: usize) -> PathBuf {
+        self.dir.join(GROUPS_DIR).join(format!("{}.json", id))
+    }
+
+    /// Reads one file as a full `AppData` document holding only that file's
+    /// records, migrated to the current schema. `wrap` places the file's
+    /// contents in the document.
+    fn read(
+        &self,
+        path: &Path,
+        wrap: impl FnOnce(Value) -> Value,
+    ) -> Result<Option<AppData>, LoadError> {
+        let name = path.to_string_lossy();
+        let Some(value) = self.read_value(path)? else {
+            return Ok(None);
+        };
+        let version = migrations::version_of(&value).map_err(|e| LoadError::new(&name, e))?;
+
+        let mut document = wrap(value);
+        document["schema_version"] = json!(version);
+        migrations::migrate(&mut document).map_err(|e| LoadError::new(&name, e))?;
+        serde_json::from_value(document)
+            .map(Some)
+            .map_err(|e| LoadError::from_json(&name, &e))
+    }
+
+    /// Reads one file as JSON, as it was written.
+    fn read_value(&self, path: &Path) -> Result<Option<Value>, LoadError> {
+        let name = path.to_string_lossy();
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(LoadError::new(&name, e)),
+        };
+        let text = encryption::decode(contents, self.key.as_ref(), false)
+            .map_err(|e| LoadError::new(&name, e))?;
+        serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|e| LoadError::from_json(&name, &e))
+    }
+
+    fn write<T: Serialize>(&self, path: &Path, data: T) -> Result<(), AppError> {
+        let versioned = Versioned {
+            schema_version: migrations::CURRENT_VERSION,
+            data,
+        };
+        let json = serde_json::to_string_pretty(&versioned)
+            .map_err(|e| AppError::StorageError(std::io::Error::other(e)))?;
+
+        let tmp_path = path.with_extension("json.tmp");
+        write_synced(&tmp_path, &encryption::encode(json, self.key.as_ref()))?;
+        fs::rename(&tmp_path, path)?;
+        debug!(path = %path.display(), "shard saved");
+        Ok(())
+    }
+
+    fn write_group(&self, group: &Group) -> Result<(), AppError> {
+        self.write(&self.group_path(group.id), group)
+    }
+
+    fn write_users(&self, users: &[AuthUser]) -> Result<(), AppError> {
+        self.write(&self.dir.join(USERS_FILE), json!({ "users": users }))
+    }
+
+    fn delete_group(&self, id: usize) -> Result<(), AppError> {
+        match fs::remove_file(self.group_path(id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every file holding data: the users, the legacy rate table and each
+    /// group.
+    pub fn files(&self) -> Result<Vec<String>, AppError> {
+        let mut paths = vec![self.dir.join(USERS_FILE), self.dir.join(LEGACY_RATES_FILE)];
+        paths.extend(self.group_ids()?.into_iter().map(|id| self.group_path(id)));
+        Ok(paths
+            .into_iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    /// Ids of the groups that have a file, in ascending order.
+    fn group_ids(&self) -> Result<Vec<usize>, AppError> {
+        let mut ids: Vec<usize> = fs::read_dir(self.dir.join(GROUPS_DIR))?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                path.file_name()?
+                    .to_str()?
+                    .strip_suffix(".json")?
+                    .parse()
+                    .ok()
+            })
+            .collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+}
+
+impl Storage for ShardedStorage {
+    /// Loads every file, reporting all unreadable ones together.
+    fn load(&self) -> Result<AppData, AppError> {
+        let mut data = empty_data();
+        let mut errors = Vec::new();
+
+        let users = self.read(&self.dir.join(USERS_FILE), |mut value| {
+            value["groups"] = json!([]);
+            value
+        });
+        match users {
+            Ok(Some(read)) => data.users = read.users,
+            Ok(None) => {}
+            Err(e) => errors.push(e),
+        }
+
+        let legacy_rates = match self.read_value(&self.dir.join(LEGACY_RATES_FILE)) {
+            Ok(value) => value.map_or(json!([]), |mut v| v["exchange_rates"].take()),
+            Err(e) => {
+                errors.push(e);
+                json!([])
+            }
+        };
+
+        for id in self.group_ids()? {
+            let group = self.read(
+                &self.group_path(id),
+                |value| json!({ "groups": [value], "exchange_rates": legacy_rates.clone() }),
+            );
+            match group {
+                Ok(Some(read)) => data.groups.extend(read.groups),
+                Ok(None) => {}
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(AppError::LoadError(errors));
+        }
+        Ok(data)
+    }
+
+    /// Writes every file and removes those of groups that no longer exist,
+    /// along with the legacy rate table. Each file is replaced atomically, but
+    /// not all of them together.
+    fn save(&self, app_data: &AppData) -> Result<(), AppError> {
+        self.write_users(&app_data.users)?;
+        for group in &app_data.groups {
+            self.write_group(group)?;
+        }
+        for id in self.group_ids()? {
+            if !app_data.groups.iter().any(|g| g.id == id) {
+                self.delete_group(id)?;
+            }
+        }
+        match fs::remove_file(self.dir.join(LEGACY_RATES_FILE)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn record(&self, _events: &[Event], changes: &[Change]) -> Result<(), AppError> {
+        for change in changes {
+            match *change {
+                Change::Group(group) => self.write_group(group)?,
+                Change::GroupDeleted(id) => self.delete_group(id)?,
+                Change::Users(users) => self.write_users(users)?,
+            }
+        }
+        Ok(())
+    }
+}
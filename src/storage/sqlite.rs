@@ -0,0 +1,1093 @@
+use rusqlite::{Connection, OptionalExtension, Row, Transaction, params};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::debug;
+
+use super::{Change, Storage};
+use crate::errors::AppError;
+use crate::events::Event;
+use crate::logic::sync_settlement;
+use crate::models::{
+    ApiKey, AppData, AuthUser, ExchangeRate, Expense, Group, Session, SettledSettlement,
+    SettlementConstraints, User,
+};
+use crate::money::Money;
+use crate::sessions;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY,
+    phone TEXT NOT NULL,
+    name TEXT NOT NULL,
+    current_group_id INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL,
+    device TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    last_used_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    access_expires_at TEXT,
+    refresh_hash TEXT,
+    used_refresh_hashes TEXT NOT NULL DEFAULT '[]'
+);
+CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    key_hash TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    last_used_at TEXT
+);
+CREATE TABLE IF NOT EXISTS "groups" (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    currency TEXT NOT NULL,
+    simplify_debts INTEGER NOT NULL,
+    settlement_algorithm TEXT NOT NULL,
+    optimal_member_limit INTEGER NOT NULL,
+    settlement_constraints TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS members (
+    group_id INTEGER NOT NULL REFERENCES "groups"(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    PRIMARY KEY (group_id, position)
+);
+CREATE TABLE IF NOT EXISTS expenses (
+    group_id INTEGER NOT NULL REFERENCES "groups"(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    id INTEGER NOT NULL,
+    description TEXT NOT NULL,
+    amount_cents INTEGER NOT NULL,
+    currency TEXT,
+    exchange_rate REAL,
+    payer_id INTEGER NOT NULL,
+    payer_name TEXT NOT NULL,
+    payers TEXT NOT NULL,
+    participants TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    category TEXT,
+    notes TEXT,
+    split TEXT NOT NULL,
+    itemization TEXT,
+    shares TEXT NOT NULL,
+    PRIMARY KEY (group_id, position)
+);
+CREATE TABLE IF NOT EXISTS settlements (
+    group_id INTEGER NOT NULL REFERENCES "groups"(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    from_name TEXT NOT NULL,
+    to_name TEXT NOT NULL,
+    amount_cents INTEGER NOT NULL,
+    settled_at TEXT NOT NULL,
+    expense_id INTEGER,
+    original_amount_cents INTEGER,
+    original_currency TEXT,
+    exchange_rate REAL,
+    PRIMARY KEY (group_id, position)
+);
+CREATE TABLE IF NOT EXISTS group_exchange_rates (
+    group_id INTEGER NOT NULL REFERENCES "groups"(id) ON DELETE CASCADE,
+    from_currency TEXT NOT NULL,
+    to_currency TEXT NOT NULL,
+    rate REAL NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (group_id, from_currency, to_currency)
+);
+CREATE INDEX IF NOT EXISTS sessions_by_user ON sessions (user_id);
+CREATE INDEX IF NOT EXISTS api_keys_by_user ON api_keys (user_id);
+CREATE INDEX IF NOT EXISTS members_by_user ON members (group_id, user_id);
+CREATE INDEX IF NOT EXISTS expenses_by_id ON expenses (group_id, id);
+CREATE INDEX IF NOT EXISTS settlements_by_expense ON settlements (group_id, expense_id);
+"#;
+
+/// Stores data in an embedded SQLite database with one table per kind of
+/// record. Nested expense details (splits, itemizations, payers) are kept as
+/// JSON columns. Recorded events update only the rows they touch.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+fn db_error(e: rusqlite::Error) -> AppError {
+    AppError::StorageError(std::io::Error::other(e))
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, AppError> {
+    serde_json::to_string(value).map_err(|e| AppError::StorageError(std::io::Error::other(e)))
+}
+
+fn from_json<T: DeserializeOwned>(column: &str, text: &str) -> Result<T, AppError> {
+    serde_json::from_str(text).map_err(|e| {
+        AppError::StorageError(std::io::Error::other(format!(
+            "invalid {} column: {}",
+            column, e
+        )))
+    })
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self, AppError> {
+        let mut conn = Connection::open(path).map_err(db_error)?;
+        // Other processes (e.g. the `backup` command) may hold the database
+        conn.busy_timeout(std::time::Duration::from_secs(5))
+            .map_err(db_error)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")
+            .map_err(db_error)?;
+        conn.execute_batch(SCHEMA).map_err(db_error)?;
+        upgrade_sessions(&conn)?;
+        upgrade_tokens(&mut conn)?;
+        upgrade_rates(&mut conn)?;
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> Result<AppData, AppError> {
+        let mut conn = self.conn.lock().map_err(|_| AppError::LockError)?;
+        // One read transaction so a concurrent writer (e.g. the server while
+        // the `backup` command runs) cannot be seen half-way through a save
+        let conn = conn.transaction().map_err(db_error)?;
+
+        let mut sessions: HashMap<usize, Vec<Session>> = HashMap::new();
+        {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT user_id, id, token_hash, device, created_at, last_used_at, expires_at,
+                            access_expires_at, refresh_hash, used_refresh_hashes
+                     FROM sessions ORDER BY user_id, created_at",
+                )
+                .map_err(db_error)?;
+            let mut rows = stmt.query([]).map_err(db_error)?;
+            while let Some(row) = rows.next().map_err(db_error)? {
+                let text = |i: usize| row.get::<_, String>(i).map_err(db_error);
+                let session = Session {
+                    id: text(1)?,
+                    token_hash: text(2)?,
+                    device: text(3)?,
+                    created_at: text(4)?,
+                    last_used_at: text(5)?,
+                    expires_at: text(6)?,
+                    access_expires_at: row.get(7).map_err(db_error)?,
+                    refresh_hash: row.get(8).map_err(db_error)?,
+                    used_refresh_hashes: from_json("used_refresh_hashes", &text(9)?)?,
+                };
+                let user_id: usize = row.get(0).map_err(db_error)?;
+                sessions.entry(user_id).or_default().push(session);
+            }
+        }
+
+        let mut api_keys: HashMap<usize, Vec<ApiKey>> = HashMap::new();
+        {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT user_id, id, name, key_hash, scope, created_at, last_used_at
+                     FROM api_keys ORDER BY user_id, rowid",
+                )
+                .map_err(db_error)?;
+            let mut rows = stmt.query([]).map_err(db_error)?;
+            while let Some(row) = rows.next().map_err(db_error)? {
+                let text = |i: usize| row.get::<_, String>(i).map_err(db_error);
+                let key = ApiKey {
+                    id: text(1)?,
+                    name: text(2)?,
+                    key_hash: text(3)?,
+                    scope: from_json("scope", &text(4)?)?,
+                    created_at: text(5)?,
+                    last_used_at: row.get(6).map_err(db_error)?,
+                };
+                let user_id: usize = row.get(0).map_err(db_error)?;
+                api_keys.entry(user_id).or_default().push(key);
+            }
+        }
+
+        let users = conn
+            .prepare("SELECT id, phone, name, current_group_id FROM users ORDER BY id")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| {
+                    let id = row.get(0)?;
+                    Ok(AuthUser {
+                        id,
+                        phone: row.get(1)?,
+                        name: row.get(2)?,
+                        current_group_id: row.get(3)?,
+                        sessions: sessions.remove(&id).unwrap_or_default(),
+                        api_keys: api_keys.remove(&id).unwrap_or_default(),
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(db_error)?;
+
+        let mut members: HashMap<usize, Vec<User>> = HashMap::new();
+        conn.prepare("SELECT group_id, user_id, name FROM members ORDER BY group_id, position")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| {
+                    Ok((
+                        row.get::<_, usize>(0)?,
+                        User {
+                            id: row.get(1)?,
+                            name: row.get(2)?,
+                        },
+                    ))
+                })?
+                .try_for_each(|row| {
+                    let (group_id, user) = row?;
+                    members.entry(group_id).or_default().push(user);
+                    Ok(())
+                })
+            })
+            .map_err(db_error)?;
+
+        let mut expenses: HashMap<usize, Vec<Expense>> = HashMap::new();
+        {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT group_id, id, description, amount_cents, currency, exchange_rate,
+                            payer_id, payer_name, payers, participants, created_at, category,
+                            notes, split, itemization, shares
+                     FROM expenses ORDER BY group_id, position",
+                )
+                .map_err(db_error)?;
+            let mut rows = stmt.query([]).map_err(db_error)?;
+            while let Some(row) = rows.next().map_err(db_error)? {
+                let text = |i: usize| row.get::<_, String>(i).map_err(db_error);
+                let itemization: Option<String> = row.get(14).map_err(db_error)?;
+                let expense = Expense {
+                    id: row.get(1).map_err(db_error)?,
+                    description: text(2)?,
+                    amount: Money::from_cents(row.get(3).map_err(db_error)?),
+                    currency: row.get(4).map_err(db_error)?,
+                    exchange_rate: row.get(5).map_err(db_error)?,
+                    payer: User {
+                        id: row.get(6).map_err(db_error)?,
+                        name: text(7)?,
+                    },
+                    payers: from_json("payers", &text(8)?)?,
+                    participants: from_json("participants", &text(9)?)?,
+                    created_at: text(10)?,
+                    category: row.get(11).map_err(db_error)?,
+                    notes: row.get(12).map_err(db_error)?,
+                    split: from_json("split", &text(13)?)?,
+                    itemization: itemization
+                        .map(|text| from_json("itemization", &text))
+                        .transpose()?,
+                    shares: from_json("shares", &text(15)?)?,
+                };
+                let group_id: usize = row.get(0).map_err(db_error)?;
+                expenses.entry(group_id).or_default().push(expense);
+            }
+        }
+
+        let mut settlements: HashMap<usize, Vec<SettledSettlement>> = HashMap::new();
+        conn.prepare(
+            "SELECT group_id, from_name, to_name, amount_cents, settled_at, expense_id,
+                    original_amount_cents, original_currency, exchange_rate
+             FROM settlements ORDER BY group_id, position",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok((row.get::<_, usize>(0)?, settlement_from_row(row, 1)?))
+            })?
+            .try_for_each(|row| {
+                let (group_id, settlement) = row?;
+                settlements.entry(group_id).or_default().push(settlement);
+                Ok(())
+            })
+        })
+        .map_err(db_error)?;
+
+        let mut rates: HashMap<usize, Vec<ExchangeRate>> = HashMap::new();
+        conn.prepare(
+            "SELECT group_id, from_currency, to_currency, rate, updated_at
+             FROM group_exchange_rates ORDER BY group_id, rowid",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, usize>(0)?,
+                    ExchangeRate {
+                        from: row.get(1)?,
+                        to: row.get(2)?,
+                        rate: row.get(3)?,
+                        updated_at: row.get(4)?,
+                    },
+                ))
+            })?
+            .try_for_each(|row| {
+                let (group_id, rate) = row?;
+                rates.entry(group_id).or_default().push(rate);
+                Ok(())
+            })
+        })
+        .map_err(db_error)?;
+
+        let mut groups = Vec::new();
+        {
+            let mut stmt = conn
+                .prepare(
+                    r#"SELECT id, name, currency, simplify_debts, settlement_algorithm,
+                              optimal_member_limit, settlement_constraints
+                       FROM "groups" ORDER BY id"#,
+                )
+                .map_err(db_error)?;
+            let mut rows = stmt.query([]).map_err(db_error)?;
+            while let Some(row) = rows.next().map_err(db_error)? {
+                let id: usize = row.get(0).map_err(db_error)?;
+                let algorithm: String = row.get(4).map_err(db_error)?;
+                let constraints: String = row.get(6).map_err(db_error)?;
+                groups.push(Group {
+                    id,
+                    name: row.get(1).map_err(db_error)?,
+                    members: members.remove(&id).unwrap_or_default(),
+                    expenses: expenses.remove(&id).unwrap_or_default(),
+                    simplify_debts: row.get(3).map_err(db_error)?,
+                    settlement_algorithm: from_json("settlement_algorithm", &algorithm)?,
+                    optimal_member_limit: row.get(5).map_err(db_error)?,
+                    settlement_constraints: from_json("settlement_constraints", &constraints)?,
+                    settled_settlements: settlements.remove(&id).unwrap_or_default(),
+                    currency: row.get(2).map_err(db_error)?,
+                    exchange_rates: rates.remove(&id).unwrap_or_default(),
+                });
+            }
+        }
+
+        Ok(AppData { groups, users })
+    }
+
+    fn save(&self, app_data: &AppData) -> Result<(), AppError> {
+        let mut conn = self.conn.lock().map_err(|_| AppError::LockError)?;
+        let tx = conn.transaction().map_err(db_error)?;
+
+        // Rewrite everything in one transaction so readers never see a mix
+        tx.execute_batch(
+            r#"DELETE FROM members; DELETE FROM expenses; DELETE FROM settlements;
+               DELETE FROM group_exchange_rates; DELETE FROM "groups"; DELETE FROM users;"#,
+        )
+        .map_err(db_error)?;
+        write_users(&tx, &app_data.users)?;
+        for group in &app_data.groups {
+            insert_group(&tx, group)?;
+        }
+
+        tx.commit().map_err(db_error)?;
+        debug!(
+            groups = app_data.groups.len(),
+            users = app_data.users.len(),
+            "data saved to sqlite"
+        );
+        Ok(())
+    }
+
+    /// Writes each event to the rows it touched, so adding an expense
+    /// inserts one row and refreshing a session updates that user's rows.
+    /// Changes recorded without events do not say which rows changed, so
+    /// their records are rewritten whole.
+    fn record(&self, events: &[Event], changes: &[Change]) -> Result<(), AppError> {
+        let mut conn = self.conn.lock().map_err(|_| AppError::LockError)?;
+        let tx = conn.transaction().map_err(db_error)?;
+
+        if events.is_empty() {
+            for change in changes {
+                match *change {
+                    Change::Group(group) => {
+                        delete_group(&tx, group.id)?;
+                        insert_group(&tx, group)?;
+                    }
+                    Change::GroupDeleted(id) => delete_group(&tx, id)?,
+                    Change::Users(users) => write_users(&tx, users)?,
+                }
+            }
+        }
+        for event in events {
+            apply_event(&tx, event)?;
+        }
+
+        tx.commit().map_err(db_error)?;
+        debug!(
+            events = events.len(),
+            changes = changes.len(),
+            "changes saved to sqlite"
+        );
+        Ok(())
+    }
+}
+
+/// Makes the changes `event` describes to the rows it touches, with the same
+/// effect `Event::apply` has in memory. Events about a group or user that is
+/// not stored are ignored in the same way.
+fn apply_event(tx: &Transaction, event: &Event) -> Result<(), AppError> {
+    match event {
+        Event::UserRegistered { user } => insert_user(tx, user),
+        Event::UserUpdated { user } => update_user(tx, user),
+        Event::GroupCreated { group } => insert_group(tx, group),
+        Event::GroupRenamed {
+            group_id,
+            name,
+            currency,
+        } => execute(
+            tx,
+            r#"UPDATE "groups" SET name = ?2, currency = ?3 WHERE id = ?1"#,
+            params![group_id, name, currency],
+        ),
+        Event::GroupDeleted { group_id } => delete_group(tx, *group_id),
+        Event::SettlementOptionsChanged {
+            group_id,
+            simplify_debts,
+            settlement_algorithm,
+            optimal_member_limit,
+            settlement_constraints,
+        } => execute(
+            tx,
+            r#"UPDATE "groups" SET simplify_debts = ?2, settlement_algorithm = ?3,
+                                   optimal_member_limit = ?4, settlement_constraints = ?5
+               WHERE id = ?1"#,
+            params![
+                group_id,
+                simplify_debts,
+                to_json(settlement_algorithm)?,
+                optimal_member_limit,
+                to_json(settlement_constraints)?
+            ],
+        ),
+        Event::MemberAdded { group_id, member } => {
+            if has_group(tx, *group_id)? {
+                let position = next_position(tx, "members", *group_id)?;
+                insert_member(tx, *group_id, position, member)?;
+            }
+            Ok(())
+        }
+        Event::MemberRemoved {
+            group_id,
+            member_id,
+        } => remove_member(tx, *group_id, *member_id),
+        Event::ExpenseCreated { group_id, expense } => {
+            if has_group(tx, *group_id)? {
+                let position = next_position(tx, "expenses", *group_id)?;
+                insert_expense(tx, *group_id, position, expense)?;
+            }
+            Ok(())
+        }
+        Event::ExpenseUpdated { group_id, expense } => update_expense(tx, *group_id, expense),
+        Event::ExpenseDeleted {
+            group_id,
+            expense_id,
+        } => delete_expense(tx, *group_id, *expense_id),
+        Event::SettlementRecorded {
+            group_id,
+            expense,
+            settlement,
+        } => {
+            if has_group(tx, *group_id)? {
+                let position = next_position(tx, "expenses", *group_id)?;
+                insert_expense(tx, *group_id, position, expense)?;
+                let position = next_position(tx, "settlements", *group_id)?;
+                insert_settlement(tx, *group_id, position, settlement)?;
+            }
+            Ok(())
+        }
+        // Events without a group predate per-group rates and apply to all
+        Event::ExchangeRateSet { group_id, rate } => {
+            // A currency pair is stored once, as `set_exchange_rate` does
+            delete_rate(tx, *group_id, &rate.from, &rate.to)?;
+            execute(
+                tx,
+                r#"INSERT INTO group_exchange_rates
+                       (group_id, from_currency, to_currency, rate, updated_at)
+                   SELECT id, ?2, ?3, ?4, ?5 FROM "groups" WHERE ?1 IS NULL OR id = ?1"#,
+                params![group_id, rate.from, rate.to, rate.rate, rate.updated_at],
+            )
+        }
+        Event::ExchangeRateDeleted { group_id, from, to } => delete_rate(tx, *group_id, from, to),
+    }
+}
+
+/// Deletes a currency pair's rate in either direction from one group, or
+/// from every group when `group_id` is `None`.
+fn delete_rate(
+    tx: &Transaction,
+    group_id: Option<usize>,
+    from: &str,
+    to: &str,
+) -> Result<(), AppError> {
+    execute(
+        tx,
+        "DELETE FROM group_exchange_rates
+         WHERE (?1 IS NULL OR group_id = ?1)
+           AND ((from_currency = ?2 AND to_currency = ?3)
+             OR (from_currency = ?3 AND to_currency = ?2))",
+        params![group_id, from, to],
+    )
+}
+
+fn execute(tx: &Transaction, sql: &str, params: impl rusqlite::Params) -> Result<(), AppError> {
+    tx.execute(sql, params).map_err(db_error)?;
+    Ok(())
+}
+
+fn has_group(tx: &Transaction, id: usize) -> Result<bool, AppError> {
+    tx.query_row(
+        r#"SELECT COUNT(*) > 0 FROM "groups" WHERE id = ?1"#,
+        params![id],
+        |row| row.get(0),
+    )
+    .map_err(db_error)
+}
+
+/// The position after the last row of a group in `table`.
+fn next_position(tx: &Transaction, table: &str, group_id: usize) -> Result<usize, AppError> {
+    tx.query_row(
+        &format!(
+            "SELECT COALESCE(MAX(position) + 1, 0) FROM {} WHERE group_id = ?1",
+            table
+        ),
+        params![group_id],
+        |row| row.get(0),
+    )
+    .map_err(db_error)
+}
+
+fn settlement_from_row(row: &Row, first: usize) -> rusqlite::Result<SettledSettlement> {
+    Ok(SettledSettlement {
+        from: row.get(first)?,
+        to: row.get(first + 1)?,
+        amount: Money::from_cents(row.get(first + 2)?),
+        settled_at: row.get(first + 3)?,
+        expense_id: row.get(first + 4)?,
+        original_amount: row.get::<_, Option<i64>>(first + 5)?.map(Money::from_cents),
+        original_currency: row.get(first + 6)?,
+        exchange_rate: row.get(first + 7)?,
+    })
+}
+
+/// Removes a member and drops them from the group's settlement constraints.
+fn remove_member(tx: &Transaction, group_id: usize, member_id: usize) -> Result<(), AppError> {
+    let member = tx
+        .query_row(
+            "SELECT rowid, name FROM members WHERE group_id = ?1 AND user_id = ?2
+             ORDER BY position LIMIT 1",
+            params![group_id, member_id],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()
+        .map_err(db_error)?;
+    let Some((rowid, name)) = member else {
+        return Ok(());
+    };
+    execute(tx, "DELETE FROM members WHERE rowid = ?1", params![rowid])?;
+
+    let constraints: String = tx
+        .query_row(
+            r#"SELECT settlement_constraints FROM "groups" WHERE id = ?1"#,
+            params![group_id],
+            |row| row.get(0),
+        )
+        .map_err(db_error)?;
+    let mut constraints: SettlementConstraints = from_json("settlement_constraints", &constraints)?;
+    constraints.remove_member(&name);
+    execute(
+        tx,
+        r#"UPDATE "groups" SET settlement_constraints = ?2 WHERE id = ?1"#,
+        params![group_id, to_json(&constraints)?],
+    )
+}
+
+/// What identifies a stored expense and the settle-up record it may have.
+struct StoredExpense {
+    rowid: i64,
+    category: Option<String>,
+    payer_name: String,
+    to_name: Option<String>,
+}
+
+impl StoredExpense {
+    fn find(tx: &Transaction, group_id: usize, id: usize) -> Result<Option<Self>, AppError> {
+        let found = tx
+            .query_row(
+                "SELECT rowid, category, payer_name, participants FROM expenses
+                 WHERE group_id = ?1 AND id = ?2 ORDER BY position LIMIT 1",
+                params![group_id, id],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                },
+            )
+            .optional()
+            .map_err(db_error)?;
+        let Some((rowid, category, payer_name, participants)) = found else {
+            return Ok(None);
+        };
+        let participants: Vec<User> = from_json("participants", &participants)?;
+        Ok(Some(StoredExpense {
+            rowid,
+            category,
+            payer_name,
+            to_name: participants.into_iter().next().map(|p| p.name),
+        }))
+    }
+
+    fn is_settlement(&self) -> bool {
+        self.category.as_deref() == Some("Settlement")
+    }
+}
+
+/// Matches the settle-up records of a payment expense: linked by id, or for
+/// older records by who paid whom. Takes the group id, expense id, payer and
+/// recipient as `?1` to `?4`.
+const SETTLEMENT_OF_EXPENSE: &str = "group_id = ?1 AND (expense_id = ?2
+    OR (expense_id IS NULL AND from_name = ?3 AND to_name = ?4))";
+
+/// Replaces a stored expense, updating or dropping its settle-up record as
+/// `logic::replace_expense` does.
+fn update_expense(tx: &Transaction, group_id: usize, expense: &Expense) -> Result<(), AppError> {
+    let Some(previous) = StoredExpense::find(tx, group_id, expense.id)? else {
+        return Ok(());
+    };
+
+    if previous.is_settlement() {
+        let record = tx
+            .query_row(
+                &format!(
+                    "SELECT rowid, from_name, to_name, amount_cents, settled_at, expense_id,
+                            original_amount_cents, original_currency, exchange_rate
+                     FROM settlements WHERE {} ORDER BY position LIMIT 1",
+                    SETTLEMENT_OF_EXPENSE
+                ),
+                params![group_id, expense.id, previous.payer_name, previous.to_name],
+                |row| Ok((row.get::<_, i64>(0)?, settlement_from_row(row, 1)?)),
+            )
+            .optional()
+            .map_err(db_error)?;
+        if let Some((rowid, mut record)) = record {
+            if sync_settlement(&mut record, expense) {
+                execute(
+                    tx,
+                    "UPDATE settlements SET from_name = ?2, to_name = ?3, amount_cents = ?4,
+                            expense_id = ?5, original_amount_cents = ?6,
+                            original_currency = ?7, exchange_rate = ?8
+                     WHERE rowid = ?1",
+                    params![
+                        rowid,
+                        record.from,
+                        record.to,
+                        record.amount.cents(),
+                        record.expense_id,
+                        record.original_amount.map(Money::cents),
+                        record.original_currency,
+                        record.exchange_rate
+                    ],
+                )?;
+            } else {
+                execute(
+                    tx,
+                    "DELETE FROM settlements WHERE rowid = ?1",
+                    params![rowid],
+                )?;
+            }
+        }
+    }
+
+    execute(
+        tx,
+        "UPDATE expenses SET description = ?2, amount_cents = ?3, currency = ?4,
+                exchange_rate = ?5, payer_id = ?6, payer_name = ?7, payers = ?8,
+                participants = ?9, created_at = ?10, category = ?11, notes = ?12,
+                split = ?13, itemization = ?14, shares = ?15
+         WHERE rowid = ?1",
+        params![
+            previous.rowid,
+            expense.description,
+            expense.amount.cents(),
+            expense.currency,
+            expense.exchange_rate,
+            expense.payer.id,
+            expense.payer.name,
+            to_json(&expense.payers)?,
+            to_json(&expense.participants)?,
+            expense.created_at,
+            expense.category,
+            expense.notes,
+            to_json(&expense.split)?,
+            expense.itemization.as_ref().map(to_json).transpose()?,
+            to_json(&expense.shares)?
+        ],
+    )
+}
+
+/// Deletes a stored expense along with its settle-up records, as
+/// `logic::remove_expense` does.
+fn delete_expense(tx: &Transaction, group_id: usize, id: usize) -> Result<(), AppError> {
+    let Some(previous) = StoredExpense::find(tx, group_id, id)? else {
+        return Ok(());
+    };
+    if previous.is_settlement() {
+        execute(
+            tx,
+            &format!("DELETE FROM settlements WHERE {}", SETTLEMENT_OF_EXPENSE),
+            params![group_id, id, previous.payer_name, previous.to_name],
+        )?;
+    }
+    execute(
+        tx,
+        "DELETE FROM expenses WHERE rowid = ?1",
+        params![previous.rowid],
+    )
+}
+
+/// Deletes a group; its members, expenses, settlements and rates go with it.
+fn delete_group(tx: &Transaction, id: usize) -> Result<(), AppError> {
+    tx.execute(r#"DELETE FROM "groups" WHERE id = ?1"#, params![id])
+        .map_err(db_error)?;
+    Ok(())
+}
+
+/// Rewrites the users table; their sessions and API keys are deleted with
+/// them and written back.
+fn write_users(tx: &Transaction, users: &[AuthUser]) -> Result<(), AppError> {
+    tx.execute("DELETE FROM users", []).map_err(db_error)?;
+    for user in users {
+        insert_user(tx, user)?;
+    }
+    Ok(())
+}
+
+fn insert_user(tx: &Transaction, user: &AuthUser) -> Result<(), AppError> {
+    tx.execute(
+        "INSERT INTO users (id, phone, name, current_group_id) VALUES (?1, ?2, ?3, ?4)",
+        params![user.id, user.phone, user.name, user.current_group_id],
+    )
+    .map_err(db_error)?;
+    for session in &user.sessions {
+        insert_session(tx, user.id, session)?;
+    }
+    for key in &user.api_keys {
+        insert_api_key(tx, user.id, key)?;
+    }
+    Ok(())
+}
+
+/// Updates one user's row, writes their current sessions and keys and
+/// deletes the ones they no longer have.
+fn update_user(tx: &Transaction, user: &AuthUser) -> Result<(), AppError> {
+    let updated = tx
+        .execute(
+            "UPDATE users SET phone = ?2, name = ?3, current_group_id = ?4 WHERE id = ?1",
+            params![user.id, user.phone, user.name, user.current_group_id],
+        )
+        .map_err(db_error)?;
+    if updated == 0 {
+        return Ok(());
+    }
+
+    let sessions: Vec<&str> = user.sessions.iter().map(|s| s.id.as_str()).collect();
+    delete_missing(tx, "sessions", user.id, &sessions)?;
+    for session in &user.sessions {
+        insert_session(tx, user.id, session)?;
+    }
+    let keys: Vec<&str> = user.api_keys.iter().map(|k| k.id.as_str()).collect();
+    delete_missing(tx, "api_keys", user.id, &keys)?;
+    for key in &user.api_keys {
+        insert_api_key(tx, user.id, key)?;
+    }
+    Ok(())
+}
+
+/// Deletes a user's rows in `table` whose id is not in `keep`.
+fn delete_missing(
+    tx: &Transaction,
+    table: &str,
+    user_id: usize,
+    keep: &[&str],
+) -> Result<(), AppError> {
+    let stored: Vec<String> = tx
+        .prepare(&format!("SELECT id FROM {} WHERE user_id = ?1", table))
+        .and_then(|mut stmt| {
+            stmt.query_map(params![user_id], |row| row.get(0))?
+                .collect::<Result<_, _>>()
+        })
+        .map_err(db_error)?;
+    for id in stored.iter().filter(|id| !keep.contains(&id.as_str())) {
+        execute(
+            tx,
+            &format!("DELETE FROM {} WHERE id = ?1", table),
+            params![id],
+        )?;
+    }
+    Ok(())
+}
+
+/// Inserts a session, or updates it if it is already stored.
+fn insert_session(tx: &Transaction, user_id: usize, session: &Session) -> Result<(), AppError> {
+    tx.execute(
+        "INSERT INTO sessions
+            (id, user_id, token_hash, device, created_at, last_used_at, expires_at,
+             access_expires_at, refresh_hash, used_refresh_hashes)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+         ON CONFLICT (id) DO UPDATE SET
+            token_hash = excluded.token_hash, device = excluded.device,
+            last_used_at = excluded.last_used_at, expires_at = excluded.expires_at,
+            access_expires_at = excluded.access_expires_at,
+            refresh_hash = excluded.refresh_hash,
+            used_refresh_hashes = excluded.used_refresh_hashes",
+        params![
+            session.id,
+            user_id,
+            session.token_hash,
+            session.device,
+            session.created_at,
+            session.last_used_at,
+            session.expires_at,
+            session.access_expires_at,
+            session.refresh_hash,
+            to_json(&session.used_refresh_hashes)?
+        ],
+    )
+    .map_err(db_error)?;
+    Ok(())
+}
+
+/// Inserts an API key, or updates it if it is already stored.
+fn insert_api_key(tx: &Transaction, user_id: usize, key: &ApiKey) -> Result<(), AppError> {
+    tx.execute(
+        "INSERT INTO api_keys (id, user_id, name, key_hash, scope, created_at, last_used_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+         ON CONFLICT (id) DO UPDATE SET
+            name = excluded.name, key_hash = excluded.key_hash, scope = excluded.scope,
+            last_used_at = excluded.last_used_at",
+        params![
+            key.id,
+            user_id,
+            key.name,
+            key.key_hash,
+            to_json(&key.scope)?,
+            key.created_at,
+            key.last_used_at
+        ],
+    )
+    .map_err(db_error)?;
+    Ok(())
+}
+
+/// Databases created before refresh tokens lack their session columns.
+/// Existing sessions get none, so their tokens keep working as before.
+fn upgrade_sessions(conn: &Connection) -> Result<(), AppError> {
+    let has_refresh: bool = conn
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('sessions') WHERE name = 'refresh_hash'",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(db_error)?;
+    if has_refresh {
+        return Ok(());
+    }
+    conn.execute_batch(
+        "ALTER TABLE sessions ADD COLUMN access_expires_at TEXT;
+         ALTER TABLE sessions ADD COLUMN refresh_hash TEXT;
+         ALTER TABLE sessions ADD COLUMN used_refresh_hashes TEXT NOT NULL DEFAULT '[]';",
+    )
+    .map_err(db_error)?;
+    debug!("added refresh token columns to sessions");
+    Ok(())
+}
+
+/// Databases created before users could have several sessions keep one
+/// token per user in the users table. Moves each token into a session,
+/// keeping only its hash, and drops the old columns.
+fn upgrade_tokens(conn: &mut Connection) -> Result<(), AppError> {
+    let has_tokens: bool = conn
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('users') WHERE name = 'token'",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(db_error)?;
+    if !has_tokens {
+        return Ok(());
+    }
+
+    let tx = conn.transaction().map_err(db_error)?;
+    let tokens = tx
+        .prepare("SELECT id, token, token_expires_at FROM users")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, usize>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(db_error)?;
+    let now = chrono::Utc::now();
+    for (user_id, token, expires_at) in tokens.iter().filter(|(_, token, _)| !token.is_empty()) {
+        let session = sessions::carried_over(token, expires_at.as_deref(), now);
+        insert_session(&tx, *user_id, &session)?;
+    }
+    tx.execute_batch(
+        "ALTER TABLE users DROP COLUMN token;
+         ALTER TABLE users DROP COLUMN token_expires_at;",
+    )
+    .map_err(db_error)?;
+    tx.commit().map_err(db_error)?;
+    debug!(users = tokens.len(), "moved user tokens into sessions");
+    Ok(())
+}
+
+/// Databases created before each group had its own rates keep a single
+/// table shared by all groups. Copies it into every group and drops it.
+fn upgrade_rates(conn: &mut Connection) -> Result<(), AppError> {
+    let has_shared: bool = conn
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'exchange_rates'",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(db_error)?;
+    if !has_shared {
+        return Ok(());
+    }
+
+    let tx = conn.transaction().map_err(db_error)?;
+    tx.execute_batch(
+        r#"INSERT OR REPLACE INTO group_exchange_rates
+               (group_id, from_currency, to_currency, rate, updated_at)
+           SELECT g.id, r.from_currency, r.to_currency, r.rate, r.updated_at
+           FROM "groups" g, exchange_rates r ORDER BY g.id, r.rowid;
+           DROP TABLE exchange_rates;"#,
+    )
+    .map_err(db_error)?;
+    tx.commit().map_err(db_error)?;
+    debug!("copied the shared exchange rates into every group");
+    Ok(())
+}
+
+fn insert_group(tx: &Transaction, group: &Group) -> Result<(), AppError> {
+    tx.execute(
+        r#"INSERT INTO "groups" (id, name, currency, simplify_debts, settlement_algorithm,
+                                 optimal_member_limit, settlement_constraints)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+        params![
+            group.id,
+            group.name,
+            group.currency,
+            group.simplify_debts,
+            to_json(&group.settlement_algorithm)?,
+            group.optimal_member_limit,
+            to_json(&group.settlement_constraints)?
+        ],
+    )
+    .map_err(db_error)?;
+
+    for (position, member) in group.members.iter().enumerate() {
+        insert_member(tx, group.id, position, member)?;
+    }
+    for (position, expense) in group.expenses.iter().enumerate() {
+        insert_expense(tx, group.id, position, expense)?;
+    }
+    for (position, settlement) in group.settled_settlements.iter().enumerate() {
+        insert_settlement(tx, group.id, position, settlement)?;
+    }
+    for rate in &group.exchange_rates {
+        tx.execute(
+            "INSERT INTO group_exchange_rates
+                 (group_id, from_currency, to_currency, rate, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![group.id, rate.from, rate.to, rate.rate, rate.updated_at],
+        )
+        .map_err(db_error)?;
+    }
+    Ok(())
+}
+
+fn insert_member(
+    tx: &Transaction,
+    group_id: usize,
+    position: usize,
+    member: &User,
+) -> Result<(), AppError> {
+    tx.execute(
+        "INSERT INTO members (group_id, position, user_id, name) VALUES (?1, ?2, ?3, ?4)",
+        params![group_id, position, member.id, member.name],
+    )
+    .map_err(db_error)?;
+    Ok(())
+}
+
+fn insert_expense(
+    tx: &Transaction,
+    group_id: usize,
+    position: usize,
+    expense: &Expense,
+) -> Result<(), AppError> {
+    tx.execute(
+        "INSERT INTO expenses (group_id, position, id, description, amount_cents,
+                               currency, exchange_rate, payer_id, payer_name, payers,
+                               participants, created_at, category, notes, split,
+                               itemization, shares)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15,
+                 ?16, ?17)",
+        params![
+            group_id,
+            position,
+            expense.id,
+            expense.description,
+            expense.amount.cents(),
+            expense.currency,
+            expense.exchange_rate,
+            expense.payer.id,
+            expense.payer.name,
+            to_json(&expense.payers)?,
+            to_json(&expense.participants)?,
+            expense.created_at,
+            expense.category,
+            expense.notes,
+            to_json(&expense.split)?,
+            expense.itemization.as_ref().map(to_json).transpose()?,
+            to_json(&expense.shares)?
+        ],
+    )
+    .map_err(db_error)?;
+    Ok(())
+}
+
+fn insert_settlement(
+    tx: &Transaction,
+    group_id: usize,
+    position: usize,
+    settlement: &SettledSettlement,
+) -> Result<(), AppError> {
+    tx.execute(
+        "INSERT INTO settlements (group_id, position, from_name, to_name, amount_cents,
+                                  settled_at, expense_id, original_amount_cents,
+                                  original_currency, exchange_rate)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            group_id,
+            position,
+            settlement.from,
+            settlement.to,
+            settlement.amount.cents(),
+            settlement.settled_at,
+            settlement.expense_id,
+            settlement.original_amount.map(Money::cents),
+            settlement.original_currency,
+            settlement.exchange_rate
+        ],
+    )
+    .map_err(db_error)?;
+    Ok(())
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error};
+
+use super::{Change, Storage};
+use crate::errors::AppError;
+use crate::events::Event;
+use crate::models::{AuthUser, Group};
+
+/// When a request's changes count as stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Each request waits until its changes are written.
+    #[default]
+    Sync,
+    /// Requests return as soon as their changes are queued; writes are
+    /// gathered over the batch window and stored together.
+    Batched,
+}
+
+/// An owned copy of a `Change`, so it can be handed to the writer thread.
+enum Changed {
+    Group(Box<Group>),
+    GroupDeleted(usize),
+    Users(Vec<AuthUser>),
+}
+
+impl From<&Change<'_>> for Changed {
+    fn from(change: &Change) -> Self {
+        match *change {
+            Change::Group(group) => Changed::Group(Box::new(group.clone())),
+            Change::GroupDeleted(id) => Changed::GroupDeleted(id),
+            Change::Users(users) => Changed::Users(users.to_vec()),
+        }
+    }
+}
+
+struct Job {
+    events: Vec<Event>,
+    changes: Vec<Changed>,
+    done: Option<oneshot::Sender<Result<(), String>>>,
+}
+
+/// Persists changes on a dedicated thread so handlers never wait on the disk
+/// while holding a lock or a runtime worker. Handlers queue their changes
+/// while still holding the lock they made them under, which keeps writes in
+/// order, then release it and wait on the returned `Pending`.
+pub struct StorageWriter {
+    queue: mpsc::UnboundedSender<Job>,
+    durability: Durability,
+}
+
+/// A queued write. Waiting on it returns once the write is stored, or
+/// straight away in batched mode.
+pub struct Pending(Option<oneshot::Receiver<Result<(), String>>>);
+
+impl Pending {
+    pub async fn wait(self) -> Result<(), AppError> {
+        let Some(done) = self.0 else {
+            return Ok(());
+        };
+        match done.await {
+            Ok(result) => result.map_err(|e| AppError::StorageError(std::io::Error::other(e))),
+            Err(_) => Err(AppError::StorageError(std::io::Error::other(
+                "storage writer stopped",
+            ))),
+        }
+    }
+}
+
+impl StorageWriter {
+    /// Starts the writer thread. In batched mode it waits `batch_window`
+    /// after the first queued write so later ones are stored with it.
+    pub fn spawn(
+        storage: Box<dyn Storage>,
+        durability: Durability,
+        batch_window: Duration,
+    ) -> Self {
+        let (queue, jobs) = mpsc::unbounded_channel();
+        let window = match durability {
+            Durability::Sync => Duration::ZERO,
+            Durability::Batched => batch_window,
+        };
+        thread::Builder::new()
+            .name("storage-writer".to_string())
+            .spawn(move || run(storage.as_ref(), jobs, window))
+            .expect("failed to start the storage writer thread");
+        StorageWriter { queue, durability }
+    }
+
+    /// Queues `events` and the records they changed.
+    pub fn record(&self, events: &[Event], changes: &[Change]) -> Pending {
+        let (done, receiver) = match self.durability {
+            Durability::Sync => {
+                let (done, receiver) = oneshot::channel();
+                (Some(done), Some(receiver))
+            }
+            Durability::Batched => (None, None),
+        };
+        let job = Job {
+            events: events.to_vec(),
+            changes: changes.iter().map(Changed::from).collect(),
+            done,
+        };
+        if self.queue.send(job).is_err() {
+            error!("storage writer stopped; change not stored");
+        }
+        Pending(receiver)
+    }
+
+    /// Waits until everything queued so far has been written.
+    pub async fn flush(&self) -> Result<(), AppError> {
+        let (done, receiver) = oneshot::channel();
+        let job = Job {
+            events: vec![],
+            changes: vec![],
+            done: Some(done),
+        };
+        if self.queue.send(job).is_err() {
+            return Err(AppError::StorageError(std::io::Error::other(
+                "storage writer stopped",
+            )));
+        }
+        Pending(Some(receiver)).wait().await
+    }
+}
+
+fn run(storage: &dyn Storage, mut jobs: mpsc::UnboundedReceiver<Job>, window: Duration) {
+    while let Some(first) = jobs.blocking_recv() {
+        if !window.is_zero() {
+            thread::sleep(window);
+        }
+        let mut batch = vec![first];
+        while let Ok(job) = jobs.try_recv() {
+            batch.push(job);
+        }
+        write_batch(storage, batch);
+    }
+    debug!("storage writer stopped");
+}
+
+/// Stores a batch with a single `record` call. Only the latest state of each
+/// changed record is written, and everyone waiting gets the same result.
+fn write_batch(storage: &dyn Storage, batch: Vec<Job>) {
+    let mut events = Vec::new();
+    let mut groups: BTreeMap<usize, Option<Group>> = BTreeMap::new();
+    let mut users = None;
+    let mut waiting = Vec::new();
+    let jobs = batch.len();
+
+    for job in batch {
+        events.extend(job.events);
+        for change in job.changes {
+            match change {
+                Changed::Group(group) => {
+                    groups.insert(group.id, Some(*group));
+                }
+                Changed::GroupDeleted(id) => {
+                    groups.insert(id, None);
+                }
+                Changed::Users(latest) => users = Some(latest),
+            }
+        }
+        waiting.extend(job.done);
+    }
+
+    let mut changes: Vec<Change> = groups
+        .iter()
+        .map(|(id, group)| match group {
+            Some(group) => Change::Group(group),
+            None => Change::GroupDeleted(*id),
+        })
+        .collect();
+    changes.extend(users.as_deref().map(Change::Users));
+
+    let result = if events.is_empty() && changes.is_empty() {
+        Ok(())
+    } else {
+        debug!(
+            jobs,
+            events = events.len(),
+            changes = changes.len(),
+            "writing batch"
+        );
+        storage.record(&events, &changes).map_err(|e| {
+            error!(error = %e, "could not store changes");
+            e.to_string()
+        })
+    };
+    for done in waiting {
+        // The request may have gone away; the write stands regardless
+        let _ = done.send(result.clone());
+    }
+}
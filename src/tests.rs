@@ -1,369 +1,3015 @@
-#[cfg(test)]
-mod tests {
-    use crate::logic::{
-        calculate_balances, calculate_settlements, calculate_simplified_settlements,
-    };
-    use crate::models::{Expense, Group, User};
+use crate::api_keys;
+use crate::cli::{PayerArg, ServeArgs, parse_interval};
+use crate::config::{AuthConfig, Config, Interval, LogFormat};
+use crate::errors::AppError;
+use crate::events::Event;
+use crate::handlers::{AppState, masked_phone};
+use crate::logic::{
+    Settlement, SettlementStatus, balance_period_start, calculate_balances,
+    calculate_optimal_settlements, calculate_settlements, calculate_simplified_settlements,
+    expense_shares, find_exchange_rate, group_total, replace_expense, settlement_plan,
+};
+use crate::models::{
+    ApiKeyScope, AppData, AuthUser, Contribution, ExchangeRate, Expense, Group, Itemization,
+    LineItem, PublicUser, SettledSettlement, SettlementAlgorithm, Split, User,
+};
+use crate::money::Money;
+use crate::otp::{CODE_DIGITS, Codes};
+use crate::rate_limit::RateLimiter;
+use crate::sessions;
+use crate::shutdown;
+use crate::sms::{self, SmsSender};
+use crate::storage::encryption::DataKey;
+use crate::storage::writer::{Durability, StorageWriter};
+use crate::storage::{self, Change, Storage};
+use proptest::prelude::*;
+use std::collections::HashMap;
+use std::time::Duration;
 
-    fn create_test_users() -> (User, User, User) {
-        (
-            User {
-                id: 1,
-                name: "Alice".to_string(),
-            },
-            User {
-                id: 2,
-                name: "Bob".to_string(),
-            },
-            User {
-                id: 3,
-                name: "Charlie".to_string(),
-            },
-        )
-    }
+fn money(amount: f64) -> Money {
+    Money::from_f64(amount).unwrap()
+}
 
-    fn create_expense(
-        id: usize,
-        description: &str,
-        amount: f64,
-        payer: User,
-        participants: Vec<User>,
-    ) -> Expense {
-        Expense {
-            id,
-            description: description.to_string(),
-            amount,
-            payer,
-            participants,
-            created_at: "2024-01-01T00:00:00Z".to_string(),
-            category: None,
-            notes: None,
-        }
+fn create_test_users() -> (User, User, User) {
+    (
+        User {
+            id: 1,
+            name: "Alice".to_string(),
+        },
+        User {
+            id: 2,
+            name: "Bob".to_string(),
+        },
+        User {
+            id: 3,
+            name: "Charlie".to_string(),
+        },
+    )
+}
+
+fn create_expense(
+    id: usize,
+    description: &str,
+    amount: f64,
+    payer: User,
+    participants: Vec<User>,
+) -> Expense {
+    Expense {
+        id,
+        description: description.to_string(),
+        amount: money(amount),
+        currency: None,
+        exchange_rate: None,
+        payer,
+        payers: vec![],
+        participants,
+        created_at: "2024-01-01T00:00:00Z".to_string(),
+        category: None,
+        notes: None,
+        split: Split::Equal,
+        itemization: None,
+        shares: vec![],
     }
+}
 
-    fn create_group(members: Vec<User>, expenses: Vec<Expense>) -> Group {
-        Group {
-            id: 1,
-            name: "Test Group".to_string(),
-            members,
-            expenses,
-            simplify_debts: false,
-            settled_settlements: vec![],
-        }
+fn create_group(members: Vec<User>, expenses: Vec<Expense>) -> Group {
+    Group {
+        id: 1,
+        name: "Test Group".to_string(),
+        members,
+        expenses,
+        simplify_debts: false,
+        settlement_algorithm: SettlementAlgorithm::Greedy,
+        optimal_member_limit: 16,
+        settlement_constraints: Default::default(),
+        settled_settlements: vec![],
+        currency: "USD".to_string(),
+        exchange_rates: vec![],
     }
+}
 
-    #[test]
-    fn test_calculate_balances_simple() {
-        let (alice, bob, _) = create_test_users();
-        let group = create_group(
+#[test]
+fn test_calculate_balances_simple() {
+    let (alice, bob, _) = create_test_users();
+    let group = create_group(
+        vec![alice.clone(), bob.clone()],
+        vec![create_expense(
+            1,
+            "Dinner",
+            50.0,
+            alice.clone(),
             vec![alice.clone(), bob.clone()],
-            vec![create_expense(
+        )],
+    );
+
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], money(25.0));
+    assert_eq!(balances["Bob"], money(-25.0));
+}
+
+#[test]
+fn test_calculate_balances_multiple_expenses() {
+    let (alice, bob, _) = create_test_users();
+    let group = create_group(
+        vec![alice.clone(), bob.clone()],
+        vec![
+            create_expense(
                 1,
                 "Dinner",
                 50.0,
                 alice.clone(),
                 vec![alice.clone(), bob.clone()],
-            )],
-        );
+            ),
+            create_expense(
+                2,
+                "Museum",
+                30.0,
+                bob.clone(),
+                vec![alice.clone(), bob.clone()],
+            ),
+        ],
+    );
 
-        let balances = calculate_balances(&group);
-        assert_eq!(balances["Alice"], 25.0);
-        assert_eq!(balances["Bob"], -25.0);
-    }
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], money(10.0));
+    assert_eq!(balances["Bob"], money(-10.0));
+}
 
-    #[test]
-    fn test_calculate_balances_multiple_expenses() {
-        let (alice, bob, _) = create_test_users();
-        let group = create_group(
+#[test]
+fn test_calculate_balances_no_expenses() {
+    let (alice, bob, _) = create_test_users();
+    let group = create_group(vec![alice.clone(), bob.clone()], vec![]);
+
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], money(0.0));
+    assert_eq!(balances["Bob"], money(0.0));
+}
+
+#[test]
+fn test_calculate_balances_three_people() {
+    let (alice, bob, charlie) = create_test_users();
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![create_expense(
+            1,
+            "Dinner",
+            90.0,
+            alice.clone(),
+            vec![alice.clone(), bob.clone(), charlie.clone()],
+        )],
+    );
+
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], money(60.0));
+    assert_eq!(balances["Bob"], money(-30.0));
+    assert_eq!(balances["Charlie"], money(-30.0));
+}
+
+#[test]
+fn test_calculate_balances_partial_split() {
+    let (alice, bob, charlie) = create_test_users();
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![create_expense(
+            1,
+            "Movie",
+            40.0,
+            alice.clone(),
             vec![alice.clone(), bob.clone()],
-            vec![
-                create_expense(
-                    1,
-                    "Dinner",
-                    50.0,
-                    alice.clone(),
-                    vec![alice.clone(), bob.clone()],
-                ),
-                create_expense(
-                    2,
-                    "Museum",
-                    30.0,
-                    bob.clone(),
-                    vec![alice.clone(), bob.clone()],
-                ),
-            ],
-        );
+        )],
+    );
 
-        let balances = calculate_balances(&group);
-        assert_eq!(balances["Alice"], 10.0);
-        assert_eq!(balances["Bob"], -10.0);
-    }
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], money(20.0));
+    assert_eq!(balances["Bob"], money(-20.0));
+    assert_eq!(balances["Charlie"], money(0.0));
+}
 
-    #[test]
-    fn test_calculate_balances_no_expenses() {
-        let (alice, bob, _) = create_test_users();
-        let group = create_group(vec![alice.clone(), bob.clone()], vec![]);
+#[test]
+fn test_settlements_simple() {
+    let (alice, bob, _) = create_test_users();
+    let group = create_group(
+        vec![alice.clone(), bob.clone()],
+        vec![create_expense(
+            1,
+            "Dinner",
+            50.0,
+            alice.clone(),
+            vec![alice.clone(), bob.clone()],
+        )],
+    );
 
-        let balances = calculate_balances(&group);
-        assert_eq!(balances["Alice"], 0.0);
-        assert_eq!(balances["Bob"], 0.0);
-    }
+    let settlements = calculate_settlements(&group);
+    assert_eq!(settlements.len(), 1);
+    assert_eq!(settlements[0].from, "Bob");
+    assert_eq!(settlements[0].to, "Alice");
+    assert_eq!(settlements[0].amount, money(25.0));
+}
 
-    #[test]
-    fn test_calculate_balances_three_people() {
-        let (alice, bob, charlie) = create_test_users();
-        let group = create_group(
+#[test]
+fn test_settlements_all_settled() {
+    let (alice, bob, _) = create_test_users();
+    let group = create_group(vec![alice.clone(), bob.clone()], vec![]);
+
+    let settlements = calculate_settlements(&group);
+    assert!(settlements.is_empty());
+}
+
+#[test]
+fn test_settlements_three_people() {
+    let (alice, bob, charlie) = create_test_users();
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![create_expense(
+            1,
+            "Dinner",
+            90.0,
+            alice.clone(),
             vec![alice.clone(), bob.clone(), charlie.clone()],
-            vec![create_expense(
+        )],
+    );
+
+    let settlements = calculate_settlements(&group);
+    assert_eq!(settlements.len(), 2);
+
+    let total_to_alice: Money = settlements
+        .iter()
+        .filter(|s| s.to == "Alice")
+        .map(|s| s.amount)
+        .sum();
+    assert_eq!(total_to_alice, money(60.0));
+}
+
+#[test]
+fn test_settlements_complex_scenario() {
+    let (alice, bob, charlie) = create_test_users();
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![
+            create_expense(
                 1,
                 "Dinner",
                 90.0,
                 alice.clone(),
                 vec![alice.clone(), bob.clone(), charlie.clone()],
-            )],
-        );
+            ),
+            create_expense(
+                2,
+                "Taxi",
+                30.0,
+                bob.clone(),
+                vec![alice.clone(), bob.clone(), charlie.clone()],
+            ),
+        ],
+    );
 
-        let balances = calculate_balances(&group);
-        assert_eq!(balances["Alice"], 60.0);
-        assert_eq!(balances["Bob"], -30.0);
-        assert_eq!(balances["Charlie"], -30.0);
-    }
+    // Use simplified settlements for total balance check
+    let settlements = calculate_simplified_settlements(&group);
 
-    #[test]
-    fn test_calculate_balances_partial_split() {
-        let (alice, bob, charlie) = create_test_users();
-        let group = create_group(
-            vec![alice.clone(), bob.clone(), charlie.clone()],
-            vec![create_expense(
-                1,
-                "Movie",
-                40.0,
-                alice.clone(),
-                vec![alice.clone(), bob.clone()],
-            )],
-        );
+    // Verify total settlements balance out (only true for simplified algorithm)
+    let balances = calculate_balances(&group);
+    let total_owed: Money = balances
+        .values()
+        .filter(|v| v.is_negative())
+        .map(|v| -*v)
+        .sum();
+    let total_settlements: Money = settlements.iter().map(|s| s.amount).sum();
 
-        let balances = calculate_balances(&group);
-        assert_eq!(balances["Alice"], 20.0);
-        assert_eq!(balances["Bob"], -20.0);
-        assert_eq!(balances["Charlie"], 0.0);
-    }
+    assert_eq!(total_owed, total_settlements);
+}
 
-    #[test]
-    fn test_settlements_simple() {
-        let (alice, bob, _) = create_test_users();
-        let group = create_group(
-            vec![alice.clone(), bob.clone()],
-            vec![create_expense(
+#[test]
+fn test_pairwise_settlements_stability() {
+    let (alice, bob, charlie) = create_test_users();
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![
+            create_expense(
                 1,
                 "Dinner",
-                50.0,
+                90.0,
                 alice.clone(),
-                vec![alice.clone(), bob.clone()],
-            )],
-        );
+                vec![alice.clone(), bob.clone(), charlie.clone()],
+            ),
+            create_expense(
+                2,
+                "Taxi",
+                30.0,
+                bob.clone(),
+                vec![alice.clone(), bob.clone(), charlie.clone()],
+            ),
+        ],
+    );
 
-        let settlements = calculate_settlements(&group);
-        assert_eq!(settlements.len(), 1);
-        assert_eq!(settlements[0].from, "Bob");
-        assert_eq!(settlements[0].to, "Alice");
-        assert_eq!(settlements[0].amount, 25.0);
-    }
+    let settlements = calculate_settlements(&group);
 
-    #[test]
-    fn test_settlements_all_settled() {
-        let (alice, bob, _) = create_test_users();
-        let group = create_group(vec![alice.clone(), bob.clone()], vec![]);
+    // With pairwise settlements:
+    // Dinner: Bob owes Alice $30, Charlie owes Alice $30
+    // Taxi: Alice owes Bob $10, Charlie owes Bob $10
+    // Net: Bob owes Alice $20, Charlie owes Alice $30, Charlie owes Bob $10
+    assert_eq!(settlements.len(), 3);
 
-        let settlements = calculate_settlements(&group);
-        assert!(settlements.is_empty());
-    }
+    let bob_to_alice = settlements
+        .iter()
+        .find(|s| s.from == "Bob" && s.to == "Alice");
+    let charlie_to_alice = settlements
+        .iter()
+        .find(|s| s.from == "Charlie" && s.to == "Alice");
+    let charlie_to_bob = settlements
+        .iter()
+        .find(|s| s.from == "Charlie" && s.to == "Bob");
 
-    #[test]
-    fn test_settlements_three_people() {
-        let (alice, bob, charlie) = create_test_users();
-        let group = create_group(
-            vec![alice.clone(), bob.clone(), charlie.clone()],
-            vec![create_expense(
+    assert!(bob_to_alice.is_some());
+    assert_eq!(bob_to_alice.unwrap().amount, money(20.0));
+
+    assert!(charlie_to_alice.is_some());
+    assert_eq!(charlie_to_alice.unwrap().amount, money(30.0));
+
+    assert!(charlie_to_bob.is_some());
+    assert_eq!(charlie_to_bob.unwrap().amount, money(10.0));
+}
+
+#[test]
+fn test_pairwise_settlements_after_settle() {
+    let (alice, bob, charlie) = create_test_users();
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![
+            create_expense(
                 1,
                 "Dinner",
                 90.0,
                 alice.clone(),
                 vec![alice.clone(), bob.clone(), charlie.clone()],
-            )],
-        );
+            ),
+            create_expense(
+                2,
+                "Taxi",
+                30.0,
+                bob.clone(),
+                vec![alice.clone(), bob.clone(), charlie.clone()],
+            ),
+            // Bob settles his debt to Alice ($20)
+            create_expense(3, "Bob paid Alice", 20.0, bob.clone(), vec![alice.clone()]),
+        ],
+    );
+
+    let settlements = calculate_settlements(&group);
+
+    // Verify Bob no longer owes Alice
+    let bob_to_alice = settlements
+        .iter()
+        .find(|s| s.from == "Bob" && s.to == "Alice");
+    assert!(
+        bob_to_alice.is_none(),
+        "Bob should not owe Alice after settlement"
+    );
 
-        let settlements = calculate_settlements(&group);
-        assert_eq!(settlements.len(), 2);
+    // Verify Charlie's settlements remain unchanged
+    let charlie_to_alice = settlements
+        .iter()
+        .find(|s| s.from == "Charlie" && s.to == "Alice");
+    let charlie_to_bob = settlements
+        .iter()
+        .find(|s| s.from == "Charlie" && s.to == "Bob");
+
+    assert!(charlie_to_alice.is_some());
+    assert_eq!(
+        charlie_to_alice.unwrap().amount,
+        money(30.0),
+        "Charlie→Alice should still be $30"
+    );
+
+    assert!(charlie_to_bob.is_some());
+    assert_eq!(
+        charlie_to_bob.unwrap().amount,
+        money(10.0),
+        "Charlie→Bob should still be $10"
+    );
+
+    assert_eq!(settlements.len(), 2, "Should have exactly 2 settlements");
+}
+
+#[test]
+fn test_balances_with_decimal_amounts() {
+    let (alice, bob, _) = create_test_users();
+    let group = create_group(
+        vec![alice.clone(), bob.clone()],
+        vec![create_expense(
+            1,
+            "Coffee",
+            7.50,
+            alice.clone(),
+            vec![alice.clone(), bob.clone()],
+        )],
+    );
+
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], money(3.75));
+    assert_eq!(balances["Bob"], money(-3.75));
+}
+
+#[test]
+fn test_uneven_split_balances_sum_to_zero() {
+    let (alice, bob, charlie) = create_test_users();
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![create_expense(
+            1,
+            "Pizza",
+            10.0,
+            alice.clone(),
+            vec![alice.clone(), bob.clone(), charlie.clone()],
+        )],
+    );
+
+    let balances = calculate_balances(&group);
+    let total: Money = balances.values().sum();
+    assert_eq!(total, Money::ZERO);
+
+    let settlements = calculate_settlements(&group);
+    let total_to_alice: Money = settlements.iter().map(|s| s.amount).sum();
+    assert_eq!(total_to_alice, balances["Alice"]);
+}
+
+#[test]
+fn test_money_parse_and_display() {
+    assert_eq!("12.34".parse::<Money>().unwrap(), money(12.34));
+    assert_eq!("7".parse::<Money>().unwrap(), money(7.0));
+    assert_eq!("-0.5".parse::<Money>().unwrap(), money(-0.5));
+    assert!("1.234".parse::<Money>().is_err());
+    assert!("abc".parse::<Money>().is_err());
+    assert_eq!(money(3.5).to_string(), "3.50");
+    assert_eq!(money(-0.07).to_string(), "-0.07");
+}
+
+#[test]
+fn test_money_accepts_legacy_float_json() {
+    let amount: Money = serde_json::from_str("33.333333333333336").unwrap();
+    assert_eq!(amount, money(33.33));
+    let amount: Money = serde_json::from_str("20").unwrap();
+    assert_eq!(amount, money(20.0));
+    assert_eq!(serde_json::to_string(&money(7.5)).unwrap(), "7.5");
+}
+
+#[test]
+fn test_amounts_beyond_the_limit_are_rejected() {
+    assert!(serde_json::from_str::<Money>("1000000000000").is_ok());
+    assert!(serde_json::from_str::<Money>("1000000000000.01").is_err());
+    assert!(serde_json::from_str::<Money>("92233720368547758").is_err());
+    assert!(serde_json::from_str::<Money>("1e300").is_err());
+    assert!("-1000000000001".parse::<Money>().is_err());
+    assert_eq!(Money::from_f64(f64::MAX), None);
+    assert_eq!(
+        Money::checked_sum([Money::MAX, Money::MAX]),
+        Some(Money::from_cents(2 * Money::MAX.cents()))
+    );
+    assert_eq!(
+        Money::checked_sum([Money::from_cents(i64::MAX), Money::from_cents(1)]),
+        None
+    );
+
+    // A group cannot grow past the limit, however it is reached
+    let (alice, bob, _) = create_test_users();
+    let mut huge = create_expense(1, "Huge", 1e12, alice.clone(), vec![alice, bob]);
+    assert_eq!(group_total([&huge]), Some(Money::MAX));
+    assert_eq!(group_total([&huge, &huge]), None);
+    huge.currency = Some("EUR".to_string());
+    huge.exchange_rate = Some(1e300);
+    assert_eq!(group_total([&huge]), None);
+}
+
+#[test]
+fn test_remainder_goes_to_payer_first() {
+    let (alice, bob, charlie) = create_test_users();
+    let expense = create_expense(
+        1,
+        "Pizza",
+        10.0,
+        bob.clone(),
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+    );
+
+    let shares: Vec<(String, Money)> = expense_shares(&expense)
+        .into_iter()
+        .map(|s| (s.name, s.amount))
+        .collect();
+    assert_eq!(
+        shares,
+        vec![
+            ("Alice".to_string(), money(3.33)),
+            ("Bob".to_string(), money(3.34)),
+            ("Charlie".to_string(), money(3.33)),
+        ]
+    );
+}
+
+#[test]
+fn test_remainder_follows_participant_order_without_payer() {
+    let (alice, bob, charlie) = create_test_users();
+    let expense = create_expense(
+        1,
+        "Gum",
+        0.05,
+        alice.clone(),
+        vec![charlie.clone(), bob.clone()],
+    );
+
+    let shares = expense_shares(&expense);
+    assert_eq!(shares[0].name, "Charlie");
+    assert_eq!(shares[0].amount, money(0.03));
+    assert_eq!(shares[1].name, "Bob");
+    assert_eq!(shares[1].amount, money(0.02));
+}
+
+#[test]
+fn test_percentage_split() {
+    let (alice, bob, _) = create_test_users();
+    let mut rent = create_expense(
+        1,
+        "Rent",
+        1000.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone()],
+    );
+    rent.split = Split::Percentage {
+        percentages: [("Alice".to_string(), 60.0), ("Bob".to_string(), 40.0)].into(),
+    };
+    let group = create_group(vec![alice.clone(), bob.clone()], vec![rent]);
 
-        let total_to_alice: f64 = settlements
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], money(400.0));
+    assert_eq!(balances["Bob"], money(-400.0));
+
+    let settlements = calculate_settlements(&group);
+    assert_eq!(settlements.len(), 1);
+    assert_eq!(settlements[0].from, "Bob");
+    assert_eq!(settlements[0].amount, money(400.0));
+}
+
+#[test]
+fn test_weighted_shares_split() {
+    let (alice, bob, charlie) = create_test_users();
+    let mut groceries = create_expense(
+        1,
+        "Groceries",
+        100.0,
+        bob.clone(),
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+    );
+    groceries.split = Split::Shares {
+        shares: [
+            ("Alice".to_string(), 2),
+            ("Bob".to_string(), 2),
+            ("Charlie".to_string(), 1),
+        ]
+        .into(),
+    };
+
+    let shares = expense_shares(&groceries);
+    assert_eq!(shares[0].amount, money(40.0));
+    assert_eq!(shares[1].amount, money(40.0));
+    assert_eq!(shares[2].amount, money(20.0));
+
+    groceries.amount = money(10.0);
+    let shares = expense_shares(&groceries);
+    // 4.00 / 4.00 / 2.00 divides evenly; 10.01 would not
+    assert_eq!(shares.iter().map(|s| s.amount).sum::<Money>(), money(10.0));
+    groceries.amount = money(10.01);
+    let shares = expense_shares(&groceries);
+    assert_eq!(shares[0].amount, money(4.0));
+    assert_eq!(shares[1].amount, money(4.01));
+    assert_eq!(shares[2].amount, money(2.0));
+}
+
+#[test]
+fn test_exact_split() {
+    let (alice, bob, charlie) = create_test_users();
+    let mut expense = create_expense(
+        1,
+        "Tickets",
+        75.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+    );
+    expense.split = Split::Exact {
+        amounts: [
+            ("Alice".to_string(), money(25.0)),
+            ("Bob".to_string(), money(35.5)),
+            ("Charlie".to_string(), money(14.5)),
+        ]
+        .into(),
+    };
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![expense],
+    );
+
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], money(50.0));
+    assert_eq!(balances["Bob"], money(-35.5));
+    assert_eq!(balances["Charlie"], money(-14.5));
+}
+
+#[test]
+fn test_multiple_payers_balances() {
+    let (alice, bob, charlie) = create_test_users();
+    let mut dinner = create_expense(
+        1,
+        "Dinner",
+        50.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+    );
+    dinner.payers = vec![
+        Contribution {
+            name: "Alice".to_string(),
+            amount: money(30.0),
+        },
+        Contribution {
+            name: "Bob".to_string(),
+            amount: money(20.0),
+        },
+    ];
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![dinner],
+    );
+
+    // Shares: Alice 16.67 (payer gets the extra cent), Bob 16.67, Charlie 16.66
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], money(13.33));
+    assert_eq!(balances["Bob"], money(3.33));
+    assert_eq!(balances["Charlie"], money(-16.66));
+    assert_eq!(balances.values().sum::<Money>(), Money::ZERO);
+}
+
+#[test]
+fn test_multiple_payers_pairwise_settlements_are_exact() {
+    let (alice, bob, charlie) = create_test_users();
+    let mut dinner = create_expense(
+        1,
+        "Dinner",
+        50.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+    );
+    dinner.payers = vec![
+        Contribution {
+            name: "Alice".to_string(),
+            amount: money(30.0),
+        },
+        Contribution {
+            name: "Bob".to_string(),
+            amount: money(20.0),
+        },
+    ];
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![dinner],
+    );
+
+    let balances = calculate_balances(&group);
+    let settlements = calculate_settlements(&group);
+    for (name, balance) in &balances {
+        let received: Money = settlements
             .iter()
-            .filter(|s| s.to == "Alice")
+            .filter(|s| s.to == *name)
             .map(|s| s.amount)
             .sum();
-        assert!((total_to_alice - 60.0).abs() < 0.01);
+        let paid: Money = settlements
+            .iter()
+            .filter(|s| s.from == *name)
+            .map(|s| s.amount)
+            .sum();
+        assert_eq!(received - paid, *balance, "{} does not reconcile", name);
     }
+}
 
-    #[test]
-    fn test_settlements_complex_scenario() {
-        let (alice, bob, charlie) = create_test_users();
-        let group = create_group(
-            vec![alice.clone(), bob.clone(), charlie.clone()],
-            vec![
-                create_expense(
-                    1,
-                    "Dinner",
-                    90.0,
-                    alice.clone(),
-                    vec![alice.clone(), bob.clone(), charlie.clone()],
-                ),
-                create_expense(
-                    2,
-                    "Taxi",
-                    30.0,
-                    bob.clone(),
-                    vec![alice.clone(), bob.clone(), charlie.clone()],
-                ),
+#[test]
+fn test_cli_checks_payers_like_the_api() {
+    let (alice, bob, charlie) = create_test_users();
+    let group = create_group(vec![alice, bob, charlie], vec![]);
+    let add = |payers: &[&str]| {
+        let payers: Vec<PayerArg> = payers.iter().map(|p| p.parse().unwrap()).collect();
+        crate::cli_expense(
+            &group,
+            "Dinner".to_string(),
+            money(50.0),
+            &payers,
+            "Alice,Bob,Charlie",
+        )
+    };
+
+    let expense = add(&["Alice:30", "Bob:20"]).unwrap();
+    assert_eq!(expense.payer.name, "Alice");
+    assert_eq!(expense.payers.len(), 2);
+    assert!(add(&["Bob"]).unwrap().payers.is_empty());
+
+    let error = add(&["Alice:30", "Alice:-10", "Bob:30"]).unwrap_err();
+    assert_eq!(error, "Payer 'Alice' is listed more than once");
+    let error = add(&["Alice:60", "Bob:-10"]).unwrap_err();
+    assert_eq!(error, "Amount paid by 'Bob' must be positive");
+    let error = add(&["Alice:50", "Bob:0"]).unwrap_err();
+    assert_eq!(error, "Amount paid by 'Bob' must be positive");
+    let error = add(&["Alice:30", "Dave:20"]).unwrap_err();
+    assert_eq!(error, "Payer 'Dave' not found");
+    let error = add(&["Alice:30", "Bob:10"]).unwrap_err();
+    assert!(error.contains("in total but the expense is"), "{}", error);
+    assert!(add(&["Alice:30", "Bob"]).is_err());
+}
+
+#[test]
+fn test_itemized_expense_shares_tax_and_tip_by_subtotal() {
+    let (alice, bob, charlie) = create_test_users();
+    let mut dinner = create_expense(
+        1,
+        "Restaurant",
+        72.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+    );
+    dinner.itemization = Some(Itemization {
+        items: vec![
+            LineItem {
+                description: "Steak".to_string(),
+                amount: money(30.0),
+                participants: vec!["Alice".to_string()],
+            },
+            LineItem {
+                description: "Pasta".to_string(),
+                amount: money(15.0),
+                participants: vec!["Bob".to_string()],
+            },
+            LineItem {
+                description: "Nachos".to_string(),
+                amount: money(15.0),
+                participants: vec![
+                    "Alice".to_string(),
+                    "Bob".to_string(),
+                    "Charlie".to_string(),
+                ],
+            },
+        ],
+        tax: money(4.8),
+        tip: money(7.2),
+    });
+
+    // Subtotals 35 / 20 / 5 of 60, so the 12.00 of tax and tip splits 7 / 4 / 1
+    let shares = expense_shares(&dinner);
+    assert_eq!(shares[0].amount, money(42.0));
+    assert_eq!(shares[1].amount, money(24.0));
+    assert_eq!(shares[2].amount, money(6.0));
+
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![dinner],
+    );
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], money(30.0));
+    assert_eq!(balances["Bob"], money(-24.0));
+    assert_eq!(balances["Charlie"], money(-6.0));
+}
+
+#[test]
+fn test_itemized_expense_rounding_adds_up() {
+    let (alice, bob, charlie) = create_test_users();
+    let mut dinner = create_expense(
+        1,
+        "Bar",
+        11.0,
+        bob.clone(),
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+    );
+    dinner.itemization = Some(Itemization {
+        items: vec![LineItem {
+            description: "Fries".to_string(),
+            amount: money(10.0),
+            participants: vec![
+                "Alice".to_string(),
+                "Bob".to_string(),
+                "Charlie".to_string(),
             ],
-        );
+        }],
+        tax: money(0.0),
+        tip: money(1.0),
+    });
+
+    let shares = expense_shares(&dinner);
+    assert_eq!(shares.iter().map(|s| s.amount).sum::<Money>(), money(11.0));
+    assert_eq!(shares[0].amount, money(3.66));
+    assert_eq!(shares[1].amount, money(3.68));
+}
 
-        // Use simplified settlements for total balance check
-        let settlements = calculate_simplified_settlements(&group);
+#[test]
+fn test_foreign_currency_expense_converts_to_group_currency() {
+    let (alice, bob, charlie) = create_test_users();
+    let mut hotel = create_expense(
+        1,
+        "Hotel",
+        100.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+    );
+    hotel.currency = Some("EUR".to_string());
+    hotel.exchange_rate = Some(1.1);
+    let taxi = create_expense(
+        2,
+        "Taxi",
+        10.0,
+        bob.clone(),
+        vec![alice.clone(), bob.clone()],
+    );
+    let group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![hotel, taxi],
+    );
 
-        // Verify total settlements balance out (only true for simplified algorithm)
-        let balances = calculate_balances(&group);
-        let total_owed: f64 = balances.values().filter(|&&v| v < 0.0).map(|v| -v).sum();
-        let total_settlements: f64 = settlements.iter().map(|s| s.amount).sum();
+    // 110.00 USD split 36.68 / 36.66 / 36.66 in proportion to the EUR shares
+    // 33.34 / 33.33 / 33.33, plus the 10.00 USD taxi
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], money(68.32));
+    assert_eq!(balances["Bob"], money(-31.66));
+    assert_eq!(balances["Charlie"], money(-36.66));
+    assert_eq!(balances.values().sum::<Money>(), Money::ZERO);
+
+    let settlements = calculate_settlements(&group);
+    let to_alice: Money = settlements
+        .iter()
+        .filter(|s| s.to == "Alice")
+        .map(|s| s.amount)
+        .sum();
+    assert_eq!(to_alice, balances["Alice"]);
+}
+
+#[test]
+fn test_find_exchange_rate_uses_inverse() {
+    let rates = vec![ExchangeRate {
+        from: "EUR".to_string(),
+        to: "USD".to_string(),
+        rate: 1.25,
+        updated_at: "2024-01-01T00:00:00Z".to_string(),
+    }];
 
-        assert!((total_owed - total_settlements).abs() < 0.01);
+    assert_eq!(find_exchange_rate(&rates, "EUR", "USD"), Some(1.25));
+    assert_eq!(find_exchange_rate(&rates, "USD", "EUR"), Some(0.8));
+    assert_eq!(find_exchange_rate(&rates, "USD", "USD"), Some(1.0));
+    assert_eq!(find_exchange_rate(&rates, "GBP", "USD"), None);
+}
+
+#[test]
+fn test_settlement_in_foreign_currency_reduces_converted_balance() {
+    let (alice, bob, _) = create_test_users();
+    let dinner = create_expense(
+        1,
+        "Dinner",
+        100.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone()],
+    );
+    let mut repayment = create_expense(2, "Bob paid Alice", 40.0, bob.clone(), vec![alice.clone()]);
+    repayment.currency = Some("EUR".to_string());
+    repayment.exchange_rate = Some(1.25);
+    repayment.category = Some("Settlement".to_string());
+    let group = create_group(vec![alice.clone(), bob.clone()], vec![dinner, repayment]);
+
+    let balances = calculate_balances(&group);
+    assert_eq!(balances["Alice"], Money::ZERO);
+    assert_eq!(balances["Bob"], Money::ZERO);
+    assert!(calculate_settlements(&group).is_empty());
+}
+
+fn create_payment(id: usize, amount: f64, from: &User, to: &User) -> (Expense, SettledSettlement) {
+    let mut expense = create_expense(
+        id,
+        &format!("{} paid {}", from.name, to.name),
+        amount,
+        from.clone(),
+        vec![to.clone()],
+    );
+    expense.category = Some("Settlement".to_string());
+    let record = SettledSettlement {
+        from: from.name.clone(),
+        to: to.name.clone(),
+        amount: money(amount),
+        settled_at: "2024-01-02T00:00:00Z".to_string(),
+        expense_id: Some(id),
+        original_amount: None,
+        original_currency: None,
+        exchange_rate: None,
+    };
+    (expense, record)
+}
+
+#[test]
+fn test_partial_settlement_leaves_remaining_debt() {
+    let (alice, bob, _) = create_test_users();
+    let dinner = create_expense(
+        1,
+        "Dinner",
+        100.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone()],
+    );
+    let (payment, record) = create_payment(2, 5.0, &bob, &alice);
+    let mut group = create_group(vec![alice.clone(), bob.clone()], vec![dinner, payment]);
+    group.settled_settlements.push(record);
+
+    let plan = settlement_plan(&group).settlements;
+    assert_eq!(plan.len(), 1);
+    assert_eq!(plan[0].from, "Bob");
+    assert_eq!(plan[0].amount, money(45.0));
+    assert_eq!(plan[0].paid, money(5.0));
+    assert_eq!(plan[0].total, money(50.0));
+    assert_eq!(plan[0].status, SettlementStatus::PartiallyPaid);
+    assert!(!plan[0].settled);
+}
+
+#[test]
+fn test_editing_a_payment_updates_its_settlement_record() {
+    let (alice, bob, _) = create_test_users();
+    let dinner = create_expense(
+        1,
+        "Dinner",
+        100.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone()],
+    );
+    let (payment, record) = create_payment(2, 5.0, &bob, &alice);
+    let mut group = create_group(vec![alice.clone(), bob.clone()], vec![dinner, payment]);
+    group.settled_settlements.push(record);
+
+    // Paying in another currency changes what the record counts
+    let mut edited = group.expenses[1].clone();
+    edited.amount = money(20.0);
+    edited.currency = Some("EUR".to_string());
+    edited.exchange_rate = Some(1.5);
+    replace_expense(&mut group, edited.clone()).unwrap();
+    let record = &group.settled_settlements[0];
+    assert_eq!(record.amount, money(30.0));
+    assert_eq!(record.original_amount, Some(money(20.0)));
+    assert_eq!(settlement_plan(&group).settlements[0].paid, money(30.0));
+
+    // Replaying the change gives the same record
+    let mut replayed = create_group(vec![alice.clone(), bob.clone()], vec![]);
+    replayed.expenses = group.expenses.clone();
+    replayed.expenses[1] = create_payment(2, 5.0, &bob, &alice).0;
+    replayed.settled_settlements = vec![create_payment(2, 5.0, &bob, &alice).1];
+    let mut data = AppData {
+        groups: vec![replayed],
+        users: vec![],
+    };
+    Event::ExpenseUpdated {
+        group_id: 1,
+        expense: edited.clone(),
     }
+    .apply(&mut data);
+    assert_eq!(data.groups[0].settled_settlements[0].amount, money(30.0));
 
-    #[test]
-    fn test_pairwise_settlements_stability() {
-        let (alice, bob, charlie) = create_test_users();
-        let group = create_group(
-            vec![alice.clone(), bob.clone(), charlie.clone()],
-            vec![
-                create_expense(
-                    1,
-                    "Dinner",
-                    90.0,
-                    alice.clone(),
-                    vec![alice.clone(), bob.clone(), charlie.clone()],
-                ),
-                create_expense(
-                    2,
-                    "Taxi",
-                    30.0,
-                    bob.clone(),
-                    vec![alice.clone(), bob.clone(), charlie.clone()],
-                ),
-            ],
-        );
+    // An expense that no longer records a payment has no record
+    edited.category = None;
+    edited.participants = vec![alice, bob];
+    replace_expense(&mut group, edited).unwrap();
+    assert!(group.settled_settlements.is_empty());
+}
 
-        let settlements = calculate_settlements(&group);
+#[test]
+fn test_full_settlement_shows_paid_until_period_ends() {
+    let (alice, bob, charlie) = create_test_users();
+    let dinner = create_expense(
+        1,
+        "Dinner",
+        90.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+    );
+    let (payment, record) = create_payment(2, 30.0, &bob, &alice);
+    let mut group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![dinner, payment],
+    );
+    group.settled_settlements.push(record);
 
-        // With pairwise settlements:
-        // Dinner: Bob owes Alice $30, Charlie owes Alice $30
-        // Taxi: Alice owes Bob $10, Charlie owes Bob $10
-        // Net: Bob owes Alice $20, Charlie owes Alice $30, Charlie owes Bob $10
-        assert_eq!(settlements.len(), 3);
+    let plan = settlement_plan(&group).settlements;
+    let bob_to_alice = plan.iter().find(|s| s.from == "Bob").unwrap();
+    assert_eq!(bob_to_alice.status, SettlementStatus::Paid);
+    assert_eq!(bob_to_alice.amount, Money::ZERO);
+    assert!(bob_to_alice.settled);
+    let charlie_to_alice = plan.iter().find(|s| s.from == "Charlie").unwrap();
+    assert_eq!(charlie_to_alice.status, SettlementStatus::Outstanding);
+    assert_eq!(charlie_to_alice.amount, money(30.0));
+}
 
-        let bob_to_alice = settlements
-            .iter()
-            .find(|s| s.from == "Bob" && s.to == "Alice");
-        let charlie_to_alice = settlements
-            .iter()
-            .find(|s| s.from == "Charlie" && s.to == "Alice");
-        let charlie_to_bob = settlements
-            .iter()
-            .find(|s| s.from == "Charlie" && s.to == "Bob");
+#[test]
+fn test_new_debt_after_settling_up_is_outstanding() {
+    let (alice, bob, _) = create_test_users();
+    let dinner = create_expense(
+        1,
+        "Dinner",
+        100.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone()],
+    );
+    let (payment, record) = create_payment(2, 50.0, &bob, &alice);
+    let lunch = create_expense(
+        3,
+        "Lunch",
+        20.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone()],
+    );
+    let mut group = create_group(
+        vec![alice.clone(), bob.clone()],
+        vec![dinner, payment, lunch],
+    );
+    group.settled_settlements.push(record);
+
+    let plan = settlement_plan(&group).settlements;
+    assert_eq!(plan.len(), 1);
+    assert_eq!(plan[0].amount, money(10.0));
+    assert_eq!(plan[0].paid, Money::ZERO);
+    assert_eq!(plan[0].status, SettlementStatus::Outstanding);
+    assert!(!plan[0].settled);
+}
 
-        assert!(bob_to_alice.is_some());
-        assert!((bob_to_alice.unwrap().amount - 20.0).abs() < 0.01);
+#[test]
+fn test_pairwise_debt_cycle_does_not_end_the_period() {
+    let (alice, bob, charlie) = create_test_users();
+    let expenses = vec![
+        create_expense(1, "Taxi", 20.0, alice.clone(), vec![bob.clone()]),
+        create_expense(2, "Coffee", 20.0, bob.clone(), vec![charlie.clone()]),
+        create_expense(3, "Tickets", 20.0, charlie.clone(), vec![alice.clone()]),
+    ];
+    let mut group = create_group(vec![alice, bob, charlie], expenses);
+
+    // Every net balance is zero, but each pair still owes 20
+    assert!(calculate_balances(&group).values().all(|b| b.is_zero()));
+    assert_eq!(balance_period_start(&group), 0);
+    let plan = settlement_plan(&group).settlements;
+    assert_eq!(plan.len(), 3);
+    assert!(
+        plan.iter()
+            .all(|s| s.amount == money(20.0) && s.status == SettlementStatus::Outstanding)
+    );
+
+    // Simplified debts net the cycle away
+    group.simplify_debts = true;
+    assert_eq!(balance_period_start(&group), 3);
+    assert!(settlement_plan(&group).settlements.is_empty());
+}
 
-        assert!(charlie_to_alice.is_some());
-        assert!((charlie_to_alice.unwrap().amount - 30.0).abs() < 0.01);
+fn create_users(names: &[&str]) -> Vec<User> {
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| User {
+            id: i + 1,
+            name: name.to_string(),
+        })
+        .collect()
+}
 
-        assert!(charlie_to_bob.is_some());
-        assert!((charlie_to_bob.unwrap().amount - 10.0).abs() < 0.01);
+/// Checks that carrying out `settlements` brings every balance to zero.
+fn settles_exactly(balances: &HashMap<String, Money>, settlements: &[Settlement]) -> bool {
+    let mut remaining = balances.clone();
+    for s in settlements {
+        *remaining.get_mut(&s.from).unwrap() += s.amount;
+        *remaining.get_mut(&s.to).unwrap() -= s.amount;
     }
+    remaining.values().all(|b| b.is_zero())
+}
 
-    #[test]
-    fn test_pairwise_settlements_after_settle() {
-        let (alice, bob, charlie) = create_test_users();
-        let group = create_group(
-            vec![alice.clone(), bob.clone(), charlie.clone()],
-            vec![
-                create_expense(
-                    1,
-                    "Dinner",
-                    90.0,
-                    alice.clone(),
-                    vec![alice.clone(), bob.clone(), charlie.clone()],
-                ),
-                create_expense(
-                    2,
-                    "Taxi",
-                    30.0,
-                    bob.clone(),
-                    vec![alice.clone(), bob.clone(), charlie.clone()],
-                ),
-                // Bob settles his debt to Alice ($20)
-                create_expense(3, "Bob paid Alice", 20.0, bob.clone(), vec![alice.clone()]),
-            ],
-        );
+#[test]
+fn test_optimal_settlements_find_zero_sum_subgroups() {
+    let users = create_users(&["A", "B", "C", "D", "E"]);
+    let (a, b, c, d, e) = (&users[0], &users[1], &users[2], &users[3], &users[4]);
+    let mut group = create_group(
+        users.clone(),
+        vec![
+            create_expense(1, "Taxi", 6.0, b.clone(), vec![a.clone()]),
+            create_expense(2, "Tickets", 5.0, c.clone(), vec![e.clone()]),
+            create_expense(3, "Snacks", 6.0, d.clone(), vec![e.clone()]),
+        ],
+    );
+
+    let balances = calculate_balances(&group);
+    let greedy = calculate_simplified_settlements(&group);
+    let optimal = calculate_optimal_settlements(&group);
+    assert_eq!(greedy.len(), 4);
+    assert_eq!(optimal.len(), 3);
+    assert!(settles_exactly(&balances, &optimal));
 
-        let settlements = calculate_settlements(&group);
+    group.simplify_debts = true;
+    group.settlement_algorithm = SettlementAlgorithm::Optimal;
+    assert_eq!(settlement_plan(&group).settlements.len(), 3);
 
-        // Verify Bob no longer owes Alice
-        let bob_to_alice = settlements
+    // Above the member limit the greedy plan is used instead
+    group.optimal_member_limit = 4;
+    assert_eq!(settlement_plan(&group).settlements.len(), 4);
+}
+
+#[test]
+fn test_forbidden_pair_is_relayed_and_explained() {
+    let users = create_users(&["A", "B", "C"]);
+    let (a, c) = (&users[0], &users[2]);
+    let mut group = create_group(
+        users.clone(),
+        vec![create_expense(
+            1,
+            "Dinner",
+            10.0,
+            c.clone(),
+            vec![a.clone()],
+        )],
+    );
+    group.settlement_constraints.forbidden_pairs = vec![("C".to_string(), "A".to_string())];
+
+    let balances = calculate_balances(&group);
+    let plan = settlement_plan(&group);
+    assert_eq!(plan.unconstrained_payments, 1);
+    assert_eq!(plan.settlements.len(), 2);
+    assert!(
+        plan.settlements
             .iter()
-            .find(|s| s.from == "Bob" && s.to == "Alice");
-        assert!(
-            bob_to_alice.is_none(),
-            "Bob should not owe Alice after settlement"
-        );
+            .all(|s| !(s.from == "A" && s.to == "C"))
+    );
+    assert!(settles_exactly(&balances, &plan.settlements));
+    assert!(plan.explanations[0].contains("2 payments instead of 1"));
+}
+
+#[test]
+fn test_hub_routes_every_payment() {
+    let users = create_users(&["A", "B", "C"]);
+    let (a, b, c) = (&users[0], &users[1], &users[2]);
+    let mut group = create_group(
+        users.clone(),
+        vec![create_expense(
+            1,
+            "Cabin",
+            20.0,
+            a.clone(),
+            vec![b.clone(), c.clone()],
+        )],
+    );
+    group.simplify_debts = true;
+    group.settlement_constraints.hub = Some("B".to_string());
 
-        // Verify Charlie's settlements remain unchanged
-        let charlie_to_alice = settlements
+    let balances = calculate_balances(&group);
+    let plan = settlement_plan(&group);
+    assert!(settles_exactly(&balances, &plan.settlements));
+    assert!(
+        plan.settlements
             .iter()
-            .find(|s| s.from == "Charlie" && s.to == "Alice");
-        let charlie_to_bob = settlements
+            .all(|s| s.from == "B" || s.to == "B")
+    );
+    let hub_payment = plan.settlements.iter().find(|s| s.from == "B").unwrap();
+    assert_eq!(hub_payment.amount, money(20.0));
+    assert_eq!(plan.settlements.len(), plan.unconstrained_payments);
+}
+
+#[test]
+fn test_removed_member_leaves_settlement_constraints() {
+    let users = create_users(&["A", "B", "C", "D"]);
+    let mut group = create_group(users.clone(), vec![]);
+    let constraints = &mut group.settlement_constraints;
+    constraints.hub = Some("D".to_string());
+    constraints.preferred_receivers = vec!["D".to_string(), "A".to_string()];
+    constraints.forbidden_pairs = vec![
+        ("B".to_string(), "D".to_string()),
+        ("B".to_string(), "C".to_string()),
+    ];
+    let mut data = AppData {
+        groups: vec![group],
+        users: vec![],
+    };
+
+    Event::MemberRemoved {
+        group_id: 1,
+        member_id: users[3].id,
+    }
+    .apply(&mut data);
+    let group = &data.groups[0];
+    assert_eq!(group.members.len(), 3);
+    let constraints = &group.settlement_constraints;
+    assert_eq!(constraints.hub, None);
+    assert_eq!(constraints.preferred_receivers, vec!["A".to_string()]);
+    assert_eq!(
+        constraints.forbidden_pairs,
+        vec![("B".to_string(), "C".to_string())]
+    );
+
+    // The plan only involves members who are left
+    let mut group = group.clone();
+    group.expenses = vec![create_expense(
+        1,
+        "Dinner",
+        30.0,
+        users[0].clone(),
+        users[..3].to_vec(),
+    )];
+    let plan = settlement_plan(&group);
+    assert!(
+        plan.settlements
             .iter()
-            .find(|s| s.from == "Charlie" && s.to == "Bob");
+            .all(|s| s.from != "D" && s.to != "D")
+    );
+}
 
-        assert!(charlie_to_alice.is_some());
-        assert!(
-            (charlie_to_alice.unwrap().amount - 30.0).abs() < 0.01,
-            "Charlie→Alice should still be $30"
+fn arbitrary_group() -> impl Strategy<Value = Group> {
+    (2usize..8).prop_flat_map(|size| {
+        let expense = (
+            0..size,
+            proptest::collection::vec(any::<bool>(), size),
+            1i64..10_000,
         );
+        proptest::collection::vec(expense, 0..12).prop_map(move |expenses| {
+            let names: Vec<String> = (0..size).map(|i| format!("M{}", i)).collect();
+            let names: Vec<&str> = names.iter().map(String::as_str).collect();
+            let users = create_users(&names);
+            let expenses = expenses
+                .into_iter()
+                .enumerate()
+                .map(|(i, (payer, included, cents))| {
+                    let mut participants: Vec<User> = users
+                        .iter()
+                        .zip(&included)
+                        .filter(|(_, inc)| **inc)
+                        .map(|(u, _)| u.clone())
+                        .collect();
+                    if participants.is_empty() {
+                        participants.push(users[payer].clone());
+                    }
+                    create_expense(
+                        i + 1,
+                        "Random",
+                        cents as f64 / 100.0,
+                        users[payer].clone(),
+                        participants,
+                    )
+                })
+                .collect();
+            create_group(users, expenses)
+        })
+    })
+}
+
+proptest! {
+    #[test]
+    fn prop_optimal_settlements_never_use_more_payments_than_greedy(
+        group in arbitrary_group()
+    ) {
+        let balances = calculate_balances(&group);
+        let greedy = calculate_simplified_settlements(&group);
+        let optimal = calculate_optimal_settlements(&group);
+
+        prop_assert!(settles_exactly(&balances, &greedy));
+        prop_assert!(settles_exactly(&balances, &optimal));
+        prop_assert!(optimal.len() <= greedy.len());
+        prop_assert!(optimal.iter().all(|s| s.amount.is_positive()));
+    }
+}
+
+fn sample_app_data() -> AppData {
+    let (alice, bob, charlie) = create_test_users();
+    let mut dinner = create_expense(
+        1,
+        "Dinner",
+        90.0,
+        alice.clone(),
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+    );
+    dinner.currency = Some("EUR".to_string());
+    dinner.exchange_rate = Some(1.1);
+    dinner.payers = vec![
+        Contribution {
+            name: "Alice".to_string(),
+            amount: money(60.0),
+        },
+        Contribution {
+            name: "Bob".to_string(),
+            amount: money(30.0),
+        },
+    ];
+    dinner.split = Split::Shares {
+        shares: [("Alice".to_string(), 2), ("Bob".to_string(), 1)]
+            .into_iter()
+            .collect(),
+    };
+    dinner.shares = expense_shares(&dinner);
+    let (payment, record) = create_payment(2, 10.0, &charlie, &alice);
+
+    let mut group = create_group(
+        vec![alice.clone(), bob.clone(), charlie.clone()],
+        vec![dinner, payment],
+    );
+    group.settled_settlements = vec![record];
+    group.settlement_constraints.hub = Some("Alice".to_string());
+    group.exchange_rates = vec![ExchangeRate {
+        from: "EUR".to_string(),
+        to: "USD".to_string(),
+        rate: 1.1,
+        updated_at: "2024-01-01T00:00:00Z".to_string(),
+    }];
+
+    AppData {
+        groups: vec![group],
+        users: vec![AuthUser {
+            id: 1,
+            phone: "5551234567".to_string(),
+            name: "Alice".to_string(),
+            current_group_id: 1,
+            sessions: vec![],
+            api_keys: vec![],
+        }],
+    }
+}
+
+fn temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("splitdumb-{}-{}", uuid::Uuid::new_v4(), name))
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[test]
+fn test_storage_backends_round_trip() {
+    let data = sample_app_data();
+    let expected = serde_json::to_value(&data).unwrap();
+
+    for spec in [
+        format!("json:{}", temp_path("data.json")),
+        format!("sqlite:{}", temp_path("data.db")),
+        format!("events:{}", temp_path("events")),
+        format!("shards:{}", temp_path("shards")),
+    ] {
+        let backend = storage::open(&spec, None).unwrap();
+        let empty = backend.load().unwrap();
+        assert!(empty.groups.is_empty() && empty.users.is_empty());
+
+        backend.save(&data).unwrap();
+        // Saving twice must replace rather than duplicate rows
+        backend.save(&data).unwrap();
+        let reopened = storage::open(&spec, None).unwrap();
+        let loaded = serde_json::to_value(reopened.load().unwrap()).unwrap();
+        assert_eq!(loaded, expected, "{}", spec);
+    }
+}
+
+#[test]
+fn test_recording_changes_updates_only_touched_records() {
+    let mut data = sample_app_data();
+    let mut second = data.groups[0].clone();
+    second.id = 2;
+    second.name = "Second".to_string();
+    data.groups.push(second);
 
-        assert!(charlie_to_bob.is_some());
-        assert!(
-            (charlie_to_bob.unwrap().amount - 10.0).abs() < 0.01,
-            "Charlie→Bob should still be $10"
+    for spec in [
+        format!("json:{}", temp_path("data.json")),
+        format!("sqlite:{}", temp_path("data.db")),
+        format!("shards:{}", temp_path("shards")),
+    ] {
+        let backend = storage::open(&spec, None).unwrap();
+        backend.save(&data).unwrap();
+
+        let mut expected = data.clone();
+        expected.groups[1].name = "Renamed".to_string();
+        expected.groups[1].exchange_rates.clear();
+        backend
+            .record(&[], &[Change::Group(&expected.groups[1])])
+            .unwrap();
+
+        let reopened = storage::open(&spec, None).unwrap();
+        assert_eq!(
+            serde_json::to_value(reopened.load().unwrap()).unwrap(),
+            serde_json::to_value(&expected).unwrap(),
+            "{}",
+            spec
         );
 
-        assert_eq!(settlements.len(), 2, "Should have exactly 2 settlements");
+        expected.groups.remove(0);
+        backend.record(&[], &[Change::GroupDeleted(1)]).unwrap();
+        let loaded = storage::open(&spec, None).unwrap().load().unwrap();
+        assert_eq!(loaded.groups.len(), 1, "{}", spec);
+        assert_eq!(loaded.groups[0].name, "Renamed", "{}", spec);
     }
 
-    #[test]
-    fn test_balances_with_decimal_amounts() {
-        let (alice, bob, _) = create_test_users();
-        let group = create_group(
-            vec![alice.clone(), bob.clone()],
-            vec![create_expense(
-                1,
-                "Coffee",
-                7.50,
-                alice.clone(),
-                vec![alice.clone(), bob.clone()],
-            )],
+    // A sharded group change leaves every other file alone
+    let dir = temp_path("shards");
+    let backend = storage::open(&format!("shards:{}", dir), None).unwrap();
+    backend.save(&data).unwrap();
+    let group_one = std::path::Path::new(&dir).join("groups").join("1.json");
+    let users = std::path::Path::new(&dir).join("users.json");
+    std::fs::remove_file(&group_one).unwrap();
+    std::fs::remove_file(&users).unwrap();
+    backend
+        .record(&[], &[Change::Group(&data.groups[1])])
+        .unwrap();
+    assert!(!group_one.exists() && !users.exists());
+}
+
+#[test]
+fn test_sqlite_writes_only_the_rows_events_touch() {
+    let mut data = sample_app_data();
+    let mut second = data.groups[0].clone();
+    second.id = 2;
+    data.groups.push(second);
+    let db = temp_path("rows.db");
+    let backend = storage::open(&format!("sqlite:{}", db), None).unwrap();
+    backend.save(&data).unwrap();
+    let rowids = |group_id: usize| -> Vec<(i64, usize)> {
+        let conn = rusqlite::Connection::open(&db).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT rowid, id FROM expenses WHERE group_id = ?1 ORDER BY position")
+            .unwrap();
+        stmt.query_map([group_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    };
+    let before = rowids(1);
+
+    let (alice, _, charlie) = create_test_users();
+    let dave = User {
+        id: 4,
+        name: "Dave".to_string(),
+    };
+    let mut options = data.groups[0].clone();
+    options.settlement_constraints.hub = Some("Dave".to_string());
+    let mut payment = data.groups[0].expenses[1].clone();
+    payment.amount = money(15.0);
+    payment.shares = expense_shares(&payment);
+    let (repayment, record) = create_payment(4, 5.0, &alice, &charlie);
+    let mut user = data.users[0].clone();
+    let (session, _) = sessions::start("Laptop", &hour_long_sessions(), chrono::Utc::now());
+    user.sessions.push(session.clone());
+    user.current_group_id = 2;
+    let mut signed_out = user.clone();
+    signed_out.sessions.clear();
+    api_keys::add(
+        &mut signed_out,
+        "Import",
+        ApiKeyScope::ReadOnly,
+        chrono::Utc::now(),
+    )
+    .unwrap();
+
+    let events = [
+        Event::ExpenseCreated {
+            group_id: 1,
+            expense: create_expense(3, "Taxi", 12.0, alice.clone(), vec![alice.clone()]),
+        },
+        Event::ExpenseUpdated {
+            group_id: 1,
+            expense: payment,
+        },
+        Event::MemberAdded {
+            group_id: 1,
+            member: dave,
+        },
+        Event::options_changed(&options),
+        Event::MemberRemoved {
+            group_id: 1,
+            member_id: 4,
+        },
+        Event::UserUpdated { user },
+        Event::UserUpdated { user: signed_out },
+        Event::ExchangeRateSet {
+            group_id: Some(2),
+            rate: ExchangeRate {
+                from: "USD".to_string(),
+                to: "EUR".to_string(),
+                rate: 0.9,
+                updated_at: "2024-02-01T00:00:00Z".to_string(),
+            },
+        },
+        Event::ExpenseDeleted {
+            group_id: 2,
+            expense_id: 2,
+        },
+        Event::SettlementRecorded {
+            group_id: 1,
+            expense: repayment,
+            settlement: record,
+        },
+        Event::GroupRenamed {
+            group_id: 2,
+            name: "Renamed".to_string(),
+            currency: "USD".to_string(),
+        },
+        Event::MemberAdded {
+            group_id: 9,
+            member: alice,
+        },
+    ];
+    backend.record(&events, &[]).unwrap();
+    for event in &events {
+        event.apply(&mut data);
+    }
+
+    let loaded = storage::open(&format!("sqlite:{}", db), None)
+        .unwrap()
+        .load()
+        .unwrap();
+    assert_eq!(
+        serde_json::to_value(&loaded).unwrap(),
+        serde_json::to_value(&data).unwrap()
+    );
+    let group = &loaded.groups[0];
+    assert_eq!(group.settled_settlements[0].amount, money(15.0));
+    assert_eq!(group.settlement_constraints.hub, None);
+    assert!(loaded.groups[1].settled_settlements.is_empty());
+    assert_eq!(loaded.users[0].api_keys.len(), 1);
+    assert!(loaded.users[0].sessions.is_empty());
+
+    // Rows no event touched were left where they were
+    assert_eq!(rowids(1)[..2], before[..]);
+}
+
+#[test]
+fn test_app_state_locks_groups_independently() {
+    let mut data = sample_app_data();
+    let mut second = data.groups[0].clone();
+    second.id = 2;
+    data.groups.push(second);
+    let backend = storage::open(&temp_path("data.json"), None).unwrap();
+    let writer = StorageWriter::spawn(backend, Durability::Sync, Duration::ZERO);
+    let state = AppState::new(data, writer);
+
+    let first = state.group(1).unwrap().unwrap();
+    let _writing = first.write().unwrap();
+    let second = state.group(2).unwrap().unwrap();
+    assert!(second.try_write().is_ok());
+    assert!(state.users.try_read().is_ok());
+    assert!(state.group(3).unwrap().is_none());
+    assert_eq!(state.all_groups().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_storage_writer_durability_modes() {
+    let data = sample_app_data();
+
+    // Sync: the write is on disk once waited on
+    let path = temp_path("data.json");
+    let writer = StorageWriter::spawn(
+        storage::open(&path, None).unwrap(),
+        Durability::Sync,
+        Duration::ZERO,
+    );
+    writer
+        .record(&[], &[Change::Group(&data.groups[0])])
+        .wait()
+        .await
+        .unwrap();
+    let loaded = storage::open(&path, None).unwrap().load().unwrap();
+    assert_eq!(loaded.groups.len(), 1);
+
+    // Batched: later changes to the same record replace earlier ones and
+    // all of them are written by the time a flush returns
+    let dir = temp_path("events");
+    let writer = StorageWriter::spawn(
+        storage::open(&format!("events:{}", dir), None).unwrap(),
+        Durability::Batched,
+        Duration::from_millis(20),
+    );
+    let mut group = data.groups[0].clone();
+    let created = Event::GroupCreated {
+        group: group.clone(),
+    };
+    writer
+        .record(&[created], &[Change::Group(&group)])
+        .wait()
+        .await
+        .unwrap();
+    group.name = "Renamed".to_string();
+    let renamed = Event::GroupRenamed {
+        group_id: group.id,
+        name: group.name.clone(),
+        currency: group.currency.clone(),
+    };
+    writer.record(&[renamed], &[Change::Group(&group)]);
+    writer.flush().await.unwrap();
+    let loaded = storage::open(&format!("events:{}", dir), None)
+        .unwrap()
+        .load()
+        .unwrap();
+    assert_eq!(loaded.groups[0].name, "Renamed");
+
+    // Sync waiters hear about failed writes
+    let missing_dir = format!("{}/data.json", temp_path("missing"));
+    let writer = StorageWriter::spawn(
+        storage::open(&missing_dir, None).unwrap(),
+        Durability::Sync,
+        Duration::ZERO,
+    );
+    let result = writer
+        .record(&[], &[Change::Group(&data.groups[0])])
+        .wait()
+        .await;
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_event_log_replays_history() {
+    let dir = temp_path("events");
+    let sample = sample_app_data();
+    let group = sample.groups[0].clone();
+    let events = vec![
+        Event::UserRegistered {
+            user: sample.users[0].clone(),
+        },
+        Event::GroupCreated {
+            group: Group {
+                expenses: vec![],
+                settled_settlements: vec![],
+                exchange_rates: vec![],
+                ..group.clone()
+            },
+        },
+        Event::ExpenseCreated {
+            group_id: 1,
+            expense: group.expenses[0].clone(),
+        },
+        Event::SettlementRecorded {
+            group_id: 1,
+            expense: group.expenses[1].clone(),
+            settlement: group.settled_settlements[0].clone(),
+        },
+        Event::ExchangeRateSet {
+            group_id: Some(1),
+            rate: group.exchange_rates[0].clone(),
+        },
+        Event::ExpenseDeleted {
+            group_id: 1,
+            expense_id: 2,
+        },
+    ];
+
+    let backend = storage::open(&format!("events:{}", dir), None).unwrap();
+    let mut data = backend.load().unwrap();
+    let mut history = vec![];
+    for event in &events {
+        event.apply(&mut data);
+        backend.record(std::slice::from_ref(event), &[]).unwrap();
+        history.push((chrono::Utc::now(), serde_json::to_value(&data).unwrap()));
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    assert!(data.groups[0].settled_settlements.is_empty());
+
+    // A crash mid-append leaves a partial line that must not break loading
+    let journal = std::path::Path::new(&dir).join("journal-000000000001.jsonl");
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&journal)
+        .unwrap();
+    std::io::Write::write_all(&mut file, b"{\"seq\":7,\"at\":").unwrap();
+
+    let reopened = storage::open(&format!("events:{}", dir), None).unwrap();
+    let (_, latest) = history.last().unwrap();
+    assert_eq!(
+        &serde_json::to_value(reopened.load().unwrap()).unwrap(),
+        latest
+    );
+    for (at, expected) in &history {
+        let past = reopened.load_as_of(*at).unwrap();
+        assert_eq!(&serde_json::to_value(past).unwrap(), expected);
+    }
+
+    // Appending continues on a clean line after the discarded one
+    let event = Event::GroupDeleted { group_id: 1 };
+    event.apply(&mut data);
+    reopened.record(&[event], &[]).unwrap();
+    let loaded = storage::open(&format!("events:{}", dir), None)
+        .unwrap()
+        .load()
+        .unwrap();
+    assert!(loaded.groups.is_empty());
+    assert_eq!(loaded.users.len(), 1);
+}
+
+#[test]
+fn test_event_log_discards_only_a_torn_final_entry() {
+    let dir = temp_path("torn");
+    let spec = format!("events:{}", dir);
+    let group = create_group(vec![], vec![]);
+    let renamed = |name: &str| Event::GroupRenamed {
+        group_id: 1,
+        name: name.to_string(),
+        currency: "EUR".to_string(),
+    };
+
+    let backend = storage::open(&spec, None).unwrap();
+    let mut data = backend.load().unwrap();
+    for event in [Event::GroupCreated { group }, renamed("Trip")] {
+        event.apply(&mut data);
+        backend.record(&[event], &[]).unwrap();
+    }
+    backend.record(&[renamed("Café")], &[]).unwrap();
+    drop(backend);
+
+    let journal = std::path::Path::new(&dir).join("journal-000000000001.jsonl");
+    let contents = std::fs::read(&journal).unwrap();
+    let last_start = contents[..contents.len() - 1]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .unwrap()
+        + 1;
+    let (kept, last) = contents.split_at(last_start);
+    let expected = serde_json::to_value(&data).unwrap();
+    let reopen = || {
+        let loaded = storage::open(&spec, None).unwrap().load().unwrap();
+        assert_eq!(serde_json::to_value(loaded).unwrap(), expected);
+        assert_eq!(std::fs::read(&journal).unwrap(), kept);
+    };
+
+    // Cut inside the two bytes of 'é'
+    let cut = last.iter().position(|&b| b == 0xC3).unwrap() + 1;
+    std::fs::write(&journal, [kept, &last[..cut]].concat()).unwrap();
+    reopen();
+
+    // A complete entry is still torn without its newline
+    std::fs::write(&journal, [kept, &last[..last.len() - 1]].concat()).unwrap();
+    reopen();
+
+    // A bad line before the end is corruption, not a torn write
+    let first_end = kept.iter().position(|&b| b == b'\n').unwrap() + 1;
+    let corrupted = [&kept[..first_end], b"{\"seq\":2,\n", &kept[first_end..]].concat();
+    std::fs::write(&journal, corrupted).unwrap();
+    assert!(storage::open(&spec, None).is_err());
+}
+
+#[test]
+fn test_event_log_rotates_journal_segments() {
+    let dir = temp_path("segments");
+    let files = |prefix: &str| {
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        names.sort();
+        names
+    };
+    let rate_set = |rate: f64| Event::ExchangeRateSet {
+        group_id: Some(1),
+        rate: ExchangeRate {
+            from: "EUR".to_string(),
+            to: "USD".to_string(),
+            rate,
+            updated_at: "2024-01-01T00:00:00Z".to_string(),
+        },
+    };
+
+    let backend = storage::open(&format!("events:{}", dir), None).unwrap();
+    let mut data = backend.load().unwrap();
+    let group = create_group(vec![], vec![]);
+    let events: Vec<Event> = std::iter::once(Event::GroupCreated { group })
+        .chain((2..=1000).map(|i| rate_set(i as f64)))
+        .collect();
+    for event in &events {
+        event.apply(&mut data);
+    }
+    backend.record(&events, &[]).unwrap();
+    let first_snapshot_at = chrono::Utc::now();
+    assert_eq!(files("snapshot-"), ["snapshot-000000001000.json"]);
+    assert_eq!(
+        files("journal-"),
+        ["journal-000000000001.jsonl", "journal-000000001001.jsonl"]
+    );
+
+    // Each snapshot starts a segment; once more than ten are kept the
+    // oldest go, along with the segments only they needed
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    for i in 1..=10 {
+        let event = rate_set(2000.0 + i as f64);
+        event.apply(&mut data);
+        backend.record(&[event], &[]).unwrap();
+        backend.save(&data).unwrap();
+    }
+    assert_eq!(files("snapshot-").len(), 10);
+    assert_eq!(files("snapshot-")[0], "snapshot-000000001001.json");
+    assert_eq!(files("journal-")[0], "journal-000000001002.jsonl");
+
+    let reopened = storage::open(&format!("events:{}", dir), None).unwrap();
+    assert_eq!(
+        serde_json::to_value(reopened.load().unwrap()).unwrap(),
+        serde_json::to_value(&data).unwrap()
+    );
+    assert!(reopened.load_as_of(first_snapshot_at).is_err());
+    let event = rate_set(1.0);
+    event.apply(&mut data);
+    reopened.record(&[event], &[]).unwrap();
+    assert_eq!(
+        files("journal-").last().unwrap(),
+        "journal-000000001011.jsonl"
+    );
+    assert_eq!(
+        reopened.load().unwrap().groups[0].exchange_rates[0].rate,
+        1.0
+    );
+}
+
+const LEGACY_DATA: &str = r#"{
+    "groups": [{
+        "id": 1,
+        "name": "Trip",
+        "members": [{"id": 1, "name": "Alice"}, {"id": 2, "name": "Bob"}],
+        "expenses": [{
+            "id": 1,
+            "description": "Dinner",
+            "amount": 30.5,
+            "payer": {"id": 1, "name": "Alice"},
+            "participants": [{"id": 1, "name": "Alice"}, {"id": 2, "name": "Bob"}]
+        }],
+        "simplify_debts": true
+    }]
+}"#;
+
+#[test]
+fn test_legacy_data_file_is_migrated() {
+    let path = temp_path("legacy.json");
+    std::fs::write(&path, LEGACY_DATA).unwrap();
+    let backend = storage::JsonFileStorage::new(&path);
+
+    let data = backend.load().unwrap();
+    let group = &data.groups[0];
+    assert_eq!(group.currency, "USD");
+    assert_eq!(group.settlement_algorithm, SettlementAlgorithm::Greedy);
+    assert_eq!(group.expenses[0].amount, money(30.5));
+
+    let dry_run = backend.migrate(true).unwrap();
+    assert_eq!(dry_run.len(), 5);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), LEGACY_DATA);
+
+    assert_eq!(backend.migrate(false).unwrap(), dry_run);
+    let stored: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(
+        stored["schema_version"],
+        storage::migrations::CURRENT_VERSION
+    );
+    assert_eq!(stored["groups"][0]["currency"], "USD");
+    assert_eq!(
+        std::fs::read_to_string(format!("{}.v1", path)).unwrap(),
+        LEGACY_DATA
+    );
+    assert!(backend.migrate(false).unwrap().is_empty());
+}
+
+#[test]
+fn test_unreadable_data_file_is_an_error() {
+    let path = temp_path("broken.json");
+    let backend = storage::JsonFileStorage::new(&path);
+    assert!(backend.load().unwrap().groups.is_empty());
+
+    std::fs::write(&path, "{\n  \"groups\": [\n    {\"id\": \"one\"}\n  ]\n}").unwrap();
+    match backend.load() {
+        Err(AppError::LoadError(errors)) => {
+            assert_eq!(errors[0].path, path);
+            assert_eq!(errors[0].line, Some(3));
+            assert!(errors[0].column.is_some());
+            assert!(errors[0].message.contains("invalid type"));
+        }
+        other => panic!("expected a load error, got {:?}", other.map(|_| ())),
+    }
+
+    let newer = format!(
+        "{{\"schema_version\": {}, \"groups\": []}}",
+        storage::migrations::CURRENT_VERSION + 1
+    );
+    std::fs::write(&path, newer).unwrap();
+    let error = backend.load().unwrap_err().to_string();
+    assert!(
+        error.contains("newer than this build supports"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_repair_salvages_valid_records() {
+    let path = temp_path("damaged.json");
+    let mut document = serde_json::to_value(sample_app_data()).unwrap();
+    document["groups"][0]["expenses"][1]["amount"] = serde_json::json!("lots");
+    document["groups"][0]["settled_settlements"][0]["to"] = serde_json::json!(null);
+    let mut broken_group = document["groups"][0].clone();
+    broken_group["id"] = serde_json::json!(2);
+    broken_group["members"] = serde_json::json!("everyone");
+    document["groups"]
+        .as_array_mut()
+        .unwrap()
+        .push(broken_group);
+    std::fs::write(&path, document.to_string()).unwrap();
+
+    let backend = storage::JsonFileStorage::new(&path);
+    assert!(backend.load().is_err());
+
+    let (source, data, dropped) = backend.repair(true).unwrap();
+    assert_eq!(source, path);
+    assert_eq!(data.groups.len(), 1);
+    assert_eq!(data.groups[0].expenses.len(), 1);
+    assert!(data.groups[0].settled_settlements.is_empty());
+    assert_eq!(data.users.len(), 1);
+    assert_eq!(dropped.len(), 5, "{:?}", dropped);
+    assert!(dropped[4].starts_with("group 2 'Test Group' with 1 expenses"));
+    assert!(backend.load().is_err(), "dry run must not write");
+
+    backend.repair(false).unwrap();
+    let repaired = backend.load().unwrap();
+    assert_eq!(repaired.groups[0].expenses[0].description, "Dinner");
+}
+
+#[test]
+fn test_rotating_backups_respect_interval_and_retention() {
+    let dir = std::path::PathBuf::from(temp_path("backups"));
+    let backups = storage::backup::BackupDir::new(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    for stamp in ["20240101T000000Z", "20240102T000000Z", "20240103T000000Z"] {
+        std::fs::write(dir.join(format!("backup-{}.json", stamp)), "{}").unwrap();
+    }
+
+    let policy = storage::backup::BackupPolicy {
+        dir: dir.clone(),
+        interval: std::time::Duration::from_secs(3600),
+        retain: 2,
+        key: None,
+    };
+    let inner = storage::open(&temp_path("data.json"), None).unwrap();
+    let backend = storage::backup::RotatingBackups::new(inner, policy).unwrap();
+
+    // The newest backup is old, so the first save takes one and prunes
+    let data = sample_app_data();
+    backend.save(&data).unwrap();
+    let listed = backups.list().unwrap();
+    assert_eq!(listed.len(), 2);
+    assert!(listed[0].1.ends_with("backup-20240103T000000Z.json"));
+
+    // Within the interval no further backups are taken
+    backend.save(&data).unwrap();
+    assert_eq!(backups.list().unwrap().len(), 2);
+
+    let restored = storage::backup::validate(&listed[1].1, None).unwrap();
+    assert_eq!(
+        serde_json::to_value(restored).unwrap(),
+        serde_json::to_value(&data).unwrap()
+    );
+}
+
+#[test]
+fn test_restore_rejects_inconsistent_backup() {
+    let mut data = sample_app_data();
+    data.groups[0].members.retain(|m| m.name != "Charlie");
+    let backups = storage::backup::BackupDir::new(temp_path("backups"));
+    let path = backups.write(&data).unwrap();
+
+    let error = storage::backup::validate(&path, None)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("Charlie, who is not a member"), "{}", error);
+}
+
+#[test]
+fn test_encrypted_data_file_round_trip() {
+    let path = temp_path("data.json");
+    let key = DataKey::generate();
+    let backend = storage::JsonFileStorage::new(&path).with_key(Some(key.clone()));
+    let data = sample_app_data();
+    backend.save(&data).unwrap();
+    backend.save(&data).unwrap();
+
+    for file in [path.clone(), format!("{}.bak", path)] {
+        let stored = std::fs::read(&file).unwrap();
+        assert!(storage::encryption::is_encrypted(&stored), "{}", file);
+        assert!(!String::from_utf8_lossy(&stored).contains("Alice"));
+    }
+    assert_eq!(
+        serde_json::to_value(backend.load().unwrap()).unwrap(),
+        serde_json::to_value(&data).unwrap()
+    );
+
+    // Without the key, or with the wrong one, nothing is loaded
+    let error = storage::JsonFileStorage::new(&path).load().unwrap_err();
+    assert!(error.to_string().contains("file is encrypted"), "{}", error);
+    let wrong = storage::JsonFileStorage::new(&path).with_key(Some(DataKey::generate()));
+    let error = wrong.load().unwrap_err();
+    assert!(error.to_string().contains("wrong data key"), "{}", error);
+
+    // Tampering is detected rather than decrypted to garbage
+    let mut stored = std::fs::read(&path).unwrap();
+    *stored.last_mut().unwrap() ^= 1;
+    assert!(key.open(&stored).is_err());
+}
+
+#[test]
+fn test_encrypting_plaintext_file_and_rotating_key() {
+    let path = temp_path("data.json");
+    let data = sample_app_data();
+    let plain = storage::JsonFileStorage::new(&path);
+    plain.save(&data).unwrap();
+    plain.save(&data).unwrap();
+
+    // With a key a plaintext file is refused except to encrypt it
+    let old_key = DataKey::generate();
+    let encrypted = storage::JsonFileStorage::new(&path).with_key(Some(old_key.clone()));
+    let error = encrypted.load().unwrap_err();
+    assert!(error.to_string().contains("not encrypted"), "{}", error);
+    let files = encrypted.files();
+    let rekey = |key: &DataKey, new_key: &DataKey, allow_plaintext| {
+        storage::rekey_files(&files, Some(key), Some(new_key), allow_plaintext)
+    };
+    assert!(rekey(&old_key, &old_key, false).is_err());
+
+    let rewritten = rekey(&old_key, &old_key, true).unwrap();
+    assert_eq!(rewritten, vec![path.clone(), format!("{}.bak", path)]);
+    assert!(storage::encryption::is_encrypted(
+        &std::fs::read(format!("{}.bak", path)).unwrap()
+    ));
+
+    // Rotating with the wrong current key leaves the files alone
+    let new_key = DataKey::generate();
+    assert!(rekey(&DataKey::generate(), &new_key, false).is_err());
+    assert!(encrypted.load().is_ok());
+
+    rekey(&old_key, &new_key, false).unwrap();
+    assert!(encrypted.load().is_err());
+    let rotated = storage::JsonFileStorage::new(&path).with_key(Some(new_key));
+    assert_eq!(
+        serde_json::to_value(rotated.load().unwrap()).unwrap(),
+        serde_json::to_value(&data).unwrap()
+    );
+}
+
+#[test]
+fn test_encrypting_shards_and_backups() {
+    let mut data = sample_app_data();
+    let mut second = data.groups[0].clone();
+    second.id = 2;
+    data.groups.push(second);
+    let spec = format!("shards:{}", temp_path("shards"));
+    storage::open(&spec, None).unwrap().save(&data).unwrap();
+    let backups = storage::backup::BackupDir::new(temp_path("backups"));
+    backups.write(&data).unwrap();
+
+    let mut files = storage::data_files(&spec).unwrap();
+    files.extend(backups.files().unwrap());
+    let key = DataKey::generate();
+    let rewritten = storage::rekey_files(&files, Some(&key), Some(&key), true).unwrap();
+    // The users, both groups and the backup; there is no legacy rate table
+    assert_eq!(rewritten.len(), 4);
+    for file in &rewritten {
+        assert!(storage::encryption::is_encrypted(
+            &std::fs::read(file).unwrap()
+        ));
+    }
+
+    let expected = serde_json::to_value(&data).unwrap();
+    let loaded = storage::open(&spec, Some(key.clone()))
+        .unwrap()
+        .load()
+        .unwrap();
+    assert_eq!(serde_json::to_value(loaded).unwrap(), expected);
+    let backup =
+        storage::backup::validate(std::path::Path::new(&rewritten[3]), Some(&key)).unwrap();
+    assert_eq!(serde_json::to_value(backup).unwrap(), expected);
+    assert!(storage::open(&spec, None).unwrap().load().is_err());
+}
+
+#[test]
+fn test_data_key_parsing() {
+    let key = DataKey::generate();
+    let parsed = DataKey::parse(&format!("{}\n", key.encode())).unwrap();
+    assert_eq!(parsed.encode(), key.encode());
+    assert!(DataKey::parse("not base64!").is_err());
+    assert!(DataKey::parse("c2hvcnQ=").unwrap_err().contains("32 bytes"));
+    assert!(storage::open("sqlite::memory:", Some(key)).is_err());
+}
+
+/// Sends a GET to `path` over a fresh connection and returns the raw
+/// response.
+async fn http_get(addr: std::net::SocketAddr, path: &str) -> std::io::Result<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        path
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+async fn slow_server(
+    timeout: Duration,
+) -> (
+    std::net::SocketAddr,
+    tokio::sync::oneshot::Sender<()>,
+    tokio::task::JoinHandle<std::io::Result<shutdown::Summary>>,
+) {
+    let app = axum::Router::new()
+        .route("/fast", axum::routing::get(|| async { "fast" }))
+        .route(
+            "/slow",
+            axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                "slow"
+            }),
         );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel();
+    let server = tokio::spawn(shutdown::serve(
+        listener,
+        app,
+        async {
+            let _ = stopped.await;
+            "test"
+        },
+        timeout,
+    ));
+    (addr, stop, server)
+}
 
-        let balances = calculate_balances(&group);
-        assert_eq!(balances["Alice"], 3.75);
-        assert_eq!(balances["Bob"], -3.75);
+#[tokio::test]
+async fn test_shutdown_drains_in_flight_requests() {
+    let (addr, stop, server) = slow_server(Duration::from_secs(5)).await;
+    assert!(http_get(addr, "/fast").await.unwrap().ends_with("fast"));
+
+    let slow = tokio::spawn(http_get(addr, "/slow"));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    stop.send(()).unwrap();
+
+    // The request already running is answered, new connections are refused
+    assert!(slow.await.unwrap().unwrap().ends_with("slow"));
+    let summary = server.await.unwrap().unwrap();
+    assert_eq!(summary.reason, "test");
+    assert_eq!(summary.served, 2);
+    assert_eq!(summary.abandoned, 0);
+    assert!(http_get(addr, "/fast").await.is_err());
+}
+
+#[tokio::test]
+async fn test_shutdown_gives_up_after_timeout() {
+    let (addr, stop, server) = slow_server(Duration::from_millis(20)).await;
+    let slow = tokio::spawn(http_get(addr, "/slow"));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    stop.send(()).unwrap();
+
+    let summary = server.await.unwrap().unwrap();
+    assert_eq!(summary.served, 0);
+    assert_eq!(summary.abandoned, 1);
+    assert!(summary.drain_time < Duration::from_millis(200));
+    slow.abort();
+}
+
+#[test]
+fn test_config_layers_file_env_and_flags() {
+    let path = temp_path("splitdumb.toml");
+    std::fs::write(
+        &path,
+        r#"
+            [server]
+            bind = "127.0.0.1:8080"
+            cors_origins = ["https://app.example.com"]
+
+            [auth]
+            token_lifetime = "12h"
+            sms = "file:codes.txt"
+
+            [storage]
+            backend = "sqlite:file.db"
+
+            [log]
+            format = "json"
+        "#,
+    )
+    .unwrap();
+    let env = |name: &str| match name {
+        "SPLITDUMB_CONFIG" => Some(path.clone()),
+        "SPLITDUMB_STORAGE" => Some("shards:env".to_string()),
+        "SPLITDUMB_RATE_LIMIT" => Some("60".to_string()),
+        _ => None,
+    };
+    let args = ServeArgs {
+        port: Some(9000),
+        rate_limit_burst: Some(5),
+        ..Default::default()
+    };
+
+    let (config, loaded_from) = Config::resolve(&args, env).unwrap();
+    assert_eq!(loaded_from.unwrap().to_string_lossy(), path);
+    // The file sets the address, the flag only its port
+    assert_eq!(config.server.bind.to_string(), "127.0.0.1:9000");
+    assert_eq!(config.server.cors_origins, vec!["https://app.example.com"]);
+    assert_eq!(
+        config.auth.token_lifetime.0,
+        Duration::from_secs(12 * 60 * 60)
+    );
+    assert_eq!(config.storage.backend, "shards:env");
+    assert_eq!(config.log.format, LogFormat::Json);
+    assert_eq!(config.rate_limit.requests_per_minute, 60);
+    assert_eq!(config.rate_limit.burst, 5);
+    // Untouched settings keep their defaults
+    assert_eq!(config.storage.durability, Durability::Sync);
+    assert_eq!(config.backups.interval, None);
+
+    // The printed configuration reads back to the same settings
+    let printed: Config = toml::from_str(&config.to_toml()).unwrap();
+    assert_eq!(printed, config);
+}
+
+#[test]
+fn test_config_rejects_invalid_settings() {
+    let resolve = |toml: &str, env: &[(&str, &str)]| {
+        let path = temp_path("splitdumb.toml");
+        std::fs::write(&path, toml).unwrap();
+        let args = ServeArgs {
+            config: Some(path.into()),
+            ..Default::default()
+        };
+        Config::resolve(&args, |name| {
+            env.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.to_string())
+                .or_else(|| (name == "SPLITDUMB_SMS").then(|| "stdout".to_string()))
+        })
+    };
+
+    assert!(resolve("", &[]).is_ok());
+    // Sign-in codes are only printed when that is asked for
+    let error = Config::resolve(&ServeArgs::default(), |_| None).unwrap_err();
+    assert!(error.contains("auth.sms"), "{}", error);
+    assert!(resolve("[server]\nport = 3000", &[]).is_err());
+    assert!(resolve("[auth]\ntoken_lifetime = \"soon\"", &[]).is_err());
+    assert!(resolve("[auth]\naccess_token_lifetime = \"1h\"", &[]).is_ok());
+    assert!(resolve("[auth]\naccess_token_lifetime = \"31d\"", &[]).is_err());
+    assert!(resolve("[server]\ncors_origins = []", &[]).is_err());
+    assert!(resolve("[server]\ncors_origins = [\"bad\\norigin\"]", &[]).is_err());
+    let error = resolve("", &[("SPLITDUMB_BIND", "localhost")]).unwrap_err();
+    assert!(error.contains("SPLITDUMB_BIND"));
+    assert!(resolve("", &[("SPLITDUMB_DURABILITY", "Batched")]).is_ok());
+    assert!(
+        resolve(
+            "",
+            &[
+                ("SPLITDUMB_RATE_LIMIT", "10"),
+                ("SPLITDUMB_RATE_LIMIT_BURST", "0")
+            ]
+        )
+        .is_err()
+    );
+
+    assert_eq!(parse_interval("50ms"), Ok(Duration::from_millis(50)));
+    assert_eq!(parse_interval(" 2h "), Ok(Duration::from_secs(2 * 60 * 60)));
+    for bad in ["", "ms", "5", "5µ", "µ", "1w", "-1s", "0s", "0ms"] {
+        assert!(parse_interval(bad).is_err(), "{}", bad);
+    }
+    let error = parse_interval(&format!("{}d", u64::MAX)).unwrap_err();
+    assert!(error.contains("too long"), "{}", error);
+    assert!(resolve("[backups]\ninterval = \"0ms\"", &[]).is_err());
+    let (config, _) = resolve("", &[("SPLITDUMB_TRUSTED_PROXIES", "127.0.0.1, ::1")]).unwrap();
+    assert_eq!(config.rate_limit.trusted_proxies.len(), 2);
+    let error = resolve("", &[("SPLITDUMB_TRUSTED_PROXIES", "nginx")]).unwrap_err();
+    assert!(error.contains("SPLITDUMB_TRUSTED_PROXIES"), "{}", error);
+
+    assert_eq!(Interval(Duration::from_millis(1500)).to_string(), "1500ms");
+    assert_eq!(Interval(Duration::from_secs(90)).to_string(), "90s");
+    assert_eq!(
+        Interval(Duration::from_secs(2 * 24 * 60 * 60)).to_string(),
+        "2d"
+    );
+}
+
+#[test]
+fn test_rate_limiter_refills_per_client() {
+    let config = crate::config::RateLimitConfig {
+        requests_per_minute: 60,
+        burst: 2,
+        ..Default::default()
+    };
+    assert!(RateLimiter::new(&Default::default()).is_none());
+    let limiter = RateLimiter::new(&config).unwrap();
+    let alice = "10.0.0.1".parse().unwrap();
+    let bob = "10.0.0.2".parse().unwrap();
+    let start = std::time::Instant::now();
+
+    assert!(limiter.check(alice, start).is_ok());
+    assert!(limiter.check(alice, start).is_ok());
+    let wait = limiter.check(alice, start).unwrap_err();
+    assert_eq!(wait, Duration::from_secs(1));
+    // Other clients have their own allowance
+    assert!(limiter.check(bob, start).is_ok());
+    // One request a second comes back
+    assert!(limiter.check(alice, start + Duration::from_secs(1)).is_ok());
+    assert!(
+        limiter
+            .check(alice, start + Duration::from_secs(1))
+            .is_err()
+    );
+}
+
+#[test]
+fn test_rate_limiter_counts_proxied_requests_per_client() {
+    let config = crate::config::RateLimitConfig {
+        requests_per_minute: 60,
+        trusted_proxies: vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()],
+        ..Default::default()
+    };
+    let limiter = RateLimiter::new(&config).unwrap();
+    let ip = |addr: &str| -> std::net::IpAddr { addr.parse().unwrap() };
+    let client = |peer: &str, headers: &[(&'static str, &str)]| {
+        let mut map = axum::http::HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        limiter.client(ip(peer), &map)
+    };
+
+    // nginx forwarding for a client, behind another trusted proxy
+    let forwarded = [("x-forwarded-for", "203.0.113.7, 10.1.2.3")];
+    assert_eq!(client("127.0.0.1", &forwarded), ip("203.0.113.7"));
+    assert_eq!(client("::ffff:127.0.0.1", &forwarded), ip("203.0.113.7"));
+    // Hops the client added itself are ignored
+    let spoofed = [
+        ("x-forwarded-for", "198.51.100.1"),
+        ("x-forwarded-for", "203.0.113.7"),
+    ];
+    assert_eq!(client("127.0.0.1", &spoofed), ip("203.0.113.7"));
+    let garbled = [("x-forwarded-for", "nonsense, 10.0.0.9")];
+    assert_eq!(client("127.0.0.1", &garbled), ip("10.0.0.9"));
+    let real_ip = [("x-real-ip", "203.0.113.9")];
+    assert_eq!(client("127.0.0.1", &real_ip), ip("203.0.113.9"));
+    assert_eq!(client("127.0.0.1", &[]), ip("127.0.0.1"));
+    // Anyone else's headers are not believed
+    assert_eq!(client("192.0.2.1", &forwarded), ip("192.0.2.1"));
+    assert_eq!(client("192.0.2.1", &real_ip), ip("192.0.2.1"));
+
+    let range: crate::config::IpRange = "fd00::/8".parse().unwrap();
+    assert!(range.contains(ip("fd12::1")) && !range.contains(ip("fe80::1")));
+    assert_eq!(range.to_string(), "fd00::/8");
+    for bad in ["10.0.0.0/33", "localhost", "10.0.0.0/"] {
+        assert!(bad.parse::<crate::config::IpRange>().is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_sign_in_codes_expire_and_limit_attempts() {
+    let config = crate::config::AuthConfig {
+        code_lifetime: Interval(Duration::from_secs(300)),
+        code_attempts: 3,
+        ..Default::default()
+    };
+    let codes = Codes::new(&config);
+    let phone = "5551234567";
+    let start = std::time::Instant::now();
+
+    // A code is good for one use
+    let code = codes.issue(phone, start).unwrap();
+    assert_eq!(code.len(), CODE_DIGITS);
+    assert!(code.chars().all(|c| c.is_ascii_digit()));
+    // Another code cannot be sent straight away
+    assert!(matches!(
+        codes.issue(phone, start),
+        Err(AppError::RateLimited(_))
+    ));
+    assert!(codes.verify("5559999999", &code, start).is_err());
+    assert!(codes.verify(phone, &code, start).is_ok());
+    assert!(codes.verify(phone, &code, start).is_err());
+
+    // Wrong guesses use up the code
+    let later = start + Duration::from_secs(60);
+    let code = codes.issue(phone, later).unwrap();
+    let wrong = if code == "000000" { "000001" } else { "000000" };
+    assert!(codes.verify(phone, wrong, later).is_err());
+    assert!(codes.verify(phone, wrong, later).is_err());
+    let error = codes.verify(phone, wrong, later).unwrap_err();
+    assert!(error.to_string().contains("Too many"));
+    assert!(codes.verify(phone, &code, later).is_err());
+
+    // Which locks the phone out of new codes for a while
+    assert!(matches!(
+        codes.issue(phone, later),
+        Err(AppError::RateLimited(secs)) if secs == 15 * 60
+    ));
+    let unlocked = later + Duration::from_secs(15 * 60);
+    assert!(codes.issue("5559999999", later).is_ok());
+
+    // And so does time
+    let code = codes.issue(phone, unlocked).unwrap();
+    let expired = unlocked + Duration::from_secs(300);
+    let error = codes.verify(phone, &code, expired).unwrap_err();
+    assert!(error.to_string().contains("expired"));
+
+    // Another lockout in a row lasts twice as long
+    let code = codes.issue(phone, expired).unwrap();
+    let wrong = if code == "000000" { "000001" } else { "000000" };
+    for _ in 0..3 {
+        assert!(codes.verify(phone, wrong, expired).is_err());
+    }
+    assert!(matches!(
+        codes.issue(phone, expired + Duration::from_secs(15 * 60)),
+        Err(AppError::RateLimited(_))
+    ));
+    assert!(
+        codes
+            .issue(phone, expired + Duration::from_secs(30 * 60))
+            .is_ok()
+    );
+}
+
+#[test]
+fn test_file_sms_sender_appends_messages() {
+    let path = temp_path("sms.txt");
+    let sender = sms::open(&format!("file:{}", path)).unwrap();
+    sender.send("5551234567", "Your code is 123456").unwrap();
+    sms::FileSender::new(&path)
+        .send("5557654321", "two\nlines")
+        .unwrap();
+
+    let sent = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(
+        sent,
+        "5551234567\tYour code is 123456\n5557654321\ttwo lines\n"
+    );
+    assert!(sms::open("stdout").is_ok());
+    assert!(sms::open("carrier-pigeon").is_err());
+    assert!(sms::open("file:").is_err());
+}
+
+#[test]
+fn test_user_tokens_become_sessions() {
+    let expires_at = "2099-01-01T00:00:00+00:00";
+
+    // A version 2 JSON file
+    let path = temp_path("tokens.json");
+    let v2 = serde_json::json!({
+        "schema_version": 2,
+        "groups": [],
+        "exchange_rates": [],
+        "users": [
+            {"id": 1, "phone": "5551234567", "name": "Alice", "token": "secret",
+             "current_group_id": 0, "token_expires_at": expires_at},
+            {"id": 2, "phone": "5557654321", "name": "Bob", "token": "",
+             "current_group_id": 0, "token_expires_at": null}
+        ]
+    });
+    std::fs::write(&path, v2.to_string()).unwrap();
+    let data = storage::JsonFileStorage::new(&path).load().unwrap();
+    let session = &data.users[0].sessions[0];
+    assert_eq!(session.token_hash, sessions::hash_token("secret"));
+    assert_eq!(session.expires_at, expires_at);
+    assert!(data.users[1].sessions.is_empty());
+
+    // Version 3 sessions predate refresh tokens
+    let mut v3 = serde_json::json!({
+        "schema_version": 3,
+        "groups": [],
+        "exchange_rates": [],
+        "users": [{"id": 1, "phone": "5551234567", "name": "Alice", "current_group_id": 0,
+            "sessions": [{"id": "s1", "token_hash": "abc", "device": "Laptop",
+                "created_at": expires_at, "last_used_at": expires_at,
+                "expires_at": expires_at}]}]
+    });
+    assert_eq!(storage::migrations::migrate(&mut v3).unwrap().len(), 3);
+    let session = v3["users"][0]["sessions"][0].as_object().unwrap();
+    assert_eq!(session["access_expires_at"], serde_json::Value::Null);
+    assert_eq!(session["refresh_hash"], serde_json::Value::Null);
+    assert_eq!(session["used_refresh_hashes"], serde_json::json!([]));
+    // Version 4 users predate API keys
+    let user = v3["users"][0].as_object().unwrap();
+    assert_eq!(user["api_keys"], serde_json::json!([]));
+
+    // A SQLite database with the token columns
+    let db = temp_path("tokens.db");
+    let conn = rusqlite::Connection::open(&db).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE users (id INTEGER PRIMARY KEY, phone TEXT NOT NULL,
+             name TEXT NOT NULL, token TEXT NOT NULL,
+             current_group_id INTEGER NOT NULL, token_expires_at TEXT);
+         INSERT INTO users VALUES (1, '5551234567', 'Alice', 'secret', 0, NULL);",
+    )
+    .unwrap();
+    drop(conn);
+    let data = storage::open(&format!("sqlite:{}", db), None)
+        .unwrap()
+        .load()
+        .unwrap();
+    let session = &data.users[0].sessions[0];
+    assert_eq!(session.token_hash, sessions::hash_token("secret"));
+    assert!(!sessions::is_expired(session, chrono::Utc::now()));
+    // Opening again finds nothing left to upgrade
+    let reopened = storage::open(&format!("sqlite:{}", db), None).unwrap();
+    assert_eq!(reopened.load().unwrap().users[0].sessions.len(), 1);
+
+    // An event log journaled before entries carried a version
+    let dir = temp_path("tokens");
+    std::fs::create_dir_all(&dir).unwrap();
+    let user = |token: &str| {
+        serde_json::json!({"id": 1, "phone": "5551234567", "name": "Alice",
+            "token": token, "current_group_id": 0, "token_expires_at": expires_at})
+    };
+    let journal = [
+        serde_json::json!({"seq": 1, "at": "2024-01-01T00:00:00Z",
+            "event": {"type": "user_registered", "user": user("secret")}}),
+        serde_json::json!({"seq": 2, "at": "2024-01-02T00:00:00Z",
+            "event": {"type": "user_updated", "user": user("rotated")}}),
+    ]
+    .map(|entry| format!("{}\n", entry))
+    .concat();
+    std::fs::write(format!("{}/journal.jsonl", dir), journal).unwrap();
+    let snapshot = serde_json::json!({"seq": 1, "at": "2024-01-01T12:00:00Z",
+        "schema_version": 2,
+        "data": {"groups": [], "exchange_rates": [], "users": [user("secret")]}});
+    std::fs::write(
+        format!("{}/snapshot-000000000001.json", dir),
+        snapshot.to_string(),
+    )
+    .unwrap();
+    let backend = storage::open(&format!("events:{}", dir), None).unwrap();
+    let data = backend.load().unwrap();
+    assert_eq!(data.users[0].sessions.len(), 1);
+    let session = &data.users[0].sessions[0];
+    assert_eq!(session.token_hash, sessions::hash_token("rotated"));
+    assert_eq!(session.expires_at, expires_at);
+
+    // Opening upgrades the stored files, so no token is left in cleartext
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        for token in ["secret", "rotated"] {
+            assert!(!contents.contains(token), "{}", path.display());
+        }
+    }
+    let at = "2024-01-01T18:00:00Z".parse().unwrap();
+    let past = backend.load_as_of(at).unwrap();
+    assert_eq!(
+        past.users[0].sessions[0].token_hash,
+        sessions::hash_token("secret")
+    );
+}
+
+/// Sessions lasting an hour, with access tokens good for 15 minutes.
+fn hour_long_sessions() -> AuthConfig {
+    AuthConfig {
+        token_lifetime: Interval(Duration::from_secs(60 * 60)),
+        access_token_lifetime: Interval(Duration::from_secs(15 * 60)),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_sessions_keep_only_token_hashes() {
+    let now = chrono::Utc::now();
+    let auth = hour_long_sessions();
+    let (session, tokens) = sessions::start("  Laptop  ", &auth, now);
+    let token = tokens.access;
+    assert_eq!(session.device, "Laptop");
+    assert_eq!(session.token_hash, sessions::hash_token(&token));
+    assert_ne!(session.token_hash, token);
+    assert!(!sessions::is_expired(&session, now));
+    assert!(sessions::is_expired(
+        &session,
+        now + chrono::Duration::hours(1)
+    ));
+
+    let (other, other_tokens) = sessions::start("", &auth, now);
+    assert_eq!(other.device, "Unknown device");
+    assert_ne!(other_tokens.access, token);
+    assert_ne!(other.id, session.id);
+    let (long, _) = sessions::start(&"x".repeat(500), &auth, now);
+    assert_eq!(long.device.len(), 100);
+
+    // Sessions round trip through every backend with the users
+    let mut data = sample_app_data();
+    data.users[0].sessions = vec![session.clone(), other];
+    for spec in [
+        temp_path("sessions.json"),
+        format!("sqlite:{}", temp_path("sessions.db")),
+        format!("shards:{}", temp_path("sessions")),
+    ] {
+        let backend = storage::open(&spec, None).unwrap();
+        backend.save(&data).unwrap();
+        let loaded = backend.load().unwrap();
+        assert_eq!(loaded.users[0].sessions.len(), 2, "{}", spec);
+        assert_eq!(loaded.users[0].sessions[0].token_hash, session.token_hash);
+    }
+}
+
+#[test]
+fn test_tokens_and_phones_stay_private() {
+    let now = chrono::Utc::now();
+    let mut data = sample_app_data();
+    let auth = hour_long_sessions();
+    let (session, tokens) = sessions::start("Laptop", &auth, now);
+    let token = tokens.access;
+    data.users[0].sessions.push(session.clone());
+    let mut bob = data.users[0].clone();
+    bob.id = 2;
+    bob.phone = "5557654321".to_string();
+    let (bob_session, bob_tokens) = sessions::start("Phone", &auth, now);
+    let bob_token = bob_tokens.access;
+    bob.sessions = vec![bob_session];
+    data.users.push(bob);
+
+    let (user, found) = sessions::find(&data.users, &token).unwrap();
+    assert_eq!((user.id, &found.id), (1, &session.id));
+    assert_eq!(sessions::find(&data.users, &bob_token).unwrap().0.id, 2);
+    assert!(sessions::find(&data.users, "guess").is_none());
+    assert!(sessions::find(&data.users, &session.token_hash).is_none());
+
+    // What users are shown and what gets logged has neither
+    let shown = serde_json::to_string(&PublicUser::from(user)).unwrap();
+    let logged = format!("{:?}", user);
+    for text in [&shown, &logged] {
+        assert!(!text.contains(&user.phone), "{}", text);
+        assert!(!text.contains(&session.token_hash), "{}", text);
+    }
+    assert!(shown.contains("Alice"));
+    assert_eq!(masked_phone("5551234567"), "***4567");
+}
+
+#[test]
+fn test_refresh_tokens_rotate_and_catch_reuse() {
+    let now = chrono::Utc::now();
+    let minutes = chrono::Duration::minutes;
+    let auth = hour_long_sessions();
+    let mut data = sample_app_data();
+    let (session, first) = sessions::start("Laptop", &auth, now);
+    data.users[0].sessions.push(session);
+
+    // The access token runs out long before the session
+    let session = &data.users[0].sessions[0];
+    assert!(!sessions::access_expired(session, now));
+    assert!(sessions::access_expired(session, now + minutes(15)));
+    assert!(!sessions::is_expired(session, now + minutes(15)));
+    let (_, _, refresh) = sessions::find_refresh(&data.users, &first.refresh).unwrap();
+    assert_eq!(refresh, sessions::Refresh::Current);
+    assert!(sessions::find_refresh(&data.users, &first.access).is_none());
+
+    // Refreshing swaps both tokens and starts the session over
+    let later = now + minutes(50);
+    let second = sessions::rotate(&mut data.users[0].sessions[0], &auth, later);
+    let session = &data.users[0].sessions[0];
+    assert!(sessions::find(&data.users, &first.access).is_none());
+    assert!(sessions::find(&data.users, &second.access).is_some());
+    assert!(!sessions::access_expired(session, later));
+    assert!(!sessions::is_expired(session, now + minutes(90)));
+    let (_, _, refresh) = sessions::find_refresh(&data.users, &second.refresh).unwrap();
+    assert_eq!(refresh, sessions::Refresh::Current);
+    let (_, found, refresh) = sessions::find_refresh(&data.users, &first.refresh).unwrap();
+    assert_eq!(refresh, sessions::Refresh::Replayed);
+    assert_eq!(found.id, session.id);
+
+    // Only so many used tokens are remembered
+    for _ in 0..40 {
+        sessions::rotate(&mut data.users[0].sessions[0], &auth, later);
+    }
+    assert_eq!(data.users[0].sessions[0].used_refresh_hashes.len(), 32);
+
+    // Refresh state is stored, and databases from before it upgrade
+    let db = temp_path("refresh.db");
+    let conn = rusqlite::Connection::open(&db).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE users (id INTEGER PRIMARY KEY, phone TEXT NOT NULL,
+             name TEXT NOT NULL, current_group_id INTEGER NOT NULL);
+         CREATE TABLE sessions (id TEXT PRIMARY KEY,
+             user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+             token_hash TEXT NOT NULL, device TEXT NOT NULL, created_at TEXT NOT NULL,
+             last_used_at TEXT NOT NULL, expires_at TEXT NOT NULL);
+         INSERT INTO users VALUES (1, '5551234567', 'Alice', 0);
+         INSERT INTO sessions VALUES ('old', 1, 'hash', 'Phone', '2024-01-01T00:00:00+00:00',
+             '2024-01-01T00:00:00+00:00', '2099-01-01T00:00:00+00:00');",
+    )
+    .unwrap();
+    drop(conn);
+    let backend = storage::open(&format!("sqlite:{}", db), None).unwrap();
+    let old = &backend.load().unwrap().users[0].sessions[0];
+    assert_eq!(
+        (old.refresh_hash.as_ref(), old.access_expires_at.as_ref()),
+        (None, None)
+    );
+    assert!(!sessions::access_expired(old, now));
+    backend.save(&data).unwrap();
+    let loaded = &backend.load().unwrap().users[0].sessions[0];
+    let session = &data.users[0].sessions[0];
+    assert_eq!(loaded.refresh_hash, session.refresh_hash);
+    assert_eq!(loaded.used_refresh_hashes, session.used_refresh_hashes);
+    assert_eq!(loaded.access_expires_at, session.access_expires_at);
+}
+
+#[test]
+fn test_api_keys_are_scoped_and_stored_hashed() {
+    use axum::http::Method;
+
+    let now = chrono::Utc::now();
+    let mut data = sample_app_data();
+    let user = &mut data.users[0];
+    assert!(api_keys::add(user, "  ", ApiKeyScope::Admin, now).is_err());
+    assert!(api_keys::add(user, &"x".repeat(101), ApiKeyScope::Admin, now).is_err());
+    let (read, read_key) = api_keys::add(user, " Reports ", ApiKeyScope::ReadOnly, now).unwrap();
+    let (_, write_key) = api_keys::add(user, "Import", ApiKeyScope::WriteExpenses, now).unwrap();
+    assert_eq!(read.name, "Reports");
+    assert!(read_key.starts_with(api_keys::KEY_PREFIX));
+    assert_ne!(read.key_hash, read_key);
+    for i in 2..20 {
+        api_keys::add(user, &format!("Key {}", i), ApiKeyScope::ReadOnly, now).unwrap();
+    }
+    assert!(api_keys::add(user, "One too many", ApiKeyScope::ReadOnly, now).is_err());
+
+    let (found_user, found) = api_keys::find(&data.users, &read_key).unwrap();
+    assert_eq!((found_user.id, &found.id), (1, &read.id));
+    assert_eq!(
+        api_keys::find(&data.users, &write_key).unwrap().1.scope,
+        ApiKeyScope::WriteExpenses
+    );
+    assert!(api_keys::find(&data.users, &read.key_hash).is_none());
+    assert!(!format!("{:?}", data.users[0]).contains(&read.key_hash));
+
+    // What each scope may do
+    let cases = [
+        (Method::GET, "/api/balances", [true, true, true]),
+        (Method::POST, "/api/expenses", [false, true, true]),
+        (Method::DELETE, "/api/expenses/3", [false, true, true]),
+        (Method::POST, "/api/settle", [false, true, true]),
+        (Method::PUT, "/api/rates", [false, false, true]),
+        (Method::POST, "/api/auth/api-keys", [false, false, true]),
+    ];
+    let scopes = [
+        ApiKeyScope::ReadOnly,
+        ApiKeyScope::WriteExpenses,
+        ApiKeyScope::Admin,
+    ];
+    for (method, path, allowed) in cases {
+        for (scope, allowed) in scopes.into_iter().zip(allowed) {
+            assert_eq!(
+                api_keys::allows(scope, &method, path),
+                allowed,
+                "{} {} {}",
+                scope,
+                method,
+                path
+            );
+        }
+    }
+
+    // Keys round trip through the backends
+    data.users[0].api_keys[0].last_used_at = Some(now.to_rfc3339());
+    for spec in [
+        temp_path("api_keys.json"),
+        format!("sqlite:{}", temp_path("api_keys.db")),
+    ] {
+        let backend = storage::open(&spec, None).unwrap();
+        backend.save(&data).unwrap();
+        let loaded = backend.load().unwrap();
+        let keys = &loaded.users[0].api_keys;
+        assert_eq!(keys.len(), 20, "{}", spec);
+        assert_eq!(keys[0].key_hash, read.key_hash);
+        assert_eq!(keys[0].last_used_at, Some(now.to_rfc3339()));
+        assert_eq!(keys[1].scope, ApiKeyScope::WriteExpenses);
+    }
+}
+
+#[tokio::test]
+async fn test_only_sessions_manage_api_keys() {
+    use tower::ServiceExt;
+
+    let now = chrono::Utc::now();
+    let mut data = sample_app_data();
+    let user = &mut data.users[0];
+    let (admin, admin_key) = api_keys::add(user, "Admin", ApiKeyScope::Admin, now).unwrap();
+    let (session, tokens) = sessions::start("Laptop", &hour_long_sessions(), now);
+    user.sessions.push(session);
+    let backend = storage::open(&temp_path("data.json"), None).unwrap();
+    let writer = StorageWriter::spawn(backend, Durability::Sync, Duration::ZERO);
+    let state = std::sync::Arc::new(AppState::new(data, writer));
+    let app = axum::Router::new()
+        .route(
+            "/api/auth/api-keys",
+            axum::routing::get(crate::handlers::auth::list_api_keys)
+                .post(crate::handlers::auth::create_api_key),
+        )
+        .route(
+            "/api/auth/api-keys/{id}",
+            axum::routing::delete(crate::handlers::auth::revoke_api_key),
+        )
+        .with_state(state);
+    let send = |method: &str, path: &str, token: &str| {
+        let request = axum::http::Request::builder()
+            .method(method)
+            .uri(path)
+            .header("authorization", format!("Bearer {}", token))
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(
+                r#"{"name": "Minted", "scope": "admin"}"#,
+            ))
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+
+    // An admin key can list keys but not create or revoke them
+    let revoke = format!("/api/auth/api-keys/{}", admin.id);
+    let status = |response: axum::response::Response| response.status().as_u16();
+    assert_eq!(
+        status(send("GET", "/api/auth/api-keys", &admin_key).await.unwrap()),
+        200
+    );
+    assert_eq!(
+        status(
+            send("POST", "/api/auth/api-keys", &admin_key)
+                .await
+                .unwrap()
+        ),
+        401
+    );
+    assert_eq!(
+        status(send("DELETE", &revoke, &admin_key).await.unwrap()),
+        401
+    );
+
+    assert_eq!(
+        status(
+            send("POST", "/api/auth/api-keys", &tokens.access)
+                .await
+                .unwrap()
+        ),
+        200
+    );
+    assert_eq!(
+        status(send("DELETE", &revoke, &tokens.access).await.unwrap()),
+        200
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_rates_belong_to_a_group() {
+    use tower::ServiceExt;
+
+    let now = chrono::Utc::now();
+    let mut data = sample_app_data();
+    let mut other = create_group(vec![create_test_users().1], vec![]);
+    other.id = 2;
+    data.groups.push(other);
+    for (id, name, current_group_id) in [(2, "Bob", 2), (4, "Eve", 1)] {
+        data.users.push(AuthUser {
+            id,
+            phone: format!("555000000{}", id),
+            name: name.to_string(),
+            current_group_id,
+            sessions: vec![],
+            api_keys: vec![],
+        });
+    }
+    let mut tokens = vec![];
+    for user in &mut data.users {
+        let (session, issued) = sessions::start("Laptop", &hour_long_sessions(), now);
+        user.sessions.push(session);
+        tokens.push(issued.access);
+    }
+    let backend = storage::open(&temp_path("data.json"), None).unwrap();
+    let writer = StorageWriter::spawn(backend, Durability::Sync, Duration::ZERO);
+    let state = std::sync::Arc::new(AppState::new(data, writer));
+    let app = axum::Router::new()
+        .route(
+            "/api/rates",
+            axum::routing::get(crate::handlers::rates::list_rates)
+                .put(crate::handlers::rates::set_rate),
+        )
+        .route(
+            "/api/rates/{from}/{to}",
+            axum::routing::delete(crate::handlers::rates::delete_rate),
+        )
+        .with_state(state.clone());
+    let send = |method: &str, path: &str, token: &str| {
+        let request = axum::http::Request::builder()
+            .method(method)
+            .uri(path)
+            .header("authorization", format!("Bearer {}", token))
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(
+                r#"{"from": "GBP", "to": "USD", "rate": 1.3}"#,
+            ))
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+    let status = |response: axum::response::Response| response.status().as_u16();
+    let rates = |id: usize| {
+        let group = state.group(id).unwrap().unwrap();
+        group.read().unwrap().exchange_rates.clone()
+    };
+    let (alice, bob, eve) = (&tokens[0], &tokens[1], &tokens[2]);
+
+    // A rate set in one group is not seen by another
+    assert_eq!(status(send("PUT", "/api/rates", bob).await.unwrap()), 200);
+    assert_eq!(rates(2).len(), 1);
+    assert_eq!(rates(1).len(), 1);
+    assert_eq!(rates(1)[0].from, "EUR");
+
+    // Only members may change a group's rates
+    assert_eq!(status(send("PUT", "/api/rates", eve).await.unwrap()), 404);
+    assert_eq!(
+        status(send("DELETE", "/api/rates/EUR/USD", eve).await.unwrap()),
+        404
+    );
+    assert_eq!(rates(1).len(), 1);
+
+    // A pair can be deleted whichever way round it is named
+    assert_eq!(
+        status(send("DELETE", "/api/rates/USD/EUR", alice).await.unwrap()),
+        200
+    );
+    assert!(rates(1).is_empty());
+    assert_eq!(
+        status(send("DELETE", "/api/rates/USD/EUR", alice).await.unwrap()),
+        404
+    );
+    assert_eq!(rates(2).len(), 1);
+}
+
+#[test]
+fn test_shared_exchange_rates_are_copied_into_groups() {
+    let rate = serde_json::json!({"from": "EUR", "to": "USD", "rate": 1.1,
+        "updated_at": "2024-01-01T00:00:00Z"});
+    let group = |id: usize| {
+        serde_json::json!({"id": id, "name": "Trip", "members": [], "expenses": [],
+            "simplify_debts": false, "currency": "USD"})
+    };
+
+    // A version 5 JSON file
+    let path = temp_path("rates.json");
+    let v5 = serde_json::json!({
+        "schema_version": 5,
+        "groups": [group(1), group(2)],
+        "users": [],
+        "exchange_rates": [rate]
+    });
+    std::fs::write(&path, v5.to_string()).unwrap();
+    let data = storage::JsonFileStorage::new(&path).load().unwrap();
+    for group in &data.groups {
+        assert_eq!(group.exchange_rates.len(), 1);
+        assert_eq!(group.exchange_rates[0].rate, 1.1);
     }
+
+    // Legacy journal events name no group and apply to all of them
+    let mut legacy = data.clone();
+    legacy
+        .groups
+        .iter_mut()
+        .for_each(|g| g.exchange_rates.clear());
+    let event: Event = serde_json::from_value(serde_json::json!({
+        "type": "exchange_rate_deleted", "from": "USD", "to": "EUR"
+    }))
+    .unwrap();
+    let mut expected = data.clone();
+    event.apply(&mut expected);
+    assert_eq!(
+        serde_json::to_value(&expected).unwrap(),
+        serde_json::to_value(&legacy).unwrap()
+    );
+
+    // Version 5 shards keep the rates in a file of their own
+    let dir = temp_path("shards");
+    let backend = storage::open(&format!("shards:{}", dir), None).unwrap();
+    let shard = |value: serde_json::Value| {
+        let mut value = value;
+        value["schema_version"] = serde_json::json!(5);
+        value.to_string()
+    };
+    let dir_path = std::path::Path::new(&dir);
+    std::fs::write(dir_path.join("groups").join("1.json"), shard(group(1))).unwrap();
+    let rates_file = dir_path.join("exchange_rates.json");
+    std::fs::write(
+        &rates_file,
+        shard(serde_json::json!({ "exchange_rates": [rate] })),
+    )
+    .unwrap();
+    let loaded = backend.load().unwrap();
+    assert_eq!(loaded.groups[0].exchange_rates.len(), 1);
+    backend.save(&loaded).unwrap();
+    assert!(!rates_file.exists());
+    let reloaded = backend.load().unwrap();
+    assert_eq!(reloaded.groups[0].exchange_rates.len(), 1);
+
+    // A SQLite database with the shared table
+    let db = temp_path("rates.db");
+    storage::open(&format!("sqlite:{}", db), None)
+        .unwrap()
+        .save(&data)
+        .unwrap();
+    let conn = rusqlite::Connection::open(&db).unwrap();
+    conn.execute_batch(
+        "DELETE FROM group_exchange_rates;
+         CREATE TABLE exchange_rates (from_currency TEXT NOT NULL, to_currency TEXT NOT NULL,
+             rate REAL NOT NULL, updated_at TEXT NOT NULL,
+             PRIMARY KEY (from_currency, to_currency));
+         INSERT INTO exchange_rates VALUES ('EUR', 'USD', 1.1, '2024-01-01T00:00:00Z');",
+    )
+    .unwrap();
+    drop(conn);
+    let loaded = storage::open(&format!("sqlite:{}", db), None)
+        .unwrap()
+        .load()
+        .unwrap();
+    assert_eq!(
+        serde_json::to_value(&loaded).unwrap(),
+        serde_json::to_value(&data).unwrap()
+    );
 }
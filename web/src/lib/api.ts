@@ -1,4 +1,4 @@
-import axios, { AxiosError } from "axios";
+import axios, { AxiosError, type InternalAxiosRequestConfig } from "axios";
 import type {
   User,
   Group,
@@ -32,6 +32,46 @@ api.interceptors.request.use((config) => {
   return config;
 });
 
+export const storeTokens = (response: AuthResponse) => {
+  localStorage.setItem("auth_token", response.token);
+  localStorage.setItem("refresh_token", response.refresh_token);
+};
+
+export const clearTokens = () => {
+  localStorage.removeItem("auth_token");
+  localStorage.removeItem("refresh_token");
+};
+
+// A refresh token is only good once, so concurrent requests share a refresh
+let refreshing: Promise<boolean> | null = null;
+
+const refreshTokens = (): Promise<boolean> => {
+  const refreshToken = localStorage.getItem("refresh_token");
+  if (!refreshToken) {
+    return Promise.resolve(false);
+  }
+  refreshing ??= axios
+    .post<AuthResponse>(
+      `${API_URL}/auth/refresh`,
+      { refresh_token: refreshToken },
+      { timeout: 10000 }
+    )
+    .then((response) => {
+      storeTokens(response.data);
+      logger.debug("session refreshed");
+      return true;
+    })
+    .catch(() => {
+      logger.warn("could not refresh session");
+      clearTokens();
+      return false;
+    })
+    .finally(() => {
+      refreshing = null;
+    });
+  return refreshing;
+};
+
 // Response interceptor for consistent error handling
 api.interceptors.response.use(
   (response) => {
@@ -41,7 +81,18 @@ api.interceptors.response.use(
     });
     return response;
   },
-  (error: AxiosError<{ error: string }>) => {
+  async (error: AxiosError<{ error: string }>) => {
+    // An expired access token is refreshed and the request tried once more
+    const config = error.config as
+      | (InternalAxiosRequestConfig & { retried?: boolean })
+      | undefined;
+    if (error.response?.status === 401 && config && !config.retried) {
+      config.retried = true;
+      if (await refreshTokens()) {
+        return api(config);
+      }
+    }
+
     const message =
       error.response?.data?.error || error.message || "An error occurred";
     logger.error("API error", {
@@ -55,29 +106,51 @@ api.interceptors.response.use(
 
 export interface AuthUser {
   id: number;
-  phone: string;
   name: string;
-  token: string;
   current_group_id: number;
 }
 
+export interface AuthResponse {
+  user: AuthUser;
+  token: string;
+  /** Seconds until `token` has to be refreshed. */
+  expires_in: number;
+  refresh_token: string;
+}
+
+export const requestCode = async (
+  phone: string
+): Promise<{ expires_in: number }> => {
+  logger.info("requesting sign-in code");
+  const response = await api.post("/auth/code", { phone });
+  return response.data;
+};
+
 export const register = async (
   phone: string,
-  name: string
-): Promise<{ user: AuthUser }> => {
-  logger.info("registering user", { phone });
-  const response = await api.post("/auth/register", { phone, name });
+  name: string,
+  code: string
+): Promise<AuthResponse> => {
+  logger.info("registering user");
+  const response = await api.post("/auth/register", { phone, name, code });
   logger.info("user registered", { userId: response.data.user.id });
   return response.data;
 };
 
-export const login = async (phone: string): Promise<{ user: AuthUser }> => {
-  logger.info("logging in", { phone });
-  const response = await api.post("/auth/login", { phone });
+export const login = async (
+  phone: string,
+  code: string
+): Promise<AuthResponse> => {
+  logger.info("logging in");
+  const response = await api.post("/auth/login", { phone, code });
   logger.info("user logged in", { userId: response.data.user.id });
   return response.data;
 };
 
+export const logout = async (): Promise<void> => {
+  await api.post("/auth/logout");
+};
+
 export const getMe = async (): Promise<AuthUser> => {
   const response = await api.get("/auth/me");
   return response.data;
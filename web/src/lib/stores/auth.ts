@@ -2,7 +2,10 @@ import { writable } from "svelte/store";
 import {
   register as apiRegister,
   login as apiLogin,
+  logout as apiLogout,
   getMe,
+  storeTokens,
+  clearTokens,
   type AuthUser,
 } from "../api";
 import { logger } from "../utils/logger";
@@ -28,27 +31,32 @@ function createAuthStore() {
       logger.info("session restored", { userId: me.id });
     } catch {
       logger.warn("token validation failed, clearing token");
-      localStorage.removeItem("auth_token");
+      clearTokens();
     } finally {
       isLoading.set(false);
     }
   }
 
-  async function register(phone: string, name: string) {
-    const response = await apiRegister(phone, name);
-    localStorage.setItem("auth_token", response.user.token);
+  async function register(phone: string, name: string, code: string) {
+    const response = await apiRegister(phone, name, code);
+    storeTokens(response);
     user.set(response.user);
   }
 
-  async function login(phone: string) {
-    const response = await apiLogin(phone);
-    localStorage.setItem("auth_token", response.user.token);
+  async function login(phone: string, code: string) {
+    const response = await apiLogin(phone, code);
+    storeTokens(response);
     user.set(response.user);
   }
 
-  function logout() {
+  async function logout() {
+    try {
+      await apiLogout();
+    } catch {
+      logger.warn("could not end session on the server");
+    }
     logger.info("user logged out");
-    localStorage.removeItem("auth_token");
+    clearTokens();
     user.set(null);
   }
 
@@ -3,15 +3,49 @@ export type User = {
   name: string;
 };
 
+export type ParticipantShare = {
+  name: string;
+  amount: number;
+};
+
+export type Split =
+  | { mode: "equal" }
+  | { mode: "exact"; amounts: Record<string, number> }
+  | { mode: "percentage"; percentages: Record<string, number> }
+  | { mode: "shares"; shares: Record<string, number> };
+
+export type LineItem = {
+  description: string;
+  amount: number;
+  participants: string[];
+};
+
+export type Itemization = {
+  items: LineItem[];
+  tax: number;
+  tip: number;
+};
+
+export type Contribution = {
+  name: string;
+  amount: number;
+};
+
 export type Expense = {
   id: number;
   description: string;
   amount: number;
+  currency?: string | null;
+  exchange_rate?: number | null;
   payer: User;
+  payers: Contribution[];
   participants: User[];
   created_at: string;
   category?: string;
   notes?: string;
+  split: Split;
+  itemization?: Itemization | null;
+  shares: ParticipantShare[];
 };
 
 export type Group = {
@@ -20,19 +54,37 @@ export type Group = {
   members: User[];
   expenses: Expense[];
   simplify_debts: boolean;
+  settlement_algorithm: "greedy" | "optimal";
+  optimal_member_limit: number;
+  settlement_constraints: SettlementConstraints;
+  currency: string;
+};
+
+export type SettlementConstraints = {
+  forbidden_pairs: [string, string][];
+  preferred_receivers: string[];
+  hub?: string | null;
 };
 
+export type SettlementStatus = "outstanding" | "partially_paid" | "paid";
+
 export type Settlement = {
   from: string;
   to: string;
   amount: number;
+  paid: number;
+  total: number;
+  status: SettlementStatus;
   settled: boolean;
 };
 
 export type BalanceResponse = {
   balances: Record<string, number>;
+  currency: string;
 };
 
 export type SettlementsResponse = {
   settlements: Settlement[];
+  currency: string;
+  explanations: string[];
 };